@@ -0,0 +1,249 @@
+//! Client code generation for serco contracts.
+//!
+//! The generator reads the `#[service_contract]` traits and the serde data
+//! types from Rust sources and emits clients for other languages. It can be
+//! used through the `serco-codegen` binary or from a build script:
+//!
+//! ```ignore
+//! serco_codegen::Generator::new()
+//!     .source_file( "src/contract.rs" )?
+//!     .write_typescript( "client/contract.ts" )?;
+//! ```
+//!
+//! The sources are parsed, not compiled, so the types the contracts use must
+//! be declared in the given sources for the generated clients to be fully
+//! typed.
+
+extern crate proc_macro2;
+#[macro_use] extern crate syn;
+#[macro_use] extern crate quote;
+extern crate serco_common;
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use syn::{Item, ItemStruct, ItemEnum, Attribute, Meta, NestedMeta};
+use serco_common::{ServiceContractModel, ServiceContractError};
+
+pub mod typescript;
+
+#[derive(Debug)]
+pub enum CodegenError {
+    Io( io::Error ),
+
+    /// The source file couldn't be parsed.
+    Parse( String ),
+
+    /// A contract trait was malformed.
+    Contract( String, ServiceContractError ),
+}
+
+impl From<io::Error> for CodegenError {
+    fn from( e: io::Error ) -> Self {
+        CodegenError::Io( e )
+    }
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result {
+        match *self {
+            CodegenError::Io( ref e ) => write!( f, "{}", e ),
+            CodegenError::Parse( ref e ) => write!( f, "Failed to parse the source: {}", e ),
+            CodegenError::Contract( ref name, ref e ) =>
+                    write!( f, "Invalid contract '{}': {:?}", name, e ),
+        }
+    }
+}
+
+impl Error for CodegenError {
+    fn description( &self ) -> &str {
+        match *self {
+            CodegenError::Io( ref e ) => e.description(),
+            CodegenError::Parse( .. ) => "Failed to parse the source",
+            CodegenError::Contract( .. ) => "Invalid contract",
+        }
+    }
+}
+
+/// Collects the contracts and types from the sources and generates the
+/// client code for them.
+#[derive(Default)]
+pub struct Generator {
+    contracts: Vec<ServiceContractModel>,
+    structs: Vec<ItemStruct>,
+    enums: Vec<ItemEnum>,
+}
+
+impl Generator {
+    pub fn new() -> Generator {
+        Default::default()
+    }
+
+    /// Reads the contracts and types from a source file.
+    pub fn source_file<P: AsRef<Path>>( self, path: P ) -> Result<Self, CodegenError>
+    {
+        let mut source = String::new();
+        File::open( path )?.read_to_string( &mut source )?;
+        self.source( &source )
+    }
+
+    /// Reads the contracts and types from source code.
+    pub fn source( mut self, source: &str ) -> Result<Self, CodegenError>
+    {
+        let file = syn::parse_file( source )
+                .map_err( |e| CodegenError::Parse( format!( "{}", e ) ) )?;
+        self.collect( file.items )?;
+        Ok( self )
+    }
+
+    pub fn contracts( &self ) -> &[ServiceContractModel] {
+        &self.contracts
+    }
+
+    /// Generates the TypeScript client.
+    pub fn typescript( &self ) -> String {
+        typescript::generate( &self.contracts, &self.structs, &self.enums )
+    }
+
+    /// Writes the TypeScript client into a file.
+    pub fn write_typescript<P: AsRef<Path>>( &self, path: P ) -> Result<(), CodegenError>
+    {
+        File::create( path )?.write_all( self.typescript().as_bytes() )?;
+        Ok( () )
+    }
+
+    fn collect( &mut self, items: Vec<Item> ) -> Result<(), CodegenError>
+    {
+        for item in items {
+            match item {
+                Item::Trait( mut item ) => {
+                    let position = item.attrs.iter()
+                            .position( |a| is_attribute( a, "service_contract" ) );
+                    if let Some( position ) = position {
+                        let attr = item.attrs.remove( position );
+                        let name = item.ident.to_string();
+                        let model = ServiceContractModel::try_from(
+                                attr.tts, quote!( #item ).into() )
+                            .map_err( |e| CodegenError::Contract( name, e ) )?;
+                        self.contracts.push( model );
+                    }
+                },
+                Item::Struct( item ) => if is_serde_type( &item.attrs ) {
+                    self.structs.push( item );
+                },
+                Item::Enum( item ) => if is_serde_type( &item.attrs ) {
+                    self.enums.push( item );
+                },
+                Item::Mod( item ) => if let Some( ( _, content ) ) = item.content {
+                    self.collect( content )?;
+                },
+                _ => {},
+            }
+        }
+        Ok( () )
+    }
+}
+
+fn is_attribute( attr: &Attribute, name: &str ) -> bool
+{
+    attr.path.segments.last()
+        .map( |s| s.value().ident == name )
+        .unwrap_or( false )
+}
+
+/// Checks whether the type derives serde traits and thus may appear in the
+/// contracts.
+fn is_serde_type( attrs: &[Attribute] ) -> bool
+{
+    attrs.iter().any( |a| match a.interpret_meta() {
+        Some( Meta::List( ref list ) ) if list.ident == "derive" =>
+            list.nested.iter().any( |n| match *n {
+                NestedMeta::Meta( Meta::Word( ref word ) ) =>
+                    word == "Serialize" || word == "Deserialize",
+                _ => false,
+            } ),
+        _ => false,
+    } )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    const CONTRACT : &str = r#"
+        #[macro_use] extern crate serco_derive;
+
+        pub mod shapes {
+            /// A shape on the board.
+            #[derive(Serialize, Deserialize)]
+            pub enum Shape {
+                Circle { radius: f64 },
+                Square( f64 ),
+                Empty,
+            }
+        }
+
+        /// Keeps the shapes on a board.
+        #[service_contract(schema)]
+        pub trait Board {
+            /// Adds a shape and returns its index.
+            fn add( &self, shape: Shape ) -> u32;
+            #[idempotent]
+            fn count( &self ) -> u32;
+            fn watch( &self ) -> ServiceStream<Shape>;
+        }
+
+        pub struct BoardService;
+    "#;
+
+    #[test]
+    pub fn generate_file() {
+        let dir = ::std::env::temp_dir();
+        let source = dir.join( format!( "serco_codegen_{}.rs", ::std::process::id() ) );
+        let output = source.with_extension( "ts" );
+        fs::write( &source, CONTRACT ).unwrap();
+
+        let generator = Generator::new().source_file( &source ).unwrap();
+        generator.write_typescript( &output ).unwrap();
+        let ts = fs::read_to_string( &output ).unwrap();
+        fs::remove_file( &source ).ok();
+        fs::remove_file( &output ).ok();
+
+        // The contract attribute arguments and the doc comments are read
+        // from the file.
+        assert_eq!( generator.contracts().len(), 1 );
+        assert!( generator.contracts()[0].schema );
+        assert!( ts.contains( "/**\n * Keeps the shapes on a board.\n */\nexport interface Board {" ) );
+        assert!( ts.contains( "    /**\n     * Adds a shape and returns its index.\n     */\n    add( shape: Shape ): Promise<number>;" ) );
+        assert!( ts.contains( "    count(): Promise<number>;" ) );
+        assert!( ts.contains( "    // 'watch' streams" ) );
+
+        // The types in the modules are generated and the others skipped.
+        assert!( ts.contains( "/**\n * A shape on the board.\n */\nexport type Shape =" ) );
+        assert!( ts.contains( "export class BoardClient implements Board {" ) );
+        assert!( !ts.contains( "BoardService" ) );
+
+        // The output matches the generator that wasn't given a file.
+        assert_eq!( ts, Generator::new().source( CONTRACT ).unwrap().typescript() );
+    }
+
+    #[test]
+    pub fn invalid_sources() {
+        match Generator::new().source( "pub trait {" ) {
+            Err( CodegenError::Parse( _ ) ) => {},
+            _ => panic!( "The source parsed" ),
+        }
+        match Generator::new().source( "#[service_contract(bogus)] pub trait Board {}" ) {
+            Err( CodegenError::Contract( ref name, _ ) ) => assert_eq!( name, "Board" ),
+            _ => panic!( "The contract was accepted" ),
+        }
+        match Generator::new().source_file( "/nonexistent/contract.rs" ) {
+            Err( CodegenError::Io( _ ) ) => {},
+            _ => panic!( "The file was read" ),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Generates clients for the serco contracts in Rust sources.
+//!
+//! Usage: `serco-codegen [-o <output.ts>] <source.rs>...`
+//!
+//! The TypeScript client is written to the standard output unless an output
+//! file is given.
+
+extern crate serco_codegen;
+
+use std::env;
+use std::io::{self, Write};
+use std::process;
+
+use serco_codegen::Generator;
+
+const USAGE : &str = "Usage: serco-codegen [-o <output.ts>] <source.rs>...";
+
+fn main() {
+    let mut output = None;
+    let mut sources = vec![];
+
+    let mut args = env::args().skip( 1 );
+    while let Some( arg ) = args.next() {
+        match arg.as_ref() {
+            "-o" | "--output" => match args.next() {
+                Some( path ) => output = Some( path ),
+                None => fail( USAGE ),
+            },
+            "-h" | "--help" => {
+                println!( "{}", USAGE );
+                return;
+            },
+            _ => sources.push( arg ),
+        }
+    }
+    if sources.is_empty() {
+        fail( USAGE );
+    }
+
+    let mut generator = Generator::new();
+    for source in sources {
+        generator = match generator.source_file( &source ) {
+            Ok( generator ) => generator,
+            Err( e ) => fail( &format!( "{}: {}", source, e ) ),
+        };
+    }
+
+    let result = match output {
+        Some( path ) => generator.write_typescript( path ),
+        None => io::stdout().write_all( generator.typescript().as_bytes() )
+                .map_err( From::from ),
+    };
+    if let Err( e ) = result {
+        fail( &format!( "{}", e ) );
+    }
+}
+
+fn fail( message: &str ) -> ! {
+    let _ = writeln!( io::stderr(), "{}", message );
+    process::exit( 1 );
+}
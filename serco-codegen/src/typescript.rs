@@ -0,0 +1,620 @@
+//! TypeScript client generation.
+//!
+//! Each contract becomes an interface and a client class that implements it
+//! on top of a `Transport`. The generated file contains two transports:
+//!
+//! - `HttpTransport` calls the operations through the `POST /<operation>`
+//!   routes of the HTTP endpoint and keeps the session in the
+//!   `X-Serco-Session` header.
+//! - `WebSocketTransport` exchanges JSON-RPC 2.0 messages over a WebSocket.
+//!   The host calls the callback contract of duplex contracts with requests
+//!   of its own, which the transport dispatches to the handlers it was
+//!   created with.
+//!
+//! Contracts used as callback contracts also get a handler interface and a
+//! function that turns an implementation of it into the transport handlers.
+//!
+//! Streaming operations are not available to the generated clients.
+
+use std::collections::HashSet;
+
+use syn::{Type, ItemStruct, ItemEnum, Fields, Generics, GenericParam,
+        PathArguments, GenericArgument};
+use serco_common::{ServiceContractModel, Operation, doc_comment, serde_attributes,
+        is_option};
+
+/// Generates the TypeScript module for the contracts and types.
+pub fn generate(
+    contracts: &[ServiceContractModel],
+    structs: &[ItemStruct],
+    enums: &[ItemEnum],
+) -> String
+{
+    let mut out = String::new();
+    out.push_str( "// Generated by serco-codegen. Do not edit.\n\n" );
+    out.push_str( RUNTIME );
+
+    for item in structs {
+        out.push_str( "\n" );
+        out.push_str( &struct_type( item ) );
+    }
+    for item in enums {
+        out.push_str( "\n" );
+        out.push_str( &enum_type( item ) );
+    }
+
+    let callbacks : HashSet<String> = contracts.iter()
+            .filter_map( |c| callback_name( c ) )
+            .collect();
+    for contract in contracts {
+        out.push_str( "\n" );
+        out.push_str( &contract_interface( contract ) );
+        out.push_str( "\n" );
+        out.push_str( &contract_client( contract ) );
+        if callbacks.contains( contract.name.as_ref() ) {
+            out.push_str( "\n" );
+            out.push_str( &contract_handler( contract ) );
+        }
+    }
+
+    out
+}
+
+/// Resolves the TypeScript type matching the JSON representation of a Rust
+/// type.
+///
+/// Types not known to serde are referred to by their name and are expected
+/// to be declared in the sources.
+pub fn ts_type( ty: &Type ) -> String
+{
+    match *ty {
+        Type::Path( ref path_ty ) => {
+            let segment = path_ty.path.segments.last()
+                    .expect( "Paths are not empty" )
+                    .into_value();
+            let args : Vec<String> = match segment.arguments {
+                PathArguments::AngleBracketed( ref generics ) => generics.args.iter()
+                        .filter_map( |a| match *a {
+                            GenericArgument::Type( ref t ) => Some( ts_type( t ) ),
+                            _ => None,
+                        } )
+                        .collect(),
+                _ => vec![],
+            };
+            let arg = |i: usize| args.get( i ).cloned()
+                    .unwrap_or_else( || "any".to_string() );
+
+            match segment.ident.as_ref() {
+                "bool" => "boolean".to_string(),
+                "i8" | "i16" | "i32" | "i64" | "isize" |
+                "u8" | "u16" | "u32" | "u64" | "usize" |
+                "f32" | "f64" => "number".to_string(),
+                "String" | "str" | "char" => "string".to_string(),
+                "Value" => "any".to_string(),
+                "Option" => format!( "{} | null", arg( 0 ) ),
+                "Vec" | "VecDeque" | "HashSet" | "BTreeSet" => array_of( &arg( 0 ) ),
+                "HashMap" | "BTreeMap" => format!( "{{ [ key: string ]: {} }}", arg( 1 ) ),
+                "Box" | "Rc" | "Arc" | "Cow" => arg( 0 ),
+                name if args.is_empty() => name.to_string(),
+                name => format!( "{}<{}>", name, args.join( ", " ) ),
+            }
+        },
+        Type::Tuple( ref tuple ) if tuple.elems.is_empty() => "null".to_string(),
+        Type::Tuple( ref tuple ) => format!( "[ {} ]", tuple.elems.iter()
+                .map( ts_type )
+                .collect::<Vec<_>>()
+                .join( ", " ) ),
+        Type::Reference( ref r ) => ts_type( &r.elem ),
+        Type::Paren( ref p ) => ts_type( &p.elem ),
+        Type::Group( ref g ) => ts_type( &g.elem ),
+        Type::Slice( ref s ) => array_of( &ts_type( &s.elem ) ),
+        Type::Array( ref a ) => array_of( &ts_type( &a.elem ) ),
+        _ => "any".to_string(),
+    }
+}
+
+fn array_of( item: &str ) -> String
+{
+    if item.contains( '|' ) {
+        format!( "( {} )[]", item )
+    } else {
+        format!( "{}[]", item )
+    }
+}
+
+fn doc( doc: Option<String>, indent: &str ) -> String
+{
+    match doc {
+        None => String::new(),
+        Some( doc ) => {
+            let mut out = format!( "{}/**\n", indent );
+            for line in doc.lines() {
+                out.push_str( &format!( "{} * {}\n", indent, line ).replace( " \n", "\n" ) );
+            }
+            out.push_str( &format!( "{} */\n", indent ) );
+            out
+        }
+    }
+}
+
+fn type_parameters( generics: &Generics ) -> String
+{
+    let params : Vec<String> = generics.params.iter()
+            .filter_map( |p| match *p {
+                GenericParam::Type( ref t ) => Some( t.ident.to_string() ),
+                _ => None,
+            } )
+            .collect();
+    if params.is_empty() {
+        String::new()
+    } else {
+        format!( "<{}>", params.join( ", " ) )
+    }
+}
+
+/// Formats the fields the way serde represents them.
+fn fields_type( fields: &Fields, indent: &str ) -> String
+{
+    match *fields {
+        Fields::Named( ref named ) => {
+            let mut out = "{\n".to_string();
+            for field in &named.named {
+                let serde = serde_attributes( &field.attrs );
+                if serde.skip {
+                    continue;
+                }
+                let name = serde.rename.unwrap_or_else(
+                        || field.ident.expect( "Named fields have names" ).to_string() );
+                let optional = if serde.default || is_option( &field.ty ) { "?" } else { "" };
+                out.push_str( &doc( doc_comment( &field.attrs ), &format!( "{}    ", indent ) ) );
+                out.push_str( &format!( "{}    {}{}: {};\n",
+                        indent, name, optional, ts_type( &field.ty ) ) );
+            }
+            out.push_str( &format!( "{}}}", indent ) );
+            out
+        },
+        Fields::Unnamed( ref unnamed ) if unnamed.unnamed.len() == 1 =>
+            unnamed.unnamed.iter().map( |f| ts_type( &f.ty ) ).collect(),
+        Fields::Unnamed( ref unnamed ) => format!( "[ {} ]", unnamed.unnamed.iter()
+                .map( |f| ts_type( &f.ty ) )
+                .collect::<Vec<_>>()
+                .join( ", " ) ),
+        Fields::Unit => "null".to_string(),
+    }
+}
+
+fn struct_type( item: &ItemStruct ) -> String
+{
+    let mut out = doc( doc_comment( &item.attrs ), "" );
+    let params = type_parameters( &item.generics );
+    match item.fields {
+        Fields::Named( .. ) => out.push_str( &format!(
+                "export interface {}{} {}\n", item.ident, params,
+                fields_type( &item.fields, "" ) ) ),
+        _ => out.push_str( &format!(
+                "export type {}{} = {};\n", item.ident, params,
+                fields_type( &item.fields, "" ) ) ),
+    }
+    out
+}
+
+/// Formats the enum as a union of the externally tagged variants.
+fn enum_type( item: &ItemEnum ) -> String
+{
+    let variants : Vec<String> = item.variants.iter()
+            .filter_map( |v| {
+                let serde = serde_attributes( &v.attrs );
+                if serde.skip {
+                    return None;
+                }
+                let name = serde.rename.unwrap_or_else( || v.ident.to_string() );
+                Some( match v.fields {
+                    Fields::Unit => format!( "\"{}\"", name ),
+                    ref fields => format!( "{{ \"{}\": {} }}",
+                            name, fields_type( fields, "    " ) ),
+                } )
+            } )
+            .collect();
+
+    let mut out = doc( doc_comment( &item.attrs ), "" );
+    out.push_str( &format!( "export type {}{} =\n    {};\n",
+            item.ident, type_parameters( &item.generics ),
+            if variants.is_empty() { "never".to_string() }
+            else { variants.join( "\n    | " ) } ) );
+    out
+}
+
+fn callback_name( contract: &ServiceContractModel ) -> Option<String>
+{
+    match contract.callback_interface {
+        Type::Path( ref path_ty ) => path_ty.path.segments.last()
+                .map( |s| s.value().ident.to_string() ),
+        _ => None,
+    }
+}
+
+fn parameters( op: &Operation ) -> String
+{
+    op.args.iter()
+        .map( |a| format!( "{}: {}", a.name, ts_type( &a.ty ) ) )
+        .collect::<Vec<_>>()
+        .join( ", " )
+}
+
+fn output_type( op: &Operation ) -> String
+{
+    if op.is_one_way() { "void".to_string() } else { ts_type( &op.output ) }
+}
+
+fn signature( op: &Operation ) -> String
+{
+    let params = parameters( op );
+    if params.is_empty() {
+        format!( "{}()", op.name )
+    } else {
+        format!( "{}( {} )", op.name, params )
+    }
+}
+
+fn streaming_note( op: &Operation ) -> String
+{
+    format!( "    // '{}' streams and is not available to the generated clients.\n",
+             op.name )
+}
+
+fn contract_interface( contract: &ServiceContractModel ) -> String
+{
+    let mut out = doc( contract.doc.clone(), "" );
+    out.push_str( &format!( "export interface {} {{\n", contract.name ) );
+    for op in &contract.operations {
+        if op.is_streaming() {
+            out.push_str( &streaming_note( op ) );
+            continue;
+        }
+        out.push_str( &doc( op.doc.clone(), "    " ) );
+        out.push_str( &format!( "    {}: Promise<{}>;\n",
+                signature( op ), output_type( op ) ) );
+    }
+    out.push_str( "}\n" );
+    out
+}
+
+fn contract_client( contract: &ServiceContractModel ) -> String
+{
+    let name = &contract.name;
+    let mut out = String::new();
+    out.push_str( &format!( "export class {}Client implements {} {{\n", name, name ) );
+    out.push_str( "    constructor( private readonly transport: Transport ) {}\n" );
+
+    match callback_name( contract ) {
+        None => {
+            out.push_str( &format!( "\n    static http( url: string ): {}Client {{\n", name ) );
+            out.push_str( &format!(
+                    "        return new {}Client( new HttpTransport( url ) );\n", name ) );
+            out.push_str( "    }\n" );
+            out.push_str( &format!( "\n    static webSocket( url: string ): {}Client {{\n", name ) );
+            out.push_str( &format!(
+                    "        return new {}Client( new WebSocketTransport( url ) );\n", name ) );
+            out.push_str( "    }\n" );
+        },
+        Some( callback ) => {
+            // Callbacks need a transport the host can call through.
+            out.push_str( &format!(
+                    "\n    static webSocket( url: string, callback: {}Handler ): {}Client {{\n",
+                    callback, name ) );
+            out.push_str( &format!(
+                    "        return new {}Client( new WebSocketTransport( url, {}( callback ) ) );\n",
+                    name, handlers_function( &callback ) ) );
+            out.push_str( "    }\n" );
+        },
+    }
+
+    for op in contract.operations.iter().filter( |op| !op.is_streaming() ) {
+        let params = op.args.iter()
+                .map( |a| format!( "{}: {}", a.name, a.name ) )
+                .collect::<Vec<_>>()
+                .join( ", " );
+        let params = if params.is_empty() { "{}".to_string() }
+                     else { format!( "{{ {} }}", params ) };
+        out.push_str( &format!( "\n    {}: Promise<{}> {{\n",
+                signature( op ), output_type( op ) ) );
+        out.push_str( &format!( "        return this.transport.call( \"{}\", {} );\n",
+                op.name, params ) );
+        out.push_str( "    }\n" );
+    }
+    out.push_str( "}\n" );
+    out
+}
+
+fn handlers_function( contract: &str ) -> String
+{
+    let mut chars = contract.chars();
+    match chars.next() {
+        Some( first ) => format!( "{}{}Handlers",
+                first.to_lowercase().collect::<String>(), chars.as_str() ),
+        None => "handlers".to_string(),
+    }
+}
+
+/// Generates the interface the clients implement to serve the callback
+/// contract and the function that binds the implementation to a transport.
+fn contract_handler( contract: &ServiceContractModel ) -> String
+{
+    let name = &contract.name;
+    let mut out = String::new();
+    out.push_str( &format!( "/**\n * Client side implementation of the {} callback contract.\n */\n",
+            name ) );
+    out.push_str( &format!( "export interface {}Handler {{\n", name ) );
+    for op in &contract.operations {
+        if op.is_streaming() {
+            out.push_str( &streaming_note( op ) );
+            continue;
+        }
+        let output = output_type( op );
+        out.push_str( &doc( op.doc.clone(), "    " ) );
+        out.push_str( &format!( "    {}: {} | Promise<{}>;\n", signature( op ), output, output ) );
+    }
+    out.push_str( "}\n\n" );
+
+    out.push_str( &format!( "export function {}( handler: {}Handler ): CallbackHandlers {{\n",
+            handlers_function( name.as_ref() ), name ) );
+    out.push_str( "    return {\n" );
+    for op in contract.operations.iter().filter( |op| !op.is_streaming() ) {
+        let args = op.args.iter()
+                .map( |a| format!( "params.{}", a.name ) )
+                .collect::<Vec<_>>()
+                .join( ", " );
+        let args = if args.is_empty() { args } else { format!( " {} ", args ) };
+        out.push_str( &format!( "        \"{}\": ( params: any ) => handler.{}({}),\n",
+                op.name, op.name, args ) );
+    }
+    out.push_str( "    };\n" );
+    out.push_str( "}\n" );
+    out
+}
+
+/// The transports and error types shared by all generated clients.
+const RUNTIME : &str = r#"export type ErrorKind =
+    "Internal"
+    | "Transport"
+    | "BadOperation"
+    | "BadRequest"
+    | "Unauthenticated"
+    | "Unauthorized"
+    | "Throttled"
+    | "MessageTooLarge";
+
+export interface ServiceError {
+    kind: ErrorKind;
+    message: string;
+}
+
+/**
+ * Rejection of the calls that failed.
+ */
+export class ServiceCallError extends Error {
+    constructor( public readonly error: ServiceError ) {
+        super( error.message );
+    }
+}
+
+export interface Transport {
+    call( name: string, params: object ): Promise<any>;
+}
+
+/**
+ * Implementations of the callback contract operations by their names.
+ */
+export type CallbackHandlers = { [ name: string ]: ( params: any ) => any };
+
+export const SESSION_HEADER = "X-Serco-Session";
+
+/**
+ * Calls the operations through the HTTP endpoint.
+ *
+ * The first call starts a session the later calls continue.
+ */
+export class HttpTransport implements Transport {
+    private session: string = "new";
+
+    constructor( private readonly url: string ) {}
+
+    async call( name: string, params: object ): Promise<any> {
+        const headers: { [ name: string ]: string } = {
+            "Content-Type": "application/json",
+            [ SESSION_HEADER ]: this.session,
+        };
+
+        let response: Response;
+        try {
+            response = await fetch( this.url.replace( /\/$/, "" ) + "/" + name, {
+                method: "POST",
+                headers: headers,
+                body: JSON.stringify( params ),
+            } );
+        } catch( e ) {
+            throw new ServiceCallError( { kind: "Transport", message: String( e ) } );
+        }
+
+        this.session = response.headers.get( SESSION_HEADER ) || this.session;
+        const body = await response.json();
+        if( !response.ok ) {
+            throw new ServiceCallError( body );
+        }
+        return body;
+    }
+}
+
+const ERROR_CODES: { [ kind: string ]: number } = {
+    Internal: -32603,
+    Transport: -32000,
+    BadOperation: -32601,
+    BadRequest: -32602,
+    Unauthenticated: -32001,
+    Unauthorized: -32002,
+    Throttled: -32003,
+    MessageTooLarge: -32004,
+};
+
+interface PendingCall {
+    resolve: ( value: any ) => void;
+    reject: ( error: ServiceCallError ) => void;
+}
+
+/**
+ * Calls the operations through JSON-RPC 2.0 messages over a WebSocket.
+ *
+ * The connection is the session. The host calls the callback contract with
+ * requests of its own, which are dispatched to the handlers.
+ */
+export class WebSocketTransport implements Transport {
+    private readonly socket: WebSocket;
+    private readonly opened: Promise<void>;
+    private readonly pending: { [ id: number ]: PendingCall } = {};
+    private nextId = 1;
+
+    constructor( url: string, private readonly handlers: CallbackHandlers = {} ) {
+        this.socket = new WebSocket( url );
+        this.opened = new Promise<void>( ( resolve, reject ) => {
+            this.socket.onopen = () => resolve();
+            this.socket.onerror = () => reject( new ServiceCallError(
+                    { kind: "Transport", message: "Failed to connect to " + url } ) );
+        } );
+        this.socket.onmessage = event => this.receive( JSON.parse( event.data ) );
+        this.socket.onclose = () => {
+            for( const id of Object.keys( this.pending ) ) {
+                this.pending[ +id ].reject( new ServiceCallError(
+                        { kind: "Transport", message: "The connection was closed" } ) );
+                delete this.pending[ +id ];
+            }
+        };
+    }
+
+    async call( name: string, params: object ): Promise<any> {
+        await this.opened;
+        const id = this.nextId++;
+        return new Promise( ( resolve, reject ) => {
+            this.pending[ id ] = { resolve: resolve, reject: reject };
+            this.socket.send( JSON.stringify(
+                    { jsonrpc: "2.0", id: id, method: name, params: params } ) );
+        } );
+    }
+
+    close(): void {
+        this.socket.close();
+    }
+
+    private receive( message: any ): void {
+        if( message.method !== undefined ) {
+            this.invoke( message );
+            return;
+        }
+
+        const pending = this.pending[ message.id ];
+        if( pending === undefined ) {
+            return;
+        }
+        delete this.pending[ message.id ];
+
+        if( message.error !== undefined ) {
+            const error: ServiceError = message.error.data !== undefined
+                    ? message.error.data
+                    : { kind: "Internal", message: message.error.message };
+            pending.reject( new ServiceCallError( error ) );
+        } else {
+            pending.resolve( message.result );
+        }
+    }
+
+    private async invoke( message: any ): Promise<void> {
+        let response: object;
+        try {
+            const handler = this.handlers[ message.method ];
+            if( handler === undefined ) {
+                throw new ServiceCallError( {
+                    kind: "BadOperation",
+                    message: "Unknown operation '" + message.method + "'",
+                } );
+            }
+            const result = await handler( message.params || {} );
+            response = { jsonrpc: "2.0", id: message.id,
+                         result: result === undefined ? null : result };
+        } catch( e ) {
+            const error: ServiceError = e instanceof ServiceCallError
+                    ? e.error
+                    : { kind: "Internal", message: String( e ) };
+            response = { jsonrpc: "2.0", id: message.id, error: {
+                code: ERROR_CODES[ error.kind ],
+                message: error.message,
+                data: error,
+            } };
+        }
+
+        // Notifications expect no response.
+        if( message.id !== undefined ) {
+            this.socket.send( JSON.stringify( response ) );
+        }
+    }
+}
+"#;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Generator;
+
+    #[test]
+    pub fn types() {
+        let cases : Vec<( Type, &str )> = vec![
+            ( parse_quote!( u32 ), "number" ),
+            ( parse_quote!( &str ), "string" ),
+            ( parse_quote!( () ), "null" ),
+            ( parse_quote!( Option<String> ), "string | null" ),
+            ( parse_quote!( Vec<Option<i64>> ), "( number | null )[]" ),
+            ( parse_quote!( HashMap<String, bool> ), "{ [ key: string ]: boolean }" ),
+            ( parse_quote!( ( u8, String ) ), "[ number, string ]" ),
+            ( parse_quote!( Box<Point> ), "Point" ),
+            ( parse_quote!( Page<Point> ), "Page<Point>" ),
+        ];
+        for ( ty, expected ) in cases {
+            assert_eq!( ts_type( &ty ), expected );
+        }
+    }
+
+    #[test]
+    pub fn contract_clients() {
+        let ts = Generator::new().source( r#"
+            #[derive(Serialize, Deserialize)]
+            pub struct Point {
+                x: i32,
+                #[serde(rename = "why")]
+                y: i32,
+                label: Option<String>,
+            }
+
+            /// Draws on the canvas.
+            #[service_contract(callback = Canvas)]
+            pub trait Pen {
+                fn line( &self, from: Point, to: Point ) -> u32;
+                fn clear( &self );
+                fn trace( &self ) -> ServiceStream<Point>;
+            }
+
+            #[service_contract]
+            pub trait Canvas {
+                fn drawn( &self, id: u32 ) -> bool;
+            }
+        "# ).unwrap().typescript();
+
+        assert!( ts.contains( "export interface Point {\n    x: number;\n    why: number;\n    label?: string | null;\n}" ) );
+        assert!( ts.contains( "/**\n * Draws on the canvas.\n */\nexport interface Pen {" ) );
+        assert!( ts.contains( "    line( from: Point, to: Point ): Promise<number>;" ) );
+        assert!( ts.contains( "    clear(): Promise<void>;" ) );
+        assert!( ts.contains( "    // 'trace' streams" ) );
+        assert!( ts.contains( "return this.transport.call( \"line\", { from: from, to: to } );" ) );
+        assert!( ts.contains( "static webSocket( url: string, callback: CanvasHandler ): PenClient" ) );
+        assert!( ts.contains( "    drawn( id: number ): boolean | Promise<boolean>;" ) );
+        assert!( ts.contains( "\"drawn\": ( params: any ) => handler.drawn( params.id )," ) );
+        assert!( !ts.contains( "PenHandler" ) );
+    }
+}
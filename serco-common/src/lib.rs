@@ -14,6 +14,7 @@ use syn::*;
 pub enum ServiceContractError {
     BadItem,
     BadArgument,
+    BadAttribute,
 }
 
 #[derive(Debug, PartialEq)]
@@ -21,7 +22,11 @@ pub struct ServiceContractModel {
     pub name: Ident,
     pub callback_interface: Type,
     pub mod_ident: Ident,
-    pub operations : Vec<Operation>
+    pub operations : Vec<Operation>,
+    pub doc: Option<String>,
+
+    /// Whether the contract exports a JSON Schema.
+    pub schema: bool,
 }
 
 #[derive(Debug, PartialEq)]
@@ -29,12 +34,38 @@ pub struct Operation {
     pub name : Ident,
     pub args : Vec<OperationArgument>,
     pub output : Type,
+    pub output_kind : ValueKind,
+    pub idempotent : bool,
+    pub authorize : Vec<Authorization>,
+    pub doc : Option<String>,
+}
+
+/// Requirement of an `#[authorize]` attribute on an operation.
+#[derive(Debug, PartialEq)]
+pub enum Authorization {
+
+    /// `#[authorize(role = "admin")]`
+    Role( String ),
+
+    /// `#[authorize(policy = "path::to::policy")]`
+    Policy( Path ),
 }
 
 #[derive(Debug, PartialEq)]
 pub struct OperationArgument {
     pub name : Ident,
     pub ty : Type,
+    pub kind : ValueKind,
+}
+
+/// Describes whether an argument or a return value is passed as a single
+/// value or as a `ServiceStream<T>` of items.
+#[derive(Debug, PartialEq)]
+pub enum ValueKind {
+    Single,
+
+    /// A stream of values. Contains the item type of the stream.
+    Stream( Type ),
 }
 
 #[derive(Debug, PartialEq)]
@@ -43,6 +74,20 @@ pub enum ServiceError {
     BadAttribute,
 }
 
+/// The implementation of a contract marked with `#[service]`.
+///
+/// The `#[authorize]` attributes of the implementation methods are checked
+/// in addition to the ones of the contract operations.
+#[derive(Debug, PartialEq)]
+pub struct ServiceImplModel {
+
+    /// The impl block without the `#[authorize]` attributes.
+    pub item: ItemImpl,
+
+    /// The methods that have `#[authorize]` attributes.
+    pub operations: Vec<( Ident, Vec<Authorization> )>,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct ServiceModel {
     pub name: Ident,
@@ -65,6 +110,8 @@ impl ServiceContractModel {
         let mod_ident = Ident::from( format!( "{}_impl_mod", input.ident ) );
 
         Ok( ServiceContractModel {
+            doc: doc_comment( &input.attrs ),
+            schema: args.schema,
             name: input.ident,
             mod_ident: mod_ident,
             callback_interface: args.callback_interface,
@@ -84,16 +131,72 @@ impl Operation {
         method : TraitItemMethod
     ) -> Result<Operation, ServiceContractError>
     {
+        let idempotent = method.attrs.iter()
+                .any( |a| is_operation_attribute( a, "idempotent" ) );
+        let authorize = method.attrs.iter()
+                .filter( |a| is_operation_attribute( a, "authorize" ) )
+                .map( Authorization::try_from )
+                .collect::<Result<Vec<_>, _>>()?;
         let mut arg_iter = method.sig.decl.inputs.into_iter();
         let _self_arg = arg_iter.next();
+        let output = method.sig.decl.output.to_type();
         Ok( Operation {
+            doc: doc_comment( &method.attrs ),
             name: method.sig.ident,
+            idempotent: idempotent,
+            authorize: authorize,
             args: arg_iter
                     .map( |i| OperationArgument::try_from( i ) )
                     .collect::<Result<Vec<_>, _>>()?,
-            output: method.sig.decl.output.to_type()
+            output_kind: ValueKind::from_type( &output ),
+            output: output,
         } )
     }
+
+    /// Checks whether the operation has stream arguments or a stream
+    /// return value.
+    pub fn is_streaming( &self ) -> bool {
+        self.output_kind != ValueKind::Single ||
+            self.args.iter().any( |a| a.kind != ValueKind::Single )
+    }
+
+    /// Checks whether the operation returns nothing.
+    pub fn is_one_way( &self ) -> bool {
+        match self.output {
+            Type::Tuple( ref tuple ) => tuple.elems.is_empty(),
+            _ => false,
+        }
+    }
+}
+
+impl Authorization {
+
+    pub fn try_from(
+        attr : &Attribute
+    ) -> Result<Authorization, ServiceContractError>
+    {
+        let nested = match attr.interpret_meta() {
+            Some( Meta::List( ref list ) ) if list.nested.len() == 1 =>
+                list.nested.first().expect( "Length checked" ).into_value().clone(),
+            _ => return Err( ServiceContractError::BadAttribute ),
+        };
+        let ( name, value ) = match nested {
+            NestedMeta::Meta( Meta::NameValue( MetaNameValue {
+                ident, lit: Lit::Str( ref value ), ..
+            } ) ) => ( ident, value.value() ),
+            _ => return Err( ServiceContractError::BadAttribute ),
+        };
+
+        if name == "role" {
+            Ok( Authorization::Role( value ) )
+        } else if name == "policy" {
+            syn::parse_str( &value )
+                .map( Authorization::Policy )
+                .map_err( |_| ServiceContractError::BadAttribute )
+        } else {
+            Err( ServiceContractError::BadAttribute )
+        }
+    }
 }
 
 impl OperationArgument {
@@ -112,11 +215,45 @@ impl OperationArgument {
         };
         Ok( OperationArgument {
             name: ident,
+            kind: ValueKind::from_type( &arg.ty ),
             ty: arg.ty,
         } )
     }
 }
 
+impl ValueKind {
+
+    /// Resolves the kind from the type.
+    ///
+    /// Streams are recognized by the `ServiceStream<T>` type name. The path
+    /// to the type doesn't matter so both `ServiceStream<T>` and
+    /// `serco::ServiceStream<T>` are considered streams.
+    pub fn from_type( ty : &Type ) -> ValueKind
+    {
+        let path = match *ty {
+            Type::Path( ref path_ty ) => &path_ty.path,
+            _ => return ValueKind::Single,
+        };
+
+        let segment = path.segments.last()
+                .expect( "Paths are not empty" )
+                .into_value();
+        if segment.ident != "ServiceStream" {
+            return ValueKind::Single;
+        }
+
+        match segment.arguments {
+            PathArguments::AngleBracketed( ref generics ) =>
+                match generics.args.first().map( |a| a.into_value() ) {
+                    Some( &GenericArgument::Type( ref item ) )
+                        => ValueKind::Stream( item.clone() ),
+                    _ => ValueKind::Single,
+                },
+            _ => ValueKind::Single,
+        }
+    }
+}
+
 impl ServiceModel {
 
     pub fn try_from(
@@ -151,8 +288,189 @@ impl ServiceModel {
     }
 }
 
+impl ServiceImplModel {
+
+    pub fn try_from(
+        tokens : TokenStream,
+    ) -> Result<ServiceImplModel, ServiceError>
+    {
+        let mut item : ItemImpl = syn::parse2( tokens )
+                .map_err( |_| ServiceError::BadItem )?;
+        if item.trait_.is_none() {
+            return Err( ServiceError::BadItem );
+        }
+
+        let mut operations = vec![];
+        for impl_item in &mut item.items {
+            if let ImplItem::Method( ref mut method ) = *impl_item {
+                let authorize = method.attrs.iter()
+                        .filter( |a| is_operation_attribute( a, "authorize" ) )
+                        .map( Authorization::try_from )
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err( |_| ServiceError::BadAttribute )?;
+                method.attrs.retain( |a| !is_operation_attribute( a, "authorize" ) );
+                if !authorize.is_empty() {
+                    operations.push( ( method.sig.ident, authorize ) );
+                }
+            }
+        }
+
+        Ok( ServiceImplModel {
+            item: item,
+            operations: operations,
+        } )
+    }
+}
+
+/// Attributes that serco recognizes on the contract operations.
+const OPERATION_ATTRIBUTES : &[&str] = &[ "idempotent", "authorize" ];
+
+fn is_operation_attribute( attr : &Attribute, name : &str ) -> bool
+{
+    attr.path.segments.len() == 1 &&
+        attr.path.segments.first()
+            .expect( "Paths are not empty" )
+            .value()
+            .ident == name
+}
+
+/// Collects the doc comment from the attributes.
+///
+/// Returns `None` if there are no doc comments.
+pub fn doc_comment( attrs : &[Attribute] ) -> Option<String>
+{
+    let lines : Vec<String> = attrs.iter()
+            .filter_map( |a| match a.interpret_meta() {
+                Some( Meta::NameValue( ref nv ) ) if nv.ident == "doc" =>
+                    match nv.lit {
+                        Lit::Str( ref s ) => Some( s.value() ),
+                        _ => None,
+                    },
+                _ => None,
+            } )
+            .map( |line| {
+                // Sources parsed from a string keep the slashes of the sugared
+                // doc comments and all of them keep the space after the slashes.
+                let line = if line.starts_with( "///" ) || line.starts_with( "//!" ) {
+                    line[ 3.. ].to_string()
+                } else {
+                    line
+                };
+                if line.starts_with( ' ' ) { line[ 1.. ].to_string() } else { line }
+            } )
+            .collect();
+
+    if lines.is_empty() {
+        None
+    } else {
+        Some( lines.join( "\n" ) )
+    }
+}
+
+/// The serde attributes that affect the JSON representation of a field or
+/// a variant.
+#[derive(Debug, Default, PartialEq)]
+pub struct SerdeAttributes {
+    pub rename: Option<String>,
+    pub skip: bool,
+    pub default: bool,
+}
+
+/// Collects the serde attributes that affect the JSON representation.
+pub fn serde_attributes( attrs : &[Attribute] ) -> SerdeAttributes
+{
+    let mut result = SerdeAttributes::default();
+    let lists = attrs.iter().filter_map( |a| match a.interpret_meta() {
+        Some( Meta::List( list ) ) if list.ident == "serde" => Some( list ),
+        _ => None,
+    } );
+
+    for list in lists {
+        for nested in list.nested {
+            match nested {
+                NestedMeta::Meta( Meta::Word( ref word ) ) => {
+                    if word == "skip" || word == "skip_serializing" {
+                        result.skip = true;
+                    } else if word == "default" {
+                        result.default = true;
+                    }
+                },
+                NestedMeta::Meta( Meta::NameValue( ref nv ) ) => {
+                    if let Lit::Str( ref value ) = nv.lit {
+                        if nv.ident == "rename" {
+                            result.rename = Some( value.value() );
+                        } else if nv.ident == "default" {
+                            result.default = true;
+                        }
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    result
+}
+
+/// Checks whether the type is an `Option<T>`, which serde allows to be
+/// missing.
+pub fn is_option( ty : &Type ) -> bool
+{
+    match *ty {
+        Type::Path( ref path_ty ) => path_ty.path.segments.last()
+                .map( |s| s.value().ident == "Option" )
+                .unwrap_or( false ),
+        _ => false,
+    }
+}
+
+/// Formats a type the way it is written in the source.
+///
+/// Used to report the argument and return types of the operations.
+pub fn type_name( ty : &Type ) -> String
+{
+    let tokens = quote!( #ty ).to_string();
+    let mut name = String::with_capacity( tokens.len() );
+    let mut prev = ' ';
+    for c in tokens.chars() {
+        let next_is_punct = "<>,:)]".contains( c );
+        if name.ends_with( ' ' ) && ( next_is_punct || "<&:([".contains( prev ) ) {
+            name.pop();
+        }
+        name.push( c );
+        if c != ' ' {
+            prev = c;
+        }
+    }
+
+    // Separate the arguments of the generic types for readability.
+    name.replace( ",", ", " ).replace( ",  ", ", " ).trim().to_string()
+}
+
+/// Removes the serco operation attributes from the contract trait.
+///
+/// The attributes are only meaningful to the `service_contract` attribute
+/// and would be reported as unknown attributes if left on the trait.
+pub fn strip_operation_attributes(
+    tokens : TokenStream
+) -> Result<TokenStream, ServiceContractError>
+{
+    let mut input : ItemTrait = syn::parse2( tokens )
+            .map_err( |_| ServiceContractError::BadItem )?;
+
+    for item in &mut input.items {
+        if let TraitItem::Method( ref mut method ) = *item {
+            method.attrs.retain( |a| !OPERATION_ATTRIBUTES.iter()
+                    .any( |name| is_operation_attribute( a, name ) ) );
+        }
+    }
+
+    Ok( quote!( #input ).into() )
+}
+
 struct ServiceContractAttributeArgs {
     callback_interface: Type,
+    schema: bool,
 }
 
 impl synom::Synom for ServiceContractAttributeArgs {
@@ -170,13 +488,15 @@ impl synom::Synom for ServiceContractAttributeArgs {
 }
 
 enum ServiceContractAttributeArg {
-    Callback( Type )
+    Callback( Type ),
+    Schema,
 }
 
 impl Default for ServiceContractAttributeArgs {
     fn default() -> Self {
         ServiceContractAttributeArgs {
-            callback_interface: parse_quote!( () )
+            callback_interface: parse_quote!( () ),
+            schema: false,
         }
     }
 }
@@ -191,6 +511,7 @@ impl<I> From<I> for ServiceContractAttributeArgs
         for arg in src {
             match arg {
                 Callback( t ) => result.callback_interface = t,
+                Schema => result.schema = true,
             }
         }
 
@@ -201,13 +522,15 @@ impl<I> From<I> for ServiceContractAttributeArgs
 impl synom::Synom for ServiceContractAttributeArg {
     named!(parse -> Self, do_parse!(
             name: syn!(Ident) >>
-            punct!(=) >>
             arg: switch!( value!( name.as_ref() ),
-                "callback" => map!(
-                    syn!(Type),
-                    ServiceContractAttributeArg::Callback
+                "callback" => do_parse!(
+                    punct!(=) >>
+                    ty: syn!(Type) >>
+                    ( ServiceContractAttributeArg::Callback( ty ) )
                 )
                 |
+                "schema" => value!( ServiceContractAttributeArg::Schema )
+                |
                 _ => reject!()
             ) >>
             ( arg )
@@ -314,28 +637,41 @@ mod test {
             name: Ident::from( "SomeContract" ),
             mod_ident: Ident::from( "SomeContract_impl_mod" ),
             callback_interface: parse_quote!( () ),
+            doc: None,
+            schema: false,
             operations: vec![
                 Operation {
                     name: Ident::from( "op_1" ),
                     output: parse_quote!( String ),
+                    output_kind: ValueKind::Single,
+                    idempotent: false,
+                    authorize: vec![],
+                    doc: None,
                     args: vec![
                         OperationArgument {
                             name: Ident::from( "a" ),
                             ty: parse_quote!( u32 ),
+                            kind: ValueKind::Single,
                         },
                         OperationArgument {
                             name: Ident::from( "b" ),
                             ty: parse_quote!( bool ),
+                            kind: ValueKind::Single,
                         },
                     ],
                 },
                 Operation {
                     name: Ident::from( "op_2" ),
                     output: parse_quote!( () ),
+                    output_kind: ValueKind::Single,
+                    idempotent: false,
+                    authorize: vec![],
+                    doc: None,
                     args: vec![
                         OperationArgument {
                             name: Ident::from( "something" ),
                             ty: parse_quote!( String ),
+                            kind: ValueKind::Single,
                         },
                     ],
                 },
@@ -356,7 +692,218 @@ mod test {
             name: Ident::from( "SomeContract" ),
             mod_ident: Ident::from( "SomeContract_impl_mod" ),
             callback_interface: parse_quote!( CallbackItf ),
-            operations: vec![]
+            operations: vec![],
+            doc: None,
+            schema: false,
+        } );
+    }
+
+    #[test]
+    pub fn streaming_operations() {
+        let model = ServiceContractModel::try_from(
+            quote!().into(),
+            quote!( trait SomeContract {
+                fn upload( &self, name: String, data: ServiceStream<u8> ) -> u32;
+                fn chat( &self, input: serco::ServiceStream<String> )
+                    -> serco::ServiceStream<String>;
+                fn plain( &self ) -> Vec<u8>;
+            } ).into()
+        ).unwrap();
+
+        let upload = &model.operations[0];
+        assert_eq!( upload.output_kind, ValueKind::Single );
+        assert_eq!( upload.args[0].kind, ValueKind::Single );
+        assert_eq!( upload.args[1].kind, ValueKind::Stream( parse_quote!( u8 ) ) );
+        assert!( upload.is_streaming() );
+
+        let chat = &model.operations[1];
+        assert_eq!( chat.output_kind, ValueKind::Stream( parse_quote!( String ) ) );
+        assert_eq!( chat.args[0].kind, ValueKind::Stream( parse_quote!( String ) ) );
+        assert!( chat.is_streaming() );
+
+        let plain = &model.operations[2];
+        assert_eq!( plain.output_kind, ValueKind::Single );
+        assert!( !plain.is_streaming() );
+    }
+
+    #[test]
+    pub fn idempotent_operations() {
+        let tokens = quote!( trait SomeContract {
+            #[idempotent]
+            fn get( &self ) -> String;
+
+            /// Not idempotent.
+            fn set( &self, value: String );
         } );
+
+        let model = ServiceContractModel::try_from(
+            quote!().into(),
+            tokens.clone().into()
+        ).unwrap();
+        assert!( model.operations[0].idempotent );
+        assert!( !model.operations[1].idempotent );
+
+        let stripped = strip_operation_attributes( tokens.into() ).unwrap();
+        let stripped : ItemTrait = syn::parse2( stripped ).unwrap();
+        let attrs : Vec<_> = stripped.items.iter().map( |i| match *i {
+            TraitItem::Method( ref m ) => m.attrs.len(),
+            _ => panic!( "Unexpected item" ),
+        } ).collect();
+
+        // The doc comment on 'set' must be preserved.
+        assert_eq!( attrs, vec![ 0, 1 ] );
+    }
+
+    #[test]
+    pub fn authorized_operations() {
+        let tokens = quote!( trait SomeContract {
+            #[authorize(role = "admin")]
+            #[authorize(policy = "policies::is_owner")]
+            fn reset( &self );
+
+            fn get( &self ) -> String;
+        } );
+
+        let model = ServiceContractModel::try_from(
+            quote!().into(),
+            tokens.clone().into()
+        ).unwrap();
+        assert_eq!( model.operations[0].authorize, vec![
+            Authorization::Role( "admin".to_string() ),
+            Authorization::Policy( parse_quote!( policies::is_owner ) ),
+        ] );
+        assert!( model.operations[1].authorize.is_empty() );
+
+        let stripped = strip_operation_attributes( tokens.into() ).unwrap();
+        let stripped : ItemTrait = syn::parse2( stripped ).unwrap();
+        match stripped.items[ 0 ] {
+            TraitItem::Method( ref m ) => assert!( m.attrs.is_empty() ),
+            _ => panic!( "Unexpected item" ),
+        }
+
+        let bad = ServiceContractModel::try_from(
+            quote!().into(),
+            quote!( trait SomeContract {
+                #[authorize(admin)]
+                fn reset( &self );
+            } ).into() );
+        assert_eq!( bad, Err( ServiceContractError::BadAttribute ) );
+    }
+
+    #[test]
+    pub fn authorized_implementation() {
+        let model = ServiceImplModel::try_from( quote!(
+            impl SomeContract for SomeService {
+                #[authorize(role = "admin")]
+                #[inline]
+                fn reset( &self ) {}
+
+                fn get( &self ) -> String { String::new() }
+            }
+        ).into() ).unwrap();
+
+        assert_eq!( model.operations, vec![
+            ( Ident::from( "reset" ), vec![ Authorization::Role( "admin".to_string() ) ] ),
+        ] );
+        match model.item.items[ 0 ] {
+            ImplItem::Method( ref m ) => assert_eq!( m.attrs.len(), 1 ),
+            _ => panic!( "Unexpected item" ),
+        }
+
+        let inherent = ServiceImplModel::try_from( quote!( impl SomeService {} ).into() );
+        assert_eq!( inherent, Err( ServiceError::BadItem ) );
+        let bad = ServiceImplModel::try_from( quote!(
+            impl SomeContract for SomeService {
+                #[authorize(admin)]
+                fn reset( &self ) {}
+            }
+        ).into() );
+        assert_eq!( bad, Err( ServiceError::BadAttribute ) );
+    }
+
+    #[test]
+    pub fn doc_comments() {
+        let model = ServiceContractModel::try_from(
+            quote!().into(),
+            quote!(
+                /// Does things.
+                ///
+                /// In detail.
+                trait SomeContract {
+                    /// Gets a value.
+                    #[idempotent]
+                    fn get( &self ) -> String;
+                    fn set( &self, value: String );
+                }
+            ).into()
+        ).unwrap();
+
+        assert_eq!( model.doc, Some( "Does things.\n\nIn detail.".to_string() ) );
+        assert_eq!( model.operations[0].doc, Some( "Gets a value.".to_string() ) );
+        assert_eq!( model.operations[1].doc, None );
+        assert!( !model.operations[0].is_one_way() );
+        assert!( model.operations[1].is_one_way() );
+    }
+
+    #[test]
+    pub fn schema_contract() {
+        let model = ServiceContractModel::try_from(
+            quote!( ( callback = CallbackItf, schema ) ).into(),
+            quote!( trait SomeContract {} ).into()
+        ).unwrap();
+
+        assert!( model.schema );
+        assert_eq!( model.callback_interface, parse_quote!( CallbackItf ) );
+    }
+
+    #[test]
+    pub fn serde_field_attributes() {
+        let item : ItemStruct = parse_quote!( struct S {
+            #[serde(rename = "b")]
+            a: u32,
+            #[serde(skip)]
+            c: u32,
+            #[serde(default, rename = "e")]
+            d: Option<u32>,
+            f: u32,
+        } );
+
+        let attrs : Vec<_> = item.fields.iter()
+                .map( |f| serde_attributes( &f.attrs ) )
+                .collect();
+        assert_eq!( attrs, vec![
+            SerdeAttributes { rename: Some( "b".to_string() ), .. Default::default() },
+            SerdeAttributes { skip: true, .. Default::default() },
+            SerdeAttributes {
+                rename: Some( "e".to_string() ),
+                default: true,
+                .. Default::default()
+            },
+            Default::default(),
+        ] );
+
+        let options : Vec<_> = item.fields.iter().map( |f| is_option( &f.ty ) ).collect();
+        assert_eq!( options, vec![ false, false, true, false ] );
+    }
+
+    #[test]
+    pub fn type_names() {
+        let names : Vec<_> = vec![
+            parse_quote!( u32 ),
+            parse_quote!( () ),
+            parse_quote!( Vec<String> ),
+            parse_quote!( HashMap<String, Vec<u8>> ),
+            parse_quote!( serco::ServiceStream<String> ),
+            parse_quote!( &str ),
+        ].iter().map( type_name ).collect();
+
+        assert_eq!( names, vec![
+            "u32",
+            "()",
+            "Vec<String>",
+            "HashMap<String, Vec<u8>>",
+            "serco::ServiceStream<String>",
+            "&str",
+        ] );
     }
 }
@@ -0,0 +1,99 @@
+#![feature(proc_macro)]
+
+extern crate serco;
+use serco::prelude::*;
+
+extern crate serco_mpsc;
+use serco_mpsc::*;
+
+#[macro_use] extern crate serde_derive;
+
+#[macro_use] extern crate futures;
+use futures::prelude::*;
+
+extern crate tokio;
+use tokio::executor::current_thread;
+
+// Service contracts
+
+#[service_contract( callback = Notifications )]
+pub trait ChatService {
+    fn publish( &self, message: String );
+}
+
+#[service_contract]
+pub trait Notifications {
+    fn notify( &self, message: String );
+}
+
+// Service implementations
+
+/// Chat service that pushes every published message to all connected
+/// sessions.
+#[service(ChatService)]
+struct MyChatService {
+    sessions: serco::ConnectedSessions<Notifications>,
+}
+impl ChatService for MyChatService {
+    fn publish( &self, message: String ) {
+        self.sessions.broadcast( |id, callback| {
+            println!( "Notifying session '{}'", id );
+            callback.notify( message.clone() );
+        } );
+    }
+}
+
+/// Notification handler provided by the clients.
+#[service(Notifications)]
+struct MyNotifications( &'static str );
+impl Notifications for MyNotifications {
+    fn notify( &self, message: String ) {
+        println!( "{} received: {}", self.0, message );
+    }
+}
+
+/// Runs the service.
+fn run_service() -> Box< Future<Item=(), Error=()> >
+{
+    let sessions = serco::ConnectedSessions::new();
+    let service = MyChatService { sessions: sessions.clone() };
+
+    let future = serco::ServiceHost::new( ChatService::singleton( service ) )
+        .connected_sessions( sessions )
+        .endpoint( MpscEndpoint::new( "chat" ) )
+        .run()
+        .map( |_| println!( "Service shut down." ) )
+        .map_err( |e| println!( "Service aborted: {:?}", e ) );
+
+    Box::new( future )
+}
+
+/// Executes the clients.
+fn run_clients() -> Box< Future<Item=(), Error=()> >
+{
+    let client = MpscClient::new( "chat" );
+    let future = client.connect_duplex::<ChatService, _, _>( MyNotifications( "Alice" ) )
+        .join( client.connect_duplex::<ChatService, _, _>( MyNotifications( "Bob" ) ) )
+        .map( |( alice, _bob )| {
+            alice.publish( "Hello everyone!".to_string() );
+        } )
+        .map_err( |e| println!( "Client encountered error: {:?}", e ) );
+
+    Box::new( future )
+}
+
+fn main() {
+
+    // Run the service in a thread.
+    std::thread::spawn( move || {
+        current_thread::run( |_| {
+            current_thread::spawn( run_service() )
+        } )
+    } );
+
+    // Run the clients.
+    std::thread::sleep( std::time::Duration::from_millis( 10 ) );
+    current_thread::run( |_| {
+        current_thread::spawn( run_clients() )
+    } )
+}
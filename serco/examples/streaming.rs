@@ -0,0 +1,98 @@
+#![feature(proc_macro)]
+
+extern crate serco;
+use serco::prelude::*;
+
+extern crate serco_mpsc;
+use serco_mpsc::*;
+
+#[macro_use] extern crate serde_derive;
+
+#[macro_use] extern crate futures;
+use futures::prelude::*;
+
+extern crate tokio;
+use tokio::executor::current_thread;
+
+// Service contracts
+
+#[service_contract]
+pub trait StreamingService {
+
+    /// Client streaming: Receives the chunks and returns the total length.
+    fn upload( &self, name: String, chunks: ServiceStream<String> ) -> String;
+
+    /// Bidirectional streaming: Echoes the lines back in upper case.
+    fn shout( &self, lines: ServiceStream<String> ) -> ServiceStream<String>;
+}
+
+// Service implementations
+
+#[service(StreamingService)]
+struct MyStreamingService;
+impl StreamingService for MyStreamingService {
+
+    fn upload( &self, name: String, chunks: ServiceStream<String> ) -> String {
+        let total = chunks
+            .fold( 0, |total, chunk| Ok::<_, serco::ServiceError>( total + chunk.len() ) )
+            .wait()
+            .unwrap();
+        format!( "{}: {} bytes", name, total )
+    }
+
+    fn shout( &self, lines: ServiceStream<String> ) -> ServiceStream<String> {
+        Box::new( lines.map( |line| line.to_uppercase() ) )
+    }
+}
+
+/// Runs the service.
+fn run_service() -> Box< Future<Item=(), Error=()> >
+{
+    let future = serco::ServiceHost::new( StreamingService::singleton( MyStreamingService ) )
+        .endpoint( MpscEndpoint::new( "streaming" ) )
+        .run()
+        .map( |_| println!( "Service shut down." ) )
+        .map_err( |e| println!( "Service aborted: {:?}", e ) );
+
+    Box::new( future )
+}
+
+/// Executes the client.
+fn run_client() -> Box< Future<Item=(), Error=()> >
+{
+    let future = MpscClient::new( "streaming" ).connect::<StreamingService>()
+        .map( |conn| {
+
+            println!( "Connection established." );
+
+            let chunks = vec![ "Hello".to_string(), "World".to_string() ];
+            let result = conn.upload(
+                    "greeting".to_string(),
+                    Box::new( futures::stream::iter_ok( chunks ) ) );
+            println!( "Uploaded: {}", result );
+
+            let lines = vec![ "foo".to_string(), "bar".to_string() ];
+            for line in conn.shout( Box::new( futures::stream::iter_ok( lines ) ) ).wait() {
+                println!( "Received: {}", line.unwrap() );
+            }
+        } )
+        .map_err( |e| println!( "Client encountered error: {:?}", e ) );
+
+    Box::new( future )
+}
+
+fn main() {
+
+    // Run the service in a thread.
+    std::thread::spawn( move || {
+        current_thread::run( |_| {
+            current_thread::spawn( run_service() )
+        } )
+    } );
+
+    // Run the client.
+    std::thread::sleep( std::time::Duration::from_millis( 10 ) );
+    current_thread::run( |_| {
+        current_thread::spawn( run_client() )
+    } )
+}
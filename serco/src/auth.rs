@@ -0,0 +1,347 @@
+//! Authentication of the clients.
+//!
+//! The endpoints collect the credentials of each connection, such as the
+//! token in the `Authorization` header, the certificate the client presented
+//! over TLS or the credentials of the process at the other end of a Unix
+//! domain socket. The `Authenticator` of the host turns the credentials into
+//! a `Principal` that the session factory stores in the session. The
+//! connections the authenticator rejects receive an `Unauthenticated` error
+//! instead of a session.
+//!
+//! Hosts without an authenticator accept all connections.
+//!
+//! The contract operations marked with `#[authorize(role = "admin")]` or
+//! `#[authorize(policy = "path::to::policy")]` are only invoked for the
+//! principals with the role or the ones the policy function accepts. The
+//! other calls fail with an `Unauthorized` error. Each attribute must be
+//! satisfied when an operation has several of them. The policy functions
+//! take the principal and return whether it may call the operation:
+//!
+//! ```
+//! # use serco::Principal;
+//! fn is_alice( principal: &Principal ) -> bool { principal.name == "alice" }
+//! ```
+//!
+//! The methods of the contract implementations take the same attributes
+//! when the impl block is marked with `#[service]`. They are checked after
+//! the ones of the contract:
+//!
+//! ```ignore
+//! #[service]
+//! impl Account for AccountService {
+//!     #[authorize(role = "admin")]
+//!     fn close( &self ) -> bool { true }
+//! }
+//! ```
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use super::{ServiceError, PeerIdentity, SessionInfo, SessionFactory};
+use super::sessions::new_session_id;
+
+/// Credentials presented by a connecting client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credentials {
+
+    /// The client presented no credentials.
+    Anonymous,
+
+    /// Bearer token.
+    Token( String ),
+
+    /// User name and password.
+    Password { username: String, password: String },
+
+    /// Certificate verified by the transport.
+    Certificate( PeerIdentity ),
+
+    /// Credentials of the local process at the other end of the connection
+    /// as reported by the operating system.
+    Peer { pid: Option<u32>, uid: u32, gid: u32 },
+}
+
+impl Credentials {
+
+    /// Parses the value of an HTTP `Authorization` header.
+    ///
+    /// Supports the `Bearer` and the `Basic` schemes.
+    pub fn from_authorization( header: &str ) -> Option<Credentials> {
+        let mut parts = header.trim().splitn( 2, ' ' );
+        let scheme = parts.next().unwrap_or( "" );
+        let value = parts.next().unwrap_or( "" ).trim();
+        if scheme.eq_ignore_ascii_case( "Bearer" ) {
+            return Some( Credentials::Token( value.to_string() ) );
+        }
+        if !scheme.eq_ignore_ascii_case( "Basic" ) {
+            return None;
+        }
+
+        let decoded = base64_decode( value )?;
+        let decoded = String::from_utf8( decoded ).ok()?;
+        let mut parts = decoded.splitn( 2, ':' );
+        Some( Credentials::Password {
+            username: parts.next().unwrap_or( "" ).to_string(),
+            password: parts.next()?.to_string(),
+        } )
+    }
+
+    /// Formats the credentials as the value of an HTTP `Authorization`
+    /// header. Only the tokens and the passwords have one.
+    pub fn to_authorization( &self ) -> Option<String> {
+        match *self {
+            Credentials::Token( ref token ) => Some( format!( "Bearer {}", token ) ),
+            Credentials::Password { ref username, ref password } => Some( format!(
+                    "Basic {}", base64_encode( format!( "{}:{}", username, password ).as_bytes() ) ) ),
+            _ => None,
+        }
+    }
+}
+
+/// The authenticated client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    pub name: String,
+    pub roles: Vec<String>,
+}
+
+impl Principal {
+    pub fn new<T: Into<String>>( name: T ) -> Principal {
+        Principal { name: name.into(), roles: vec![] }
+    }
+
+    /// Adds a role to the principal.
+    pub fn role<T: Into<String>>( mut self, role: T ) -> Self {
+        self.roles.push( role.into() );
+        self
+    }
+
+    pub fn has_role( &self, role: &str ) -> bool {
+        self.roles.iter().any( |r| r == role )
+    }
+}
+
+/// Decides who the connecting clients are.
+pub trait Authenticator {
+
+    /// Authenticates the credentials of a new connection.
+    ///
+    /// The connections are refused with the error. Authenticators should
+    /// use `ServiceError::unauthenticated` unless the credentials couldn't
+    /// be checked at all.
+    fn authenticate( &self, credentials: &Credentials ) -> Result<Principal, ServiceError>;
+}
+
+impl<F> Authenticator for F
+    where F: Fn( &Credentials ) -> Result<Principal, ServiceError>
+{
+    fn authenticate( &self, credentials: &Credentials ) -> Result<Principal, ServiceError> {
+        self( credentials )
+    }
+}
+
+thread_local! {
+    static PRINCIPAL: RefCell<Option<Rc<Principal>>> = RefCell::new( None );
+}
+
+/// Restores the previous principal once the call is done.
+struct PrincipalGuard( Option<Rc<Principal>> );
+
+impl Drop for PrincipalGuard {
+    fn drop( &mut self ) {
+        let previous = self.0.take();
+        PRINCIPAL.with( |cell| *cell.borrow_mut() = previous );
+    }
+}
+
+/// Makes the calls in `f` as the principal.
+///
+/// The host invokes the sessions this way so the operations see the
+/// principal of their session.
+pub fn with_principal<F, R>( principal: Option<Rc<Principal>>, f: F ) -> R
+    where F: FnOnce() -> R
+{
+    let previous = PRINCIPAL.with( |cell| cell.replace( principal ) );
+    let _guard = PrincipalGuard( previous );
+    f()
+}
+
+/// Gets the principal of the session whose operation is being invoked.
+pub fn current_principal() -> Option<Rc<Principal>> {
+    PRINCIPAL.with( |cell| cell.borrow().clone() )
+}
+
+/// Checks the current principal against the policy of an operation.
+///
+/// Used by the invoke targets of the contracts for the `#[authorize]`
+/// attributes.
+pub fn authorize<F>( operation: &str, policy: F ) -> Result<(), ServiceError>
+    where F: FnOnce( &Principal ) -> bool
+{
+    match current_principal() {
+        Some( ref principal ) if policy( principal ) => Ok( () ),
+        Some( principal ) => Err( ServiceError::unauthorized( format!(
+                "'{}' is not authorized to call '{}'", principal.name, operation ) ) ),
+        None => Err( ServiceError::unauthorized( format!(
+                "Calling '{}' requires an authenticated client", operation ) ) ),
+    }
+}
+
+/// Session that knows the principal it was created for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthenticatedSession {
+    pub id: String,
+    pub principal: Option<Principal>,
+}
+
+impl SessionInfo for AuthenticatedSession {
+    fn key( &self ) -> Cow<str> { Cow::from( self.id.as_str() ) }
+    fn principal( &self ) -> Option<&Principal> { self.principal.as_ref() }
+}
+
+/// Session factory that stores the principals in the sessions.
+///
+/// The session IDs are random so the clients can't guess the sessions of
+/// the others. The factory remembers the sessions while they are alive so
+/// the sessions looked up by their ID keep their principal.
+#[derive(Default)]
+pub struct PrincipalSessionFactory {
+    sessions: RefCell<HashMap<String, Weak<AuthenticatedSession>>>,
+}
+
+impl PrincipalSessionFactory {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn create( &self, principal: Option<Principal> ) -> ( String, Rc<AuthenticatedSession> ) {
+        let id = new_session_id();
+        let session = Rc::new( AuthenticatedSession { id: id.clone(), principal: principal } );
+
+        let mut sessions = self.sessions.borrow_mut();
+        sessions.retain( |_, session| session.upgrade().is_some() );
+        sessions.insert( id.clone(), Rc::downgrade( &session ) );
+        ( id, session )
+    }
+}
+
+impl SessionFactory for PrincipalSessionFactory {
+    type SessionInfo = AuthenticatedSession;
+
+    fn create_session( &self ) -> ( String, Rc<AuthenticatedSession> ) {
+        self.create( None )
+    }
+
+    fn get_session( &self, key: &str ) -> Rc<AuthenticatedSession> {
+        self.sessions.borrow()
+            .get( key )
+            .and_then( Weak::upgrade )
+            .unwrap_or_else( || Rc::new( AuthenticatedSession {
+                id: key.to_string(),
+                principal: None,
+            } ) )
+    }
+
+    fn create_authenticated_session(
+        &self,
+        principal: Principal
+    ) -> ( String, Rc<AuthenticatedSession> )
+    {
+        self.create( Some( principal ) )
+    }
+}
+
+const BASE64 : &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode( bytes: &[u8] ) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks( 3 ) {
+        let b = [ chunk[ 0 ], *chunk.get( 1 ).unwrap_or( &0 ), *chunk.get( 2 ).unwrap_or( &0 ) ];
+        let n = ( b[ 0 ] as usize ) << 16 | ( b[ 1 ] as usize ) << 8 | b[ 2 ] as usize;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push( BASE64[ n >> ( 18 - 6 * i ) & 0x3f ] as char );
+            } else {
+                out.push( '=' );
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode( text: &str ) -> Option<Vec<u8>> {
+    let text = text.trim_right_matches( '=' ).as_bytes();
+    let mut out = vec![];
+    for chunk in text.chunks( 4 ) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut n = 0;
+        for i in 0..4 {
+            let value = match chunk.get( i ) {
+                Some( c ) => BASE64.iter().position( |b| b == c )?,
+                None => 0,
+            };
+            n = n << 6 | value;
+        }
+        for i in 0..chunk.len() - 1 {
+            out.push( ( n >> ( 16 - 8 * i ) ) as u8 );
+        }
+    }
+    Some( out )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn authorization_headers() {
+        let password = Credentials::Password {
+            username: "Aladdin".to_string(),
+            password: "open sesame".to_string(),
+        };
+        let header = password.to_authorization().unwrap();
+        assert_eq!( header, "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==" );
+        assert_eq!( Credentials::from_authorization( &header ), Some( password ) );
+
+        let token = Credentials::Token( "abc.def".to_string() );
+        assert_eq!( Credentials::from_authorization( "bearer abc.def" ), Some( token ) );
+        assert_eq!( Credentials::from_authorization( "Digest abc" ), None );
+        assert_eq!( Credentials::from_authorization( "Basic %%%" ), None );
+    }
+
+    #[test]
+    pub fn current_principals() {
+        let admin = Rc::new( Principal::new( "alice" ).role( "admin" ) );
+        let is_admin = |p: &Principal| p.has_role( "admin" );
+
+        assert!( authorize( "reset", &is_admin ).is_err() );
+        with_principal( Some( admin.clone() ), || {
+            assert!( authorize( "reset", &is_admin ).is_ok() );
+            with_principal( Some( Rc::new( Principal::new( "bob" ) ) ), || {
+                let e = authorize( "reset", &is_admin ).unwrap_err();
+                assert_eq!( e.kind, ::ErrorKind::Unauthorized );
+            } );
+            assert_eq!( current_principal(), Some( admin.clone() ) );
+        } );
+        assert_eq!( current_principal(), None );
+    }
+
+    #[test]
+    pub fn principal_sessions() {
+        let factory = PrincipalSessionFactory::new();
+        let ( alice, session ) = factory.create_authenticated_session( Principal::new( "alice" ) );
+        let ( anonymous, _ ) = factory.create_session();
+        assert_ne!( alice, anonymous );
+
+        assert_eq!( factory.get_session( &alice ), session );
+        assert_eq!( factory.get_session( &anonymous ).principal, None );
+
+        // The sessions are forgotten once they are dropped.
+        drop( session );
+        assert_eq!( factory.get_session( &alice ).principal, None );
+    }
+}
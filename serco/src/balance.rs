@@ -0,0 +1,822 @@
+//! Spreading the calls of a client across several endpoints.
+//!
+//! The `BalancingForwarder` holds a connection to each endpoint, created
+//! through the transport `Connector`, and selects the endpoint for each call
+//! according to the `BalancePolicy`. Endpoints whose calls fail with
+//! transport errors are ejected from the rotation for a while.
+//!
+//! `check_health` probes the endpoints with the `serco.Health.status`
+//! operation of the built-in `Health` contract and ejects the ones that
+//! don't report serving in time.
+//!
+//! Each connection has its own session on its host. Sessionful clients must
+//! enable `sessionful` so all calls stay on the endpoint that owns the
+//! session.
+//!
+//! A call counts as outstanding on its endpoint from the moment the endpoint
+//! is selected until its future completes or its result stream ends, so the
+//! `LeastOutstanding` policy sees the calls whose futures are still pending.
+//! The generated proxies wait for each call to complete, so the calls made
+//! through them overlap only when they return streams or are made from
+//! several proxies sharing the forwarder.
+
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use futures::future::{self, Loop};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use super::{ServiceError, ErrorKind, CallInfo, Forwarder, ServiceProxy, ServiceStream};
+use super::{batch, stream};
+use super::health::{self, HealthReport, ServingStatus};
+use super::reconnect::Connector;
+use super::retry::{self, RetryForwarder, RetryPolicy, Timer};
+
+/// Number of points each endpoint has on the consistent hash ring.
+const RING_POINTS : usize = 64;
+
+/// Specifies how the endpoint is selected for the calls.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BalancePolicy {
+
+    /// Cycles through the endpoints.
+    RoundRobin,
+
+    /// Selects the endpoint with the least calls in progress.
+    LeastOutstanding,
+
+    /// Selects the endpoint by hashing the session key. Clients using the
+    /// same key end up on the same endpoint as long as it is healthy.
+    ConsistentHash { session_key: String },
+}
+
+/// The connection attempt shared by the calls waiting for it.
+type Connecting = future::Shared<Box<Future<Item=(), Error=ServiceError>>>;
+
+struct Backend<C: Connector> {
+    address: String,
+    connector: C,
+    forwarder: RefCell<Option<C::Forwarder>>,
+    connecting: RefCell<Option<Connecting>>,
+    outstanding: Cell<usize>,
+    failures: Cell<u32>,
+    ejected_until: Cell<Option<Instant>>,
+}
+
+impl<C: Connector> Backend<C> {
+
+    fn is_available( &self, now: Instant ) -> bool {
+        match self.ejected_until.get() {
+            Some( until ) => until <= now,
+            None => true,
+        }
+    }
+
+    fn disconnect( &self ) {
+        if let Some( forwarder ) = self.forwarder.borrow_mut().take() {
+            forwarder.close();
+        }
+    }
+}
+
+struct Shared<C: Connector> {
+    backends: Vec<Backend<C>>,
+    ring: Vec<( u64, usize )>,
+    policy: BalancePolicy,
+    sessionful: bool,
+    max_failures: u32,
+    ejection_time: Duration,
+    health_timeout: Duration,
+    timer: Timer,
+    next: Cell<usize>,
+    pinned: Cell<Option<usize>>,
+}
+
+impl<C: Connector> Shared<C> {
+
+    /// Selects the backend for the next call.
+    fn select( &self ) -> Option<usize> {
+        let now = Instant::now();
+
+        if self.sessionful {
+            if let Some( pinned ) = self.pinned.get() {
+                if self.backends[ pinned ].is_available( now ) {
+                    return Some( pinned );
+                }
+            }
+        }
+
+        let count = self.backends.len();
+        let selected = match self.policy {
+            BalancePolicy::RoundRobin => {
+                let start = self.next.get();
+                let selected = ( 0..count )
+                        .map( |i| ( start + i ) % count )
+                        .find( |&i| self.backends[ i ].is_available( now ) );
+                if let Some( i ) = selected {
+                    self.next.set( i + 1 );
+                }
+                selected
+            },
+            BalancePolicy::LeastOutstanding => {
+                ( 0..count )
+                    .filter( |&i| self.backends[ i ].is_available( now ) )
+                    .min_by_key( |&i| self.backends[ i ].outstanding.get() )
+            },
+            BalancePolicy::ConsistentHash { ref session_key } => {
+                let hash = hash_of( session_key );
+                let start = match self.ring.binary_search_by_key(
+                        &hash, |&( point, _ )| point ) {
+                    Ok( i ) | Err( i ) => i,
+                };
+                ( 0..self.ring.len() )
+                    .map( |i| self.ring[ ( start + i ) % self.ring.len() ].1 )
+                    .find( |&i| self.backends[ i ].is_available( now ) )
+            },
+        };
+
+        if self.sessionful {
+            self.pinned.set( selected );
+        }
+        selected
+    }
+
+    /// Resolves once the backend is connected, connecting if necessary.
+    ///
+    /// The calls made while the backend connects wait for the same attempt.
+    fn connect( shared: &Rc<Self>, index: usize ) -> Box<Future<Item=(), Error=ServiceError>> {
+        let backend = &shared.backends[ index ];
+        if backend.forwarder.borrow().is_some() {
+            return Box::new( future::ok( () ) );
+        }
+
+        let connecting = backend.connecting.borrow().clone();
+        let connecting = match connecting {
+            Some( connecting ) => connecting,
+            None => {
+                let weak : Weak<Self> = Rc::downgrade( shared );
+                let connected = backend.connector.connect( None ).then( move |result| {
+                    let shared = match weak.upgrade() {
+                        Some( shared ) => shared,
+                        None => return Err( ServiceError::new(
+                                ErrorKind::Transport, "The forwarder was closed" ) ),
+                    };
+                    let backend = &shared.backends[ index ];
+                    backend.connecting.borrow_mut().take();
+                    let ( _, forwarder ) = result?;
+                    *backend.forwarder.borrow_mut() = Some( forwarder );
+                    Ok( () )
+                } );
+                let connecting = ( Box::new( connected ) as Box<Future<Item=(), Error=ServiceError>> ).shared();
+                *backend.connecting.borrow_mut() = Some( connecting.clone() );
+                connecting
+            },
+        };
+        Box::new( connecting
+            .map( |_| () )
+            .map_err( |e| ( *e ).clone() ) )
+    }
+
+    /// Selects a backend and connects to it. The call counts as outstanding
+    /// on the backend until the returned guard is dropped.
+    ///
+    /// Backends that fail to connect are ejected and the selection is
+    /// repeated.
+    fn select_connected( shared: &Rc<Self> ) -> Box<Future<Item=Outstanding<C>, Error=ServiceError>> {
+        let shared = shared.clone();
+        Box::new( future::loop_fn( 0, move |attempt| -> Box<Future<Item=Loop<Outstanding<C>, usize>, Error=ServiceError>> {
+            let index = match shared.select() {
+                Some( index ) if attempt < shared.backends.len() => index,
+                _ => return Box::new( future::err( ServiceError::new(
+                        ErrorKind::Transport, "No healthy endpoints available" ) ) ),
+            };
+
+            let guard = Outstanding::new( shared.clone(), index );
+            Box::new( Shared::connect( &shared, index ).then( move |result| match result {
+                Ok( () ) => Ok( Loop::Break( guard ) ),
+                Err( ref e ) if e.is_transient() => {
+                    guard.shared.eject( index );
+                    Ok( Loop::Continue( attempt + 1 ) )
+                },
+                Err( e ) => Err( e ),
+            } ) )
+        } ) )
+    }
+
+    /// Connects to the backend and asks for its health.
+    ///
+    /// Fails unless the backend reports serving before the health timeout.
+    /// Hosts that don't serve the `Health` contract count as serving as
+    /// long as they respond.
+    fn probe( shared: &Rc<Self>, index: usize ) -> Box<Future<Item=(), Error=ServiceError>> {
+        let weak : Weak<Self> = Rc::downgrade( shared );
+        let status = Shared::connect( shared, index ).and_then( move |_| {
+            let shared = match weak.upgrade() {
+                Some( shared ) => shared,
+                None => return Box::new( future::err( ServiceError::new(
+                        ErrorKind::Transport, "The forwarder was closed" ) ) )
+                        as Box<Future<Item=HealthReport, Error=ServiceError>>,
+            };
+            let forwarder = shared.backends[ index ].forwarder.borrow();
+            match *forwarder {
+                Some( ref forwarder ) => {
+                    let call = CallInfo { name: health::HEALTH_STATUS, idempotent: true, request_id: None };
+                    forwarder.forward_call( call, () )
+                },
+                None => Box::new( future::err( ServiceError::new(
+                        ErrorKind::Transport, "Connection lost" ) ) ),
+            }
+        } ).then( |result| match result {
+            Ok( HealthReport { status: ServingStatus::Serving, .. } ) => Ok( () ),
+            Ok( _ ) => Err( ServiceError::new( ErrorKind::Transport, "The endpoint is not serving" ) ),
+            Err( ref e ) if e.kind == ErrorKind::BadOperation => Ok( () ),
+            Err( e ) => Err( e ),
+        } );
+
+        let timeout = ( *shared.timer )( shared.health_timeout ).then( |_| Err( ServiceError::new(
+                ErrorKind::Transport, "The health check timed out" ) ) );
+        Box::new( status.select( timeout ).map( |( ok, _ )| ok ).map_err( |( e, _ )| e ) )
+    }
+
+    fn health( &self ) -> Vec<EndpointHealth> {
+        let now = Instant::now();
+        self.backends.iter().map( |b| EndpointHealth {
+            address: b.address.clone(),
+            healthy: b.is_available( now ),
+            outstanding: b.outstanding.get(),
+        } ).collect()
+    }
+
+    fn record_success( &self, index: usize ) {
+        let backend = &self.backends[ index ];
+        backend.failures.set( 0 );
+        backend.ejected_until.set( None );
+    }
+
+    fn record_failure( &self, index: usize ) {
+        let backend = &self.backends[ index ];
+        backend.disconnect();
+        backend.failures.set( backend.failures.get() + 1 );
+        if backend.failures.get() >= self.max_failures {
+            self.eject( index );
+        }
+    }
+
+    fn eject( &self, index: usize ) {
+        let backend = &self.backends[ index ];
+        backend.disconnect();
+        backend.ejected_until.set( Some( Instant::now() + self.ejection_time ) );
+    }
+
+    fn record<T>( &self, index: usize, result: &Result<T, ServiceError> ) {
+        match *result {
+            Err( ref e ) if e.is_transient() => self.record_failure( index ),
+            _ => self.record_success( index ),
+        }
+    }
+}
+
+fn hash_of<T: Hash>( value: T ) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash( &mut hasher );
+    hasher.finish()
+}
+
+/// Keeps a call counted as outstanding until the guard is dropped.
+struct Outstanding<C: Connector> {
+    shared: Rc<Shared<C>>,
+    index: usize,
+}
+
+impl<C: Connector> Outstanding<C> {
+    fn new( shared: Rc<Shared<C>>, index: usize ) -> Self {
+        {
+            let backend = &shared.backends[ index ];
+            backend.outstanding.set( backend.outstanding.get() + 1 );
+        }
+        Outstanding { shared: shared, index: index }
+    }
+
+    /// Borrows the forwarder of the backend.
+    fn forwarder( &self ) -> Result<::std::cell::Ref<C::Forwarder>, ServiceError> {
+        let forwarder = self.shared.backends[ self.index ].forwarder.borrow();
+        if forwarder.is_none() {
+            return Err( ServiceError::new( ErrorKind::Transport, "Connection lost" ) );
+        }
+        Ok( ::std::cell::Ref::map( forwarder, |f| f.as_ref().unwrap() ) )
+    }
+}
+
+impl<C: Connector> Drop for Outstanding<C> {
+    fn drop( &mut self ) {
+        let backend = &self.shared.backends[ self.index ];
+        backend.outstanding.set( backend.outstanding.get() - 1 );
+    }
+}
+
+/// Forwarder that spreads the calls across several endpoints.
+pub struct BalancingForwarder<C: Connector> {
+    shared: Rc<Shared<C>>,
+}
+
+/// Builds a `BalancingForwarder`.
+pub struct BalancingForwarderBuilder<C: Connector> {
+    endpoints: Vec<( String, C )>,
+    policy: BalancePolicy,
+    sessionful: bool,
+    max_failures: u32,
+    ejection_time: Duration,
+    health_timeout: Duration,
+    timer: Timer,
+}
+
+impl<C: Connector> BalancingForwarderBuilder<C> {
+
+    pub fn new( policy: BalancePolicy ) -> Self {
+        BalancingForwarderBuilder {
+            endpoints: vec![],
+            policy: policy,
+            sessionful: false,
+            max_failures: 1,
+            ejection_time: Duration::from_secs( 10 ),
+            health_timeout: Duration::from_secs( 5 ),
+            timer: retry::thread_timer(),
+        }
+    }
+
+    /// Adds an endpoint with the connector used to connect to it.
+    pub fn endpoint<T: Into<String>>( mut self, address: T, connector: C ) -> Self {
+        self.endpoints.push( ( address.into(), connector ) );
+        self
+    }
+
+    /// Keeps all calls on the endpoint selected for the first call so they
+    /// share the session on that endpoint.
+    ///
+    /// If the endpoint is ejected, the calls move to another endpoint and
+    /// continue with a new session.
+    pub fn sessionful( mut self, sessionful: bool ) -> Self {
+        self.sessionful = sessionful;
+        self
+    }
+
+    /// Specifies the number of consecutive transport failures after which an
+    /// endpoint is ejected.
+    pub fn max_failures( mut self, max_failures: u32 ) -> Self {
+        self.max_failures = max_failures.max( 1 );
+        self
+    }
+
+    /// Specifies how long an ejected endpoint is kept out of the rotation
+    /// before it is tried again.
+    pub fn ejection_time( mut self, ejection_time: Duration ) -> Self {
+        self.ejection_time = ejection_time;
+        self
+    }
+
+    /// Specifies how long `check_health` waits for each endpoint to report
+    /// its health before ejecting it.
+    pub fn health_timeout( mut self, health_timeout: Duration ) -> Self {
+        self.health_timeout = health_timeout;
+        self
+    }
+
+    /// Specifies the timer that times out the health checks. By default the
+    /// timeouts are waited out with the `retry::thread_timer`.
+    pub fn timer( mut self, timer: Timer ) -> Self {
+        self.timer = timer;
+        self
+    }
+
+    pub fn build( self ) -> BalancingForwarder<C> {
+
+        let mut ring = vec![];
+        for ( index, &( ref address, _ ) ) in self.endpoints.iter().enumerate() {
+            for point in 0..RING_POINTS {
+                ring.push( ( hash_of( ( address, point ) ), index ) );
+            }
+        }
+        ring.sort();
+
+        let backends = self.endpoints.into_iter()
+                .map( |( address, connector )| Backend {
+                    address: address,
+                    connector: connector,
+                    forwarder: RefCell::new( None ),
+                    connecting: RefCell::new( None ),
+                    outstanding: Cell::new( 0 ),
+                    failures: Cell::new( 0 ),
+                    ejected_until: Cell::new( None ),
+                } )
+                .collect();
+
+        BalancingForwarder {
+            shared: Rc::new( Shared {
+                backends: backends,
+                ring: ring,
+                policy: self.policy,
+                sessionful: self.sessionful,
+                max_failures: self.max_failures,
+                ejection_time: self.ejection_time,
+                health_timeout: self.health_timeout,
+                timer: self.timer,
+                next: Cell::new( 0 ),
+                pinned: Cell::new( None ),
+            } )
+        }
+    }
+}
+
+/// Health of a single endpoint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EndpointHealth {
+    pub address: String,
+    pub healthy: bool,
+    pub outstanding: usize,
+}
+
+impl<C: Connector> BalancingForwarder<C> {
+
+    /// Probes the endpoints by calling `serco.Health.status` on each of
+    /// them, connecting to the ones that aren't connected.
+    ///
+    /// Endpoints that can't be connected, report that they aren't serving
+    /// or don't respond within the health timeout are ejected. The healthy
+    /// endpoints are returned into the rotation. Resolves to the health of
+    /// the endpoints once all of them have been probed.
+    pub fn check_health( &self ) -> Box<Future<Item=Vec<EndpointHealth>, Error=ServiceError>> {
+        let probes = ( 0..self.shared.backends.len() ).map( |index| {
+            let shared = self.shared.clone();
+            Shared::probe( &self.shared, index ).then( move |result| {
+                match result {
+                    Ok( () ) => shared.record_success( index ),
+                    Err( _ ) => shared.eject( index ),
+                }
+                Ok::<(), ServiceError>( () )
+            } )
+        } ).collect::<Vec<_>>();
+
+        let shared = self.shared.clone();
+        Box::new( future::join_all( probes ).map( move |_| shared.health() ) )
+    }
+
+    /// Reports the health of the endpoints without probing them.
+    pub fn health( &self ) -> Vec<EndpointHealth> {
+        self.shared.health()
+    }
+
+    /// Gets the address of the endpoint the session is pinned to.
+    pub fn pinned_endpoint( &self ) -> Option<String> {
+        self.shared.pinned.get()
+            .map( |i| self.shared.backends[ i ].address.clone() )
+    }
+
+    /// Selects the backend and invokes the function on its forwarder once
+    /// connected. The result is recorded for the health of the backend.
+    fn with_forwarder<T, F>( &self, f: F ) -> Box<Future<Item=T, Error=ServiceError>>
+        where F: FnOnce( &C::Forwarder ) -> Box<Future<Item=T, Error=ServiceError>> + 'static,
+              T: 'static,
+    {
+        Box::new( Shared::select_connected( &self.shared ).and_then( |guard| {
+            let result = match guard.forwarder() {
+                Ok( forwarder ) => f( &forwarder ),
+                Err( e ) => Box::new( future::err( e ) ),
+            };
+            result.then( move |result| {
+                guard.shared.record( guard.index, &result );
+                result
+            } )
+        } ) )
+    }
+}
+
+impl<C: Connector> Forwarder for BalancingForwarder<C> {
+
+    fn forward<D, S>(
+        &self,
+        name: &'static str,
+        params : S,
+    ) -> Box<Future<Item=D, Error=ServiceError>>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static
+    {
+        self.forward_call( CallInfo::new( name ), params )
+    }
+
+    fn forward_call<D, S>(
+        &self,
+        call: CallInfo,
+        params : S,
+    ) -> Box<Future<Item=D, Error=ServiceError>>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static
+    {
+        self.with_forwarder( move |f| f.forward_call( call, params ) )
+    }
+
+    fn forward_streaming<D, S>(
+        &self,
+        name: &'static str,
+        params : S,
+        streams : Vec<stream::OutgoingStream>,
+    ) -> ServiceStream<D>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static
+    {
+        let selected = Shared::select_connected( &self.shared ).map( move |guard| -> ServiceStream<D> {
+            let result = match guard.forwarder() {
+                Ok( forwarder ) => forwarder.forward_streaming( name, params, streams ),
+                Err( e ) => Box::new( ::futures::stream::once( Err( e ) ) ),
+            };
+
+            // The guard keeps the call outstanding until the stream ends.
+            Box::new( result.then( move |result| {
+                guard.shared.record( guard.index, &result );
+                result
+            } ) )
+        } );
+        Box::new( selected.flatten_stream() )
+    }
+
+    fn forward_batch(
+        &self,
+        calls : Vec<batch::Call>,
+        mode : batch::BatchMode,
+    ) -> Box<Future<Item=Vec<Result<stream::IncomingItem, ServiceError>>,
+                    Error=ServiceError>>
+    {
+        self.with_forwarder( move |f| f.forward_batch( calls, mode ) )
+    }
+
+    fn close( self ) {
+        for backend in &self.shared.backends {
+            backend.disconnect();
+        }
+    }
+}
+
+/// A service connection that spreads the calls across several endpoints.
+///
+/// Failed calls are retried according to the retry policy, which lets the
+/// retries land on another endpoint.
+pub struct BalancedConnection<S: ?Sized, C: Connector> {
+    proxy: ServiceProxy<S, RetryForwarder<BalancingForwarder<C>>>,
+}
+
+impl<S: ?Sized, C: Connector> BalancedConnection<S, C> {
+
+    pub fn new( forwarder: BalancingForwarder<C>, retry_policy: RetryPolicy ) -> Self {
+        BalancedConnection {
+            proxy: ServiceProxy::new( RetryForwarder::new( forwarder, retry_policy ) ),
+        }
+    }
+
+    /// Gets the load balancing forwarder for health checks.
+    pub fn balancer( &self ) -> &BalancingForwarder<C> {
+        self.proxy.forwarder.inner()
+    }
+
+    pub fn close( self ) {
+        self.proxy.close();
+    }
+}
+
+/// Dereference the connection into the service proxy.
+/// The proxy implements the actual service trait.
+impl<S: ?Sized, C: Connector> Deref for BalancedConnection<S, C> {
+    type Target = ServiceProxy<S, RetryForwarder<BalancingForwarder<C>>>;
+
+    fn deref( &self ) -> &Self::Target {
+        &self.proxy
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Responds with the name of the endpoint unless the endpoint is down.
+    ///
+    /// The health checks get the status of the endpoint. Endpoints without
+    /// a status never respond to them.
+    struct TestForwarder {
+        name: &'static str,
+        down: Rc<Cell<bool>>,
+        status: Rc<Cell<Option<ServingStatus>>>,
+    }
+
+    impl Forwarder for TestForwarder {
+        fn forward<D, S>( &self, name: &'static str, _params : S ) -> Box<Future<Item=D, Error=ServiceError>>
+            where D: DeserializeOwned + 'static, S: Serialize + 'static
+        {
+            if self.down.get() {
+                return Box::new( future::err( ServiceError::transport( "Connection lost" ) ) );
+            }
+            let value = match ( name, self.status.get() ) {
+                ( health::HEALTH_STATUS, Some( status ) ) => json!( HealthReport {
+                    status: status,
+                    uptime_secs: 0,
+                    active_sessions: 0,
+                    endpoints: vec![],
+                } ),
+                ( health::HEALTH_STATUS, None ) => return Box::new( future::empty() ),
+                _ => json!( self.name ),
+            };
+            Box::new( future::result( ::serde_json::from_value( value )
+                    .map_err( ServiceError::from ) ) )
+        }
+
+        fn close( self ) {}
+    }
+
+    struct TestConnector {
+        name: &'static str,
+        down: Rc<Cell<bool>>,
+        status: Rc<Cell<Option<ServingStatus>>>,
+    }
+
+    impl Connector for TestConnector {
+        type Forwarder = TestForwarder;
+
+        fn connect(
+            &self,
+            _session_id: Option<&str>
+        ) -> Box<Future<Item=( String, TestForwarder ), Error=ServiceError>>
+        {
+            if self.down.get() {
+                return Box::new( future::err( ServiceError::transport( "Connection refused" ) ) );
+            }
+            Box::new( future::ok( ( self.name.to_string(), TestForwarder {
+                name: self.name,
+                down: self.down.clone(),
+                status: self.status.clone(),
+            } ) ) )
+        }
+    }
+
+    /// Creates a forwarder over the endpoints a, b and c. The flags take
+    /// the endpoints down.
+    fn balancer(
+        builder: BalancingForwarderBuilder<TestConnector>
+    ) -> ( BalancingForwarder<TestConnector>, Vec<Rc<Cell<bool>>> )
+    {
+        let ( forwarder, down, _ ) = probed_balancer( builder );
+        ( forwarder, down )
+    }
+
+    /// Creates a forwarder over the endpoints a, b and c along with the
+    /// statuses the endpoints report to the health checks.
+    fn probed_balancer(
+        builder: BalancingForwarderBuilder<TestConnector>
+    ) -> ( BalancingForwarder<TestConnector>,
+           Vec<Rc<Cell<bool>>>,
+           Vec<Rc<Cell<Option<ServingStatus>>>> )
+    {
+        let down : Vec<_> = ( 0..3 ).map( |_| Rc::new( Cell::new( false ) ) ).collect();
+        let status : Vec<_> = ( 0..3 ).map( |_| Rc::new( Cell::new( Some( ServingStatus::Serving ) ) ) ).collect();
+        let builder = [ "a", "b", "c" ].iter().zip( down.iter().zip( &status ) ).fold(
+                builder, |builder, ( &name, ( down, status ) )| {
+            builder.endpoint( name, TestConnector {
+                name: name,
+                down: down.clone(),
+                status: status.clone(),
+            } )
+        } );
+        ( builder.build(), down, status )
+    }
+
+    fn call( forwarder: &BalancingForwarder<TestConnector> ) -> Result<String, ServiceError> {
+        forwarder.forward( "call", () ).wait()
+    }
+
+    fn calls( forwarder: &BalancingForwarder<TestConnector>, count: usize ) -> Vec<String> {
+        ( 0..count ).map( |_| call( forwarder ).unwrap() ).collect()
+    }
+
+    #[test]
+    pub fn round_robin() {
+        let ( forwarder, _ ) = balancer( BalancingForwarderBuilder::new( BalancePolicy::RoundRobin ) );
+        assert_eq!( calls( &forwarder, 6 ), vec![ "a", "b", "c", "a", "b", "c" ] );
+    }
+
+    #[test]
+    pub fn least_outstanding() {
+        let ( forwarder, _ ) = balancer( BalancingForwarderBuilder::new( BalancePolicy::LeastOutstanding ) );
+
+        // The calls count as outstanding until their futures complete.
+        let first = forwarder.forward::<String, _>( "call", () );
+        let second = forwarder.forward::<String, _>( "call", () );
+        let outstanding : Vec<_> = forwarder.health().iter().map( |h| h.outstanding ).collect();
+        assert_eq!( outstanding, vec![ 1, 1, 0 ] );
+        assert_eq!( call( &forwarder ).unwrap(), "c" );
+
+        assert_eq!( second.wait().unwrap(), "b" );
+        assert_eq!( call( &forwarder ).unwrap(), "b" );
+        assert_eq!( first.wait().unwrap(), "a" );
+        let outstanding : Vec<_> = forwarder.health().iter().map( |h| h.outstanding ).collect();
+        assert_eq!( outstanding, vec![ 0, 0, 0 ] );
+    }
+
+    #[test]
+    pub fn consistent_hash() {
+        let policy = BalancePolicy::ConsistentHash { session_key: "alice".to_string() };
+        let ( forwarder, down ) = balancer( BalancingForwarderBuilder::new( policy.clone() ) );
+        let selected = call( &forwarder ).unwrap();
+        assert!( calls( &forwarder, 5 ).iter().all( |name| *name == selected ) );
+
+        // Other clients with the same key end up on the same endpoint.
+        let ( other, _ ) = balancer( BalancingForwarderBuilder::new( policy ) );
+        assert_eq!( call( &other ).unwrap(), selected );
+
+        // The calls move to another endpoint while the selected one is down
+        // and stay there.
+        let index = [ "a", "b", "c" ].iter().position( |name| *name == selected ).unwrap();
+        down[ index ].set( true );
+        assert!( call( &forwarder ).is_err() );
+        let fallback = call( &forwarder ).unwrap();
+        assert!( fallback != selected );
+        assert!( calls( &forwarder, 5 ).iter().all( |name| *name == fallback ) );
+    }
+
+    #[test]
+    pub fn failover() {
+        let ( forwarder, down ) = balancer( BalancingForwarderBuilder::new( BalancePolicy::RoundRobin )
+                .ejection_time( Duration::from_millis( 100 ) ) );
+
+        // Endpoints that can't be connected are skipped.
+        down[ 1 ].set( true );
+        assert_eq!( calls( &forwarder, 4 ), vec![ "a", "c", "a", "c" ] );
+        let healthy : Vec<_> = forwarder.health().iter().map( |h| h.healthy ).collect();
+        assert_eq!( healthy, vec![ true, false, true ] );
+
+        // The call that fails on a connected endpoint fails and ejects it.
+        down[ 0 ].set( true );
+        assert_eq!( call( &forwarder ).unwrap_err().kind, ErrorKind::Transport );
+        assert_eq!( calls( &forwarder, 2 ), vec![ "c", "c" ] );
+
+        // Nothing is left once all of them are down.
+        down[ 2 ].set( true );
+        assert!( call( &forwarder ).is_err() );
+        assert_eq!( call( &forwarder ).unwrap_err().message, "No healthy endpoints available" );
+
+        // The endpoints return after the ejection time once they recover.
+        for flag in &down {
+            flag.set( false );
+        }
+        ::std::thread::sleep( Duration::from_millis( 150 ) );
+        let mut names = calls( &forwarder, 3 );
+        names.sort();
+        assert_eq!( names, vec![ "a", "b", "c" ] );
+    }
+
+    #[test]
+    pub fn sessionful() {
+        let ( forwarder, down ) = balancer( BalancingForwarderBuilder::new( BalancePolicy::RoundRobin )
+                .sessionful( true ) );
+        assert_eq!( calls( &forwarder, 3 ), vec![ "a", "a", "a" ] );
+        assert_eq!( forwarder.pinned_endpoint().unwrap(), "a" );
+
+        // The session moves once its endpoint is ejected.
+        down[ 0 ].set( true );
+        assert!( call( &forwarder ).is_err() );
+        assert_eq!( calls( &forwarder, 2 ), vec![ "b", "b" ] );
+        assert_eq!( forwarder.pinned_endpoint().unwrap(), "b" );
+    }
+
+    #[test]
+    pub fn check_health() {
+        let ( forwarder, down, status ) = probed_balancer(
+                BalancingForwarderBuilder::new( BalancePolicy::RoundRobin )
+                    .health_timeout( Duration::from_millis( 50 ) ) );
+        down[ 2 ].set( true );
+        let health = forwarder.check_health().wait().unwrap();
+        let healthy : Vec<_> = health.iter().map( |h| h.healthy ).collect();
+        assert_eq!( healthy, vec![ true, true, false ] );
+        assert_eq!( health[ 0 ].address, "a" );
+
+        // Connected endpoints that aren't serving or don't respond in time
+        // are taken out of the rotation.
+        down[ 2 ].set( false );
+        status[ 0 ].set( Some( ServingStatus::NotServing ) );
+        status[ 1 ].set( None );
+        let health = forwarder.check_health().wait().unwrap();
+        let healthy : Vec<_> = health.iter().map( |h| h.healthy ).collect();
+        assert_eq!( healthy, vec![ false, false, true ] );
+        assert_eq!( calls( &forwarder, 2 ), vec![ "c", "c" ] );
+
+        // They return once they pass the check again.
+        status[ 0 ].set( Some( ServingStatus::Serving ) );
+        status[ 1 ].set( Some( ServingStatus::Serving ) );
+        let health = forwarder.check_health().wait().unwrap();
+        assert!( health.iter().all( |h| h.healthy ) );
+    }
+}
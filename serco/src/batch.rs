@@ -0,0 +1,189 @@
+//! Batching several operation calls into a single round trip.
+//!
+//! The calls are recorded into a `Batch` started with `Batch::new( &proxy )`
+//! through the typed builders the contracts generate, `batch.calls()`. The
+//! builders have a method for each operation that isn't streaming and
+//! nothing else so the methods can't collide with the operation names. The
+//! recorded calls return `BatchResult` handles, which are used to retrieve
+//! the typed results once the batch has been executed.
+//!
+//! ```ignore
+//! let mut batch = Batch::new( &proxy );
+//! let sum = batch.calls().add( 1, 2 );
+//! let product = batch.calls().multiply( 3, 4 );
+//! let mut results = batch.execute().wait()?;
+//! assert_eq!( results.get( sum )?, 3 );
+//!
+//! let mut batch = Batch::new( &proxy ).mode( BatchMode::Parallel );
+//! ```
+//!
+//! By default the host executes the calls one after another in the order
+//! they were recorded, so each call sees the effects of the previous ones.
+//! With `BatchMode::Parallel` the host starts all the calls at once, which
+//! lets the asynchronous operations overlap. The results are returned in
+//! the order of the calls in either mode.
+
+use std::marker::PhantomData;
+
+use futures::prelude::*;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use erased_serde;
+
+use super::{ServiceError, ErrorKind, ServiceProxy, Forwarder};
+use super::stream::IncomingItem;
+
+/// Specifies how the host executes the calls of a batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub enum BatchMode {
+
+    /// Each call is started only once the previous one has completed.
+    Sequential,
+
+    /// All calls are started at once.
+    Parallel,
+}
+
+impl Default for BatchMode {
+    fn default() -> Self {
+        BatchMode::Sequential
+    }
+}
+
+/// A single call recorded in a batch.
+pub struct Call {
+    pub name: &'static str,
+    pub params: Box<erased_serde::Serialize>,
+}
+
+/// Handle to the result of a call in a batch.
+pub struct BatchResult<T> {
+    index: usize,
+    phantom_data: PhantomData<T>,
+}
+
+impl<T> Clone for BatchResult<T> {
+    fn clone( &self ) -> Self {
+        BatchResult { index: self.index, phantom_data: PhantomData }
+    }
+}
+
+impl<T> Copy for BatchResult<T> {}
+
+/// Implemented by the contracts for their typed batch builders.
+pub trait BatchCalls<'b, 'a: 'b, F: 'a> : 'a {
+    type Builder;
+
+    fn builder( batch: &'b mut Batch<'a, Self, F> ) -> Self::Builder;
+}
+
+/// Collects calls into a batch.
+pub struct Batch<'a, S: ?Sized + 'a, F: 'a> {
+    proxy: &'a ServiceProxy<S, F>,
+    calls: Vec<Call>,
+    mode: BatchMode,
+}
+
+impl<'a, S: ?Sized, F: Forwarder> Batch<'a, S, F> {
+
+    pub fn new( proxy: &'a ServiceProxy<S, F> ) -> Self {
+        Batch {
+            proxy: proxy,
+            calls: vec![],
+            mode: Default::default(),
+        }
+    }
+
+    /// Returns the typed builder of the contract for recording calls.
+    pub fn calls<'b>(
+        &'b mut self
+    ) -> <S as BatchCalls<'b, 'a, F>>::Builder
+        where S: BatchCalls<'b, 'a, F>
+    {
+        S::builder( self )
+    }
+
+    /// Records a call to the named operation.
+    ///
+    /// Used by the generated batch builders.
+    pub fn call<T, P>(
+        &mut self,
+        name: &'static str,
+        params: P,
+    ) -> BatchResult<T>
+        where P: Serialize + 'static
+    {
+        self.calls.push( Call { name: name, params: Box::new( params ) } );
+        BatchResult {
+            index: self.calls.len() - 1,
+            phantom_data: PhantomData,
+        }
+    }
+
+    /// Specifies how the host executes the calls.
+    pub fn mode( mut self, mode: BatchMode ) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Returns the number of calls in the batch.
+    pub fn len( &self ) -> usize {
+        self.calls.len()
+    }
+
+    pub fn is_empty( &self ) -> bool {
+        self.calls.is_empty()
+    }
+
+    /// Sends the batch to the host.
+    ///
+    /// The future fails only if the batch as a whole could not be executed.
+    /// Failures of individual calls are reported through `BatchResults`.
+    pub fn execute( self ) -> Box<Future<Item=BatchResults, Error=ServiceError>> {
+        Box::new( self.proxy.forwarder
+                .forward_batch( self.calls, self.mode )
+                .map( BatchResults::new ) )
+    }
+}
+
+/// Results of an executed batch.
+pub struct BatchResults {
+    results: Vec<Option<Result<IncomingItem, ServiceError>>>,
+}
+
+impl BatchResults {
+
+    pub fn new( results: Vec<Result<IncomingItem, ServiceError>> ) -> Self {
+        BatchResults {
+            results: results.into_iter().map( Some ).collect()
+        }
+    }
+
+    /// Takes the result of a call.
+    ///
+    /// Each result can be taken only once.
+    pub fn get<T: DeserializeOwned>(
+        &mut self,
+        handle: BatchResult<T>
+    ) -> Result<T, ServiceError>
+    {
+        let result = self.results
+                .get_mut( handle.index )
+                .and_then( |r| r.take() )
+                .ok_or_else( || ServiceError::new( ErrorKind::BadRequest, format!(
+                    "Result {} is not available", handle.index ) ) )?;
+
+        result.and_then( |item|
+                T::deserialize( item ).map_err( ServiceError::from ) )
+    }
+
+    /// Returns the number of calls in the batch.
+    pub fn len( &self ) -> usize {
+        self.results.len()
+    }
+
+    pub fn is_empty( &self ) -> bool {
+        self.results.is_empty()
+    }
+}
@@ -0,0 +1,70 @@
+//! Descriptions of the contracts available at runtime.
+//!
+//! The `service_contract` attribute generates a static description for each
+//! contract, which is available through `MyContract::description()`. The
+//! descriptions drive the introspection and other tooling that needs to know
+//! the operations without compile time access to the contract.
+
+/// Describes a service contract.
+#[derive(Debug, Serialize)]
+pub struct ContractDescription {
+    pub name: &'static str,
+
+    /// Doc comment of the contract trait.
+    pub doc: Option<&'static str>,
+
+    /// Name of the callback contract of duplex contracts.
+    pub callback: Option<&'static str>,
+
+    pub operations: &'static [OperationDescription],
+}
+
+/// Describes a single operation of a contract.
+#[derive(Debug, Serialize)]
+pub struct OperationDescription {
+    pub name: &'static str,
+    pub doc: Option<&'static str>,
+    pub args: &'static [ArgumentDescription],
+
+    /// The return type as written in the contract.
+    pub output: &'static str,
+
+    /// The item type if the operation returns a `ServiceStream`.
+    pub output_stream: Option<&'static str>,
+
+    /// Whether the operation returns nothing, in which case the result
+    /// carries only the success of the call.
+    pub one_way: bool,
+
+    /// Whether the operation is marked `#[idempotent]`.
+    pub idempotent: bool,
+}
+
+impl OperationDescription {
+
+    /// Checks whether the operation has stream arguments or a stream
+    /// return value.
+    pub fn is_streaming( &self ) -> bool {
+        self.output_stream.is_some() || self.args.iter().any( |a| a.stream.is_some() )
+    }
+}
+
+/// Describes an argument of an operation.
+#[derive(Debug, Serialize)]
+pub struct ArgumentDescription {
+    pub name: &'static str,
+
+    /// The type as written in the contract.
+    pub type_name: &'static str,
+
+    /// The item type if the argument is a `ServiceStream`.
+    pub stream: Option<&'static str>,
+}
+
+impl ContractDescription {
+
+    /// Finds an operation by its name.
+    pub fn operation( &self, name: &str ) -> Option<&OperationDescription> {
+        self.operations.iter().find( |o| o.name == name )
+    }
+}
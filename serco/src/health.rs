@@ -0,0 +1,395 @@
+//! Built-in contracts for probing the hosts.
+//!
+//! Hosts built with `ServiceHost::health( true )` or
+//! `ServiceHost::introspection( true )` serve the `Health` and
+//! `Introspection` contracts on all of their endpoints next to the hosted
+//! contract. The operations of the built-in contracts use qualified names so
+//! they never collide with the operations of the hosted contract. Clients
+//! connect to them as to any other contract.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures::prelude::*;
+use serde::{Serialize, Serializer, Deserializer};
+
+use super::{ServiceError, ErrorKind, ServiceContract, InvokeTarget, CallInfo,
+        Forwarder, ServiceProxy};
+use super::registry::EndpointAddress;
+use super::description::{ContractDescription, OperationDescription};
+
+/// Whether the host is able to serve calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub enum ServingStatus {
+    Serving,
+    NotServing,
+}
+
+/// State of a host endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub enum EndpointState {
+    Running,
+    Stopped,
+    Failed( String ),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub struct EndpointStatus {
+
+    /// The registered address of the endpoint if it has one.
+    pub address: Option<EndpointAddress>,
+    pub state: EndpointState,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub struct HealthReport {
+    pub status: ServingStatus,
+    pub uptime_secs: u64,
+    pub active_sessions: usize,
+    pub endpoints: Vec<EndpointStatus>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub struct ArgumentInfo {
+    pub name: String,
+    pub type_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub struct OperationInfo {
+    pub name: String,
+    pub args: Vec<ArgumentInfo>,
+    pub output: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub struct ContractInfo {
+    pub name: String,
+    pub operations: Vec<OperationInfo>,
+
+    /// Name of the callback contract for duplex contracts.
+    pub callback: Option<String>,
+}
+
+impl ContractInfo {
+
+    /// Gets the information of a contract from its description.
+    pub fn of<S: ServiceContract + ?Sized>() -> ContractInfo {
+        ContractInfo::from( S::description() )
+    }
+}
+
+impl<'a> From<&'a ContractDescription> for ContractInfo {
+    fn from( description: &ContractDescription ) -> Self {
+        ContractInfo {
+            name: description.name.to_string(),
+            operations: description.operations.iter().map( |o| OperationInfo {
+                name: o.name.to_string(),
+                args: o.args.iter().map( |a| ArgumentInfo {
+                    name: a.name.to_string(),
+                    type_name: a.type_name.to_string(),
+                } ).collect(),
+                output: o.output.to_string(),
+            } ).collect(),
+            callback: description.callback.map( String::from ),
+        }
+    }
+}
+
+/// Reports the health of the host.
+pub trait Health {
+    fn status( &self ) -> HealthReport;
+}
+
+/// Lists the contracts the host serves.
+pub trait Introspection {
+    fn contracts( &self ) -> Vec<ContractInfo>;
+}
+
+/// Name of the operation that reports the health of the host.
+pub const HEALTH_STATUS : &str = "serco.Health.status";
+const INTROSPECTION_CONTRACTS : &str = "serco.Introspection.contracts";
+
+fn respond<T: Serialize, S: 'static>(
+    value: T,
+    mut output: S,
+) -> Box<Future<Item=S, Error=ServiceError>>
+    where for <'a> &'a mut S: Serializer
+{
+    if let Err( e ) = value.serialize( &mut output ) {
+        return Box::new( ::futures::future::err( ServiceError::from( e ) ) );
+    }
+    Box::new( ::futures::future::ok( output ) )
+}
+
+fn respond_direct<T: 'static>( value: T ) -> Box<Future<Item=Box<Any>, Error=ServiceError>>
+{
+    Box::new( ::futures::future::ok( Box::new( value ) as Box<Any> ) )
+}
+
+fn unknown_operation<S: 'static>( name: &str ) -> Box<Future<Item=S, Error=ServiceError>>
+{
+    Box::new( ::futures::future::err( ServiceError::new(
+            ErrorKind::BadOperation,
+            format!( "Unknown operation '{}'", name ) ) ) )
+}
+
+fn call<D: ::serde::de::DeserializeOwned + 'static, F: Forwarder>(
+    forwarder: &F,
+    name: &'static str,
+) -> D
+{
+    let call = CallInfo { name: name, idempotent: true, request_id: None };
+    forwarder.forward_call( call, () ).wait().unwrap()
+}
+
+// The built-in contracts are implemented by hand as the contract derive
+// can't be used within serco itself.
+
+impl ServiceContract for Health {
+    type CallbackContract = ();
+
+    fn contract_name() -> &'static str { "serco.Health" }
+
+    fn description() -> &'static ContractDescription {
+        static DESCRIPTION : ContractDescription = ContractDescription {
+            name: "serco.Health",
+            doc: Some( "Reports the health of the host." ),
+            callback: None,
+            operations: &[ OperationDescription {
+                name: HEALTH_STATUS,
+                doc: None,
+                args: &[],
+                output: "HealthReport",
+                output_stream: None,
+                one_way: false,
+                idempotent: true,
+            } ],
+        };
+        &DESCRIPTION
+    }
+
+    fn set_task_callback<F: Forwarder>( _ : Arc<ServiceProxy<Self, F>> ) {}
+    fn get_task_callback() -> Arc<Self> {
+        panic!( "Health is never used as a callback contract" )
+    }
+    fn from_proxy<F: Forwarder>( proxy : Arc<ServiceProxy<Self, F>> ) -> Arc<Self> {
+        proxy
+    }
+}
+
+impl InvokeTarget<Health> for Health {
+    fn invoke<'de, D, S>(
+        &self,
+        name: &str,
+        _params : D,
+        output : S
+    ) -> Box<Future<Item=S, Error=ServiceError>>
+        where
+            D: Deserializer<'de>,
+            S: 'static,
+            for <'a> &'a mut S: Serializer
+    {
+        match name {
+            HEALTH_STATUS => respond( self.status(), output ),
+            _ => unknown_operation( name ),
+        }
+    }
+
+    fn invoke_direct(
+        &self,
+        name: &str,
+        _params : Box<Any>,
+    ) -> Box<Future<Item=Box<Any>, Error=ServiceError>>
+    {
+        match name {
+            HEALTH_STATUS => respond_direct( self.status() ),
+            _ => unknown_operation( name ),
+        }
+    }
+}
+
+impl<F: Forwarder> Health for ServiceProxy<Health, F> {
+    fn status( &self ) -> HealthReport {
+        call( &self.forwarder, HEALTH_STATUS )
+    }
+}
+
+impl ServiceContract for Introspection {
+    type CallbackContract = ();
+
+    fn contract_name() -> &'static str { "serco.Introspection" }
+
+    fn description() -> &'static ContractDescription {
+        static DESCRIPTION : ContractDescription = ContractDescription {
+            name: "serco.Introspection",
+            doc: Some( "Lists the contracts the host serves." ),
+            callback: None,
+            operations: &[ OperationDescription {
+                name: INTROSPECTION_CONTRACTS,
+                doc: None,
+                args: &[],
+                output: "Vec<ContractInfo>",
+                output_stream: None,
+                one_way: false,
+                idempotent: true,
+            } ],
+        };
+        &DESCRIPTION
+    }
+
+    fn set_task_callback<F: Forwarder>( _ : Arc<ServiceProxy<Self, F>> ) {}
+    fn get_task_callback() -> Arc<Self> {
+        panic!( "Introspection is never used as a callback contract" )
+    }
+    fn from_proxy<F: Forwarder>( proxy : Arc<ServiceProxy<Self, F>> ) -> Arc<Self> {
+        proxy
+    }
+}
+
+impl InvokeTarget<Introspection> for Introspection {
+    fn invoke<'de, D, S>(
+        &self,
+        name: &str,
+        _params : D,
+        output : S
+    ) -> Box<Future<Item=S, Error=ServiceError>>
+        where
+            D: Deserializer<'de>,
+            S: 'static,
+            for <'a> &'a mut S: Serializer
+    {
+        match name {
+            INTROSPECTION_CONTRACTS => respond( self.contracts(), output ),
+            _ => unknown_operation( name ),
+        }
+    }
+
+    fn invoke_direct(
+        &self,
+        name: &str,
+        _params : Box<Any>,
+    ) -> Box<Future<Item=Box<Any>, Error=ServiceError>>
+    {
+        match name {
+            INTROSPECTION_CONTRACTS => respond_direct( self.contracts() ),
+            _ => unknown_operation( name ),
+        }
+    }
+}
+
+impl<F: Forwarder> Introspection for ServiceProxy<Introspection, F> {
+    fn contracts( &self ) -> Vec<ContractInfo> {
+        call( &self.forwarder, INTROSPECTION_CONTRACTS )
+    }
+}
+
+/// Host side implementation of the built-in contracts.
+pub struct Builtins {
+    health: bool,
+    introspection: bool,
+    started: Instant,
+    sessions: Box<Fn() -> usize>,
+    endpoints: Rc<RefCell<Vec<EndpointStatus>>>,
+    contracts: Vec<ContractInfo>,
+}
+
+impl Builtins {
+
+    pub fn new(
+        health: bool,
+        introspection: bool,
+        sessions: Box<Fn() -> usize>,
+        endpoints: Rc<RefCell<Vec<EndpointStatus>>>,
+        contracts: Vec<ContractInfo>,
+    ) -> Builtins
+    {
+        Builtins {
+            health: health,
+            introspection: introspection,
+            started: Instant::now(),
+            sessions: sessions,
+            endpoints: endpoints,
+            contracts: contracts,
+        }
+    }
+
+    /// Invokes the operation if it belongs to an enabled built-in contract.
+    ///
+    /// Returns the parameters and the output back if it doesn't.
+    pub fn try_invoke<'de, D, S>(
+        &self,
+        name: &str,
+        params: D,
+        output: S,
+    ) -> Result<Box<Future<Item=S, Error=ServiceError>>, ( D, S )>
+        where
+            D: Deserializer<'de>,
+            S: 'static,
+            for <'a> &'a mut S: Serializer
+    {
+        if self.health && name == HEALTH_STATUS {
+            Ok( ( self as &Health ).invoke( name, params, output ) )
+        } else if self.introspection && name == INTROSPECTION_CONTRACTS {
+            Ok( ( self as &Introspection ).invoke( name, params, output ) )
+        } else {
+            Err( ( params, output ) )
+        }
+    }
+
+    /// Invokes the operation directly if it belongs to an enabled built-in
+    /// contract.
+    ///
+    /// Returns the parameters back if it doesn't.
+    pub fn try_invoke_direct(
+        &self,
+        name: &str,
+        params: Box<Any>,
+    ) -> Result<Box<Future<Item=Box<Any>, Error=ServiceError>>, Box<Any>>
+    {
+        if self.health && name == HEALTH_STATUS {
+            Ok( ( self as &Health ).invoke_direct( name, params ) )
+        } else if self.introspection && name == INTROSPECTION_CONTRACTS {
+            Ok( ( self as &Introspection ).invoke_direct( name, params ) )
+        } else {
+            Err( params )
+        }
+    }
+}
+
+impl Health for Builtins {
+    fn status( &self ) -> HealthReport {
+        let endpoints = self.endpoints.borrow().clone();
+        let serving = endpoints.iter().any( |e| e.state == EndpointState::Running );
+        HealthReport {
+            status: if serving { ServingStatus::Serving }
+                    else { ServingStatus::NotServing },
+            uptime_secs: self.started.elapsed().as_secs(),
+            active_sessions: ( self.sessions )(),
+            endpoints: endpoints,
+        }
+    }
+}
+
+impl Introspection for Builtins {
+    fn contracts( &self ) -> Vec<ContractInfo> {
+        let mut contracts = self.contracts.clone();
+        if self.health {
+            contracts.push( ContractInfo::of::<Health>() );
+        }
+        if self.introspection {
+            contracts.push( ContractInfo::of::<Introspection>() );
+        }
+        contracts
+    }
+}
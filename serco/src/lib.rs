@@ -1,10 +1,15 @@
-extern crate futures;
+#[macro_use] extern crate futures;
 use futures::prelude::*;
 
 extern crate serde;
 use serde::{Serializer, Serialize, Deserializer};
 use serde::de::DeserializeOwned;
 #[macro_use] extern crate serde_derive;
+extern crate erased_serde;
+extern crate rand;
+#[macro_use] extern crate serde_json;
+#[macro_use] extern crate tracing;
+#[cfg(test)] extern crate tokio_core;
 
 // The crate doesn't really need the macros. However Rust will complain that
 // the import does nothing if we don't define #[macro_use]. Once we define
@@ -25,21 +30,156 @@ extern crate serco_derive;
 pub mod prelude {
     pub use serco_derive::*;
     pub use super::ServiceContract;
+    pub use super::ServiceStream;
 }
 
+pub mod stream;
+pub use stream::ServiceStream;
+
+pub mod sessions;
+pub use sessions::{ConnectedSessions, PeerIdentity};
+
+pub mod batch;
+
+pub mod retry;
+
+pub mod reconnect;
+
+pub mod balance;
+
+pub mod registry;
+
+pub mod description;
+pub use description::ContractDescription;
+
+pub mod schema;
+
+pub mod health;
+pub use health::{Health, Introspection};
+
+pub mod auth;
+pub use auth::{Authenticator, Credentials, Principal};
+
+pub mod throttle;
+pub use throttle::{Throttling, RateLimit};
+
+pub mod limits;
+pub use limits::MessageLimits;
+
+pub mod metrics;
+pub use metrics::{Metrics, MetricsCollector};
+
+pub mod trace;
+pub use trace::TraceContext;
+
+use registry::{ServiceRegistry, Registration};
+
+use std::any::Any;
 use std::rc::Rc;
+use std::cell::{Cell, RefCell};
 use std::sync::Arc;
 use std::marker::PhantomData;
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
 use std::borrow::Cow;
+use std::time::{Duration, Instant};
+
+/// How long the sessions the clients may resume are kept once no client
+/// uses them.
+pub const DEFAULT_SESSION_TIMEOUT : Duration = Duration::from_secs( 600 );
 
-#[derive(Debug)]
+/// Classifies the service errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[derive(Serialize, Deserialize)]
-pub struct ServiceError(pub String);
+pub enum ErrorKind {
+
+    /// The operation or the host failed to process the call.
+    Internal,
+
+    /// The call couldn't be delivered or its response was lost.
+    Transport,
+
+    /// The contract has no operation with the requested name.
+    BadOperation,
+
+    /// The operation parameters couldn't be deserialized.
+    BadRequest,
+
+    /// The client couldn't be authenticated.
+    Unauthenticated,
+
+    /// The client isn't allowed to call the operation.
+    Unauthorized,
+
+    /// The host refused the work over its limits.
+    Throttled,
+
+    /// The message is over the size or nesting limits.
+    MessageTooLarge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct ServiceError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
 impl ServiceError {
+    pub fn new<T: Into<String>>( kind: ErrorKind, message: T ) -> ServiceError
+    {
+        ServiceError { kind: kind, message: message.into() }
+    }
+
     pub fn from<T: std::fmt::Debug>( src: T ) -> ServiceError
     {
-        ServiceError( format!( "{:?}", src ) )
+        ServiceError::new( ErrorKind::Internal, format!( "{:?}", src ) )
+    }
+
+    pub fn transport<T: std::fmt::Debug>( src: T ) -> ServiceError
+    {
+        ServiceError::new( ErrorKind::Transport, format!( "{:?}", src ) )
+    }
+
+    pub fn unauthenticated<T: Into<String>>( message: T ) -> ServiceError
+    {
+        ServiceError::new( ErrorKind::Unauthenticated, message )
+    }
+
+    pub fn unauthorized<T: Into<String>>( message: T ) -> ServiceError
+    {
+        ServiceError::new( ErrorKind::Unauthorized, message )
+    }
+
+    pub fn throttled<T: Into<String>>( message: T ) -> ServiceError
+    {
+        ServiceError::new( ErrorKind::Throttled, message )
+    }
+
+    pub fn message_too_large<T: Into<String>>( message: T ) -> ServiceError
+    {
+        ServiceError::new( ErrorKind::MessageTooLarge, message )
+    }
+
+    /// Checks whether the call might succeed if it was retried.
+    pub fn is_transient( &self ) -> bool
+    {
+        self.kind == ErrorKind::Transport
+    }
+}
+
+/// Errors created from plain messages are internal errors, as were all
+/// errors before they had a kind.
+impl From<String> for ServiceError {
+    fn from( message: String ) -> ServiceError
+    {
+        ServiceError::new( ErrorKind::Internal, message )
+    }
+}
+
+impl<'a> From<&'a str> for ServiceError {
+    fn from( message: &'a str ) -> ServiceError
+    {
+        ServiceError::new( ErrorKind::Internal, message )
     }
 }
 
@@ -54,7 +194,16 @@ pub struct ServiceHost<
     hosted: THostImplementation,
     session_factory: TSessionFactory,
     endpoints: Vec<Box<ServiceEndpoint<TService, TSessionFactory, THostImplementation>>>,
-    sessions: HashMap<String, Rc<THostImplementation::ServiceInstance>>,
+    connected: ConnectedSessions<TService::CallbackContract>,
+    registry: Option<Arc<ServiceRegistry>>,
+    metadata: BTreeMap<String, String>,
+    health: bool,
+    introspection: bool,
+    authenticator: Option<Box<Authenticator>>,
+    throttling: Option<Throttling>,
+    message_limits: MessageLimits,
+    metrics: Option<Arc<Metrics>>,
+    session_timeout: Duration,
 
     p_service: PhantomData<TService>,
 }
@@ -82,7 +231,16 @@ impl<TService,
 
             session_factory: Default::default(),
             endpoints: Default::default(),
-            sessions: Default::default(),
+            connected: Default::default(),
+            registry: None,
+            metadata: Default::default(),
+            health: false,
+            introspection: false,
+            authenticator: None,
+            throttling: None,
+            message_limits: MessageLimits::new(),
+            metrics: None,
+            session_timeout: DEFAULT_SESSION_TIMEOUT,
 
             p_service: PhantomData,
         }
@@ -116,12 +274,105 @@ impl<TService,
 
             session_factory: session_factory,
             endpoints: Default::default(),
-            sessions: Default::default(),
+            connected: self.connected,
+            registry: self.registry,
+            metadata: self.metadata,
+            health: self.health,
+            introspection: self.introspection,
+            authenticator: self.authenticator,
+            throttling: self.throttling,
+            message_limits: self.message_limits,
+            metrics: self.metrics,
+            session_timeout: self.session_timeout,
 
             p_service: PhantomData,
         }
     }
 
+    /// Specifies the set that records the sessions connected to the host.
+    ///
+    /// The service implementation can hold on to a clone of the set to push
+    /// callbacks to the clients outside of their own calls.
+    pub fn connected_sessions(
+        mut self,
+        connected: ConnectedSessions<TService::CallbackContract>
+    ) -> Self
+    {
+        self.connected = connected;
+        self
+    }
+
+    /// Specifies the registry where the endpoints are registered while the
+    /// host is running.
+    pub fn registry<TRegistry: ServiceRegistry + 'static>(
+        mut self,
+        registry: TRegistry
+    ) -> Self
+    {
+        self.registry = Some( Arc::new( registry ) );
+        self
+    }
+
+    /// Adds metadata to the registrations of the endpoints.
+    pub fn metadata<K: Into<String>, V: Into<String>>(
+        mut self,
+        key: K,
+        value: V
+    ) -> Self
+    {
+        self.metadata.insert( key.into(), value.into() );
+        self
+    }
+
+    /// Serves the built-in `Health` contract on the endpoints.
+    pub fn health( mut self, enabled: bool ) -> Self {
+        self.health = enabled;
+        self
+    }
+
+    /// Serves the built-in `Introspection` contract on the endpoints.
+    pub fn introspection( mut self, enabled: bool ) -> Self {
+        self.introspection = enabled;
+        self
+    }
+
+    /// Authenticates the connecting clients. See the `auth` module.
+    pub fn authenticator<TAuthenticator: Authenticator + 'static>(
+        mut self,
+        authenticator: TAuthenticator
+    ) -> Self
+    {
+        self.authenticator = Some( Box::new( authenticator ) );
+        self
+    }
+
+    /// Limits the work the host accepts. See the `throttle` module.
+    pub fn throttling( mut self, throttling: Throttling ) -> Self {
+        self.throttling = Some( throttling );
+        self
+    }
+
+    /// Limits the size of the messages. See the `limits` module.
+    pub fn message_limits( mut self, limits: MessageLimits ) -> Self {
+        self.message_limits = limits;
+        self
+    }
+
+    /// Reports the calls, sessions and transfers of the host. See the
+    /// `metrics` module.
+    pub fn metrics( mut self, metrics: Arc<Metrics> ) -> Self {
+        self.metrics = Some( metrics );
+        self
+    }
+
+    /// Specifies how long the sessions the clients may resume by their IDs
+    /// are kept once no client uses them. Defaults to
+    /// `DEFAULT_SESSION_TIMEOUT`.
+    pub fn session_timeout( mut self, timeout: Duration ) -> Self {
+        self.session_timeout = timeout;
+        self
+    }
+
     pub fn endpoint<TEndpoint: ServiceEndpoint<TService, TSessionFactory, THostImplementation> + 'static>(
         mut self,
         endpoint: TEndpoint
@@ -133,34 +384,114 @@ impl<TService,
 
     pub fn run( self ) -> Box<Future<Item=Self, Error=ServiceError>>
     {
+        let registrations : Vec<Registration> = self.endpoints.iter()
+                .filter_map( |endpoint| endpoint.address() )
+                .map( |address| Registration {
+                    contract: TService::contract_id().to_string(),
+                    endpoint: address,
+                    metadata: self.metadata.clone(),
+                } )
+                .collect();
+        let endpoint_status = Rc::new( RefCell::new( self.endpoints.iter()
+                .map( |endpoint| health::EndpointStatus {
+                    address: endpoint.address(),
+                    state: health::EndpointState::Running,
+                } )
+                .collect::<Vec<_>>() ) );
+
+        let ( health, introspection ) = ( self.health, self.introspection );
+        let builtins = if health || introspection {
+            let connected = self.connected.clone();
+            let mut contracts = vec![ health::ContractInfo::of::<TService>() ];
+            if TService::CallbackContract::contract_name() != "()" {
+                contracts.push( health::ContractInfo::of::<TService::CallbackContract>() );
+            }
+            Some( Rc::new( health::Builtins::new(
+                    health,
+                    introspection,
+                    Box::new( move || connected.len() ),
+                    endpoint_status.clone(),
+                    contracts ) ) )
+        } else {
+            None
+        };
+
+        let registry = self.registry;
+        let metadata = self.metadata;
+        let throttling = self.throttling;
         let runtime = Rc::new( HostRuntime {
             hosted: self.hosted,
             session_factory: self.session_factory,
-            sessions: self.sessions,
+            sessions: Default::default(),
+            connected: self.connected,
+            builtins: builtins,
+            authenticator: self.authenticator,
+            throttle: throttling.clone().map( |t| Rc::new( throttle::Throttle::new( t ) ) ),
+            message_limits: self.message_limits,
+            metrics: self.metrics,
+            session_timeout: self.session_timeout,
         } );
 
         let runtime_clone = runtime.clone();
         let run_futures = futures::future::join_all( self.endpoints
             .into_iter()
-            .map( move |endpoint| {
-                endpoint.run( runtime_clone.clone() ).map( |_| endpoint )
+            .enumerate()
+            .map( move |( index, endpoint )| {
+                let endpoint_status = endpoint_status.clone();
+                endpoint.run( runtime_clone.clone() ).then( move |result| {
+                    endpoint_status.borrow_mut()[ index ].state = match result {
+                        Ok( _ ) => health::EndpointState::Stopped,
+                        Err( ref e ) => health::EndpointState::Failed(
+                                e.message.clone() ),
+                    };
+                    result.map( |_| endpoint )
+                } )
             } ) );
 
-        let final_future = run_futures.then( |run_results| match run_results {
-            Ok(endpoints) => {
-                let runtime = Rc::try_unwrap( runtime )
-                            .map_err( |_| "Leaking RCs" )
-                            .unwrap();
-
-                Ok( ServiceHost {
-                    hosted: runtime.hosted,
-                    session_factory: runtime.session_factory,
-                    sessions: runtime.sessions,
-                    endpoints: endpoints,
-                    p_service: PhantomData,
-                } )
-            },
-            Err( e ) => Err( ServiceError::from(e) ),
+        // The endpoints are listening once they have been started so they
+        // can be registered.
+        if let Some( ref registry ) = registry {
+            for registration in &registrations {
+                if let Err( e ) = registry.register( registration.clone() ) {
+                    return Box::new( futures::future::err( e ) );
+                }
+            }
+        }
+
+        let final_future = run_futures.then( move |run_results| {
+
+            if let Some( ref registry ) = registry {
+                for registration in &registrations {
+                    let _ = registry.unregister(
+                            &registration.contract, &registration.endpoint );
+                }
+            }
+
+            match run_results {
+                Ok(endpoints) => {
+                    let runtime = Rc::try_unwrap( runtime )
+                                .map_err( |_| "Leaking RCs" )
+                                .unwrap();
+
+                    Ok( ServiceHost {
+                        hosted: runtime.hosted,
+                        session_factory: runtime.session_factory,
+                        connected: runtime.connected,
+                        registry: registry,
+                        metadata: metadata,
+                        health: health,
+                        introspection: introspection,
+                        authenticator: runtime.authenticator,
+                        throttling: throttling,
+                        message_limits: runtime.message_limits,
+                        metrics: runtime.metrics,
+                        session_timeout: runtime.session_timeout,
+                        endpoints: endpoints,
+                        p_service: PhantomData,
+                    } )
+                },
+                Err( e ) => Err( e ),
+            }
         } );
 
         Box::new( final_future )
@@ -177,15 +508,48 @@ pub struct HostRuntime<TService,
 {
     hosted: THostImplementation,
     session_factory: TSessionFactory,
-    sessions: HashMap<String, Rc<THostImplementation::ServiceInstance>>,
+
+    /// The sessions the clients may resume by their IDs.
+    sessions: RefCell<HashMap<String, StoredSession<THostImplementation::ServiceInstance>>>,
+    connected: ConnectedSessions<TService::CallbackContract>,
+    builtins: Option<Rc<health::Builtins>>,
+    authenticator: Option<Box<Authenticator>>,
+    throttle: Option<Rc<throttle::Throttle>>,
+    message_limits: MessageLimits,
+    metrics: Option<Arc<Metrics>>,
+    session_timeout: Duration,
+}
+
+/// Identifies the client that opened a session. Only the same client may
+/// resume it.
+#[derive(PartialEq)]
+enum SessionOwner {
+    Anonymous,
+    Principal( Principal ),
+    Peer( PeerIdentity ),
+}
+
+/// A session opened with `HostRuntime::open_resumable_session`.
+struct StoredSession<T> {
+    target: Rc<sessions::SessionTarget<T>>,
+    owner: SessionOwner,
+    last_used: Cell<Instant>,
+
+    /// The responses of the recent calls by their request IDs. Kept with the
+    /// session so the calls retried after a reconnect aren't executed again.
+    responses: Rc<RefCell<retry::ResponseCache<String>>>,
 }
 
 pub trait SessionInfo {
     fn key(&self) -> Cow<str>;
+
+    /// Gets the principal the session was created for if the host
+    /// authenticates its clients.
+    fn principal(&self) -> Option<&Principal> { None }
 }
 
 impl SessionInfo for SessionId {
-    fn key(&self) -> Cow<str> { Cow::from( self.0.as_ref() ) }
+    fn key(&self) -> Cow<str> { Cow::from( self.0.as_str() ) }
 }
 
 impl SessionInfo for usize {
@@ -205,24 +569,216 @@ impl<TService,
           TSessionFactory: SessionFactory + 'static,
           THostImplementation: HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
 {
-    pub fn get_session<'a, 'b>(
-        &'a self,
-        id: Option<&'b str>
-    ) -> ( Cow<'b, str>, THostImplementation::ServiceInstance )
-    {
-        match id {
-            Some( id ) => {
-                let session_info = self.session_factory.get_session( id );
-                let session = self.hosted.get_session( session_info );
-                ( Cow::from( id ), session )
-            }
-            None => {
-                let ( id, session_info ) = self.session_factory.create_session();
-                let session = self.hosted.get_session( session_info );
-                ( Cow::from( id ), session )
+    /// Authenticates a new connection and creates its session.
+    ///
+    /// Fails if the authenticator of the host rejects the credentials or if
+    /// the host has reached its limit of sessions. Hosts without an
+    /// authenticator accept all connections and pass the certificates
+    /// verified by the transport to the session factory as the identity of
+    /// the peer.
+    pub fn open_session(
+        &self,
+        credentials: &Credentials
+    ) -> Result<( String, sessions::SessionTarget<THostImplementation::ServiceInstance> ), ServiceError>
+    {
+        self.open( credentials ).map( |( id, target, _ )| ( id, target ) )
+    }
+
+    /// Opens a session the client can resume later by its ID with
+    /// `resume_session`.
+    pub fn open_resumable_session(
+        &self,
+        credentials: &Credentials
+    ) -> Result<( String, Rc<sessions::SessionTarget<THostImplementation::ServiceInstance>> ), ServiceError>
+    {
+        let ( id, target, owner ) = self.open( credentials )?;
+        let target = Rc::new( target );
+        self.sessions.borrow_mut().insert( id.clone(), StoredSession {
+            target: target.clone(),
+            owner: owner,
+            last_used: Cell::new( Instant::now() ),
+            responses: Default::default(),
+        } );
+        Ok( ( id, target ) )
+    }
+
+    /// Resumes a session opened with `open_resumable_session`.
+    ///
+    /// The client is authenticated again as the session ID alone doesn't
+    /// prove who the client is. Fails unless the client is the one that
+    /// opened the session or if the session is not found.
+    pub fn resume_session(
+        &self,
+        id: &str,
+        credentials: &Credentials
+    ) -> Result<Rc<sessions::SessionTarget<THostImplementation::ServiceInstance>>, ServiceError>
+    {
+        let owner = session_owner( self.authenticate( credentials )?, credentials );
+        self.expire_sessions();
+        let sessions = self.sessions.borrow();
+        let stored = match sessions.get( id ) {
+            Some( stored ) => stored,
+            None => return Err( ServiceError::new(
+                    ErrorKind::BadRequest,
+                    format!( "Session '{}' not found", id ) ) ),
+        };
+        if stored.owner != owner {
+            return Err( ServiceError::unauthorized(
+                    format!( "Session '{}' belongs to another client", id ) ) );
+        }
+        stored.last_used.set( Instant::now() );
+        Ok( stored.target.clone() )
+    }
+
+    /// Gets the responses of the recent calls of a session opened with
+    /// `open_resumable_session` by their request IDs.
+    ///
+    /// The endpoints respond to the retried calls from the cache instead of
+    /// executing them again. The cache is shared by all connections of the
+    /// session, whichever endpoint they use.
+    pub fn session_responses(
+        &self,
+        id: &str
+    ) -> Option<Rc<RefCell<retry::ResponseCache<String>>>>
+    {
+        self.sessions.borrow().get( id ).map( |stored| stored.responses.clone() )
+    }
+
+    /// Forgets a session opened with `open_resumable_session`. The session
+    /// ends once the endpoints drop their references to it.
+    pub fn close_session( &self, id: &str ) {
+        self.sessions.borrow_mut().remove( id );
+    }
+
+    /// Forgets the resumable sessions no endpoint has used within the
+    /// session timeout. Dropping them releases their session permits.
+    fn expire_sessions( &self ) {
+        let now = Instant::now();
+        let timeout = self.session_timeout;
+        self.sessions.borrow_mut().retain( |_, stored| {
+
+            // The sessions the endpoints hold on to are in use.
+            if Rc::strong_count( &stored.target ) > 1 {
+                stored.last_used.set( now );
+                return true;
             }
+            now.duration_since( stored.last_used.get() ) < timeout
+        } );
+    }
+
+    /// Authenticates the client and creates its session.
+    fn open(
+        &self,
+        credentials: &Credentials
+    ) -> Result<( String, sessions::SessionTarget<THostImplementation::ServiceInstance>, SessionOwner ), ServiceError>
+    {
+        let principal = self.authenticate( credentials )?;
+
+        // The expired sessions give up their permits before the new session
+        // asks for one.
+        self.expire_sessions();
+        let permit = match self.throttle {
+            Some( ref throttle ) => Some( throttle.open_session()? ),
+            None => None,
+        };
+        let ( id, session_info ) = match principal {
+            Some( ref principal ) =>
+                self.session_factory.create_authenticated_session( principal.clone() ),
+            None => match *credentials {
+                Credentials::Certificate( ref identity ) =>
+                    self.session_factory.create_peer_session( identity ),
+                _ => self.session_factory.create_session(),
+            },
+        };
+        let target = self.session_target( &id, session_info, permit );
+        Ok( ( id, target, session_owner( principal, credentials ) ) )
+    }
+
+    /// Authenticates the credentials without creating a session.
+    ///
+    /// Resolves to `None` if the host has no authenticator.
+    pub fn authenticate(
+        &self,
+        credentials: &Credentials
+    ) -> Result<Option<Principal>, ServiceError>
+    {
+        match self.authenticator {
+            Some( ref authenticator ) => authenticator.authenticate( credentials ).map( Some ),
+            None => Ok( None ),
         }
     }
+
+    /// Creates the session and wraps it into the target the endpoints
+    /// invoke the calls on.
+    fn session_target(
+        &self,
+        id: &str,
+        session_info: Rc<TSessionFactory::SessionInfo>,
+        permit: Option<throttle::Permit>,
+    ) -> sessions::SessionTarget<THostImplementation::ServiceInstance>
+    {
+        let principal = session_info.principal().cloned().map( Rc::new );
+        let throttle = self.throttle.as_ref()
+                .map( |throttle| throttle::Throttle::session( throttle, permit ) );
+        let session = self.hosted.get_session( session_info );
+        sessions::SessionTarget::new(
+                session, id, self.builtins.clone(), principal, throttle, self.metrics.clone() )
+    }
+
+    /// The metrics the host reports to, if any.
+    pub fn metrics( &self ) -> Option<&Arc<Metrics>> {
+        self.metrics.as_ref()
+    }
+
+    /// Creates the metrics of the bytes an endpoint transfers.
+    pub fn endpoint_metrics( &self, endpoint: &'static str ) -> metrics::EndpointMetrics {
+        metrics::EndpointMetrics::new( self.metrics.clone(), endpoint )
+    }
+
+    /// The limits the endpoints check the messages against.
+    pub fn message_limits( &self ) -> &MessageLimits {
+        &self.message_limits
+    }
+
+    /// Creates the connection limit of an endpoint. Each endpoint creates
+    /// its own when it starts.
+    pub fn connection_limit( &self ) -> throttle::ConnectionLimit {
+        match self.throttle {
+            Some( ref throttle ) => throttle.connections(),
+            None => throttle::ConnectionLimit::unlimited(),
+        }
+    }
+
+    /// Records a session as connected along with its callback proxy.
+    pub fn connect_session<F: Forwarder>(
+        &self,
+        id: &str,
+        callback: Arc<ServiceProxy<TService::CallbackContract, F>>
+    ) {
+        self.connected.connect( id, callback );
+    }
+
+    /// Removes a session from the connected sessions.
+    pub fn disconnect_session( &self, id: &str ) {
+        self.connected.disconnect( id );
+    }
+
+    /// Gets the sessions currently connected to the host.
+    pub fn connected_sessions( &self ) -> &ConnectedSessions<TService::CallbackContract> {
+        &self.connected
+    }
+}
+
+/// Identifies the owner of a session by the principal the authenticator
+/// accepted or, without an authenticator, by the verified peer identity.
+fn session_owner( principal: Option<Principal>, credentials: &Credentials ) -> SessionOwner {
+    match principal {
+        Some( principal ) => SessionOwner::Principal( principal ),
+        None => match *credentials {
+            Credentials::Certificate( ref identity ) => SessionOwner::Peer( identity.clone() ),
+            _ => SessionOwner::Anonymous,
+        },
+    }
 }
 
 pub trait ServiceEndpoint<TService,
@@ -241,12 +797,34 @@ pub trait ServiceEndpoint<TService,
                 THostImplementation,
         >>
     ) -> Box<Future<Item=(), Error=ServiceError>>;
+
+    /// Gets the address under which the endpoint is registered.
+    ///
+    /// Endpoints that return `None` are not registered.
+    fn address( &self ) -> Option<registry::EndpointAddress> { None }
 }
 
 pub trait SessionFactory {
     type SessionInfo : SessionInfo;
     fn create_session( &self ) -> ( String, Rc<Self::SessionInfo> );
     fn get_session( &self, key: &str ) -> Rc<Self::SessionInfo>;
+
+    /// Creates a session for a peer whose identity the transport has
+    /// verified, such as a TLS client presenting a certificate.
+    ///
+    /// The identity is ignored unless the factory overrides this.
+    fn create_peer_session( &self, _identity: &PeerIdentity ) -> ( String, Rc<Self::SessionInfo> ) {
+        self.create_session()
+    }
+
+    /// Creates a session for a client the authenticator of the host has
+    /// accepted.
+    ///
+    /// The principal is dropped unless the factory overrides this. The
+    /// `auth::PrincipalSessionFactory` stores it in the session.
+    fn create_authenticated_session( &self, _principal: Principal ) -> ( String, Rc<Self::SessionInfo> ) {
+        self.create_session()
+    }
 }
 pub struct DefaultSessionFactory;
 impl Default for DefaultSessionFactory {
@@ -258,16 +836,13 @@ impl SessionFactory for DefaultSessionFactory {
     type SessionInfo = SessionId;
     fn create_session( &self ) -> ( String, Rc<Self::SessionInfo> )
     {
-        // TODO: Implement.
-        (
-            String::from( "" ),
-            Rc::new( SessionId( "".into() ) )
-        )
+        // The connected sessions are told apart by their IDs.
+        let id = sessions::new_session_id();
+        ( id.clone(), Rc::new( SessionId( id ) ) )
     }
-    fn get_session( &self, _key : &str ) -> Rc<Self::SessionInfo>
+    fn get_session( &self, key : &str ) -> Rc<Self::SessionInfo>
     {
-        // TODO: Implement.
-        Rc::new( SessionId( "".into() ) )
+        Rc::new( SessionId( key.to_string() ) )
     }
 }
 
@@ -275,17 +850,50 @@ impl SessionFactory for DefaultSessionFactory {
 ///
 /// Implemented by the `#[service_contract]` attribute.
 pub trait ServiceContract : InvokeTarget<Self> {
-    type CallbackContract: ServiceContract<CallbackContract = ()> + ?Sized;
+    type CallbackContract: ServiceContract<CallbackContract = ()> + ?Sized + 'static;
+
+    /// Gets the name of the contract.
+    fn contract_name() -> &'static str;
+
+    /// Gets the ID the endpoints of the contract are registered and resolved
+    /// with. The contracts declared with `#[service_contract]` qualify their
+    /// name with the path of the module they are declared in so contracts of
+    /// the same name don't share their registrations.
+    fn contract_id() -> &'static str { Self::contract_name() }
+
+    /// Gets the description of the contract generated from its definition.
+    fn description() -> &'static description::ContractDescription;
+
+    /// Gets the JSON Schema of the contract.
+    ///
+    /// Only available for contracts declared with
+    /// `#[service_contract(schema)]`.
+    fn schema() -> Option<schema::Schema> { None }
 
     fn set_task_callback<F: Forwarder>( callback : Arc<ServiceProxy<Self, F>> );
     fn get_task_callback() -> Arc<Self>;
+
+    /// Turns a proxy into a contract reference.
+    fn from_proxy<F: Forwarder>( proxy : Arc<ServiceProxy<Self, F>> ) -> Arc<Self>;
 }
 
 impl ServiceContract for () {
     type CallbackContract = ();
 
+    fn contract_name() -> &'static str { "()" }
+    fn description() -> &'static description::ContractDescription {
+        static DESCRIPTION : description::ContractDescription =
+            description::ContractDescription {
+                name: "()",
+                doc: None,
+                callback: None,
+                operations: &[],
+            };
+        &DESCRIPTION
+    }
     fn set_task_callback<F: Forwarder>( _ : Arc<ServiceProxy<Self, F>> ) {}
     fn get_task_callback() -> Arc<Self> { Arc::new(())}
+    fn from_proxy<F: Forwarder>( _ : Arc<ServiceProxy<Self, F>> ) -> Arc<Self> { Arc::new(()) }
 }
 
 impl InvokeTarget<()> for () {
@@ -300,8 +908,9 @@ impl InvokeTarget<()> for () {
             S: 'static,
             for <'a> &'a mut S: Serializer
     {
-        // TODO: Replace with bad method service error.
-        panic!( "Nothing should ever be invoked on ()" );
+        Box::new( futures::future::err( ServiceError::new(
+                ErrorKind::BadOperation,
+                "Nothing should ever be invoked on ()" ) ) )
     }
 }
 
@@ -413,6 +1022,24 @@ pub trait InvokeTarget<Service>
             D: Deserializer<'de>,
             S: 'static,
             for <'a> &'a mut S: Serializer;
+
+    /// Invokes an operation with the parameters as the proxies pass them to
+    /// their forwarder, without serializing them.
+    ///
+    /// The `params` hold the parameters the generated proxies build for the
+    /// operation and the result holds the return value of the operation.
+    /// Targets that are only invoked through serialization can rely on the
+    /// default implementation, which fails the call.
+    fn invoke_direct(
+        &self,
+        name: &str,
+        _params : Box<Any>,
+    ) -> Box<Future<Item=Box<Any>, Error=ServiceError>>
+    {
+        Box::new( futures::future::err( ServiceError::new(
+                ErrorKind::BadOperation,
+                format!( "Operation '{}' can't be invoked directly", name ) ) ) )
+    }
 }
 
 impl<A, B> InvokeTarget<A> for std::rc::Rc<B>
@@ -432,6 +1059,15 @@ impl<A, B> InvokeTarget<A> for std::rc::Rc<B>
     {
         ( self as &B ).invoke( name, params, output )
     }
+
+    fn invoke_direct(
+        &self,
+        name: &str,
+        params : Box<Any>,
+    ) -> Box<Future<Item=Box<Any>, Error=ServiceError>>
+    {
+        ( self as &B ).invoke_direct( name, params )
+    }
 }
 
 impl<A, B> InvokeTarget<A> for Box<B>
@@ -451,6 +1087,36 @@ impl<A, B> InvokeTarget<A> for Box<B>
     {
         ( self as &B ).invoke( name, params, output )
     }
+
+    fn invoke_direct(
+        &self,
+        name: &str,
+        params : Box<Any>,
+    ) -> Box<Future<Item=Box<Any>, Error=ServiceError>>
+    {
+        ( self as &B ).invoke_direct( name, params )
+    }
+}
+
+/// Describes a call made through a forwarder.
+#[derive(Debug, Clone)]
+pub struct CallInfo {
+
+    /// Name of the operation.
+    pub name: &'static str,
+
+    /// Whether the operation is marked `#[idempotent]` in the contract.
+    pub idempotent: bool,
+
+    /// Identifies the call across retries so the host can de-duplicate
+    /// calls it has already executed.
+    pub request_id: Option<String>,
+}
+
+impl CallInfo {
+    pub fn new( name: &'static str ) -> CallInfo {
+        CallInfo { name: name, idempotent: false, request_id: None }
+    }
 }
 
 /// A service forwarder used by the proxy implementation.
@@ -471,6 +1137,65 @@ pub trait Forwarder : 'static {
             D: DeserializeOwned + 'static,
             S: Serialize + 'static;
 
+    /// Forwards a call along with the information on the call.
+    ///
+    /// The generated proxies use this method for all operations that don't
+    /// involve streams. Forwarders that can't make use of the information
+    /// can rely on the default implementation, which calls `forward`.
+    fn forward_call<D, S>(
+        &self,
+        call: CallInfo,
+        params : S,
+    ) -> Box<Future<Item=D, Error=ServiceError>>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static
+    {
+        self.forward( call.name, params )
+    }
+
+    /// Forwards a call to an operation that takes or returns streams.
+    ///
+    /// The `params` contain the non-stream arguments while the `streams`
+    /// contain the stream arguments in the order they appear in the
+    /// operation signature. Operations that return a single value resolve
+    /// it as the only item of the result stream.
+    ///
+    /// Forwarders that don't support streaming can rely on the default
+    /// implementation, which fails the call.
+    fn forward_streaming<D, S>(
+        &self,
+        name: &'static str,
+        _params : S,
+        _streams : Vec<stream::OutgoingStream>,
+    ) -> ServiceStream<D>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static
+    {
+        Box::new( futures::stream::once( Err( ServiceError::new(
+                ErrorKind::BadOperation, format!(
+                    "Forwarder does not support streaming operation '{}'",
+                    name ) ) ) ) )
+    }
+
+    /// Forwards a batch of calls in a single request.
+    ///
+    /// The results are returned in the order of the calls. Forwarders that
+    /// don't support batching can rely on the default implementation, which
+    /// fails the whole batch.
+    fn forward_batch(
+        &self,
+        _calls : Vec<batch::Call>,
+        _mode : batch::BatchMode,
+    ) -> Box<Future<Item=Vec<Result<stream::IncomingItem, ServiceError>>,
+                    Error=ServiceError>>
+    {
+        Box::new( futures::future::err( ServiceError::new(
+                ErrorKind::BadOperation,
+                "Forwarder does not support batches" ) ) )
+    }
+
     fn close( self );
 }
 
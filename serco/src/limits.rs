@@ -0,0 +1,147 @@
+//! Limits on the size of the messages.
+//!
+//! Hosts built with `ServiceHost::message_limits` check the messages
+//! against the limits before deserializing them and refuse the ones over
+//! the limits with a `MessageTooLarge` error:
+//!
+//! - The requests over the maximum size are refused before they are read
+//!   in full when the transport tells their size up front.
+//! - The JSON requests with arrays and objects nested deeper than the
+//!   maximum depth are refused.
+//! - The responses over the maximum size are replaced with the error.
+//!
+//! The endpoints that stream the messages over a connection, such as TCP,
+//! close the connection after refusing a request as the rest of the stream
+//! can't be trusted. The endpoints that receive each request on its own,
+//! such as HTTP and MPSC, refuse only the single request. The in-process
+//! endpoints invoke the service directly without any messages and aren't
+//! limited.
+
+use super::ServiceError;
+
+/// Message limits of a host. Nothing is limited by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MessageLimits {
+    max_request_size: Option<usize>,
+    max_response_size: Option<usize>,
+    max_depth: Option<usize>,
+}
+
+impl MessageLimits {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Limits the size of the requests in bytes.
+    pub fn max_request_size( mut self, bytes: usize ) -> Self {
+        self.max_request_size = Some( bytes );
+        self
+    }
+
+    /// Limits the size of the responses in bytes.
+    pub fn max_response_size( mut self, bytes: usize ) -> Self {
+        self.max_response_size = Some( bytes );
+        self
+    }
+
+    /// Limits how deeply the arrays and objects of the JSON requests nest.
+    pub fn max_depth( mut self, depth: usize ) -> Self {
+        self.max_depth = Some( depth );
+        self
+    }
+
+    /// Checks the size of a request before reading it or while it is being
+    /// read.
+    pub fn check_request_size( &self, size: usize ) -> Result<(), ServiceError> {
+        check_size( "request", size, self.max_request_size )
+    }
+
+    /// Checks a JSON request before deserializing it.
+    pub fn check_request( &self, message: &[u8] ) -> Result<(), ServiceError> {
+        self.check_request_size( message.len() )?;
+        if let Some( max ) = self.max_depth {
+            if json_depth( message ) > max {
+                return Err( ServiceError::message_too_large( format!(
+                        "The request nests deeper than the limit of {} levels", max ) ) );
+            }
+        }
+        Ok( () )
+    }
+
+    /// Checks the size of a response before sending it.
+    pub fn check_response( &self, message: &[u8] ) -> Result<(), ServiceError> {
+        check_size( "response", message.len(), self.max_response_size )
+    }
+}
+
+fn check_size( what: &str, size: usize, max: Option<usize> ) -> Result<(), ServiceError> {
+    match max {
+        Some( max ) if size > max => Err( ServiceError::message_too_large( format!(
+                "The {} of {} bytes is over the limit of {} bytes", what, size, max ) ) ),
+        _ => Ok( () ),
+    }
+}
+
+/// Measures how deeply the arrays and objects of a JSON document nest
+/// without parsing it. The brackets within the strings don't count.
+pub fn json_depth( json: &[u8] ) -> usize {
+    let mut depth = 0usize;
+    let mut deepest = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for &b in json {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if b == b'\\' {
+                escaped = true;
+            } else if b == b'"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match b {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                deepest = deepest.max( depth );
+            },
+            b']' | b'}' => depth = depth.saturating_sub( 1 ),
+            _ => {},
+        }
+    }
+    deepest
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn depth() {
+        assert_eq!( json_depth( b"1" ), 0 );
+        assert_eq!( json_depth( b"[1, 2]" ), 1 );
+        assert_eq!( json_depth( br#"{"a": [{"b": []}], "c": {}}"# ), 4 );
+        assert_eq!( json_depth( br#"["[[[", "\"{{"]"# ), 1 );
+    }
+
+    #[test]
+    pub fn limits() {
+        let limits = MessageLimits::new()
+                .max_request_size( 10 )
+                .max_response_size( 5 )
+                .max_depth( 2 );
+
+        assert!( limits.check_request( b"[[1]]" ).is_ok() );
+        assert!( limits.check_request( b"[[[1]]]" ).is_err() );
+        assert_eq!( limits.check_request( b"[1, 2, 3, 4]" ).unwrap_err().kind,
+                    ::ErrorKind::MessageTooLarge );
+        assert!( limits.check_request_size( 11 ).is_err() );
+        assert!( limits.check_response( b"12345" ).is_ok() );
+        assert!( limits.check_response( b"123456" ).is_err() );
+
+        let unlimited = MessageLimits::new();
+        assert!( unlimited.check_request( &[ b'['; 1000 ] ).is_ok() );
+        assert!( unlimited.check_response( &[ 0; 1000 ] ).is_ok() );
+    }
+}
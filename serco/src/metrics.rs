@@ -0,0 +1,372 @@
+//! Metrics of the hosts and the clients.
+//!
+//! The hosts built with `ServiceHost::metrics` report their calls, sessions
+//! and the bytes their endpoints transfer to a `Metrics` implementation.
+//! The clients report their calls by wrapping their forwarders in a
+//! `MeteredForwarder`. The `MetricsCollector` keeps the metrics in memory
+//! and renders them in the Prometheus text format, which the HTTP endpoint
+//! serves at `/metrics`.
+//!
+//! The calls to the built-in contracts of the hosts aren't counted.
+//! Streaming calls and batches of the clients are forwarded without
+//! metrics.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use super::{ServiceError, ErrorKind, CallInfo, Forwarder, ServiceStream};
+use super::{batch, stream};
+
+/// Receives the metrics of a host or a client.
+///
+/// The implementations are shared by the threads of the clients and the
+/// endpoints so they must synchronize their state.
+pub trait Metrics : Send + Sync {
+
+    /// A call to the operation started.
+    fn call_started( &self, operation: &str );
+
+    /// A call to the operation completed. Failed calls specify the kind of
+    /// their error.
+    fn call_finished( &self, operation: &str, error: Option<ErrorKind>, latency: Duration );
+
+    fn session_opened( &self );
+    fn session_closed( &self );
+
+    fn bytes_received( &self, endpoint: &str, bytes: usize );
+    fn bytes_sent( &self, endpoint: &str, bytes: usize );
+
+    /// Renders the metrics in the Prometheus text format. Implementations
+    /// that export the metrics elsewhere render nothing.
+    fn prometheus( &self ) -> String {
+        String::new()
+    }
+}
+
+/// Upper bounds of the latency histogram buckets in seconds.
+const LATENCY_BUCKETS : &[f64] = &[ 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0 ];
+
+/// Keeps the metrics in memory for the Prometheus exporter.
+pub struct MetricsCollector {
+    prefix: String,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    calls: BTreeMap<String, u64>,
+    errors: BTreeMap<( String, String ), u64>,
+    latencies: BTreeMap<String, Histogram>,
+    in_flight: i64,
+    sessions: i64,
+    received: BTreeMap<String, u64>,
+    sent: BTreeMap<String, u64>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe( &mut self, value: f64 ) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![ 0; LATENCY_BUCKETS.len() ];
+        }
+        for ( bucket, &bound ) in self.buckets.iter_mut().zip( LATENCY_BUCKETS ) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+impl Default for MetricsCollector {
+    fn default() -> Self {
+        MetricsCollector { prefix: "serco".to_string(), state: Default::default() }
+    }
+}
+
+impl MetricsCollector {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Specifies the prefix of the metric names, `serco` by default. Hosts
+    /// and clients that share an exporter need prefixes of their own.
+    pub fn prefix<T: Into<String>>( mut self, prefix: T ) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+}
+
+impl Metrics for MetricsCollector {
+
+    fn call_started( &self, operation: &str ) {
+        let mut state = self.state.lock().unwrap();
+        *state.calls.entry( operation.to_string() ).or_insert( 0 ) += 1;
+        state.in_flight += 1;
+    }
+
+    fn call_finished( &self, operation: &str, error: Option<ErrorKind>, latency: Duration ) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        if let Some( kind ) = error {
+            let key = ( operation.to_string(), format!( "{:?}", kind ) );
+            *state.errors.entry( key ).or_insert( 0 ) += 1;
+        }
+        let seconds = latency.as_secs() as f64 + f64::from( latency.subsec_nanos() ) / 1e9;
+        state.latencies.entry( operation.to_string() ).or_insert_with( Default::default )
+                .observe( seconds );
+    }
+
+    fn session_opened( &self ) {
+        self.state.lock().unwrap().sessions += 1;
+    }
+
+    fn session_closed( &self ) {
+        self.state.lock().unwrap().sessions -= 1;
+    }
+
+    fn bytes_received( &self, endpoint: &str, bytes: usize ) {
+        let mut state = self.state.lock().unwrap();
+        *state.received.entry( endpoint.to_string() ).or_insert( 0 ) += bytes as u64;
+    }
+
+    fn bytes_sent( &self, endpoint: &str, bytes: usize ) {
+        let mut state = self.state.lock().unwrap();
+        *state.sent.entry( endpoint.to_string() ).or_insert( 0 ) += bytes as u64;
+    }
+
+    fn prometheus( &self ) -> String {
+        let state = self.state.lock().unwrap();
+        let p = &self.prefix;
+        let mut out = String::new();
+
+        header( &mut out, p, "calls_total", "counter", "Calls by operation." );
+        for ( operation, count ) in &state.calls {
+            writeln!( out, "{}_calls_total{{operation=\"{}\"}} {}",
+                      p, escape( operation ), count ).unwrap();
+        }
+
+        header( &mut out, p, "call_errors_total", "counter",
+                "Failed calls by operation and error kind." );
+        for ( &( ref operation, ref kind ), count ) in &state.errors {
+            writeln!( out, "{}_call_errors_total{{operation=\"{}\",kind=\"{}\"}} {}",
+                      p, escape( operation ), kind, count ).unwrap();
+        }
+
+        header( &mut out, p, "call_duration_seconds", "histogram",
+                "Latency of the calls by operation." );
+        for ( operation, histogram ) in &state.latencies {
+            let operation = escape( operation );
+            for ( count, bound ) in histogram.buckets.iter().zip( LATENCY_BUCKETS ) {
+                writeln!( out, "{}_call_duration_seconds_bucket{{operation=\"{}\",le=\"{}\"}} {}",
+                          p, operation, bound, count ).unwrap();
+            }
+            writeln!( out, "{}_call_duration_seconds_bucket{{operation=\"{}\",le=\"+Inf\"}} {}",
+                      p, operation, histogram.count ).unwrap();
+            writeln!( out, "{}_call_duration_seconds_sum{{operation=\"{}\"}} {}",
+                      p, operation, histogram.sum ).unwrap();
+            writeln!( out, "{}_call_duration_seconds_count{{operation=\"{}\"}} {}",
+                      p, operation, histogram.count ).unwrap();
+        }
+
+        header( &mut out, p, "calls_in_flight", "gauge", "Calls in progress." );
+        writeln!( out, "{}_calls_in_flight {}", p, state.in_flight ).unwrap();
+
+        header( &mut out, p, "sessions_active", "gauge", "Open sessions." );
+        writeln!( out, "{}_sessions_active {}", p, state.sessions ).unwrap();
+
+        header( &mut out, p, "received_bytes_total", "counter", "Bytes received by endpoint." );
+        for ( endpoint, bytes ) in &state.received {
+            writeln!( out, "{}_received_bytes_total{{endpoint=\"{}\"}} {}",
+                      p, escape( endpoint ), bytes ).unwrap();
+        }
+
+        header( &mut out, p, "sent_bytes_total", "counter", "Bytes sent by endpoint." );
+        for ( endpoint, bytes ) in &state.sent {
+            writeln!( out, "{}_sent_bytes_total{{endpoint=\"{}\"}} {}",
+                      p, escape( endpoint ), bytes ).unwrap();
+        }
+
+        out
+    }
+}
+
+fn header( out: &mut String, prefix: &str, name: &str, kind: &str, help: &str ) {
+    writeln!( out, "# HELP {}_{} {}", prefix, name, help ).unwrap();
+    writeln!( out, "# TYPE {}_{} {}", prefix, name, kind ).unwrap();
+}
+
+/// Escapes a label value.
+fn escape( value: &str ) -> String {
+    value.replace( '\\', "\\\\" ).replace( '"', "\\\"" ).replace( '\n', "\\n" )
+}
+
+/// Reports the bytes an endpoint transfers. The endpoints get theirs with
+/// `HostRuntime::endpoint_metrics` when they start.
+#[derive(Clone)]
+pub struct EndpointMetrics {
+    metrics: Option<Arc<Metrics>>,
+    endpoint: &'static str,
+}
+
+impl EndpointMetrics {
+    pub fn new( metrics: Option<Arc<Metrics>>, endpoint: &'static str ) -> Self {
+        EndpointMetrics { metrics: metrics, endpoint: endpoint }
+    }
+
+    pub fn received( &self, bytes: usize ) {
+        if let Some( ref metrics ) = self.metrics {
+            metrics.bytes_received( self.endpoint, bytes );
+        }
+    }
+
+    pub fn sent( &self, bytes: usize ) {
+        if let Some( ref metrics ) = self.metrics {
+            metrics.bytes_sent( self.endpoint, bytes );
+        }
+    }
+}
+
+/// Makes a call and records it once it completes. The latency includes the
+/// work done before the call returns its future.
+pub fn record<T, F>(
+    metrics: &Arc<Metrics>,
+    operation: &str,
+    call: F,
+) -> Box<Future<Item=T, Error=ServiceError>>
+    where T: 'static,
+          F: FnOnce() -> Box<Future<Item=T, Error=ServiceError>>,
+{
+    let metrics = metrics.clone();
+    let operation = operation.to_string();
+    let start = Instant::now();
+    metrics.call_started( &operation );
+    Box::new( call().then( move |result| {
+        let error = result.as_ref().err().map( |e| e.kind );
+        metrics.call_finished( &operation, error, start.elapsed() );
+        result
+    } ) )
+}
+
+/// Forwarder that reports the calls of another forwarder.
+pub struct MeteredForwarder<F> {
+    inner: F,
+    metrics: Arc<Metrics>,
+}
+
+impl<F: Forwarder> MeteredForwarder<F> {
+
+    pub fn new( inner: F, metrics: Arc<Metrics> ) -> Self {
+        MeteredForwarder { inner: inner, metrics: metrics }
+    }
+
+    pub fn inner( &self ) -> &F {
+        &self.inner
+    }
+}
+
+impl<F: Forwarder> Forwarder for MeteredForwarder<F> {
+
+    fn forward<D, S>(
+        &self,
+        name: &'static str,
+        params : S,
+    ) -> Box<Future<Item=D, Error=ServiceError>>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static
+    {
+        self.forward_call( CallInfo::new( name ), params )
+    }
+
+    fn forward_call<D, S>(
+        &self,
+        call: CallInfo,
+        params : S,
+    ) -> Box<Future<Item=D, Error=ServiceError>>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static
+    {
+        let name = call.name;
+        record( &self.metrics, name, || self.inner.forward_call( call, params ) )
+    }
+
+    fn forward_streaming<D, S>(
+        &self,
+        name: &'static str,
+        params : S,
+        streams : Vec<stream::OutgoingStream>,
+    ) -> ServiceStream<D>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static
+    {
+        self.inner.forward_streaming( name, params, streams )
+    }
+
+    fn forward_batch(
+        &self,
+        calls : Vec<batch::Call>,
+        mode : batch::BatchMode,
+    ) -> Box<Future<Item=Vec<Result<stream::IncomingItem, ServiceError>>,
+                    Error=ServiceError>>
+    {
+        self.inner.forward_batch( calls, mode )
+    }
+
+    fn close( self ) {
+        self.inner.close()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future;
+
+    #[test]
+    pub fn prometheus() {
+        let collector = Arc::new( MetricsCollector::new() );
+        let metrics : Arc<Metrics> = collector.clone();
+
+        record( &metrics, "add", || Box::new( future::ok( 1 ) ) ).wait().unwrap();
+        record( &metrics, "add", || Box::new( future::err::<i32, _>(
+                ServiceError::new( ErrorKind::BadRequest, "Bad" ) ) ) ).wait().unwrap_err();
+        metrics.call_started( "say \"hi\"" );
+        metrics.call_finished( "say \"hi\"", None, Duration::from_millis( 20 ) );
+        metrics.session_opened();
+        metrics.bytes_received( "http", 10 );
+        metrics.bytes_received( "http", 5 );
+        metrics.bytes_sent( "http", 7 );
+
+        let text = collector.prometheus();
+        let lines : Vec<&str> = text.lines().collect();
+        assert!( lines.contains( &"# TYPE serco_calls_total counter" ) );
+        assert!( lines.contains( &"serco_calls_total{operation=\"add\"} 2" ) );
+        assert!( lines.contains( &"serco_call_errors_total{operation=\"add\",kind=\"BadRequest\"} 1" ) );
+        assert!( lines.contains( &"serco_call_duration_seconds_count{operation=\"add\"} 2" ) );
+        assert!( lines.contains( &"serco_call_duration_seconds_bucket{operation=\"add\",le=\"+Inf\"} 2" ) );
+        assert!( lines.contains(
+                &"serco_call_duration_seconds_bucket{operation=\"say \\\"hi\\\"\",le=\"0.01\"} 0" ) );
+        assert!( lines.contains(
+                &"serco_call_duration_seconds_bucket{operation=\"say \\\"hi\\\"\",le=\"0.05\"} 1" ) );
+        assert!( lines.contains( &"serco_calls_in_flight 0" ) );
+        assert!( lines.contains( &"serco_sessions_active 1" ) );
+        assert!( lines.contains( &"serco_received_bytes_total{endpoint=\"http\"} 15" ) );
+        assert!( lines.contains( &"serco_sent_bytes_total{endpoint=\"http\"} 7" ) );
+    }
+}
@@ -0,0 +1,537 @@
+//! Client connections that re-establish themselves after connection loss.
+//!
+//! The transports implement the `Connector` trait, which establishes a new
+//! connection and resumes the session if a session ID is given. The
+//! `ReconnectingForwarder` uses the connector to connect on demand and to
+//! reconnect with a backoff once a call fails with a transport error.
+//!
+//! Connecting doesn't block. The calls made while the forwarder connects
+//! wait for the same connection attempts, and the backoff between the
+//! attempts is waited out with the `Timer` of the forwarder.
+//!
+//! The hosts keep the sessions the clients may resume alive until they have
+//! been unused for the session timeout of the host. A reconnect within the
+//! timeout resumes the same session instance along with its state. Once the
+//! session has expired the host refuses it and the forwarder opens a new
+//! session instead, in which case the state of the old session is lost.
+
+use std::cell::RefCell;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use futures::prelude::*;
+use futures::future::{self, Loop};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use super::{ServiceError, ErrorKind, CallInfo, Forwarder, ServiceProxy, ServiceStream};
+use super::{batch, stream};
+use super::retry::{self, RetryForwarder, RetryPolicy, Timer};
+
+/// Establishes connections to a host.
+///
+/// Implemented by the transports. The connector is responsible for
+/// registering the duplex callback of the client on each new connection.
+pub trait Connector : 'static {
+    type Forwarder : Forwarder;
+
+    /// Connects to the host.
+    ///
+    /// If a session ID is given, the host should resume the existing
+    /// session and fail with `ErrorKind::BadRequest` if it no longer has it.
+    /// Resolves to the ID of the session and the forwarder for the
+    /// connection.
+    fn connect(
+        &self,
+        session_id: Option<&str>
+    ) -> Box<Future<Item=( String, Self::Forwarder ), Error=ServiceError>>;
+}
+
+/// State of a reconnecting connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+
+    /// Not connected. The next call will attempt to connect.
+    Disconnected,
+
+    /// Connecting to the host.
+    Connecting { attempt: u32 },
+
+    /// Connected to the host.
+    Connected { session_id: String },
+
+    /// All attempts to connect have failed.
+    Failed( ServiceError ),
+}
+
+/// The connection attempts shared by the calls waiting for them.
+type Connecting = future::Shared<Box<Future<Item=(), Error=ServiceError>>>;
+
+struct Shared<C: Connector> {
+    connector: C,
+    policy: RetryPolicy,
+    timer: RefCell<Timer>,
+    forwarder: RefCell<Option<C::Forwarder>>,
+    connecting: RefCell<Option<Connecting>>,
+    session_id: RefCell<Option<String>>,
+    state: RefCell<ConnectionState>,
+    listeners: RefCell<Vec<Box<Fn( &ConnectionState )>>>,
+}
+
+impl<C: Connector> Shared<C> {
+
+    fn set_state( &self, state: ConnectionState ) {
+        if *self.state.borrow() == state {
+            return;
+        }
+
+        *self.state.borrow_mut() = state.clone();
+        for listener in self.listeners.borrow().iter() {
+            listener( &state );
+        }
+    }
+
+    /// Resolves once there is a connection, connecting if necessary.
+    ///
+    /// The calls made while connecting wait for the same attempts.
+    fn ensure_connected( shared: &Rc<Self> ) -> Box<Future<Item=(), Error=ServiceError>> {
+        if shared.forwarder.borrow().is_some() {
+            return Box::new( future::ok( () ) );
+        }
+
+        let connecting = shared.connecting.borrow().clone();
+        let connecting = match connecting {
+            Some( connecting ) => connecting,
+            None => {
+                let connecting = Shared::connect( shared.clone() ).shared();
+                *shared.connecting.borrow_mut() = Some( connecting.clone() );
+                connecting
+            },
+        };
+        Box::new( connecting
+            .map( |_| () )
+            .map_err( |e| ( *e ).clone() ) )
+    }
+
+    /// Makes the connection attempts, waiting out the backoff between them.
+    fn connect( shared: Rc<Self> ) -> Box<Future<Item=(), Error=ServiceError>> {
+        Box::new( future::loop_fn( 1, move |attempt| {
+            shared.set_state( ConnectionState::Connecting { attempt: attempt } );
+
+            let session_id = shared.session_id.borrow().clone();
+            let shared = shared.clone();
+            shared.connector
+                .connect( session_id.as_ref().map( |s| s.as_ref() ) )
+                .then( move |result| -> Box<Future<Item=Loop<(), u32>, Error=ServiceError>> {
+                    match result {
+                        Ok( ( session_id, forwarder ) ) => {
+                            *shared.forwarder.borrow_mut() = Some( forwarder );
+                            *shared.session_id.borrow_mut() = Some( session_id.clone() );
+                            shared.connecting.borrow_mut().take();
+                            shared.set_state( ConnectionState::Connected {
+                                session_id: session_id
+                            } );
+                            Box::new( future::ok( Loop::Break( () ) ) )
+                        },
+
+                        // The host no longer has the session so start over
+                        // with a new one.
+                        Err( ref e ) if e.kind == ErrorKind::BadRequest &&
+                                        shared.session_id.borrow().is_some() => {
+                            shared.session_id.borrow_mut().take();
+                            Box::new( future::ok( Loop::Continue( attempt ) ) )
+                        },
+                        Err( ref e ) if e.is_transient() &&
+                                        shared.policy.allows_retry( attempt ) => {
+                            let delay = shared.policy.delay( attempt );
+                            let timer = shared.timer.borrow().clone();
+                            Box::new( ( *timer )( delay )
+                                .map( move |_| Loop::Continue( attempt + 1 ) ) )
+                        },
+                        Err( e ) => {
+                            shared.connecting.borrow_mut().take();
+                            shared.set_state( ConnectionState::Failed( e.clone() ) );
+                            Box::new( future::err( e ) )
+                        },
+                    }
+                } )
+        } ) )
+    }
+
+    /// Drops the current connection after a transport failure.
+    fn disconnected( &self ) {
+        if let Some( forwarder ) = self.forwarder.borrow_mut().take() {
+            forwarder.close();
+        }
+        self.set_state( ConnectionState::Disconnected );
+    }
+}
+
+/// Forwarder that connects on demand and reconnects after connection loss.
+///
+/// The call that detects the connection loss fails with the transport error.
+/// Combine with a `RetryForwarder` to retry such calls over the new
+/// connection.
+pub struct ReconnectingForwarder<C: Connector> {
+    shared: Rc<Shared<C>>,
+}
+
+impl<C: Connector> ReconnectingForwarder<C> {
+
+    /// Creates a forwarder using the policy for the reconnect backoff. The
+    /// backoff is waited out with the `retry::thread_timer`.
+    pub fn new( connector: C, policy: RetryPolicy ) -> Self {
+        ReconnectingForwarder {
+            shared: Rc::new( Shared {
+                connector: connector,
+                policy: policy,
+                timer: RefCell::new( retry::thread_timer() ),
+                forwarder: RefCell::new( None ),
+                connecting: RefCell::new( None ),
+                session_id: RefCell::new( None ),
+                state: RefCell::new( ConnectionState::Disconnected ),
+                listeners: RefCell::new( vec![] ),
+            } )
+        }
+    }
+
+    /// Specifies the timer that waits out the backoff between the
+    /// connection attempts.
+    pub fn timer( self, timer: Timer ) -> Self {
+        *self.shared.timer.borrow_mut() = timer;
+        self
+    }
+
+    /// Connects to the host unless already connected.
+    pub fn connect( &self ) -> Box<Future<Item=(), Error=ServiceError>> {
+        Shared::ensure_connected( &self.shared )
+    }
+
+    /// Gets a monitor for observing the connection state.
+    pub fn monitor( &self ) -> ConnectionMonitor<C> {
+        ConnectionMonitor { shared: self.shared.clone() }
+    }
+
+    /// Invokes the function on the current forwarder once connected.
+    ///
+    /// A transport failure of the result drops the connection so the next
+    /// call reconnects.
+    fn with_forwarder<T, F>( &self, f: F ) -> Box<Future<Item=T, Error=ServiceError>>
+        where F: FnOnce( &C::Forwarder ) -> Box<Future<Item=T, Error=ServiceError>> + 'static,
+              T: 'static,
+    {
+        let shared = self.shared.clone();
+        Box::new( Shared::ensure_connected( &self.shared )
+            .and_then( move |_| -> Box<Future<Item=T, Error=ServiceError>> {
+                let result = {
+                    let forwarder = shared.forwarder.borrow();
+                    match forwarder.as_ref() {
+                        Some( forwarder ) => f( forwarder ),
+                        None => return Box::new( future::err( ServiceError::new(
+                                ErrorKind::Transport, "Connection lost" ) ) ),
+                    }
+                };
+                Box::new( result.map_err( move |e| {
+                    if e.is_transient() {
+                        shared.disconnected();
+                    }
+                    e
+                } ) )
+            } ) )
+    }
+}
+
+impl<C: Connector> Forwarder for ReconnectingForwarder<C> {
+
+    fn forward<D, S>(
+        &self,
+        name: &'static str,
+        params : S,
+    ) -> Box<Future<Item=D, Error=ServiceError>>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static
+    {
+        self.forward_call( CallInfo::new( name ), params )
+    }
+
+    fn forward_call<D, S>(
+        &self,
+        call: CallInfo,
+        params : S,
+    ) -> Box<Future<Item=D, Error=ServiceError>>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static
+    {
+        self.with_forwarder( move |f| f.forward_call( call, params ) )
+    }
+
+    fn forward_streaming<D, S>(
+        &self,
+        name: &'static str,
+        params : S,
+        streams : Vec<stream::OutgoingStream>,
+    ) -> ServiceStream<D>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static
+    {
+        let shared = self.shared.clone();
+        let connected = Shared::ensure_connected( &self.shared ).map( move |_| -> ServiceStream<D> {
+            let result = match shared.forwarder.borrow().as_ref() {
+                Some( forwarder ) => forwarder.forward_streaming( name, params, streams ),
+                None => return Box::new( ::futures::stream::once( Err( ServiceError::new(
+                        ErrorKind::Transport, "Connection lost" ) ) ) ),
+            };
+            Box::new( result.map_err( move |e| {
+                if e.is_transient() {
+                    shared.disconnected();
+                }
+                e
+            } ) )
+        } );
+        Box::new( connected.flatten_stream() )
+    }
+
+    fn forward_batch(
+        &self,
+        calls : Vec<batch::Call>,
+        mode : batch::BatchMode,
+    ) -> Box<Future<Item=Vec<Result<stream::IncomingItem, ServiceError>>,
+                    Error=ServiceError>>
+    {
+        self.with_forwarder( move |f| f.forward_batch( calls, mode ) )
+    }
+
+    fn close( self ) {
+        if let Some( forwarder ) = self.shared.forwarder.borrow_mut().take() {
+            forwarder.close();
+        }
+    }
+}
+
+/// Observes the state of a reconnecting connection.
+pub struct ConnectionMonitor<C: Connector> {
+    shared: Rc<Shared<C>>,
+}
+
+impl<C: Connector> Clone for ConnectionMonitor<C> {
+    fn clone( &self ) -> Self {
+        ConnectionMonitor { shared: self.shared.clone() }
+    }
+}
+
+impl<C: Connector> ConnectionMonitor<C> {
+
+    /// Gets the current connection state.
+    pub fn state( &self ) -> ConnectionState {
+        self.shared.state.borrow().clone()
+    }
+
+    /// Gets the ID of the session the connection has established.
+    ///
+    /// The session ID is retained over reconnects.
+    pub fn session_id( &self ) -> Option<String> {
+        self.shared.session_id.borrow().clone()
+    }
+
+    /// Registers a listener that is invoked on each state change.
+    pub fn on_state_change<F>( &self, listener: F )
+        where F: Fn( &ConnectionState ) + 'static
+    {
+        self.shared.listeners.borrow_mut().push( Box::new( listener ) );
+    }
+}
+
+/// A service connection that reconnects after connection loss.
+///
+/// Retries the calls according to the retry policy once the connection has
+/// been re-established.
+pub struct ReconnectingConnection<S: ?Sized, C: Connector> {
+    proxy: ServiceProxy<S, RetryForwarder<ReconnectingForwarder<C>>>,
+    monitor: ConnectionMonitor<C>,
+}
+
+impl<S: ?Sized, C: Connector> ReconnectingConnection<S, C> {
+
+    /// Creates a connection without connecting yet.
+    ///
+    /// The `reconnect_policy` controls the backoff between the connection
+    /// attempts while the `retry_policy` controls retrying the calls.
+    pub fn new(
+        connector: C,
+        reconnect_policy: RetryPolicy,
+        retry_policy: RetryPolicy,
+    ) -> Self
+    {
+        let forwarder = ReconnectingForwarder::new( connector, reconnect_policy );
+        let monitor = forwarder.monitor();
+        ReconnectingConnection {
+            proxy: ServiceProxy::new( RetryForwarder::new( forwarder, retry_policy ) ),
+            monitor: monitor,
+        }
+    }
+
+    /// Specifies the timer that waits out the backoff between both the
+    /// connection attempts and the attempts of the calls.
+    pub fn timer( self, timer: Timer ) -> Self {
+        let ReconnectingConnection { proxy, monitor } = self;
+        *monitor.shared.timer.borrow_mut() = timer.clone();
+        ReconnectingConnection {
+            proxy: ServiceProxy::new( proxy.forwarder.timer( timer ) ),
+            monitor: monitor,
+        }
+    }
+
+    /// Connects to the host unless already connected.
+    pub fn connect( &self ) -> Box<Future<Item=(), Error=ServiceError>> {
+        self.proxy.forwarder.inner().connect()
+    }
+
+    /// Gets a monitor for observing the connection state.
+    pub fn monitor( &self ) -> &ConnectionMonitor<C> {
+        &self.monitor
+    }
+
+    pub fn close( self ) {
+        self.proxy.close();
+    }
+}
+
+/// Dereference the connection into the service proxy.
+/// The proxy implements the actual service trait.
+impl<S: ?Sized, C: Connector> Deref for ReconnectingConnection<S, C> {
+    type Target = ServiceProxy<S, RetryForwarder<ReconnectingForwarder<C>>>;
+
+    fn deref( &self ) -> &Self::Target {
+        &self.proxy
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use retry::new_timer;
+
+    struct NullForwarder;
+
+    impl Forwarder for NullForwarder {
+        fn forward<D, S>( &self, _name: &'static str, _params : S ) -> Box<Future<Item=D, Error=ServiceError>>
+            where D: DeserializeOwned + 'static, S: Serialize + 'static
+        {
+            Box::new( future::result( ::serde_json::from_value( json!( null ) )
+                    .map_err( ServiceError::from ) ) )
+        }
+
+        fn close( self ) {}
+    }
+
+    /// Fails the first connects and records the sessions asked for.
+    struct FlakyConnector {
+        failures: Cell<u32>,
+        error: ErrorKind,
+        requested: Rc<RefCell<Vec<Option<String>>>>,
+    }
+
+    impl Connector for FlakyConnector {
+        type Forwarder = NullForwarder;
+
+        fn connect(
+            &self,
+            session_id: Option<&str>
+        ) -> Box<Future<Item=( String, NullForwarder ), Error=ServiceError>>
+        {
+            self.requested.borrow_mut().push( session_id.map( String::from ) );
+            if self.failures.get() > 0 {
+                self.failures.set( self.failures.get() - 1 );
+                return Box::new( future::err( ServiceError::new( self.error, "Refused" ) ) );
+            }
+            let id = format!( "session{}", self.requested.borrow().len() );
+            Box::new( future::ok( ( id, NullForwarder ) ) )
+        }
+    }
+
+    fn flaky( failures: u32, error: ErrorKind ) -> ( ReconnectingForwarder<FlakyConnector>, Rc<RefCell<Vec<Option<String>>>> ) {
+        let requested = Rc::new( RefCell::new( vec![] ) );
+        let connector = FlakyConnector {
+            failures: Cell::new( failures ),
+            error: error,
+            requested: requested.clone(),
+        };
+        let forwarder = ReconnectingForwarder::new( connector, RetryPolicy::default().max_attempts( 5 ) )
+                .timer( new_timer( |_| future::ok( () ) ) );
+        ( forwarder, requested )
+    }
+
+    #[test]
+    pub fn reconnects() {
+        let ( forwarder, requested ) = flaky( 2, ErrorKind::Transport );
+        let states = Rc::new( RefCell::new( vec![] ) );
+        let recorded = states.clone();
+        forwarder.monitor().on_state_change( move |state| recorded.borrow_mut().push( state.clone() ) );
+
+        forwarder.connect().wait().unwrap();
+        assert_eq!( *states.borrow(), vec![
+            ConnectionState::Connecting { attempt: 1 },
+            ConnectionState::Connecting { attempt: 2 },
+            ConnectionState::Connecting { attempt: 3 },
+            ConnectionState::Connected { session_id: "session3".to_string() },
+        ] );
+
+        // The session is resumed after the connection is lost.
+        forwarder.shared.disconnected();
+        forwarder.forward::<(), _>( "call", () ).wait().unwrap();
+        assert_eq!( requested.borrow().last().unwrap().as_ref().unwrap(), "session3" );
+        assert_eq!( forwarder.monitor().session_id().unwrap(), "session4" );
+    }
+
+    #[test]
+    pub fn expired_session() {
+        let ( forwarder, requested ) = flaky( 0, ErrorKind::BadRequest );
+        forwarder.connect().wait().unwrap();
+        forwarder.shared.disconnected();
+
+        // A session the host no longer has is replaced with a new one.
+        forwarder.shared.connector.failures.set( 1 );
+        forwarder.connect().wait().unwrap();
+        assert_eq!( *requested.borrow(), vec![
+                None, Some( "session1".to_string() ), None ] );
+        assert_eq!( forwarder.monitor().session_id().unwrap(), "session3" );
+    }
+
+    #[test]
+    pub fn shared_attempts() {
+        let ( forwarder, requested ) = flaky( 1, ErrorKind::Transport );
+        let ( elapsed_tx, elapsed_rx ) = ::futures::sync::oneshot::channel();
+        let elapsed = RefCell::new( Some( elapsed_rx ) );
+        let forwarder = forwarder.timer( new_timer( move |_| {
+            let elapsed = elapsed.borrow_mut().take().unwrap();
+            elapsed.map_err( ServiceError::from )
+        } ) );
+
+        // The calls made while waiting out the backoff wait for the same
+        // attempts.
+        let first = forwarder.forward::<(), _>( "call", () );
+        let second = forwarder.forward::<(), _>( "call", () );
+        assert_eq!( forwarder.monitor().state(), ConnectionState::Connecting { attempt: 1 } );
+        elapsed_tx.send( () ).unwrap();
+        first.join( second ).wait().unwrap();
+        assert_eq!( requested.borrow().len(), 2 );
+        assert_eq!( forwarder.monitor().state(),
+                    ConnectionState::Connected { session_id: "session2".to_string() } );
+    }
+
+    #[test]
+    pub fn gives_up() {
+        let ( forwarder, requested ) = flaky( 10, ErrorKind::Transport );
+        let result = forwarder.connect().wait();
+        assert_eq!( result.unwrap_err().kind, ErrorKind::Transport );
+        assert_eq!( requested.borrow().len(), 5 );
+        match forwarder.monitor().state() {
+            ConnectionState::Failed( ref e ) => assert_eq!( e.kind, ErrorKind::Transport ),
+            state => panic!( "Unexpected state {:?}", state ),
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//! Discovering the endpoints of the services.
+//!
+//! Hosts that are given a registry register each of their endpoints under the
+//! `contract_id` of the contract they serve. Clients resolve the contract ID
+//! into the registered endpoints and connect to the ones using a transport
+//! they support.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand;
+use serde_json;
+
+use super::{ServiceError, ErrorKind};
+
+/// The transport and the address of an endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub struct EndpointAddress {
+
+    /// The transport of the endpoint, such as `mpsc`.
+    pub kind: String,
+
+    /// Transport specific address of the endpoint.
+    pub address: String,
+}
+
+impl EndpointAddress {
+    pub fn new<K: Into<String>, A: Into<String>>( kind: K, address: A ) -> Self {
+        EndpointAddress { kind: kind.into(), address: address.into() }
+    }
+}
+
+/// An endpoint registered for a contract.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub struct Registration {
+
+    /// The `contract_id` of the contract.
+    pub contract: String,
+    pub endpoint: EndpointAddress,
+
+    /// Free form information about the endpoint, such as a version.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Registration {
+    pub fn new<T: Into<String>>( contract: T, endpoint: EndpointAddress ) -> Self {
+        Registration {
+            contract: contract.into(),
+            endpoint: endpoint,
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    fn is_same( &self, other: &Registration ) -> bool {
+        self.contract == other.contract && self.endpoint == other.endpoint
+    }
+}
+
+/// Records the endpoints of the services.
+pub trait ServiceRegistry : Send + Sync {
+
+    /// Registers an endpoint, replacing an earlier registration of the same
+    /// endpoint for the same contract.
+    fn register( &self, registration: Registration ) -> Result<(), ServiceError>;
+
+    /// Removes the registration of an endpoint.
+    fn unregister(
+        &self,
+        contract: &str,
+        endpoint: &EndpointAddress,
+    ) -> Result<(), ServiceError>;
+
+    /// Resolves the endpoints registered for a contract.
+    fn resolve( &self, contract: &str ) -> Result<Vec<Registration>, ServiceError>;
+
+    /// Resolves the addresses of the endpoints of a specific transport.
+    fn resolve_addresses(
+        &self,
+        contract: &str,
+        kind: &str,
+    ) -> Result<Vec<String>, ServiceError>
+    {
+        Ok( self.resolve( contract )?
+            .into_iter()
+            .filter( |r| r.endpoint.kind == kind )
+            .map( |r| r.endpoint.address )
+            .collect() )
+    }
+}
+
+fn register_into( registrations: &mut Vec<Registration>, registration: Registration ) {
+    registrations.retain( |r| !r.is_same( &registration ) );
+    registrations.push( registration );
+}
+
+fn unregister_from(
+    registrations: &mut Vec<Registration>,
+    contract: &str,
+    endpoint: &EndpointAddress,
+) {
+    registrations.retain( |r| r.contract != contract || r.endpoint != *endpoint );
+}
+
+/// Registry for the hosts and clients within a single process.
+///
+/// Clones of the registry share the registrations.
+#[derive(Clone, Default)]
+pub struct InProcessRegistry {
+    registrations: Arc<Mutex<Vec<Registration>>>,
+}
+
+impl InProcessRegistry {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl ServiceRegistry for InProcessRegistry {
+
+    fn register( &self, registration: Registration ) -> Result<(), ServiceError> {
+        let mut guard = self.registrations.lock().map_err( ServiceError::from )?;
+        register_into( &mut guard, registration );
+        Ok( () )
+    }
+
+    fn unregister(
+        &self,
+        contract: &str,
+        endpoint: &EndpointAddress,
+    ) -> Result<(), ServiceError>
+    {
+        let mut guard = self.registrations.lock().map_err( ServiceError::from )?;
+        unregister_from( &mut guard, contract, endpoint );
+        Ok( () )
+    }
+
+    fn resolve( &self, contract: &str ) -> Result<Vec<Registration>, ServiceError> {
+        let guard = self.registrations.lock().map_err( ServiceError::from )?;
+        Ok( guard.iter().filter( |r| r.contract == contract ).cloned().collect() )
+    }
+}
+
+/// Registry stored in a JSON file, which lets the processes on the same
+/// machine find each other.
+///
+/// Modifications are serialized with a lock file next to the registry file.
+/// The lock file holds the ID of the process that created it. Locks left
+/// behind by processes that have exited are removed, as are the locks older
+/// than the stale lock age on the platforms where the processes can't be
+/// checked. Registrations of processes that exit without unregistering
+/// remain in the file until they are replaced or removed.
+pub struct FileRegistry {
+    path: PathBuf,
+    lock_timeout: Duration,
+    stale_lock_age: Duration,
+}
+
+/// Removes the lock file once the modification is done.
+struct FileLock {
+    path: PathBuf,
+}
+
+/// Checks whether the process is running. `None` if it can't be checked.
+#[cfg(target_os = "linux")]
+fn process_exists( pid: u32 ) -> Option<bool> {
+    Some( Path::new( "/proc" ).join( pid.to_string() ).exists() )
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_exists( _pid: u32 ) -> Option<bool> {
+    None
+}
+
+fn read_lock( path: &Path ) -> io::Result<String> {
+    let mut content = String::new();
+    File::open( path )?.read_to_string( &mut content )?;
+    Ok( content )
+}
+
+impl Drop for FileLock {
+    fn drop( &mut self ) {
+        let _ = fs::remove_file( &self.path );
+    }
+}
+
+impl FileRegistry {
+    pub fn new<P: AsRef<Path>>( path: P ) -> Self {
+        FileRegistry {
+            path: path.as_ref().to_path_buf(),
+            lock_timeout: Duration::from_secs( 5 ),
+            stale_lock_age: Duration::from_secs( 30 ),
+        }
+    }
+
+    /// Specifies how long to wait for other processes to finish modifying
+    /// the registry.
+    pub fn lock_timeout( mut self, timeout: Duration ) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Specifies the age after which a lock is considered left behind when
+    /// the process holding it can't be checked.
+    pub fn stale_lock_age( mut self, age: Duration ) -> Self {
+        self.stale_lock_age = age;
+        self
+    }
+
+    fn lock( &self ) -> Result<FileLock, ServiceError> {
+        let path = self.path.with_extension( "lock" );
+        let owner = format!( "{} {:016x}", process::id(), rand::random::<u64>() );
+        let start = Instant::now();
+        loop {
+            let created = OpenOptions::new().write( true ).create_new( true ).open( &path )
+                    .and_then( |mut file| {
+                        let lock = FileLock { path: path.clone() };
+                        file.write_all( owner.as_bytes() )?;
+                        Ok( lock )
+                    } );
+            match created {
+                Ok( lock ) => return Ok( lock ),
+                Err( ref e ) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    if self.remove_stale_lock( &path ) {
+                        continue;
+                    }
+                    if start.elapsed() >= self.lock_timeout {
+                        return Err( ServiceError::new(
+                                ErrorKind::Internal,
+                                format!( "Timed out waiting for the lock '{}'", path.display() ) ) );
+                    }
+                    thread::sleep( Duration::from_millis( 10 ) );
+                },
+                Err( e ) => return Err( ServiceError::new(
+                        ErrorKind::Internal,
+                        format!( "Failed to lock '{}': {}", path.display(), e ) ) ),
+            }
+        }
+    }
+
+    /// Removes the lock if the process holding it has exited or the lock has
+    /// reached the stale lock age.
+    fn remove_stale_lock( &self, path: &Path ) -> bool {
+        let content = match read_lock( path ) {
+            Ok( content ) => content,
+            Err( _ ) => return false,
+        };
+
+        // The lock is empty for a moment after it has been created.
+        let pid = content.split_whitespace().next().and_then( |pid| pid.parse().ok() );
+        let stale = match pid.and_then( process_exists ) {
+            Some( exists ) => !exists,
+            None => fs::metadata( path )
+                    .and_then( |metadata| metadata.modified() )
+                    .ok()
+                    .and_then( |modified| modified.elapsed().ok() )
+                    .map_or( false, |age| age >= self.stale_lock_age ),
+        };
+        if !stale {
+            return false;
+        }
+
+        // Leave the lock alone if another process already replaced it.
+        match read_lock( path ) {
+            Ok( ref current ) if *current == content => fs::remove_file( path ).is_ok(),
+            _ => false,
+        }
+    }
+
+    fn read( &self ) -> Result<Vec<Registration>, ServiceError> {
+        let mut content = String::new();
+        match File::open( &self.path ) {
+            Ok( mut file ) => { file.read_to_string( &mut content )
+                                    .map_err( ServiceError::from )?; },
+            Err( ref e ) if e.kind() == io::ErrorKind::NotFound => {},
+            Err( e ) => return Err( ServiceError::from( e ) ),
+        }
+
+        if content.trim().is_empty() {
+            return Ok( vec![] );
+        }
+
+        serde_json::from_str( &content ).map_err( ServiceError::from )
+    }
+
+    fn write( &self, registrations: &[Registration] ) -> Result<(), ServiceError> {
+        let content = serde_json::to_string_pretty( registrations )
+                .map_err( ServiceError::from )?;
+
+        // Write through a temporary file so readers never see partial content.
+        let temp = self.path.with_extension( "tmp" );
+        File::create( &temp )
+            .and_then( |mut file| file.write_all( content.as_bytes() ) )
+            .and_then( |_| fs::rename( &temp, &self.path ) )
+            .map_err( ServiceError::from )
+    }
+
+    fn modify<F>( &self, f: F ) -> Result<(), ServiceError>
+        where F: FnOnce( &mut Vec<Registration> )
+    {
+        let _lock = self.lock()?;
+        let mut registrations = self.read()?;
+        f( &mut registrations );
+        self.write( &registrations )
+    }
+}
+
+impl ServiceRegistry for FileRegistry {
+
+    fn register( &self, registration: Registration ) -> Result<(), ServiceError> {
+        self.modify( |registrations| register_into( registrations, registration ) )
+    }
+
+    fn unregister(
+        &self,
+        contract: &str,
+        endpoint: &EndpointAddress,
+    ) -> Result<(), ServiceError>
+    {
+        self.modify( |registrations|
+                unregister_from( registrations, contract, endpoint ) )
+    }
+
+    fn resolve( &self, contract: &str ) -> Result<Vec<Registration>, ServiceError> {
+        Ok( self.read()?
+            .into_iter()
+            .filter( |r| r.contract == contract )
+            .collect() )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn registry_path( name: &str ) -> PathBuf {
+        let path = ::std::env::temp_dir().join(
+                format!( "serco_registry_{}_{}.json", name, process::id() ) );
+        fs::remove_file( &path ).ok();
+        fs::remove_file( path.with_extension( "lock" ) ).ok();
+        path
+    }
+
+    fn write_lock( path: &Path, content: &str ) {
+        File::create( path ).unwrap().write_all( content.as_bytes() ).unwrap();
+    }
+
+    fn registration( contract: &str, address: &str ) -> Registration {
+        Registration::new( contract, EndpointAddress::new( "mpsc", address ) )
+    }
+
+    fn check_registrations<R: ServiceRegistry>( registry: &R ) {
+        registry.register( registration( "a::Echo", "one" ) ).unwrap();
+        registry.register( registration( "a::Echo", "two" ) ).unwrap();
+        registry.register( registration( "b::Echo", "three" ) ).unwrap();
+        assert_eq!( registry.resolve_addresses( "a::Echo", "mpsc" ).unwrap(), vec![ "one", "two" ] );
+        assert_eq!( registry.resolve_addresses( "b::Echo", "mpsc" ).unwrap(), vec![ "three" ] );
+        assert!( registry.resolve_addresses( "a::Echo", "http" ).unwrap().is_empty() );
+
+        // Registering the same endpoint again replaces the registration.
+        let mut updated = registration( "a::Echo", "one" );
+        updated.metadata.insert( "version".to_string(), "2".to_string() );
+        registry.register( updated.clone() ).unwrap();
+        let resolved = registry.resolve( "a::Echo" ).unwrap();
+        assert_eq!( resolved.len(), 2 );
+        assert!( resolved.contains( &updated ) );
+
+        registry.unregister( "a::Echo", &EndpointAddress::new( "mpsc", "one" ) ).unwrap();
+        assert_eq!( registry.resolve_addresses( "a::Echo", "mpsc" ).unwrap(), vec![ "two" ] );
+        assert_eq!( registry.resolve_addresses( "b::Echo", "mpsc" ).unwrap(), vec![ "three" ] );
+    }
+
+    #[test]
+    pub fn in_process() {
+        check_registrations( &InProcessRegistry::new() );
+    }
+
+    #[test]
+    pub fn file() {
+        let path = registry_path( "file" );
+        check_registrations( &FileRegistry::new( &path ) );
+
+        // Other processes see the same registrations.
+        let other = FileRegistry::new( &path );
+        assert_eq!( other.resolve_addresses( "a::Echo", "mpsc" ).unwrap(), vec![ "two" ] );
+        assert!( !path.with_extension( "lock" ).exists() );
+        fs::remove_file( &path ).ok();
+    }
+
+    #[test]
+    pub fn held_lock() {
+        let path = registry_path( "held" );
+        let registry = FileRegistry::new( &path ).lock_timeout( Duration::from_millis( 50 ) );
+
+        // The lock of a running process is waited for.
+        let lock = path.with_extension( "lock" );
+        write_lock( &lock, &format!( "{} 0", process::id() ) );
+        assert!( registry.register( registration( "a::Echo", "one" ) ).is_err() );
+        fs::remove_file( &lock ).unwrap();
+        registry.register( registration( "a::Echo", "one" ) ).unwrap();
+        fs::remove_file( &path ).ok();
+    }
+
+    #[test]
+    pub fn stale_lock() {
+        let path = registry_path( "stale" );
+        let lock = path.with_extension( "lock" );
+
+        // The lock of a process that has exited is removed.
+        if cfg!( target_os = "linux" ) {
+            write_lock( &lock, &format!( "{} 0", u32::max_value() ) );
+            FileRegistry::new( &path ).register( registration( "a::Echo", "one" ) ).unwrap();
+            assert!( !lock.exists() );
+        }
+
+        // Locks that can't be checked are removed once they are old enough.
+        write_lock( &lock, "" );
+        let registry = FileRegistry::new( &path )
+                .lock_timeout( Duration::from_secs( 5 ) )
+                .stale_lock_age( Duration::from_millis( 50 ) );
+        registry.register( registration( "a::Echo", "two" ) ).unwrap();
+        assert_eq!( registry.resolve( "a::Echo" ).unwrap().len(),
+                    if cfg!( target_os = "linux" ) { 2 } else { 1 } );
+        fs::remove_file( &path ).ok();
+    }
+}
@@ -0,0 +1,455 @@
+//! Retrying calls that fail because of transient transport failures.
+//!
+//! The client side consists of the `RetryForwarder`, which wraps another
+//! forwarder and retries the calls according to a `RetryPolicy`. Only the
+//! operations marked `#[idempotent]` in the contract are retried by
+//! default. Each retried call carries a request ID that the host uses to
+//! de-duplicate calls it has already executed with the `ResponseCache`.
+//!
+//! The delays between the attempts are waited out with a `Timer` so the
+//! retries don't block the thread that polls the calls. The default timer
+//! waits on a thread of its own. Clients running on a reactor can use its
+//! timeouts instead, for example with `tokio_core::reactor::Timeout`:
+//!
+//! ```ignore
+//! let handle = core.handle();
+//! let timer = retry::new_timer( move |delay| {
+//!     future::result( Timeout::new( delay, &handle ) ).flatten()
+//!         .map_err( ServiceError::from )
+//! } );
+//! let forwarder = RetryForwarder::new( forwarder, policy ).timer( timer );
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use futures::prelude::*;
+use futures::future::{self, Loop};
+use futures::sync::oneshot;
+use serde::{Serialize, Serializer};
+use serde::de::DeserializeOwned;
+use rand;
+
+use super::{ServiceError, ErrorKind, CallInfo, Forwarder, ServiceStream};
+use super::{batch, stream};
+
+/// Specifies when and how often failed calls are retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f64,
+    jitter: f64,
+    retry_non_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis( 100 ),
+            max_backoff: Duration::from_secs( 5 ),
+            multiplier: 2.0,
+            jitter: 0.2,
+            retry_non_idempotent: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+
+    /// A policy that never retries.
+    pub fn none() -> Self {
+        RetryPolicy { max_attempts: 1, .. Default::default() }
+    }
+
+    /// Specifies the maximum number of attempts including the first one.
+    pub fn max_attempts( mut self, max_attempts: u32 ) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Specifies the delay before the first retry and the upper bound for
+    /// the delays.
+    pub fn backoff( mut self, initial: Duration, max: Duration ) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Specifies the factor by which the delay grows after each retry.
+    pub fn multiplier( mut self, multiplier: f64 ) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// Specifies the random variation of the delays as a fraction of the
+    /// delay. A jitter of 0.2 varies the delays by up to ±20%.
+    pub fn jitter( mut self, jitter: f64 ) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Allows retrying operations that are not marked `#[idempotent]`.
+    ///
+    /// This relies on the host de-duplicating the calls by their request
+    /// IDs. Hosts only remember a limited number of recent calls so a
+    /// non-idempotent call may still be executed twice if the retry arrives
+    /// very late.
+    pub fn retry_non_idempotent( mut self, retry: bool ) -> Self {
+        self.retry_non_idempotent = retry;
+        self
+    }
+
+    /// Checks whether the policy retries the call.
+    pub fn applies_to( &self, call: &CallInfo ) -> bool {
+        self.max_attempts > 1 && ( call.idempotent || self.retry_non_idempotent )
+    }
+
+    /// Checks whether another attempt is allowed after the given attempt.
+    pub fn allows_retry( &self, attempt: u32 ) -> bool {
+        attempt < self.max_attempts
+    }
+
+    /// Resolves the delay before the retry following the given attempt.
+    pub fn delay( &self, attempt: u32 ) -> Duration {
+        let initial = duration_to_secs( self.initial_backoff );
+        let max = duration_to_secs( self.max_backoff );
+        let exponent = attempt.saturating_sub( 1 ) as i32;
+        let delay = ( initial * self.multiplier.powi( exponent ) ).min( max );
+
+        let variation = ( rand::random::<f64>() * 2.0 - 1.0 ) * self.jitter;
+        secs_to_duration( delay * ( 1.0 + variation ) )
+    }
+}
+
+fn duration_to_secs( d: Duration ) -> f64 {
+    d.as_secs() as f64 + f64::from( d.subsec_nanos() ) / 1e9
+}
+
+fn secs_to_duration( secs: f64 ) -> Duration {
+    let secs = secs.max( 0.0 );
+    Duration::new( secs.trunc() as u64, ( secs.fract() * 1e9 ) as u32 )
+}
+
+/// Creates the futures that complete once the delay has passed.
+pub type Timer = Rc<Fn( Duration ) -> Box<Future<Item=(), Error=ServiceError>>>;
+
+/// Creates a timer from a function that creates the futures.
+pub fn new_timer<F, T>( f: F ) -> Timer
+    where F: Fn( Duration ) -> T + 'static,
+          T: Future<Item=(), Error=ServiceError> + 'static,
+{
+    Rc::new( move |delay| Box::new( f( delay ) ) as Box<Future<Item=(), Error=ServiceError>> )
+}
+
+/// Creates a timer that waits for each delay on a thread of its own.
+pub fn thread_timer() -> Timer {
+    new_timer( |delay| {
+        let ( elapsed_tx, elapsed_rx ) = oneshot::channel();
+        thread::spawn( move || {
+            thread::sleep( delay );
+            elapsed_tx.send( () ).ok();
+        } );
+        elapsed_rx.map_err( |_| ServiceError::new( ErrorKind::Internal, "The timer stopped" ) )
+    } )
+}
+
+/// Generates a new unique request ID.
+pub fn new_request_id() -> String {
+    format!( "{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>() )
+}
+
+/// Forwarder that retries the calls of another forwarder.
+///
+/// The next attempt is chained on the timer so the call keeps waiting
+/// without blocking the thread in between.
+pub struct RetryForwarder<F> {
+    inner: Rc<F>,
+    policy: RetryPolicy,
+    timer: Timer,
+}
+
+impl<F: Forwarder> RetryForwarder<F> {
+
+    /// Creates a forwarder that waits between the attempts with the
+    /// `thread_timer`.
+    pub fn new( inner: F, policy: RetryPolicy ) -> Self {
+        RetryForwarder { inner: Rc::new( inner ), policy: policy, timer: thread_timer() }
+    }
+
+    /// Specifies the timer that waits between the attempts.
+    pub fn timer( mut self, timer: Timer ) -> Self {
+        self.timer = timer;
+        self
+    }
+
+    pub fn policy( &self ) -> &RetryPolicy {
+        &self.policy
+    }
+
+    pub fn inner( &self ) -> &F {
+        &self.inner
+    }
+}
+
+impl<F: Forwarder> Forwarder for RetryForwarder<F> {
+
+    fn forward<D, S>(
+        &self,
+        name: &'static str,
+        params : S,
+    ) -> Box<Future<Item=D, Error=ServiceError>>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static
+    {
+        self.forward_call( CallInfo::new( name ), params )
+    }
+
+    fn forward_call<D, S>(
+        &self,
+        mut call: CallInfo,
+        params : S,
+    ) -> Box<Future<Item=D, Error=ServiceError>>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static
+    {
+        if !self.policy.applies_to( &call ) {
+            return self.inner.forward_call( call, params );
+        }
+
+        if call.request_id.is_none() {
+            call.request_id = Some( new_request_id() );
+        }
+
+        let inner = self.inner.clone();
+        let policy = self.policy.clone();
+        let timer = self.timer.clone();
+        let params = Rc::new( params );
+        Box::new( future::loop_fn( 1, move |attempt| {
+            let policy = policy.clone();
+            let timer = timer.clone();
+            inner.forward_call::<D, _>( call.clone(), SharedParams( params.clone() ) )
+                .then( move |result| -> Box<Future<Item=Loop<D, u32>, Error=ServiceError>> {
+                    match result {
+                        Err( ref e ) if e.is_transient() &&
+                                        policy.allows_retry( attempt ) => {
+                            Box::new( ( *timer )( policy.delay( attempt ) )
+                                .map( move |_| Loop::Continue( attempt + 1 ) ) )
+                        },
+                        result => Box::new( future::result( result.map( Loop::Break ) ) ),
+                    }
+                } )
+        } ) )
+    }
+
+    fn forward_streaming<D, S>(
+        &self,
+        name: &'static str,
+        params : S,
+        streams : Vec<stream::OutgoingStream>,
+    ) -> ServiceStream<D>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static
+    {
+        // Streams can't be replayed so streaming calls are never retried.
+        self.inner.forward_streaming( name, params, streams )
+    }
+
+    fn forward_batch(
+        &self,
+        calls : Vec<batch::Call>,
+        mode : batch::BatchMode,
+    ) -> Box<Future<Item=Vec<Result<stream::IncomingItem, ServiceError>>,
+                    Error=ServiceError>>
+    {
+        self.inner.forward_batch( calls, mode )
+    }
+
+    fn close( self ) {
+        if let Ok( inner ) = Rc::try_unwrap( self.inner ) {
+            inner.close();
+        }
+    }
+}
+
+/// Parameters shared between the attempts of a call.
+struct SharedParams<S>( Rc<S> );
+
+impl<S: Serialize> Serialize for SharedParams<S> {
+    fn serialize<Ser: Serializer>(
+        &self,
+        serializer: Ser
+    ) -> Result<Ser::Ok, Ser::Error>
+    {
+        self.0.serialize( serializer )
+    }
+}
+
+/// Remembers the responses of recent calls by their request IDs.
+///
+/// Used by the hosts to respond to retried calls without executing them
+/// again. The cache holds a limited number of responses and forgets the
+/// oldest ones first.
+pub struct ResponseCache<V> {
+    capacity: usize,
+    order: VecDeque<String>,
+    responses: HashMap<String, V>,
+}
+
+impl<V: Clone> ResponseCache<V> {
+
+    pub fn new( capacity: usize ) -> Self {
+        ResponseCache {
+            capacity: capacity,
+            order: VecDeque::with_capacity( capacity ),
+            responses: HashMap::with_capacity( capacity ),
+        }
+    }
+
+    /// Gets the response of a call that has already been executed.
+    pub fn get( &self, request_id: &str ) -> Option<V> {
+        self.responses.get( request_id ).cloned()
+    }
+
+    /// Records the response of an executed call.
+    pub fn insert( &mut self, request_id: String, response: V ) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.responses.insert( request_id.clone(), response ).is_some() {
+            return;
+        }
+
+        self.order.push_back( request_id );
+        while self.order.len() > self.capacity {
+            if let Some( oldest ) = self.order.pop_front() {
+                self.responses.remove( &oldest );
+            }
+        }
+    }
+}
+
+impl<V: Clone> Default for ResponseCache<V> {
+    fn default() -> Self {
+        ResponseCache::new( 1024 )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::time::Instant;
+    use tokio_core::reactor::{Core, Timeout};
+
+    /// Fails the first calls with a transport error.
+    struct FlakyForwarder {
+        failures: Cell<u32>,
+        calls: Rc<RefCell<Vec<Option<String>>>>,
+    }
+
+    impl Forwarder for FlakyForwarder {
+        fn forward<D, S>( &self, _name: &'static str, _params : S ) -> Box<Future<Item=D, Error=ServiceError>>
+            where D: DeserializeOwned + 'static, S: Serialize + 'static
+        {
+            unreachable!()
+        }
+
+        fn forward_call<D, S>( &self, call: CallInfo, _params : S ) -> Box<Future<Item=D, Error=ServiceError>>
+            where D: DeserializeOwned + 'static, S: Serialize + 'static
+        {
+            self.calls.borrow_mut().push( call.request_id );
+            if self.failures.get() > 0 {
+                self.failures.set( self.failures.get() - 1 );
+                return Box::new( future::err( ServiceError::transport( "Connection lost" ) ) );
+            }
+            Box::new( future::result( ::serde_json::from_value( json!( "done" ) )
+                    .map_err( ServiceError::from ) ) )
+        }
+
+        fn close( self ) {}
+    }
+
+    fn flaky( failures: u32 ) -> ( FlakyForwarder, Rc<RefCell<Vec<Option<String>>>> ) {
+        let calls = Rc::new( RefCell::new( vec![] ) );
+        ( FlakyForwarder { failures: Cell::new( failures ), calls: calls.clone() }, calls )
+    }
+
+    fn idempotent() -> CallInfo {
+        CallInfo { idempotent: true, .. CallInfo::new( "call" ) }
+    }
+
+    #[test]
+    pub fn retries() {
+        let ( inner, calls ) = flaky( 2 );
+        let delays = Rc::new( RefCell::new( vec![] ) );
+        let recorded = delays.clone();
+        let policy = RetryPolicy::default()
+                .backoff( Duration::from_millis( 100 ), Duration::from_secs( 1 ) )
+                .jitter( 0.0 );
+        let forwarder = RetryForwarder::new( inner, policy )
+                .timer( new_timer( move |delay| {
+                    recorded.borrow_mut().push( delay );
+                    future::ok( () )
+                } ) );
+
+        let result : String = forwarder.forward_call( idempotent(), () ).wait().unwrap();
+        assert_eq!( result, "done" );
+        assert_eq!( *delays.borrow(), vec![ Duration::from_millis( 100 ), Duration::from_millis( 200 ) ] );
+
+        // The attempts share the request ID.
+        let calls = calls.borrow();
+        assert_eq!( calls.len(), 3 );
+        assert!( calls[ 0 ].is_some() );
+        assert!( calls.iter().all( |id| *id == calls[ 0 ] ) );
+    }
+
+    #[test]
+    pub fn gives_up() {
+        let ( inner, calls ) = flaky( 5 );
+        let forwarder = RetryForwarder::new( inner, RetryPolicy::default() )
+                .timer( new_timer( |_| future::ok( () ) ) );
+        let result : Result<String, _> = forwarder.forward_call( idempotent(), () ).wait();
+        assert_eq!( result.unwrap_err().kind, ErrorKind::Transport );
+        assert_eq!( calls.borrow().len(), 3 );
+
+        // Operations that aren't idempotent aren't retried.
+        let ( inner, calls ) = flaky( 1 );
+        let forwarder = RetryForwarder::new( inner, RetryPolicy::default() );
+        let result : Result<String, _> = forwarder.forward_call( CallInfo::new( "call" ), () ).wait();
+        assert!( result.is_err() );
+        assert_eq!( calls.borrow().len(), 1 );
+    }
+
+    #[test]
+    pub fn backoff_doesnt_block() {
+        let mut core = Core::new().unwrap();
+        let ( inner, _ ) = flaky( 1 );
+        let policy = RetryPolicy::default()
+                .backoff( Duration::from_millis( 200 ), Duration::from_millis( 200 ) )
+                .jitter( 0.0 );
+        let forwarder = RetryForwarder::new( inner, policy );
+
+        // Other work on the reactor goes on while the call waits to retry.
+        let start = Instant::now();
+        let call = forwarder.forward_call::<String, _>( idempotent(), () );
+        let timeout = Timeout::new( Duration::from_millis( 20 ), &core.handle() ).unwrap();
+        let first = core.run( call.select2( timeout.map_err( ServiceError::from ) ) ).ok().unwrap();
+        assert!( start.elapsed() < Duration::from_millis( 150 ) );
+
+        // The call still completes afterwards.
+        let call = match first {
+            future::Either::B( ( _, call ) ) => call,
+            future::Either::A( _ ) => panic!( "The call completed before the backoff" ),
+        };
+        assert_eq!( core.run( call ).unwrap(), "done" );
+    }
+}
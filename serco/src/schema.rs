@@ -0,0 +1,442 @@
+//! JSON Schema export for the contracts.
+//!
+//! Types used in the contracts describe themselves through the `JsonSchema`
+//! trait, which can be derived with `#[derive(JsonSchema)]`. Contracts
+//! declared with `#[service_contract(schema)]` require all of their argument
+//! and return types to implement the trait and export a schema describing
+//! the request and the response of each operation through
+//! `MyContract::json_schema()`.
+//!
+//! The schemas follow the JSON representation the serde derives produce by
+//! default.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use serde_json::{Map, Value};
+
+use super::{ServiceError, ErrorKind};
+use super::description::ContractDescription;
+
+/// A JSON Schema document or a part of one.
+pub type Schema = Value;
+
+/// The JSON Schema draft the exported schemas conform to.
+pub const SCHEMA_VERSION : &str = "http://json-schema.org/draft-07/schema#";
+
+/// Describes the JSON representation of a type.
+pub trait JsonSchema {
+
+    /// Name of the type in the schema definitions.
+    fn schema_name() -> String;
+
+    /// Whether the schema is placed in the definitions and referred to by
+    /// name. Simple types are inlined instead.
+    fn is_referenceable() -> bool { true }
+
+    /// Creates the schema of the type.
+    ///
+    /// Schemas of the other types should be resolved through the generator
+    /// so their definitions are collected.
+    fn json_schema( gen: &mut SchemaGenerator ) -> Schema;
+}
+
+/// Collects the definitions of the types while creating schemas.
+#[derive(Default)]
+pub struct SchemaGenerator {
+    definitions: BTreeMap<String, Schema>,
+}
+
+impl SchemaGenerator {
+
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Gets the schema to use where the type appears.
+    ///
+    /// Referenceable types are added to the definitions and a reference to
+    /// the definition is returned.
+    pub fn subschema_for<T: JsonSchema + ?Sized>( &mut self ) -> Schema {
+        if !T::is_referenceable() {
+            return T::json_schema( self );
+        }
+
+        let name = T::schema_name();
+        if !self.definitions.contains_key( &name ) {
+
+            // Reserve the name first so recursive types terminate.
+            self.definitions.insert( name.clone(), Value::Bool( true ) );
+            let schema = T::json_schema( self );
+            self.definitions.insert( name.clone(), schema );
+        }
+
+        json!({ "$ref": format!( "#/definitions/{}", name ) })
+    }
+
+    /// Gets the definitions collected so far.
+    pub fn definitions( &self ) -> &BTreeMap<String, Schema> {
+        &self.definitions
+    }
+
+    pub fn into_definitions( self ) -> BTreeMap<String, Schema> {
+        self.definitions
+    }
+}
+
+/// Creates the root schema of a type.
+pub fn schema_for<T: JsonSchema + ?Sized>() -> Schema {
+    let mut gen = SchemaGenerator::new();
+    let mut schema = T::json_schema( &mut gen );
+    if let Value::Object( ref mut map ) = schema {
+        map.insert( "$schema".to_string(), Value::from( SCHEMA_VERSION ) );
+        map.insert( "title".to_string(), Value::from( T::schema_name() ) );
+        if !gen.definitions.is_empty() {
+            map.insert( "definitions".to_string(), definitions_value( gen.definitions ) );
+        }
+    }
+    schema
+}
+
+fn definitions_value( definitions: BTreeMap<String, Schema> ) -> Value {
+    Value::Object( definitions.into_iter().collect() )
+}
+
+/// Creates the schema of an object with the given properties.
+///
+/// Used by the `JsonSchema` derive.
+pub fn object_schema( properties: Vec<( &str, Schema )>, required: Vec<&str> ) -> Schema {
+    json!({
+        "type": "object",
+        "properties": Value::Object( properties.into_iter()
+                .map( |( name, schema )| ( name.to_string(), schema ) )
+                .collect() ),
+        "required": required,
+    })
+}
+
+/// Creates the schema of a fixed length array such as a tuple.
+pub fn tuple_schema( items: Vec<Schema> ) -> Schema {
+    let len = items.len();
+    json!({
+        "type": "array",
+        "items": items,
+        "minItems": len,
+        "maxItems": len,
+    })
+}
+
+/// Creates the schema of a string with a fixed set of values.
+pub fn string_enum_schema( values: Vec<&str> ) -> Schema {
+    json!({ "type": "string", "enum": values })
+}
+
+/// Creates the schema of an object with a single property.
+///
+/// Used for the enum variants that carry data as serde tags them with the
+/// variant name.
+pub fn tagged_schema( tag: &str, schema: Schema ) -> Schema {
+    object_schema( vec![ ( tag, schema ) ], vec![ tag ] )
+}
+
+/// Creates the schema matching exactly one of the schemas.
+pub fn one_of_schema( schemas: Vec<Schema> ) -> Schema {
+    json!({ "oneOf": schemas })
+}
+
+/// Adds a description to the schema.
+pub fn describe( mut schema: Schema, description: &str ) -> Schema {
+    if let Value::Object( ref mut map ) = schema {
+        map.insert( "description".to_string(), Value::from( description ) );
+    }
+    schema
+}
+
+/// Builds the schema of a contract.
+///
+/// Used by the generated `json_schema` functions.
+pub struct ContractSchema {
+    description: &'static ContractDescription,
+    gen: SchemaGenerator,
+    operations: Map<String, Value>,
+}
+
+impl ContractSchema {
+
+    pub fn new( description: &'static ContractDescription ) -> Self {
+        ContractSchema {
+            description: description,
+            gen: SchemaGenerator::new(),
+            operations: Map::new(),
+        }
+    }
+
+    /// Starts describing an operation.
+    pub fn operation( &mut self, name: &'static str ) -> OperationSchema {
+        OperationSchema {
+            contract: self,
+            name: name,
+            properties: Map::new(),
+            required: vec![],
+        }
+    }
+
+    /// Completes the schema.
+    ///
+    /// The operations are listed under `operations` with a `request` and a
+    /// `response` schema each. The `request` describes the parameters object
+    /// while the `response` describes the result of the operation. The
+    /// errors are described by the `ServiceError` definition.
+    pub fn build( mut self ) -> Schema {
+        let error = self.gen.subschema_for::<ServiceError>();
+
+        let mut schema = Map::new();
+        schema.insert( "$schema".to_string(), Value::from( SCHEMA_VERSION ) );
+        schema.insert( "title".to_string(), Value::from( self.description.name ) );
+        if let Some( doc ) = self.description.doc {
+            schema.insert( "description".to_string(), Value::from( doc ) );
+        }
+        schema.insert( "operations".to_string(), Value::Object( self.operations ) );
+        schema.insert( "error".to_string(), error );
+        schema.insert( "definitions".to_string(),
+                       definitions_value( self.gen.into_definitions() ) );
+        Value::Object( schema )
+    }
+}
+
+/// Builds the schema of a single operation.
+pub struct OperationSchema<'a> {
+    contract: &'a mut ContractSchema,
+    name: &'static str,
+    properties: Map<String, Value>,
+    required: Vec<Value>,
+}
+
+impl<'a> OperationSchema<'a> {
+
+    /// Adds an argument to the request.
+    pub fn argument<T: JsonSchema>( mut self, name: &'static str ) -> Self {
+        let schema = self.contract.gen.subschema_for::<T>();
+        self.properties.insert( name.to_string(), schema );
+        self.required.push( Value::from( name ) );
+        self
+    }
+
+    /// Completes the operation with the type of the result.
+    pub fn response<T: JsonSchema>( self ) -> &'a mut ContractSchema {
+        let response = self.contract.gen.subschema_for::<T>();
+        self.complete( response, false )
+    }
+
+    /// Completes the operation with the type of the items of the result
+    /// stream.
+    pub fn stream_response<T: JsonSchema>( self ) -> &'a mut ContractSchema {
+        let response = self.contract.gen.subschema_for::<T>();
+        self.complete( response, true )
+    }
+
+    fn complete( self, response: Schema, stream: bool ) -> &'a mut ContractSchema {
+        let mut operation = Map::new();
+
+        let description : &'static ContractDescription = self.contract.description;
+        let op = description.operation( self.name );
+        if let Some( doc ) = op.and_then( |op| op.doc ) {
+            operation.insert( "description".to_string(), Value::from( doc ) );
+        }
+
+        operation.insert( "request".to_string(), json!({
+            "type": "object",
+            "properties": Value::Object( self.properties ),
+            "required": Value::Array( self.required ),
+        }) );
+        operation.insert( "response".to_string(), response );
+
+        // Stream arguments and results are passed item by item outside the
+        // request and the response.
+        if stream {
+            operation.insert( "x-stream-response".to_string(), Value::Bool( true ) );
+        }
+        let stream_args : Vec<Value> = op.map( |op| op.args.iter()
+                .filter_map( |a| a.stream.map( |_| Value::from( a.name ) ) )
+                .collect() ).unwrap_or_default();
+        if !stream_args.is_empty() {
+            operation.insert( "x-stream-arguments".to_string(), Value::Array( stream_args ) );
+        }
+
+        self.contract.operations.insert( self.name.to_string(), Value::Object( operation ) );
+        self.contract
+    }
+}
+
+macro_rules! simple_schema {
+    ( $( $ty:ty => $name:expr, $schema:expr; )* ) => { $(
+        impl JsonSchema for $ty {
+            fn schema_name() -> String { $name.to_string() }
+            fn is_referenceable() -> bool { false }
+            fn json_schema( _: &mut SchemaGenerator ) -> Schema { $schema }
+        }
+    )* }
+}
+
+simple_schema! {
+    bool => "bool", json!({ "type": "boolean" });
+    i8 => "int8", json!({ "type": "integer", "format": "int8" });
+    i16 => "int16", json!({ "type": "integer", "format": "int16" });
+    i32 => "int32", json!({ "type": "integer", "format": "int32" });
+    i64 => "int64", json!({ "type": "integer", "format": "int64" });
+    isize => "int", json!({ "type": "integer" });
+    u8 => "uint8", json!({ "type": "integer", "format": "uint8", "minimum": 0 });
+    u16 => "uint16", json!({ "type": "integer", "format": "uint16", "minimum": 0 });
+    u32 => "uint32", json!({ "type": "integer", "format": "uint32", "minimum": 0 });
+    u64 => "uint64", json!({ "type": "integer", "format": "uint64", "minimum": 0 });
+    usize => "uint", json!({ "type": "integer", "minimum": 0 });
+    f32 => "float", json!({ "type": "number", "format": "float" });
+    f64 => "double", json!({ "type": "number", "format": "double" });
+    char => "char", json!({ "type": "string", "minLength": 1, "maxLength": 1 });
+    String => "string", json!({ "type": "string" });
+    str => "string", json!({ "type": "string" });
+    () => "null", json!({ "type": "null" });
+    Value => "any", json!( true );
+}
+
+impl<T: JsonSchema> JsonSchema for Option<T> {
+    fn schema_name() -> String { format!( "Nullable_{}", T::schema_name() ) }
+    fn is_referenceable() -> bool { false }
+    fn json_schema( gen: &mut SchemaGenerator ) -> Schema {
+        json!({ "anyOf": [ gen.subschema_for::<T>(), { "type": "null" } ] })
+    }
+}
+
+macro_rules! array_schema {
+    ( $( $ty:ident, $unique:expr; )* ) => { $(
+        impl<T: JsonSchema> JsonSchema for $ty<T> {
+            fn schema_name() -> String { format!( "Array_of_{}", T::schema_name() ) }
+            fn is_referenceable() -> bool { false }
+            fn json_schema( gen: &mut SchemaGenerator ) -> Schema {
+                let mut schema = json!({
+                    "type": "array",
+                    "items": gen.subschema_for::<T>(),
+                });
+                if $unique {
+                    schema[ "uniqueItems" ] = Value::Bool( true );
+                }
+                schema
+            }
+        }
+    )* }
+}
+
+array_schema! {
+    Vec, false;
+    VecDeque, false;
+    HashSet, true;
+    BTreeSet, true;
+}
+
+impl<T: JsonSchema> JsonSchema for [T] {
+    fn schema_name() -> String { format!( "Array_of_{}", T::schema_name() ) }
+    fn is_referenceable() -> bool { false }
+    fn json_schema( gen: &mut SchemaGenerator ) -> Schema {
+        json!({ "type": "array", "items": gen.subschema_for::<T>() })
+    }
+}
+
+macro_rules! map_schema {
+    ( $( $ty:ident; )* ) => { $(
+        impl<K, V: JsonSchema> JsonSchema for $ty<K, V> {
+            fn schema_name() -> String { format!( "Map_of_{}", V::schema_name() ) }
+            fn is_referenceable() -> bool { false }
+            fn json_schema( gen: &mut SchemaGenerator ) -> Schema {
+                json!({
+                    "type": "object",
+                    "additionalProperties": gen.subschema_for::<V>(),
+                })
+            }
+        }
+    )* }
+}
+
+map_schema! {
+    HashMap;
+    BTreeMap;
+}
+
+macro_rules! wrapper_schema {
+    ( $( $ty:ident; )* ) => { $(
+        impl<T: JsonSchema + ?Sized> JsonSchema for $ty<T> {
+            fn schema_name() -> String { T::schema_name() }
+            fn is_referenceable() -> bool { T::is_referenceable() }
+            fn json_schema( gen: &mut SchemaGenerator ) -> Schema {
+                T::json_schema( gen )
+            }
+        }
+    )* }
+}
+
+wrapper_schema! {
+    Box;
+    Rc;
+    Arc;
+}
+
+impl<'a, T: JsonSchema + ?Sized> JsonSchema for &'a T {
+    fn schema_name() -> String { T::schema_name() }
+    fn is_referenceable() -> bool { T::is_referenceable() }
+    fn json_schema( gen: &mut SchemaGenerator ) -> Schema {
+        T::json_schema( gen )
+    }
+}
+
+macro_rules! tuple_schema {
+    ( $( ( $( $name:ident ),+ ) => $len:expr; )* ) => { $(
+        impl< $( $name: JsonSchema ),+ > JsonSchema for ( $( $name, )+ ) {
+            fn schema_name() -> String {
+                let names : Vec<String> = vec![ $( $name::schema_name() ),+ ];
+                format!( "Tuple_of_{}", names.join( "_and_" ) )
+            }
+            fn is_referenceable() -> bool { false }
+            fn json_schema( gen: &mut SchemaGenerator ) -> Schema {
+                json!({
+                    "type": "array",
+                    "items": [ $( gen.subschema_for::<$name>() ),+ ],
+                    "minItems": $len,
+                    "maxItems": $len,
+                })
+            }
+        }
+    )* }
+}
+
+tuple_schema! {
+    ( A ) => 1;
+    ( A, B ) => 2;
+    ( A, B, C ) => 3;
+    ( A, B, C, D ) => 4;
+    ( A, B, C, D, E ) => 5;
+    ( A, B, C, D, E, F ) => 6;
+}
+
+impl JsonSchema for ErrorKind {
+    fn schema_name() -> String { "ErrorKind".to_string() }
+    fn json_schema( _: &mut SchemaGenerator ) -> Schema {
+        json!({
+            "type": "string",
+            "enum": [ "Internal", "Transport", "BadOperation", "BadRequest", "Unauthenticated", "Unauthorized", "Throttled", "MessageTooLarge" ],
+        })
+    }
+}
+
+impl JsonSchema for ServiceError {
+    fn schema_name() -> String { "ServiceError".to_string() }
+    fn json_schema( gen: &mut SchemaGenerator ) -> Schema {
+        json!({
+            "type": "object",
+            "properties": {
+                "kind": gen.subschema_for::<ErrorKind>(),
+                "message": gen.subschema_for::<String>(),
+            },
+            "required": [ "kind", "message" ],
+        })
+    }
+}
@@ -0,0 +1,319 @@
+//! Tracking of the sessions connected to a service host.
+//!
+//! The callback proxy available through `get_callback` is only set for the
+//! duration of a call made by the client. Hosts also record the callback
+//! proxies of all connected sessions in `ConnectedSessions`, which the
+//! service can hold on to in order to push notifications to the clients
+//! outside of their calls.
+//!
+//! The endpoints invoke the calls of each session through a
+//! `SessionTarget`, which applies the policies of the host to the calls.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use futures::prelude::*;
+use serde::{Serializer, Deserializer};
+
+use super::{ServiceError, ServiceContract, InvokeTarget, ServiceProxy, Forwarder};
+use super::auth::{self, Principal};
+use super::health::Builtins;
+use super::throttle::{Permit, SessionThrottle};
+use super::metrics::{self, Metrics};
+use super::trace;
+
+/// Identity of a peer verified by the transport, such as the certificate a
+/// TLS client presented.
+///
+/// Passed to `SessionFactory::create_peer_session` when the connection is
+/// established.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+
+    /// Name of the peer, such as the common name of the certificate subject.
+    pub name: Option<String>,
+
+    /// DER encoded certificate of the peer.
+    pub certificate: Vec<u8>,
+}
+
+/// Creates a random session ID. The IDs are unique and the clients can't
+/// guess the IDs of the other sessions.
+pub fn new_session_id() -> String {
+    format!( "{:016x}{:016x}", ::rand::random::<u64>(), ::rand::random::<u64>() )
+}
+
+/// Callback proxies of the sessions connected to a host.
+///
+/// The handle is cheap to clone. All clones refer to the same set of
+/// sessions.
+pub struct ConnectedSessions<C: ?Sized> {
+    sessions: Rc<RefCell<HashMap<String, Connection<C>>>>,
+}
+
+/// The most recent callback of a session and the number of connections the
+/// session has. Clients that resume a session may connect before the host
+/// has noticed their previous connection closing.
+struct Connection<C: ?Sized> {
+    callback: Arc<C>,
+    count: usize,
+}
+
+impl<C: ?Sized> Clone for ConnectedSessions<C> {
+    fn clone( &self ) -> Self {
+        ConnectedSessions { sessions: self.sessions.clone() }
+    }
+}
+
+impl<C: ?Sized> Default for ConnectedSessions<C> {
+    fn default() -> Self {
+        ConnectedSessions { sessions: Default::default() }
+    }
+}
+
+impl<C> ConnectedSessions<C>
+    where C: ServiceContract + ?Sized
+{
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Records a connected session.
+    ///
+    /// Called by the endpoints once the session has been established.
+    pub fn connect<F: Forwarder>(
+        &self,
+        id: &str,
+        callback: Arc<ServiceProxy<C, F>>
+    ) {
+        let callback = C::from_proxy( callback );
+        let mut sessions = self.sessions.borrow_mut();
+        let connection = sessions.entry( id.to_string() )
+                .or_insert_with( || Connection {
+                    callback: callback.clone(),
+                    count: 0
+                } );
+        connection.callback = callback;
+        connection.count += 1;
+    }
+
+    /// Removes a session that has disconnected.
+    ///
+    /// Called by the endpoints once the connection has been closed. The
+    /// session remains connected as long as it has other connections.
+    pub fn disconnect( &self, id: &str ) {
+        let mut sessions = self.sessions.borrow_mut();
+        let remaining = match sessions.get_mut( id ) {
+            Some( connection ) => {
+                connection.count -= 1;
+                connection.count
+            },
+            None => return,
+        };
+
+        if remaining == 0 {
+            sessions.remove( id );
+        }
+    }
+
+    /// Lists the IDs of the connected sessions.
+    pub fn ids( &self ) -> Vec<String> {
+        self.sessions.borrow().keys().cloned().collect()
+    }
+
+    /// Returns the number of connected sessions.
+    pub fn len( &self ) -> usize {
+        self.sessions.borrow().len()
+    }
+
+    pub fn is_empty( &self ) -> bool {
+        self.sessions.borrow().is_empty()
+    }
+
+    /// Gets the callback proxy of a session.
+    ///
+    /// The proxy remains usable after the call that acquired it has
+    /// completed for as long as the session stays connected.
+    pub fn get( &self, id: &str ) -> Option<Arc<C>> {
+        self.sessions.borrow().get( id ).map( |c| c.callback.clone() )
+    }
+
+    /// Invokes the callback on all connected sessions.
+    pub fn broadcast<F>( &self, f: F )
+        where F: Fn( &str, &C )
+    {
+        self.broadcast_where( |_| true, f )
+    }
+
+    /// Invokes the callback on the listed sessions.
+    ///
+    /// Sessions that are no longer connected are skipped.
+    pub fn broadcast_to<I, S, F>( &self, ids: I, f: F )
+        where I: IntoIterator<Item=S>,
+              S: AsRef<str>,
+              F: Fn( &str, &C )
+    {
+        for id in ids {
+            if let Some( callback ) = self.get( id.as_ref() ) {
+                f( id.as_ref(), &callback );
+            }
+        }
+    }
+
+    /// Invokes the callback on the sessions accepted by the predicate.
+    pub fn broadcast_where<P, F>( &self, predicate: P, f: F )
+        where P: Fn( &str ) -> bool,
+              F: Fn( &str, &C )
+    {
+        // Collect the targets first so the callbacks are free to connect or
+        // disconnect sessions while the broadcast is in progress.
+        let targets : Vec<_> = self.sessions.borrow()
+                .iter()
+                .filter( |&( id, _ )| predicate( id ) )
+                .map( |( id, c )| ( id.clone(), c.callback.clone() ) )
+                .collect();
+
+        for ( id, callback ) in targets {
+            f( &id, &callback );
+        }
+    }
+}
+
+/// Invoke target the endpoints use for the sessions of a host.
+///
+/// Routes the calls of the built-in contracts to the host and the rest to
+/// the session. The calls to the session are made as the principal of the
+/// session for the `#[authorize]` checks and within the limits of the host.
+/// The target reports the session and its calls to the metrics of the host
+/// and traces the calls in spans tagged with the session ID.
+pub struct SessionTarget<T> {
+    session: T,
+    session_id: String,
+    builtins: Option<Rc<Builtins>>,
+    principal: Option<Rc<Principal>>,
+    throttle: Option<SessionThrottle>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl<T> SessionTarget<T> {
+    pub fn new(
+        session: T,
+        session_id: &str,
+        builtins: Option<Rc<Builtins>>,
+        principal: Option<Rc<Principal>>,
+        throttle: Option<SessionThrottle>,
+        metrics: Option<Arc<Metrics>>,
+    ) -> Self {
+        if let Some( ref metrics ) = metrics {
+            metrics.session_opened();
+        }
+        SessionTarget {
+            session: session,
+            session_id: session_id.to_string(),
+            builtins: builtins,
+            principal: principal,
+            throttle: throttle,
+            metrics: metrics,
+        }
+    }
+
+    /// Admits a call within the limits of the host. The permit is held
+    /// until the call completes.
+    fn begin_call( &self, name: &str ) -> Result<Option<Permit>, ServiceError> {
+        match self.throttle {
+            Some( ref throttle ) => throttle.begin_call( name ).map( Some ),
+            None => Ok( None ),
+        }
+    }
+}
+
+impl<T> Drop for SessionTarget<T> {
+    fn drop( &mut self ) {
+        if let Some( ref metrics ) = self.metrics {
+            metrics.session_closed();
+        }
+    }
+}
+
+impl<C, T> InvokeTarget<C> for SessionTarget<T>
+    where C: ServiceContract + ?Sized,
+          T: InvokeTarget<C>,
+{
+    fn invoke<'de, D, S>(
+        &self,
+        name: &str,
+        params : D,
+        output : S
+    ) -> Box<Future<Item=S, Error=ServiceError>>
+        where
+            D: Deserializer<'de>,
+            S: 'static,
+            for <'a> &'a mut S: Serializer
+    {
+        let ( params, output ) = match self.builtins {
+            Some( ref builtins ) => match builtins.try_invoke( name, params, output ) {
+                Ok( result ) => return result,
+                Err( args ) => args,
+            },
+            None => ( params, output ),
+        };
+
+        let permit = match self.begin_call( name ) {
+            Ok( permit ) => permit,
+            Err( e ) => return Box::new( ::futures::future::err( e ) ),
+        };
+        let principal = self.principal.clone();
+        let call = || trace::serve( C::contract_name(), name, Some( &self.session_id ), || {
+            auth::with_principal( principal, || self.session.invoke( name, params, output ) )
+        } );
+        let result = match self.metrics {
+            Some( ref metrics ) => metrics::record( metrics, name, call ),
+            None => call(),
+        };
+        match permit {
+            Some( permit ) => Box::new( result.then( move |result| {
+                drop( permit );
+                result
+            } ) ),
+            None => result,
+        }
+    }
+
+    fn invoke_direct(
+        &self,
+        name: &str,
+        params : Box<Any>,
+    ) -> Box<Future<Item=Box<Any>, Error=ServiceError>>
+    {
+        let params = match self.builtins {
+            Some( ref builtins ) => match builtins.try_invoke_direct( name, params ) {
+                Ok( result ) => return result,
+                Err( params ) => params,
+            },
+            None => params,
+        };
+
+        let permit = match self.begin_call( name ) {
+            Ok( permit ) => permit,
+            Err( e ) => return Box::new( ::futures::future::err( e ) ),
+        };
+        let principal = self.principal.clone();
+        let call = || trace::serve( C::contract_name(), name, Some( &self.session_id ), || {
+            auth::with_principal( principal, || self.session.invoke_direct( name, params ) )
+        } );
+        let result = match self.metrics {
+            Some( ref metrics ) => metrics::record( metrics, name, call ),
+            None => call(),
+        };
+        match permit {
+            Some( permit ) => Box::new( result.then( move |result| {
+                drop( permit );
+                result
+            } ) ),
+            None => result,
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Support for operations that take or return streams.
+//!
+//! The generated `InvokeTarget::invoke` implementations are format agnostic
+//! so the streams can't be passed through the `Deserializer` and
+//! `Serializer` the endpoints use for the rest of the call. Instead the
+//! endpoint stores the incoming streams in thread local storage right
+//! before the invoke and collects the outgoing stream right after it. The
+//! invoke runs on the thread of the endpoint so nothing else gets between.
+
+use std::cell::RefCell;
+
+use futures::prelude::*;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use erased_serde;
+
+use super::{ServiceError, ErrorKind};
+
+/// A stream of values passed to or returned from a service operation.
+pub type ServiceStream<T> = Box<Stream<Item=T, Error=ServiceError>>;
+
+/// Type erased stream item that the endpoint can serialize.
+pub type StreamItem = Box<erased_serde::Serialize>;
+
+/// Type erased stream item that the operation can deserialize.
+pub type IncomingItem = Box<erased_serde::Deserializer<'static>>;
+
+/// Stream of items sent by the operation or a proxy.
+pub type OutgoingStream = ServiceStream<StreamItem>;
+
+/// Stream of items received by the operation or a proxy.
+pub type IncomingStream = ServiceStream<IncomingItem>;
+
+thread_local!{
+    static INCOMING: RefCell<Vec<Option<IncomingStream>>> =
+            RefCell::new( vec![] )
+}
+
+thread_local!{
+    static OUTGOING: RefCell<Option<OutgoingStream>> = RefCell::new( None )
+}
+
+/// Stores the stream arguments for the next invoke on the current thread.
+///
+/// Called by the endpoints. The streams are ordered by the position of the
+/// stream arguments in the operation signature.
+pub fn set_incoming( streams: Vec<IncomingStream> )
+{
+    INCOMING.with( |cell| cell.replace(
+            streams.into_iter().map( Some ).collect() ) );
+}
+
+/// Takes a stream argument stored with `set_incoming`.
+///
+/// Called by the generated invoke implementation. If the endpoint didn't
+/// provide the stream, the resulting stream fails on first poll.
+pub fn take_incoming<T: DeserializeOwned + 'static>(
+    index: usize
+) -> ServiceStream<T>
+{
+    let stream = INCOMING.with( |cell| {
+        cell.borrow_mut()
+            .get_mut( index )
+            .and_then( |s| s.take() )
+    } );
+
+    match stream {
+        Some( stream ) => Box::new( stream.and_then( |item|
+                T::deserialize( item ).map_err( ServiceError::from ) ) ),
+        None => Box::new( ::futures::stream::once( Err( ServiceError::new(
+                ErrorKind::BadRequest,
+                format!( "Stream argument {} was not provided", index ) ) ) ) ),
+    }
+}
+
+/// Stores the stream returned by the operation.
+///
+/// Called by the generated invoke implementation.
+pub fn set_outgoing<T: Serialize + 'static>( stream: ServiceStream<T> )
+{
+    OUTGOING.with( |cell| cell.replace( Some( erase( stream ) ) ) );
+}
+
+/// Takes the stream stored with `set_outgoing`.
+///
+/// Called by the endpoints once the invoke has completed. Returns `None` if
+/// the operation didn't return a stream.
+pub fn take_outgoing() -> Option<OutgoingStream>
+{
+    OUTGOING.with( |cell| cell.borrow_mut().take() )
+}
+
+/// Erases the item type of the stream so the endpoints can serialize it.
+pub fn erase<T: Serialize + 'static>( stream: ServiceStream<T> ) -> OutgoingStream
+{
+    Box::new( stream.map( |item| Box::new( item ) as StreamItem ) )
+}
+
+/// Resolves the only item of a stream.
+///
+/// Used by the proxies of the operations that take stream arguments but
+/// return a single value.
+pub fn single<T: 'static>(
+    stream: ServiceStream<T>
+) -> Box<Future<Item=T, Error=ServiceError>>
+{
+    Box::new( stream.into_future()
+        .map_err( |( e, _ )| e )
+        .and_then( |( item, _ )| item.ok_or_else(
+                || ServiceError::new( ErrorKind::Internal,
+                                      "Stream ended without a result" ) ) ) )
+}
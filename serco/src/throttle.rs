@@ -0,0 +1,300 @@
+//! Limits on the work a host accepts.
+//!
+//! Hosts built with `ServiceHost::throttling` refuse the work over the
+//! configured limits with a `Throttled` error instead of queuing it:
+//!
+//! - The calls over the maximum number of calls in progress fail.
+//! - The connections over the maximum number of sessions are refused.
+//! - The connections over the maximum number of connections of an endpoint
+//!   are refused. Each endpoint has its own count.
+//! - The calls over the rate limit of their session or their operation
+//!   fail. The operation limits are shared by all of the sessions.
+//!
+//! The rate limits are token buckets that allow bursts of calls up to the
+//! size of the bucket and refill at the rate of the limit.
+//!
+//! All sessions count towards the maximum number of sessions, including
+//! those of the in-process clients. The sessions the clients may resume by
+//! their IDs, such as those of the HTTP endpoints, stay open until they have
+//! been unused for the session timeout of the host. The calls to the
+//! built-in contracts are never throttled so the hosts can still be probed
+//! under load.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Instant;
+
+use super::ServiceError;
+
+/// Limits of a host. Nothing is limited by default.
+#[derive(Debug, Clone, Default)]
+pub struct Throttling {
+    max_concurrent_calls: Option<usize>,
+    max_concurrent_sessions: Option<usize>,
+    max_connections: Option<usize>,
+    session_rate: Option<RateLimit>,
+    operation_rates: HashMap<String, RateLimit>,
+}
+
+impl Throttling {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Limits the calls in progress across all of the sessions.
+    pub fn max_concurrent_calls( mut self, max: usize ) -> Self {
+        self.max_concurrent_calls = Some( max );
+        self
+    }
+
+    /// Limits the sessions open at once.
+    pub fn max_concurrent_sessions( mut self, max: usize ) -> Self {
+        self.max_concurrent_sessions = Some( max );
+        self
+    }
+
+    /// Limits the connections open at once on each endpoint.
+    pub fn max_connections( mut self, max: usize ) -> Self {
+        self.max_connections = Some( max );
+        self
+    }
+
+    /// Limits the rate of the calls of each session.
+    pub fn session_rate( mut self, limit: RateLimit ) -> Self {
+        self.session_rate = Some( limit );
+        self
+    }
+
+    /// Limits the rate of the calls to an operation.
+    pub fn operation_rate<T: Into<String>>( mut self, operation: T, limit: RateLimit ) -> Self {
+        self.operation_rates.insert( operation.into(), limit );
+        self
+    }
+}
+
+/// Rate of calls allowed by a token bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    per_second: f64,
+    burst: f64,
+}
+
+impl RateLimit {
+
+    /// Allows the calls at the rate. The bursts are limited to a second's
+    /// worth of calls unless specified with `burst`.
+    pub fn per_second( calls: f64 ) -> Self {
+        RateLimit { per_second: calls, burst: calls.max( 1.0 ) }
+    }
+
+    /// Specifies the number of calls allowed at once after a quiet period.
+    pub fn burst( mut self, calls: u32 ) -> Self {
+        self.burst = calls as f64;
+        self
+    }
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new( limit: RateLimit ) -> Self {
+        TokenBucket { limit: limit, tokens: limit.burst, updated: Instant::now() }
+    }
+
+    /// Takes a token for a call if there is one left.
+    fn take( &mut self, now: Instant ) -> bool {
+        if now > self.updated {
+            let elapsed = now - self.updated;
+            let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+            self.tokens = ( self.tokens + elapsed * self.limit.per_second ).min( self.limit.burst );
+            self.updated = now;
+        }
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Holds a place in a limited count until dropped.
+#[derive(Debug)]
+pub struct Permit( Rc<Cell<usize>> );
+
+impl Drop for Permit {
+    fn drop( &mut self ) {
+        self.0.set( self.0.get() - 1 );
+    }
+}
+
+/// Counts the places in use up to an optional maximum.
+#[derive(Clone)]
+struct Counter {
+    max: Option<usize>,
+    count: Rc<Cell<usize>>,
+}
+
+impl Counter {
+    fn new( max: Option<usize> ) -> Self {
+        Counter { max: max, count: Rc::new( Cell::new( 0 ) ) }
+    }
+
+    fn acquire( &self, what: &str ) -> Result<Permit, ServiceError> {
+        let count = self.count.get();
+        if let Some( max ) = self.max {
+            if count >= max {
+                return Err( ServiceError::throttled(
+                        format!( "The host is at its limit of {} {}", max, what ) ) );
+            }
+        }
+        self.count.set( count + 1 );
+        Ok( Permit( self.count.clone() ) )
+    }
+}
+
+/// The limits of a running host and the work it has in progress.
+pub struct Throttle {
+    calls: Counter,
+    sessions: Counter,
+    max_connections: Option<usize>,
+    session_rate: Option<RateLimit>,
+    operations: RefCell<HashMap<String, TokenBucket>>,
+}
+
+impl Throttle {
+    pub fn new( throttling: Throttling ) -> Self {
+        Throttle {
+            calls: Counter::new( throttling.max_concurrent_calls ),
+            sessions: Counter::new( throttling.max_concurrent_sessions ),
+            max_connections: throttling.max_connections,
+            session_rate: throttling.session_rate,
+            operations: RefCell::new( throttling.operation_rates.into_iter()
+                    .map( |( name, limit )| ( name, TokenBucket::new( limit ) ) )
+                    .collect() ),
+        }
+    }
+
+    /// Reserves a place for a new session of a remote client.
+    pub fn open_session( &self ) -> Result<Permit, ServiceError> {
+        self.sessions.acquire( "sessions" )
+    }
+
+    /// Creates the limits of a session. The session keeps the permit
+    /// until it is dropped.
+    pub fn session( throttle: &Rc<Throttle>, permit: Option<Permit> ) -> SessionThrottle {
+        SessionThrottle {
+            throttle: throttle.clone(),
+            rate: throttle.session_rate.map( |limit| RefCell::new( TokenBucket::new( limit ) ) ),
+            _permit: permit,
+        }
+    }
+
+    /// Creates the connection count of an endpoint.
+    pub fn connections( &self ) -> ConnectionLimit {
+        ConnectionLimit( Counter::new( self.max_connections ) )
+    }
+}
+
+/// The limits of a single session.
+pub struct SessionThrottle {
+    throttle: Rc<Throttle>,
+    rate: Option<RefCell<TokenBucket>>,
+    _permit: Option<Permit>,
+}
+
+impl SessionThrottle {
+
+    /// Admits a call to the operation. The call counts as in progress until
+    /// the permit is dropped.
+    pub fn begin_call( &self, operation: &str ) -> Result<Permit, ServiceError> {
+        let now = Instant::now();
+        if let Some( ref rate ) = self.rate {
+            if !rate.borrow_mut().take( now ) {
+                return Err( ServiceError::throttled( "The session is over its rate limit" ) );
+            }
+        }
+        if let Some( bucket ) = self.throttle.operations.borrow_mut().get_mut( operation ) {
+            if !bucket.take( now ) {
+                return Err( ServiceError::throttled(
+                        format!( "'{}' is over its rate limit", operation ) ) );
+            }
+        }
+        self.throttle.calls.acquire( "calls in progress" )
+    }
+}
+
+/// Connection count of a single endpoint.
+///
+/// The endpoints get theirs with `HostRuntime::connection_limit` when they
+/// start and hold a permit for each open connection.
+#[derive(Clone)]
+pub struct ConnectionLimit( Counter );
+
+impl ConnectionLimit {
+
+    /// A limit that admits all connections.
+    pub fn unlimited() -> Self {
+        ConnectionLimit( Counter::new( None ) )
+    }
+
+    /// Admits a new connection.
+    pub fn acquire( &self ) -> Result<Permit, ServiceError> {
+        self.0.acquire( "connections" )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    pub fn token_buckets() {
+        let mut bucket = TokenBucket::new( RateLimit::per_second( 10.0 ).burst( 2 ) );
+        let start = bucket.updated;
+        assert!( bucket.take( start ) );
+        assert!( bucket.take( start ) );
+        assert!( !bucket.take( start ) );
+
+        // A token is added every 100 ms.
+        assert!( !bucket.take( start + Duration::from_millis( 50 ) ) );
+        assert!( bucket.take( start + Duration::from_millis( 110 ) ) );
+        assert!( bucket.take( start + Duration::from_secs( 10 ) ) );
+        assert!( bucket.take( start + Duration::from_secs( 10 ) ) );
+        assert!( !bucket.take( start + Duration::from_secs( 10 ) ) );
+    }
+
+    #[test]
+    pub fn limits() {
+        let throttle = Rc::new( Throttle::new( Throttling::new()
+                .max_concurrent_calls( 1 )
+                .max_concurrent_sessions( 1 )
+                .max_connections( 1 )
+                .operation_rate( "add", RateLimit::per_second( 1.0 ) ) ) );
+
+        let session = throttle.open_session().unwrap();
+        assert!( throttle.open_session().is_err() );
+        drop( session );
+        let session = Throttle::session( &throttle, Some( throttle.open_session().unwrap() ) );
+
+        let call = session.begin_call( "sub" ).unwrap();
+        assert_eq!( session.begin_call( "sub" ).unwrap_err().kind, ::ErrorKind::Throttled );
+        drop( call );
+        drop( session.begin_call( "add" ).unwrap() );
+        assert!( session.begin_call( "add" ).is_err() );
+        assert!( session.begin_call( "sub" ).is_ok() );
+
+        let first = throttle.connections();
+        let second = throttle.connections();
+        let connection = first.acquire().unwrap();
+        assert!( first.acquire().is_err() );
+        assert!( second.acquire().is_ok() );
+        drop( connection );
+        assert!( first.acquire().is_ok() );
+    }
+}
@@ -0,0 +1,319 @@
+//! Tracing spans of the calls.
+//!
+//! Each call creates a `tracing` span on the client when the proxy forwards
+//! it and another on the host when the host invokes it. The spans are
+//! tagged with the contract, the operation and, on the host, the session
+//! ID. The subscriber of the `tracing` crate decides where they end up.
+//!
+//! The spans of a call chain are tied together by a `TraceContext` that the
+//! forwarders pass to the hosts in their request envelopes. The context is
+//! current only at these points:
+//!
+//! - The endpoints invoke the calls within `with_context` with the context
+//!   they received. It is current only until `invoke` returns, which is
+//!   enough for the host span to pick it up as its parent.
+//! - `serve` creates the host span and makes its context current while the
+//!   operation is invoked and again each time the future of the call is
+//!   polled, so the calls the operation makes, including the callbacks to
+//!   its client, continue the same trace even once the call has gone
+//!   asynchronous.
+//! - `client_call` makes the context of the client span current for the
+//!   whole call as the proxies block until the call completes.
+//!
+//! Futures the operations spawn on their own don't carry the context. The
+//! IDs follow the W3C Trace Context format so the HTTP based transports
+//! pass the context in the `traceparent` header.
+//!
+//! Batches don't pass the context along. Streaming calls are traced on the
+//! client only until the proxy returns the stream.
+
+use std::any::Any;
+use std::cell::RefCell;
+
+use futures::prelude::*;
+use serde::{Serializer, Deserializer};
+use tracing::{Level, Span};
+use tracing::field::Empty;
+
+use super::{ServiceContract, ServiceError, InvokeTarget};
+
+/// Header carrying the trace context on the HTTP based transports.
+pub const TRACEPARENT_HEADER : &str = "traceparent";
+
+/// Identifies the span of a call within a trace.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceContext {
+
+    /// Identifies the whole call chain as 32 hex digits.
+    pub trace_id: String,
+
+    /// Identifies the span of a single call as 16 hex digits.
+    pub span_id: String,
+}
+
+impl TraceContext {
+
+    /// Starts a new trace.
+    pub fn root() -> Self {
+        TraceContext {
+            trace_id: format!( "{:016x}{:016x}", ::rand::random::<u64>(), ::rand::random::<u64>() ),
+            span_id: new_span_id(),
+        }
+    }
+
+    /// Creates the context of a span within the same trace.
+    pub fn child( &self ) -> Self {
+        TraceContext {
+            trace_id: self.trace_id.clone(),
+            span_id: new_span_id(),
+        }
+    }
+
+    /// Formats the context as a `traceparent` header value.
+    pub fn to_traceparent( &self ) -> String {
+        format!( "00-{}-{}-01", self.trace_id, self.span_id )
+    }
+
+    /// Parses a `traceparent` header value. The flags are ignored.
+    pub fn from_traceparent( value: &str ) -> Option<Self> {
+        let parts : Vec<&str> = value.trim().split( '-' ).collect();
+        if parts.len() < 4 || parts[ 1 ].len() != 32 || parts[ 2 ].len() != 16 {
+            return None;
+        }
+        if !parts[ 1..3 ].iter().all( |part| part.chars().all( |c| c.is_digit( 16 ) ) ) {
+            return None;
+        }
+        Some( TraceContext {
+            trace_id: parts[ 1 ].to_lowercase(),
+            span_id: parts[ 2 ].to_lowercase(),
+        } )
+    }
+}
+
+fn new_span_id() -> String {
+    format!( "{:016x}", ::rand::random::<u64>() )
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Option<TraceContext>> = RefCell::new( None );
+}
+
+/// Restores the previous context once the call is done.
+struct ContextGuard( Option<TraceContext> );
+
+impl Drop for ContextGuard {
+    fn drop( &mut self ) {
+        let previous = self.0.take();
+        CONTEXT.with( |cell| *cell.borrow_mut() = previous );
+    }
+}
+
+/// Runs `f` with the context as the current one.
+///
+/// The context is restored once `f` returns so futures `f` returns don't
+/// see it when they are polled. The endpoints invoke the calls this way
+/// with the context they received with the call so the host span, which
+/// `serve` creates during the invocation, continues the trace of the
+/// client. `serve` carries the context of the span over to the future.
+pub fn with_context<F, R>( context: Option<TraceContext>, f: F ) -> R
+    where F: FnOnce() -> R
+{
+    let previous = CONTEXT.with( |cell| cell.replace( context ) );
+    let _guard = ContextGuard( previous );
+    f()
+}
+
+/// Gets the context of the call being made or served, if any. The
+/// forwarders pass it to the host with the call.
+pub fn current() -> Option<TraceContext> {
+    CONTEXT.with( |cell| cell.borrow().clone() )
+}
+
+/// Creates the span of a call as a child of the current context.
+fn call_span( kind: &str, contract: &str, operation: &str ) -> ( Span, TraceContext ) {
+    let parent = current();
+    let context = match parent {
+        Some( ref parent ) => parent.child(),
+        None => TraceContext::root(),
+    };
+    let span = span!( Level::INFO, "serco.call",
+            kind = kind,
+            contract = contract,
+            operation = operation,
+            session = Empty,
+            trace_id = context.trace_id.as_str(),
+            span_id = context.span_id.as_str(),
+            parent_id = Empty );
+    if let Some( ref parent ) = parent {
+        span.record( "parent_id", &parent.span_id.as_str() );
+    }
+    ( span, context )
+}
+
+/// Makes a call from a proxy within a client span.
+///
+/// The proxies block until the call completes so the span and its context
+/// cover the whole call. The streaming calls are covered only until the
+/// stream is returned.
+pub fn client_call<F, R>( contract: &str, operation: &str, call: F ) -> R
+    where F: FnOnce() -> R
+{
+    let ( span, context ) = call_span( "client", contract, operation );
+    let _entered = span.enter();
+    with_context( Some( context ), call )
+}
+
+/// Invokes a call on the host within a host span.
+///
+/// The span continues the current context, which the endpoint sets to the
+/// one it received with the call. The span and its context stay current
+/// whenever the future of the call is polled.
+pub fn serve<T, F>(
+    contract: &str,
+    operation: &str,
+    session: Option<&str>,
+    call: F,
+) -> Box<Future<Item=T, Error=ServiceError>>
+    where T: 'static,
+          F: FnOnce() -> Box<Future<Item=T, Error=ServiceError>>,
+{
+    let ( span, context ) = call_span( "host", contract, operation );
+    if let Some( session ) = session {
+        span.record( "session", &session );
+    }
+    let inner = {
+        let _entered = span.enter();
+        with_context( Some( context.clone() ), call )
+    };
+    Box::new( Traced { inner: inner, span: span, context: context } )
+}
+
+/// Future that keeps the span and the context of a call current while it
+/// is polled.
+struct Traced<F> {
+    inner: F,
+    span: Span,
+    context: TraceContext,
+}
+
+impl<F: Future> Future for Traced<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll( &mut self ) -> Poll<F::Item, F::Error> {
+        let _entered = self.span.enter();
+        let inner = &mut self.inner;
+        with_context( Some( self.context.clone() ), || inner.poll() )
+    }
+}
+
+/// Invoke target that serves the calls of another target within host
+/// spans.
+///
+/// The hosts trace the calls of their sessions on their own. The clients
+/// wrap their callback targets in this so the callbacks get their spans.
+pub struct TracedTarget<T>( pub T );
+
+impl<C, T> InvokeTarget<C> for TracedTarget<T>
+    where C: ServiceContract + ?Sized,
+          T: InvokeTarget<C>,
+{
+    fn invoke<'de, D, S>(
+        &self,
+        name: &str,
+        params : D,
+        output : S
+    ) -> Box<Future<Item=S, Error=ServiceError>>
+        where
+            D: Deserializer<'de>,
+            S: 'static,
+            for <'a> &'a mut S: Serializer
+    {
+        serve( C::contract_name(), name, None, || self.0.invoke( name, params, output ) )
+    }
+
+    fn invoke_direct(
+        &self,
+        name: &str,
+        params : Box<Any>,
+    ) -> Box<Future<Item=Box<Any>, Error=ServiceError>>
+    {
+        serve( C::contract_name(), name, None, || self.0.invoke_direct( name, params ) )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future;
+
+    #[test]
+    pub fn traceparent() {
+        let context = TraceContext::root();
+        assert_eq!( context.trace_id.len(), 32 );
+        assert_eq!( context.span_id.len(), 16 );
+        assert_eq!( TraceContext::from_traceparent( &context.to_traceparent() ),
+                    Some( context.clone() ) );
+
+        let parsed = TraceContext::from_traceparent(
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01" ).unwrap();
+        assert_eq!( parsed.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736" );
+        assert_eq!( parsed.span_id, "00f067aa0ba902b7" );
+
+        assert_eq!( TraceContext::from_traceparent( "00-123-456-01" ), None );
+        assert_eq!( TraceContext::from_traceparent(
+                "00-4bf92f3577b34da6a3ce929d0e0e47zz-00f067aa0ba902b7-01" ), None );
+    }
+
+    #[test]
+    pub fn propagation() {
+        assert_eq!( current(), None );
+
+        // The client continues the trace of the caller in a span of its own.
+        let root = TraceContext::root();
+        let client = with_context( Some( root.clone() ), || {
+            client_call( "Contract", "operation", current ) } ).unwrap();
+        assert_eq!( client.trace_id, root.trace_id );
+        assert_ne!( client.span_id, root.span_id );
+        assert_eq!( current(), None );
+
+        // The host continues the trace of the client both while invoking the
+        // call and while polling its future.
+        let ( invoked, polled ) = with_context( Some( client.clone() ), || {
+            let invoked = RefCell::new( None );
+            let result = serve( "Contract", "operation", Some( "session" ), || {
+                *invoked.borrow_mut() = current();
+                Box::new( future::lazy( || Ok( current() ) ) )
+                        as Box<Future<Item=Option<TraceContext>, Error=ServiceError>>
+            } );
+            ( invoked.into_inner().unwrap(), result )
+        } );
+        let polled = polled.wait().unwrap().unwrap();
+        assert_eq!( invoked, polled );
+        assert_eq!( polled.trace_id, root.trace_id );
+        assert_ne!( polled.span_id, client.span_id );
+        assert_eq!( current(), None );
+    }
+
+    #[test]
+    pub fn later_polls() {
+        // The call completes only after its future has been polled once.
+        let ( tx, rx ) = ::futures::sync::oneshot::channel::<()>();
+        let client = TraceContext::root();
+        let result = with_context( Some( client.clone() ), || {
+            serve( "Contract", "operation", None, || {
+                Box::new( rx.map_err( ServiceError::transport ).map( |_| current() ) )
+                        as Box<Future<Item=Option<TraceContext>, Error=ServiceError>>
+            } )
+        } );
+        ::std::thread::spawn( move || {
+            ::std::thread::sleep( ::std::time::Duration::from_millis( 10 ) );
+            tx.send( () ).unwrap();
+        } );
+
+        let polled = result.wait().unwrap().unwrap();
+        assert_eq!( polled.trace_id, client.trace_id );
+        assert_ne!( polled.span_id, client.span_id );
+        assert_eq!( current(), None );
+    }
+}
@@ -3,11 +3,13 @@
 
 extern crate serco_common;
 extern crate proc_macro;
+#[macro_use] extern crate syn;
 
 #[macro_use] extern crate quote;
 
 use std::iter::FromIterator;
-use proc_macro::TokenStream;
+use proc_macro::{TokenStream, TokenTree, Group, Delimiter};
+use serco_common::{Authorization, ValueKind, ServiceImplModel};
 
 #[proc_macro_attribute]
 pub fn service(
@@ -15,8 +17,15 @@ pub fn service(
     input: TokenStream,
 ) -> TokenStream
 {
+    // On the contract implementations the attribute collects the
+    // #[authorize] attributes of the methods.
+    match ServiceImplModel::try_from( input.clone().into() ) {
+        Err( serco_common::ServiceError::BadItem ) => {},
+        model => return service_impl( model.unwrap() ),
+    }
+
     let model = serco_common::ServiceModel
-                    ::try_from( attr.into(), input.clone().into() ).unwrap();
+                    ::try_from( attribute_args( attr ).into(), input.clone().into() ).unwrap();
     let struct_ident = model.name;
     let mod_ident = model.mod_ident;
 
@@ -48,11 +57,23 @@ pub fn service(
                 {
                     ( self as &#service ).invoke( name, params, output )
                 }
+
+                fn invoke_direct(
+                    &self,
+                    name: &str,
+                    params: Box<::std::any::Any>,
+                ) -> Box<Future<Item=Box<::std::any::Any>, Error=serco::ServiceError>>
+                {
+                    ( self as &#service ).invoke_direct( name, params )
+                }
             }
         ) );
     }
 
     let output = quote!( #[allow(non_snake_case)] mod #mod_ident {
+
+        use super::*;
+
         extern crate serco;
 
         extern crate futures;
@@ -78,70 +99,269 @@ pub fn service_contract(
 ) -> TokenStream
 {
     let model = serco_common::ServiceContractModel
-                    ::try_from( attr.into(), input.clone().into() ).unwrap();
-
-    let ( op_arms, proxy_fns ) : ( Vec<_>, Vec<_> ) = model.operations
-                                   .into_iter()
-                                   .map( |o| {
+                    ::try_from( attribute_args( attr ).into(), input.clone().into() ).unwrap();
+
+    let mut op_arms = vec![];
+    let mut direct_arms = vec![];
+    let mut params_structs = vec![];
+    let mut proxy_fns = vec![];
+    let mut batch_fns = vec![];
+    let mut op_descs = vec![];
+    let mut schema_ops = vec![];
+    let contract_name = model.name.to_string();
+    model.operations.into_iter().for_each( |o| {
+            let streaming = o.is_streaming();
+            let one_way = o.is_one_way();
+            let idempotent = o.idempotent;
             let name = o.name;
             let output = o.output;
+            let output_kind = o.output_kind;
             let name_str = name.to_string();
+            let output_name = serco_common::type_name( &output );
+            let params_ident = syn::Ident::from( format!( "Params_{}", name ) );
+            let doc = option_tokens( o.doc );
+
+            // The policies are checked against the principal of the session
+            // before the parameters are even deserialized.
+            // The ones of the implementation are checked after the ones of
+            // the contract.
+            let mut authorize : Vec<_> = o.authorize.iter().map( |a| {
+                let policy = policy_tokens( a, quote!( serco ) );
+                quote!(
+                    if let Err( e ) = serco::auth::authorize( #name_str, #policy ) {
+                        return Box::new( futures::future::err( e ) );
+                    }
+                )
+            } ).collect();
+            authorize.push( quote!(
+                if let Err( e ) = self.serco_authorize( #name_str ) {
+                    return Box::new( futures::future::err( e ) );
+                }
+            ) );
+            let authorize = &authorize;
+            let output_stream = match output_kind {
+                ValueKind::Single => quote!( None ),
+                ValueKind::Stream( ref item ) => {
+                    let item = serco_common::type_name( item );
+                    quote!( Some( #item ) )
+                },
+            };
 
             // Generate argument specific tokens.
+            //
+            // Stream arguments are not part of the Params struct. Instead
+            // they are passed through the serco::stream functions.
             let mut args = vec![];
             let mut arg_defs = vec![];
+            let mut param_defs = vec![];
             let mut params = vec![];
+            let mut streams = vec![];
+            let mut arg_descs = vec![];
+            let mut schema_args = vec![];
             o.args.into_iter().for_each( |a| {
                         let name = a.name;
                         let ty = a.ty;
 
-                        args.push( quote!( #name ) );
+                        let arg_name = name.to_string();
+                        let type_name = serco_common::type_name( &ty );
+                        let stream = match a.kind {
+                            ValueKind::Single => quote!( None ),
+                            ValueKind::Stream( ref item ) => {
+                                let item = serco_common::type_name( item );
+                                quote!( Some( #item ) )
+                            },
+                        };
+                        arg_descs.push( quote!(
+                            serco::description::ArgumentDescription {
+                                name: #arg_name,
+                                type_name: #type_name,
+                                stream: #stream,
+                            } ) );
+
                         arg_defs.push( quote!( #name : #ty ) );
-                        params.push( quote!( params.#name ) );
+                        match a.kind {
+                            ValueKind::Single => {
+                                schema_args.push( quote!(
+                                    .argument::< #ty >( #arg_name ) ) );
+                                args.push( quote!( #name ) );
+                                param_defs.push( quote!( #name : #ty ) );
+                                params.push( quote!( params.#name ) );
+                            },
+                            ValueKind::Stream( item ) => {
+                                let index = streams.len();
+                                params.push( quote!(
+                                    serco::stream::take_incoming::< #item >( #index ) ) );
+                                streams.push( quote!(
+                                    serco::stream::erase( #name ) ) );
+                            },
+                        }
                     } );
 
-            // Turn the sub tokens into references so the quote!()s don't take
-            // their ownership.
-            let arg_defs = &arg_defs;
-            let params = &params;
-            (
-                quote!( #name_str => {
-                    #[derive(Deserialize)]
-                    struct Params {
-                        #( #arg_defs ),*
-                    }
-
-                    #[allow(unused_variables)]
-                    let params = Params::deserialize(params).unwrap();
-                    let rval = self.#name( #( #params ),* );
-
+            let respond = match output_kind {
+                ValueKind::Single => quote!(
                     if let Err(e) = rval.serialize( &mut output ) {
                         return Box::new(
                             Err( serco::ServiceError::from(e) ).into_future()
                         );
                     }
+                ),
+                ValueKind::Stream( _ ) => quote!(
+                    serco::stream::set_outgoing( rval );
+                ),
+            };
+
+            let forward = match ( streaming, &output_kind ) {
+                ( false, _ ) => quote!(
+                    let call = serco::CallInfo {
+                        name: #name_str,
+                        idempotent: #idempotent,
+                        request_id: None,
+                    };
+                    let result = self.forwarder.forward_call( call, params );
+                    result.wait().unwrap()
+                ),
+                ( true, ValueKind::Single ) => quote!(
+                    let result = self.forwarder.forward_streaming::< #output, _ >(
+                            #name_str, params, vec![ #( #streams ),* ] );
+                    serco::stream::single( result ).wait().unwrap()
+                ),
+                ( true, ValueKind::Stream( item ) ) => quote!(
+                    self.forwarder.forward_streaming::< #item, _ >(
+                            #name_str, params, vec![ #( #streams ),* ] )
+                ),
+            };
 
-                    Ok( output ).into_future()
-                } ),
-                quote!( fn #name( &self, #( #arg_defs ),* ) -> #output {
+            // Turn the sub tokens into references so the quote!()s don't take
+            // their ownership.
+            let args = &args;
+            let arg_defs = &arg_defs;
+            let param_defs = &param_defs;
+            let params = &params;
 
-                    #[derive(Serialize)]
-                    struct Params {
-                        #( #arg_defs ),*
+            // The proxies and the invoke targets share the Params struct so
+            // the in-process forwarders can pass it as it is.
+            params_structs.push( quote!(
+                #[derive(Serialize, Deserialize)]
+                #[allow(non_camel_case_types)]
+                struct #params_ident {
+                    #( #param_defs ),*
+                }
+            ) );
+
+            // Streaming operations can't be batched as the batch results are
+            // single values.
+            if !streaming {
+                batch_fns.push( quote!(
+                    pub fn #name(
+                        &mut self, #( #arg_defs ),*
+                    ) -> serco::batch::BatchResult< #output > {
+                        let params = #params_ident { #( #args ),* };
+                        self.0.call( #name_str, params )
                     }
+                ) );
+            }
 
-                    let params = Params { #( #args ),* };
-                    let result = self.forwarder.forward( #name_str, params );
+            let schema_response = match output_kind {
+                ValueKind::Single => quote!( .response::< #output >() ),
+                ValueKind::Stream( ref item ) => quote!( .stream_response::< #item >() ),
+            };
+            schema_ops.push( quote!(
+                schema.operation( #name_str ) #( #schema_args )* #schema_response;
+            ) );
+
+            op_descs.push( quote!( serco::description::OperationDescription {
+                name: #name_str,
+                doc: #doc,
+                args: &[ #( #arg_descs ),* ],
+                output: #output_name,
+                output_stream: #output_stream,
+                one_way: #one_way,
+                idempotent: #idempotent,
+            } ) );
+
+            op_arms.push(
+                quote!( #name_str => {
+                    #( #authorize )*
+                    #[allow(unused_variables)]
+                    let params = match #params_ident::deserialize(params) {
+                        Ok( params ) => params,
+                        Err( e ) => return Box::new( futures::future::err(
+                                serco::ServiceError::new(
+                                    serco::ErrorKind::BadRequest,
+                                    format!( "{}", e ) ) ) ),
+                    };
+                    let rval = self.#name( #( #params ),* );
 
-                    result.wait().unwrap()
+                    #respond
+
+                    Box::new( Ok( output ).into_future() )
+                } ) );
+            // Streams can't be passed without the serco::stream functions so
+            // the streaming operations can't be invoked directly.
+            direct_arms.push( if streaming {
+                quote!( #name_str => Box::new( futures::future::err(
+                        serco::ServiceError::new(
+                            serco::ErrorKind::BadOperation,
+                            format!( "Streaming operation '{}' can't be invoked directly",
+                                     #name_str ) ) ) ) )
+            } else {
+                quote!( #name_str => {
+                    #( #authorize )*
+                    #[allow(unused_variables)]
+                    let params = match params.downcast::< #params_ident >() {
+                        Ok( params ) => *params,
+                        Err( _ ) => return Box::new( futures::future::err(
+                                serco::ServiceError::new(
+                                    serco::ErrorKind::BadRequest,
+                                    format!( "Invalid parameters for '{}'", #name_str ) ) ) ),
+                    };
+                    let rval = self.#name( #( #params ),* );
+                    Box::new( futures::future::ok( Box::new( rval ) as Box<Any> ) )
                 } )
-            )
-        } )
-        .unzip();
+            } );
+            // The calls are made within the client spans so the forwarders
+            // pass the trace context to the host.
+            proxy_fns.push(
+                quote!( fn #name( &self, #( #arg_defs ),* ) -> #output {
+                    let params = #params_ident { #( #args ),* };
+                    serco::trace::client_call( #contract_name, #name_str, || {
+                        #forward
+                    } )
+                } ) );
+        } );
 
     let service_name = model.name;
     let mod_ident = model.mod_ident;
     let callback = model.callback_interface;
+    let batch_ident = syn::Ident::from( format!( "{}Batch", service_name ) );
+    let id_ident = syn::Ident::from( format!( "_SERCO_CONTRACT_ID_FOR_{}", service_name ) );
+    let contract_doc = option_tokens( model.doc );
+    let callback_name = match serco_common::type_name( &callback ).as_ref() {
+        "()" => quote!( None ),
+        name => quote!( Some( #name ) ),
+    };
+    // The schema requires all argument and return types to implement
+    // JsonSchema so it is generated only on request.
+    let ( schema_fn, schema_impl ) = if model.schema {
+        (
+            quote!(
+                /// Gets the JSON Schema of the contract operations.
+                pub fn json_schema() -> serco::schema::Schema {
+                    let mut schema = serco::schema::ContractSchema::new( &DESCRIPTION );
+                    #( #schema_ops )*
+                    schema.build()
+                }
+            ),
+            quote!(
+                fn schema() -> Option<serco::schema::Schema> {
+                    Some( #service_name::json_schema() )
+                }
+            ),
+        )
+    } else {
+        ( quote!(), quote!() )
+    };
+
     let output = quote!( #[allow(non_snake_case)] mod #mod_ident {
 
         use super::*;
@@ -159,17 +379,43 @@ pub fn service_contract(
         use self::serco::{ServiceContract, ServiceProxy, Forwarder,
                 SingletonService, SessionService};
 
+        #[allow(unused_imports)] use std::any::Any;
         #[allow(unused_imports)] use std::cell::RefCell;
         #[allow(unused_imports)] use std::sync::Arc;
-        task_local!{
+        futures::task_local!{
             static CALLBACK: RefCell<Option<Arc<#service_name + Send + Sync>>> =
                     RefCell::new(None)
         }
 
+        static DESCRIPTION : serco::description::ContractDescription =
+            serco::description::ContractDescription {
+                name: stringify!( #service_name ),
+                doc: #contract_doc,
+                callback: #callback_name,
+                operations: &[ #( #op_descs ),* ],
+            };
+
         impl ServiceContract for #service_name
         {
             type CallbackContract = #callback;
 
+            fn contract_name() -> &'static str
+            {
+                stringify!( #service_name )
+            }
+
+            fn contract_id() -> &'static str
+            {
+                super::#id_ident
+            }
+
+            fn description() -> &'static serco::description::ContractDescription
+            {
+                &DESCRIPTION
+            }
+
+            #schema_impl
+
             fn set_task_callback<F: Forwarder>(
                 callback : Arc<ServiceProxy<Self, F>>
             ) {
@@ -182,6 +428,13 @@ pub fn service_contract(
                     cell.borrow().as_ref().map( |o| o.clone() ).unwrap()
                 } )
             }
+
+            fn from_proxy<F: Forwarder>(
+                proxy : Arc<ServiceProxy<Self, F>>
+            ) -> Arc<Self>
+            {
+                proxy
+            }
         }
 
         impl serco::InvokeTarget<#service_name> for #service_name {
@@ -198,13 +451,32 @@ pub fn service_contract(
                     for <'a> &'a mut S: Serializer
             {
                 match name {
-                    #( #op_arms ),*
+                    #( #op_arms, )*
+                    _ => Box::new( futures::future::err(
+                                serco::ServiceError::new(
+                                    serco::ErrorKind::BadOperation,
+                                    format!( "Unknown operation '{}'", name ) ) ) ),
+                }
+            }
+
+            fn invoke_direct(
+                &self,
+                name: &str,
+                params: Box<Any>,
+            ) -> Box<Future<Item=Box<Any>, Error=serco::ServiceError>>
+            {
+                match name {
+                    #( #direct_arms, )*
                     _ => Box::new( futures::future::err(
-                                serco::ServiceError::from( "Bad fn" ) ) ),
+                                serco::ServiceError::new(
+                                    serco::ErrorKind::BadOperation,
+                                    format!( "Unknown operation '{}'", name ) ) ) ),
                 }
             }
         }
 
+        #( #params_structs )*
+
         impl #service_name {
 
             pub fn singleton<T, I>(
@@ -222,6 +494,13 @@ pub fn service_contract(
                 serco::hosted::Session::new()
             }
 
+            /// Gets the description of the contract.
+            pub fn description() -> &'static serco::description::ContractDescription {
+                &DESCRIPTION
+            }
+
+            #schema_fn
+
             pub fn get_callback() -> Arc<#callback> {
                 <Self as ServiceContract>::CallbackContract::get_task_callback()
             }
@@ -230,9 +509,272 @@ pub fn service_contract(
         impl<F: Forwarder> #service_name for ServiceProxy< #service_name, F > {
             #( #proxy_fns )*
         }
+
+        /// Typed batch builder for the contract operations.
+        ///
+        /// The builder has no methods besides the operations so they can't
+        /// collide with the operation names. The batch is executed through
+        /// the `Batch` it records the calls into.
+        pub struct #batch_ident<'b, 'a: 'b, F: Forwarder + 'a>(
+            &'b mut serco::batch::Batch<'a, #service_name + 'static, F>
+        );
+
+        impl<'b, 'a: 'b, F: Forwarder> #batch_ident<'b, 'a, F> {
+            #( #batch_fns )*
+        }
+
+        impl<'b, 'a: 'b, F: Forwarder> serco::batch::BatchCalls<'b, 'a, F>
+            for #service_name
+        {
+            type Builder = #batch_ident<'b, 'a, F>;
+
+            fn builder(
+                batch: &'b mut serco::batch::Batch<'a, Self, F>
+            ) -> Self::Builder
+            {
+                #batch_ident( batch )
+            }
+        }
     } );
 
+    // The operation attributes are stripped from the trait since they aren't
+    // real attributes outside of the service_contract. The trait gets the
+    // check the #[service] implementations override for their #[authorize]
+    // attributes.
+    let mut input : syn::ItemTrait = syn::parse2( serco_common::strip_operation_attributes(
+                    input.into() ).unwrap() ).unwrap();
+    input.items.push( parse_quote!(
+        #[doc(hidden)]
+        fn serco_authorize(
+            &self,
+            _operation: &str
+        ) -> ::std::result::Result<(), ::serco::ServiceError>
+        {
+            Ok( () )
+        }
+    ) );
+
+    // The ID is resolved next to the trait as the module path within the
+    // generated module would include the module itself.
+    let input : TokenStream = quote!(
+        #input
+
+        #[doc(hidden)]
+        #[allow(non_upper_case_globals)]
+        const #id_ident : &str = concat!( module_path!(), "::", stringify!( #service_name ) );
+    ).into();
     let output_stream : TokenStream = output.into();
     TokenStream::from_iter(
         input.into_iter().chain( output_stream.into_iter() ) )
 }
+
+/// Overrides the `serco_authorize` check of the contract with the
+/// `#[authorize]` attributes of the implementation methods.
+fn service_impl( mut model: ServiceImplModel ) -> TokenStream
+{
+    let arms : Vec<_> = model.operations.iter().map( |&( ref name, ref authorize )| {
+        let name_str = name.to_string();
+        let checks = authorize.iter().map( |a| {
+            let policy = policy_tokens( a, quote!( ::serco ) );
+            quote!( ::serco::auth::authorize( #name_str, #policy )?; )
+        } );
+        quote!( #name_str => {
+            #( #checks )*
+            Ok( () )
+        } )
+    } ).collect();
+
+    model.item.items.push( parse_quote!(
+        fn serco_authorize(
+            &self,
+            operation: &str
+        ) -> ::std::result::Result<(), ::serco::ServiceError>
+        {
+            match operation {
+                #( #arms, )*
+                _ => Ok( () ),
+            }
+        }
+    ) );
+
+    let item = model.item;
+    quote!( #item ).into()
+}
+
+/// Turns an `#[authorize]` attribute into the policy the principal is
+/// checked against.
+fn policy_tokens( authorization: &Authorization, serco: quote::Tokens ) -> quote::Tokens
+{
+    match *authorization {
+        Authorization::Role( ref role ) => quote!(
+            |principal: &#serco::Principal| principal.has_role( #role ) ),
+        Authorization::Policy( ref path ) => quote!( #path ),
+    }
+}
+
+/// Wraps the attribute arguments in parentheses.
+///
+/// Newer compilers pass the arguments without the parentheses the parsers
+/// expect.
+fn attribute_args( attr: TokenStream ) -> TokenStream
+{
+    let mut tokens = attr.clone().into_iter();
+    match ( tokens.next(), tokens.next() ) {
+        ( None, _ ) => attr,
+        ( Some( TokenTree::Group( ref group ) ), None )
+            if group.delimiter() == Delimiter::Parenthesis => attr,
+        _ => TokenStream::from( TokenTree::Group(
+                Group::new( Delimiter::Parenthesis, attr ) ) ),
+    }
+}
+
+/// Turns an optional string into `Some( "..." )` or `None` tokens.
+fn option_tokens( value: Option<String> ) -> quote::Tokens
+{
+    match value {
+        Some( value ) => quote!( Some( #value ) ),
+        None => quote!( None ),
+    }
+}
+
+#[proc_macro_derive(JsonSchema, attributes(serde))]
+pub fn derive_json_schema(
+    input: TokenStream
+) -> TokenStream
+{
+    let input : syn::DeriveInput = syn::parse( input ).unwrap();
+    let name = input.ident;
+    let name_str = name.to_string();
+
+    // Generic types require their type parameters to have schemas too.
+    let mut generics = input.generics.clone();
+    let mut param_names = vec![];
+    for param in generics.params.iter_mut() {
+        if let syn::GenericParam::Type( ref mut ty ) = *param {
+            ty.bounds.push( parse_quote!( _serco::schema::JsonSchema ) );
+            let ident = ty.ident;
+            param_names.push( quote!( <#ident as _serco::schema::JsonSchema>::schema_name() ) );
+        }
+    }
+    let ( impl_generics, ty_generics, where_clause ) = generics.split_for_impl();
+
+    let schema = match input.data {
+        syn::Data::Struct( ref data ) => fields_schema( &data.fields ),
+        syn::Data::Enum( ref data ) => {
+            let mut unit_variants = vec![];
+            let mut data_variants = vec![];
+            for variant in &data.variants {
+                let serde = serco_common::serde_attributes( &variant.attrs );
+                if serde.skip {
+                    continue;
+                }
+                let tag = serde.rename.unwrap_or_else( || variant.ident.to_string() );
+                match variant.fields {
+                    syn::Fields::Unit => unit_variants.push( tag ),
+                    ref fields => {
+                        let schema = fields_schema( fields );
+                        data_variants.push( quote!(
+                            _serco::schema::tagged_schema( #tag, #schema ) ) );
+                    },
+                }
+            }
+
+            // Serde represents the unit variants as plain strings and the
+            // rest as objects keyed by the variant name.
+            if !unit_variants.is_empty() {
+                data_variants.insert( 0, quote!(
+                    _serco::schema::string_enum_schema(
+                        vec![ #( #unit_variants ),* ] ) ) );
+            }
+            quote!( _serco::schema::one_of_schema( vec![ #( #data_variants ),* ] ) )
+        },
+        syn::Data::Union( _ ) => panic!( "JsonSchema can't be derived for unions" ),
+    };
+
+    let schema = match serco_common::doc_comment( &input.attrs ) {
+        Some( doc ) => quote!( _serco::schema::describe( #schema, #doc ) ),
+        None => schema,
+    };
+
+    let schema_name = if param_names.is_empty() {
+        quote!( #name_str.to_string() )
+    } else {
+        quote!( {
+            let params : Vec<String> = vec![ #( #param_names ),* ];
+            format!( "{}_{}", #name_str, params.join( "_" ) )
+        } )
+    };
+
+    let const_ident = syn::Ident::from( format!( "_IMPL_JSON_SCHEMA_FOR_{}", name ) );
+    let output = quote!(
+        #[allow(non_upper_case_globals, unused_attributes, unused_qualifications)]
+        const #const_ident : () = {
+            extern crate serco as _serco;
+
+            impl #impl_generics _serco::schema::JsonSchema for #name #ty_generics #where_clause {
+
+                fn schema_name() -> String {
+                    #schema_name
+                }
+
+                fn json_schema(
+                    gen: &mut _serco::schema::SchemaGenerator
+                ) -> _serco::schema::Schema
+                {
+                    #schema
+                }
+            }
+        };
+    );
+
+    output.into()
+}
+
+/// Generates the schema of the fields of a struct or an enum variant.
+fn fields_schema( fields: &syn::Fields ) -> quote::Tokens
+{
+    match *fields {
+        syn::Fields::Named( ref named ) => {
+            let mut properties = vec![];
+            let mut required = vec![];
+            for field in &named.named {
+                let serde = serco_common::serde_attributes( &field.attrs );
+                if serde.skip {
+                    continue;
+                }
+
+                let ident = field.ident.expect( "Named fields have names" );
+                let name = serde.rename.unwrap_or_else( || ident.to_string() );
+                let ty = &field.ty;
+                let schema = match serco_common::doc_comment( &field.attrs ) {
+                    Some( doc ) => quote!( _serco::schema::describe(
+                            gen.subschema_for::< #ty >(), #doc ) ),
+                    None => quote!( gen.subschema_for::< #ty >() ),
+                };
+                properties.push( quote!( ( #name, #schema ) ) );
+
+                if !serde.default && !serco_common::is_option( ty ) {
+                    required.push( name );
+                }
+            }
+
+            quote!( _serco::schema::object_schema(
+                    vec![ #( #properties ),* ],
+                    vec![ #( #required ),* ] ) )
+        },
+
+        // Newtypes are represented by their only field.
+        syn::Fields::Unnamed( ref unnamed ) if unnamed.unnamed.len() == 1 => {
+            let ty = &unnamed.unnamed.iter().next().expect( "Length checked" ).ty;
+            quote!( gen.subschema_for::< #ty >() )
+        },
+        syn::Fields::Unnamed( ref unnamed ) => {
+            let items : Vec<_> = unnamed.unnamed.iter().map( |field| {
+                let ty = &field.ty;
+                quote!( gen.subschema_for::< #ty >() )
+            } ).collect();
+            quote!( _serco::schema::tuple_schema( vec![ #( #items ),* ] ) )
+        },
+        syn::Fields::Unit => quote!( gen.subschema_for::< () >() ),
+    }
+}
@@ -0,0 +1,500 @@
+//! Conversion between the protobuf wire format and JSON values.
+//!
+//! The endpoint invokes the operations with JSON values so the messages are
+//! transcoded using the protobuf model of the contract instead of generated
+//! message types.
+
+use serde_json::{self, Map, Value};
+
+use serco::{ServiceError, ErrorKind};
+
+use super::proto::{ProtoService, Message, MessageKind, Field, FieldType, Label};
+
+const VARINT : u8 = 0;
+const FIXED64 : u8 = 1;
+const LENGTH_DELIMITED : u8 = 2;
+const FIXED32 : u8 = 5;
+
+/// Encodes a JSON value as a message.
+pub fn encode(
+    service: &ProtoService,
+    message: &str,
+    value: &Value
+) -> Result<Vec<u8>, ServiceError>
+{
+    let mut out = vec![];
+    Codec { service: service }.encode_message( message, value, &mut out )?;
+    Ok( out )
+}
+
+/// Decodes a message into a JSON value.
+pub fn decode(
+    service: &ProtoService,
+    message: &str,
+    bytes: &[u8]
+) -> Result<Value, ServiceError>
+{
+    Codec { service: service }.decode_message( message, bytes )
+}
+
+fn bad_request<T: Into<String>>( message: T ) -> ServiceError {
+    ServiceError::new( ErrorKind::BadRequest, message )
+}
+
+/// A field value as read from the wire.
+#[derive(Debug, Clone, Copy)]
+enum WireValue<'a> {
+    Varint( u64 ),
+    Fixed64( u64 ),
+    Fixed32( u32 ),
+    Bytes( &'a [u8] ),
+}
+
+struct Codec<'a> {
+    service: &'a ProtoService,
+}
+
+impl<'a> Codec<'a> {
+
+    fn message( &self, name: &str ) -> Result<&'a Message, ServiceError> {
+        self.service.message( name ).ok_or_else( || ServiceError::new(
+                ErrorKind::Internal, format!( "Unknown message '{}'", name ) ) )
+    }
+
+    fn encode_message(
+        &self,
+        name: &str,
+        value: &Value,
+        out: &mut Vec<u8>
+    ) -> Result<(), ServiceError>
+    {
+        let message = self.message( name )?;
+        match message.kind {
+            MessageKind::Object => {
+                let object = value.as_object().ok_or_else(
+                        || bad_request( format!( "Expected an object for {}", name ) ) )?;
+                for f in &message.fields {
+                    if let Some( v ) = object.get( &f.json_name ) {
+                        self.encode_field( f, v, out )?;
+                    }
+                }
+            },
+            MessageKind::Tuple => {
+                let items = value.as_array().ok_or_else(
+                        || bad_request( format!( "Expected an array for {}", name ) ) )?;
+                for ( f, v ) in message.fields.iter().zip( items ) {
+                    self.encode_field( f, v, out )?;
+                }
+            },
+            MessageKind::Value => {
+                if let Some( f ) = message.fields.first() {
+                    self.encode_field( f, value, out )?;
+                }
+            },
+            MessageKind::Union => {
+                let ( tag, payload ) = match *value {
+                    Value::String( ref tag ) => ( tag, &Value::Null ),
+                    Value::Object( ref map ) if map.len() == 1 => {
+                        let ( tag, payload ) = map.iter().next().expect( "Length checked" );
+                        ( tag, payload )
+                    },
+                    _ => return Err( bad_request( format!( "Expected a variant of {}", name ) ) ),
+                };
+                let f = message.fields.iter().find( |f| &f.json_name == tag ).ok_or_else(
+                        || bad_request( format!( "Unknown variant '{}' of {}", tag, name ) ) )?;
+
+                // Unit variants are set with the empty message.
+                write_tag( f.number, wire_type( &f.ty ), out );
+                self.encode_raw( &f.ty, payload, out )?;
+            },
+        }
+        Ok( () )
+    }
+
+    fn encode_field(
+        &self,
+        f: &Field,
+        value: &Value,
+        out: &mut Vec<u8>
+    ) -> Result<(), ServiceError>
+    {
+        if value.is_null() {
+            return Ok( () );
+        }
+
+        match f.label {
+            Label::Single | Label::Optional => {
+                write_tag( f.number, wire_type( &f.ty ), out );
+                self.encode_raw( &f.ty, value, out )?;
+            },
+            Label::Repeated => {
+                let items = value.as_array().ok_or_else(
+                        || bad_request( format!( "Expected an array for '{}'", f.json_name ) ) )?;
+                if is_packable( &f.ty ) {
+                    let mut packed = vec![];
+                    for item in items {
+                        self.encode_raw( &f.ty, item, &mut packed )?;
+                    }
+                    write_tag( f.number, LENGTH_DELIMITED, out );
+                    write_varint( packed.len() as u64, out );
+                    out.extend( packed );
+                } else {
+                    for item in items {
+                        write_tag( f.number, wire_type( &f.ty ), out );
+                        self.encode_raw( &f.ty, item, out )?;
+                    }
+                }
+            },
+            Label::Map => {
+                let entries = value.as_object().ok_or_else(
+                        || bad_request( format!( "Expected an object for '{}'", f.json_name ) ) )?;
+                for ( key, v ) in entries {
+                    let mut entry = vec![];
+                    write_tag( 1, LENGTH_DELIMITED, &mut entry );
+                    self.encode_raw( &FieldType::String, &Value::from( key.as_str() ), &mut entry )?;
+                    if !v.is_null() {
+                        write_tag( 2, wire_type( &f.ty ), &mut entry );
+                        self.encode_raw( &f.ty, v, &mut entry )?;
+                    }
+                    write_tag( f.number, LENGTH_DELIMITED, out );
+                    write_varint( entry.len() as u64, out );
+                    out.extend( entry );
+                }
+            },
+        }
+        Ok( () )
+    }
+
+    /// Encodes a single value without the field tag.
+    fn encode_raw(
+        &self,
+        ty: &FieldType,
+        value: &Value,
+        out: &mut Vec<u8>
+    ) -> Result<(), ServiceError>
+    {
+        let mismatch = || bad_request( format!( "Expected {:?}, got {}", ty, value ) );
+        match *ty {
+            FieldType::Double => {
+                let v = value.as_f64().ok_or_else( mismatch )?;
+                write_fixed64( v.to_bits(), out );
+            },
+            FieldType::Float => {
+                let v = value.as_f64().ok_or_else( mismatch )?;
+                write_fixed32( ( v as f32 ).to_bits(), out );
+            },
+            FieldType::Int32 | FieldType::Int64 => {
+                // Negative values are sign extended to ten bytes.
+                let v = value.as_i64().ok_or_else( mismatch )?;
+                write_varint( v as u64, out );
+            },
+            FieldType::Uint32 | FieldType::Uint64 => {
+                let v = value.as_u64().ok_or_else( mismatch )?;
+                write_varint( v, out );
+            },
+            FieldType::Bool => {
+                let v = value.as_bool().ok_or_else( mismatch )?;
+                write_varint( v as u64, out );
+            },
+            FieldType::String => {
+                let v = value.as_str().ok_or_else( mismatch )?;
+                write_bytes( v.as_bytes(), out );
+            },
+            FieldType::Json => {
+                let v = serde_json::to_string( value ).map_err( ServiceError::from )?;
+                write_bytes( v.as_bytes(), out );
+            },
+            FieldType::Enum( ref name ) => {
+                let e = self.service.enumeration( name ).ok_or_else( || ServiceError::new(
+                        ErrorKind::Internal, format!( "Unknown enum '{}'", name ) ) )?;
+                let v = value.as_str().ok_or_else( mismatch )?;
+                let number = e.values.iter().position( |value| value == v ).ok_or_else(
+                        || bad_request( format!( "Unknown value '{}' of {}", v, name ) ) )?;
+                write_varint( number as u64, out );
+            },
+            FieldType::Message( ref name ) => {
+                let mut message = vec![];
+                self.encode_message( name, value, &mut message )?;
+                write_bytes( &message, out );
+            },
+            FieldType::Empty => write_varint( 0, out ),
+        }
+        Ok( () )
+    }
+
+    fn decode_message( &self, name: &str, bytes: &[u8] ) -> Result<Value, ServiceError>
+    {
+        let message = self.message( name )?;
+        let wire = read_fields( bytes )?;
+        let values = |f: &Field| wire.iter()
+                .filter( |&&( number, _ )| number == f.number )
+                .map( |&( _, value )| value )
+                .collect::<Vec<_>>();
+
+        Ok( match message.kind {
+            MessageKind::Object => {
+                let mut object = Map::new();
+                for f in &message.fields {
+                    object.insert( f.json_name.clone(), self.decode_field( f, &values( f ) )? );
+                }
+                Value::Object( object )
+            },
+            MessageKind::Tuple => Value::Array( message.fields.iter()
+                    .map( |f| self.decode_field( f, &values( f ) ) )
+                    .collect::<Result<_, _>>()? ),
+            MessageKind::Value => match message.fields.first() {
+                Some( f ) => self.decode_field( f, &values( f ) )?,
+                None => Value::Null,
+            },
+            MessageKind::Union => {
+                // The last variant on the wire wins as with all oneof fields.
+                let last = wire.iter().rev()
+                        .filter_map( |&( number, value )| message.fields.iter()
+                                .find( |f| f.number == number )
+                                .map( |f| ( f, value ) ) )
+                        .next();
+                match last {
+                    Some( ( f, _ ) ) if f.ty == FieldType::Empty =>
+                        Value::String( f.json_name.clone() ),
+                    Some( ( f, value ) ) => {
+                        let mut object = Map::new();
+                        object.insert( f.json_name.clone(), self.decode_raw( &f.ty, value )? );
+                        Value::Object( object )
+                    },
+                    None => return Err( bad_request(
+                            format!( "No variant of {} was set", name ) ) ),
+                }
+            },
+        } )
+    }
+
+    fn decode_field( &self, f: &Field, values: &[WireValue] ) -> Result<Value, ServiceError>
+    {
+        Ok( match f.label {
+            Label::Single => match values.last() {
+                Some( value ) => self.decode_raw( &f.ty, *value )?,
+                None => self.default_value( &f.ty )?,
+            },
+            Label::Optional => match values.last() {
+                Some( value ) => self.decode_raw( &f.ty, *value )?,
+                None => Value::Null,
+            },
+            Label::Repeated => {
+                let mut items = vec![];
+                for value in values {
+                    match *value {
+                        WireValue::Bytes( bytes ) if is_packable( &f.ty ) => {
+                            for item in read_packed( &f.ty, bytes )? {
+                                items.push( self.decode_raw( &f.ty, item )? );
+                            }
+                        },
+                        value => items.push( self.decode_raw( &f.ty, value )? ),
+                    }
+                }
+                Value::Array( items )
+            },
+            Label::Map => {
+                let mut entries = Map::new();
+                for value in values {
+                    let bytes = match *value {
+                        WireValue::Bytes( bytes ) => bytes,
+                        _ => return Err( bad_request( "Invalid map entry" ) ),
+                    };
+                    let entry = read_fields( bytes )?;
+                    let key = match entry.iter().rev().find( |e| e.0 == 1 ) {
+                        Some( &( _, key ) ) => self.decode_raw( &FieldType::String, key )?,
+                        None => Value::from( "" ),
+                    };
+                    let value = match entry.iter().rev().find( |e| e.0 == 2 ) {
+                        Some( &( _, value ) ) => self.decode_raw( &f.ty, value )?,
+                        None => self.default_value( &f.ty )?,
+                    };
+                    entries.insert( key.as_str().unwrap_or_default().to_string(), value );
+                }
+                Value::Object( entries )
+            },
+        } )
+    }
+
+    fn decode_raw( &self, ty: &FieldType, value: WireValue ) -> Result<Value, ServiceError>
+    {
+        let mismatch = || bad_request( format!( "Unexpected wire type for {:?}", ty ) );
+        Ok( match ( ty, value ) {
+            ( &FieldType::Double, WireValue::Fixed64( v ) ) => Value::from( f64::from_bits( v ) ),
+            ( &FieldType::Float, WireValue::Fixed32( v ) ) =>
+                Value::from( f32::from_bits( v ) as f64 ),
+            ( &FieldType::Int32, WireValue::Varint( v ) ) => Value::from( v as i64 as i32 ),
+            ( &FieldType::Int64, WireValue::Varint( v ) ) => Value::from( v as i64 ),
+            ( &FieldType::Uint32, WireValue::Varint( v ) ) => Value::from( v as u32 ),
+            ( &FieldType::Uint64, WireValue::Varint( v ) ) => Value::from( v ),
+            ( &FieldType::Bool, WireValue::Varint( v ) ) => Value::Bool( v != 0 ),
+            ( &FieldType::String, WireValue::Bytes( bytes ) ) => Value::from( utf8( bytes )? ),
+            ( &FieldType::Json, WireValue::Bytes( bytes ) ) =>
+                serde_json::from_str( utf8( bytes )? )
+                    .map_err( |e| bad_request( format!( "{}", e ) ) )?,
+            ( &FieldType::Enum( ref name ), WireValue::Varint( v ) ) => {
+                let e = self.service.enumeration( name ).ok_or_else( || ServiceError::new(
+                        ErrorKind::Internal, format!( "Unknown enum '{}'", name ) ) )?;
+                let value = e.values.get( v as usize ).ok_or_else(
+                        || bad_request( format!( "Unknown value {} of {}", v, name ) ) )?;
+                Value::from( value.as_str() )
+            },
+            ( &FieldType::Message( ref name ), WireValue::Bytes( bytes ) ) =>
+                self.decode_message( name, bytes )?,
+            ( &FieldType::Empty, WireValue::Bytes( _ ) ) => Value::Null,
+            _ => return Err( mismatch() ),
+        } )
+    }
+
+    /// The value of a field missing from the message.
+    fn default_value( &self, ty: &FieldType ) -> Result<Value, ServiceError>
+    {
+        Ok( match *ty {
+            FieldType::Double | FieldType::Float => Value::from( 0.0 ),
+            FieldType::Int32 | FieldType::Int64 |
+            FieldType::Uint32 | FieldType::Uint64 => Value::from( 0 ),
+            FieldType::Bool => Value::Bool( false ),
+            FieldType::String => Value::from( "" ),
+            FieldType::Enum( .. ) => self.decode_raw( ty, WireValue::Varint( 0 ) )?,
+            FieldType::Message( ref name ) => self.decode_message( name, &[] )?,
+            FieldType::Empty | FieldType::Json => Value::Null,
+        } )
+    }
+}
+
+fn wire_type( ty: &FieldType ) -> u8
+{
+    match *ty {
+        FieldType::Double => FIXED64,
+        FieldType::Float => FIXED32,
+        FieldType::Int32 | FieldType::Int64 | FieldType::Uint32 | FieldType::Uint64 |
+        FieldType::Bool | FieldType::Enum( .. ) => VARINT,
+        FieldType::String | FieldType::Json | FieldType::Message( .. ) |
+        FieldType::Empty => LENGTH_DELIMITED,
+    }
+}
+
+/// Checks whether repeated values of the type are packed.
+fn is_packable( ty: &FieldType ) -> bool
+{
+    wire_type( ty ) != LENGTH_DELIMITED
+}
+
+fn utf8( bytes: &[u8] ) -> Result<&str, ServiceError>
+{
+    ::std::str::from_utf8( bytes ).map_err( |e| bad_request( format!( "{}", e ) ) )
+}
+
+fn write_tag( number: u32, wire_type: u8, out: &mut Vec<u8> )
+{
+    write_varint( ( ( number as u64 ) << 3 ) | wire_type as u64, out );
+}
+
+fn write_varint( mut value: u64, out: &mut Vec<u8> )
+{
+    while value >= 0x80 {
+        out.push( ( value as u8 ) | 0x80 );
+        value >>= 7;
+    }
+    out.push( value as u8 );
+}
+
+fn write_fixed64( value: u64, out: &mut Vec<u8> )
+{
+    for i in 0..8 {
+        out.push( ( value >> ( i * 8 ) ) as u8 );
+    }
+}
+
+fn write_fixed32( value: u32, out: &mut Vec<u8> )
+{
+    for i in 0..4 {
+        out.push( ( value >> ( i * 8 ) ) as u8 );
+    }
+}
+
+fn write_bytes( bytes: &[u8], out: &mut Vec<u8> )
+{
+    write_varint( bytes.len() as u64, out );
+    out.extend_from_slice( bytes );
+}
+
+/// Reads from a message buffer.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+
+    fn is_empty( &self ) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn take( &mut self, len: usize ) -> Result<&'a [u8], ServiceError> {
+        if self.bytes.len() < len {
+            return Err( bad_request( "Truncated message" ) );
+        }
+        let ( taken, rest ) = self.bytes.split_at( len );
+        self.bytes = rest;
+        Ok( taken )
+    }
+
+    fn varint( &mut self ) -> Result<u64, ServiceError> {
+        let mut value = 0u64;
+        for shift in 0..10 {
+            let byte = self.take( 1 )?[ 0 ];
+            value |= ( ( byte & 0x7f ) as u64 ) << ( shift * 7 );
+            if byte & 0x80 == 0 {
+                return Ok( value );
+            }
+        }
+        Err( bad_request( "Invalid varint" ) )
+    }
+
+    fn fixed64( &mut self ) -> Result<u64, ServiceError> {
+        Ok( self.take( 8 )?.iter().rev().fold( 0, |acc, &b| ( acc << 8 ) | b as u64 ) )
+    }
+
+    fn fixed32( &mut self ) -> Result<u32, ServiceError> {
+        Ok( self.take( 4 )?.iter().rev().fold( 0, |acc, &b| ( acc << 8 ) | b as u32 ) )
+    }
+}
+
+/// Splits the message into its fields.
+fn read_fields( bytes: &[u8] ) -> Result<Vec<( u32, WireValue )>, ServiceError>
+{
+    let mut reader = Reader { bytes: bytes };
+    let mut fields = vec![];
+    while !reader.is_empty() {
+        let key = reader.varint()?;
+        let number = ( key >> 3 ) as u32;
+        let value = match ( key & 0x7 ) as u8 {
+            VARINT => WireValue::Varint( reader.varint()? ),
+            FIXED64 => WireValue::Fixed64( reader.fixed64()? ),
+            LENGTH_DELIMITED => {
+                let len = reader.varint()? as usize;
+                WireValue::Bytes( reader.take( len )? )
+            },
+            FIXED32 => WireValue::Fixed32( reader.fixed32()? ),
+            wire_type => return Err( bad_request(
+                    format!( "Unsupported wire type {}", wire_type ) ) ),
+        };
+        fields.push( ( number, value ) );
+    }
+    Ok( fields )
+}
+
+/// Splits a packed repeated field into its items.
+fn read_packed<'a>( ty: &FieldType, bytes: &'a [u8] ) -> Result<Vec<WireValue<'a>>, ServiceError>
+{
+    let mut reader = Reader { bytes: bytes };
+    let mut items = vec![];
+    while !reader.is_empty() {
+        items.push( match wire_type( ty ) {
+            FIXED64 => WireValue::Fixed64( reader.fixed64()? ),
+            FIXED32 => WireValue::Fixed32( reader.fixed32()? ),
+            _ => WireValue::Varint( reader.varint()? ),
+        } );
+    }
+    Ok( items )
+}
@@ -0,0 +1,577 @@
+//! gRPC endpoint for serco services.
+//!
+//! Serves the contract as a gRPC service over HTTP/2 so it can be called
+//! with the standard gRPC tooling. The service is described by the `.proto`
+//! file returned by `proto_file`, which is derived from the contract JSON
+//! Schema. Only contracts declared with `#[service_contract(schema)]` can be
+//! served. See the `proto` module for how the Rust types map to protobuf.
+//!
+//! Each HTTP/2 connection is a session. The unary operations are supported;
+//! streaming operations, duplex callbacks and message compression are not.
+//!
+//! The host authenticates the credentials of the `authorization` metadata of
+//! each call. The session of the connection is created for the credentials
+//! of its first call. The rejected calls fail with `UNAUTHENTICATED`.
+//!
+//! The calls with messages over the size limits of the host fail with
+//! `RESOURCE_EXHAUSTED`. The request bodies are read only up to the size
+//! limit. The depth limit doesn't apply as the protobuf messages nest only
+//! as deep as the contract types.
+//!
+//! The calls continue the trace of their `traceparent` metadata.
+
+#![cfg_attr(test, feature(proc_macro))]
+
+extern crate futures;
+use futures::prelude::*;
+
+extern crate bytes;
+extern crate h2;
+extern crate http;
+extern crate tokio;
+
+extern crate serco;
+use serco::{ServiceContract, ServiceError, ErrorKind, InvokeTarget, Credentials, MessageLimits};
+use serco::metrics::EndpointMetrics;
+extern crate serde;
+#[macro_use] extern crate serde_json;
+
+#[cfg(test)] #[macro_use] extern crate serde_derive;
+#[cfg(test)] extern crate serco_derive;
+#[cfg(test)] extern crate tokio_core;
+
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use bytes::Bytes;
+use h2::RecvStream;
+use h2::server::SendResponse;
+use http::{HeaderMap, Request, Response, StatusCode};
+use http::header::HeaderValue;
+
+pub mod codec;
+pub mod proto;
+
+use proto::{ProtoService, Method};
+
+/// Kind under which the gRPC endpoints are registered in service registries.
+pub const ENDPOINT_KIND : &str = "grpc";
+
+/// Package of the services unless specified otherwise.
+pub const DEFAULT_PACKAGE : &str = "serco";
+
+/// gRPC status codes.
+pub mod status {
+    pub const OK : u32 = 0;
+    pub const INVALID_ARGUMENT : u32 = 3;
+    pub const PERMISSION_DENIED : u32 = 7;
+    pub const RESOURCE_EXHAUSTED : u32 = 8;
+    pub const UNIMPLEMENTED : u32 = 12;
+    pub const INTERNAL : u32 = 13;
+    pub const UNAVAILABLE : u32 = 14;
+    pub const UNAUTHENTICATED : u32 = 16;
+}
+
+/// Generates the `.proto` file describing the contract.
+pub fn proto_file<S: ServiceContract + ?Sized>( package: &str ) -> Result<String, ServiceError>
+{
+    ProtoService::of::<S>( package ).map( |service| service.to_proto() )
+}
+
+/// Resolves the gRPC status code for an error.
+pub fn status_code( kind: ErrorKind ) -> u32 {
+    match kind {
+        ErrorKind::Internal => status::INTERNAL,
+        ErrorKind::Transport => status::UNAVAILABLE,
+        ErrorKind::BadOperation => status::UNIMPLEMENTED,
+        ErrorKind::BadRequest => status::INVALID_ARGUMENT,
+        ErrorKind::Unauthenticated => status::UNAUTHENTICATED,
+        ErrorKind::Unauthorized => status::PERMISSION_DENIED,
+        ErrorKind::Throttled | ErrorKind::MessageTooLarge => status::RESOURCE_EXHAUSTED,
+    }
+}
+
+pub struct GrpcEndpoint {
+    address: SocketAddr,
+    package: String,
+}
+
+impl GrpcEndpoint {
+    pub fn new( address: SocketAddr ) -> Self {
+        GrpcEndpoint {
+            address: address,
+            package: DEFAULT_PACKAGE.to_string(),
+        }
+    }
+
+    /// Specifies the protobuf package of the service.
+    pub fn package<T: Into<String>>( mut self, package: T ) -> Self {
+        self.package = package.into();
+        self
+    }
+}
+
+impl<TService,
+        THostImplementation,
+        TSessionFactory>
+    serco::ServiceEndpoint<TService,
+        TSessionFactory,
+        THostImplementation,
+    >
+    for GrpcEndpoint
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+          THostImplementation::ServiceInstance: 'static,
+{
+    fn run(
+        &self,
+        host: Rc<serco::HostRuntime<
+                TService,
+                TSessionFactory,
+                THostImplementation,
+        >>
+    ) -> Box<Future<Item=(), Error=serco::ServiceError>>
+    {
+        let service = match ProtoService::of::<TService>( &self.package ) {
+            Ok( service ) => Rc::new( service ),
+            Err( e ) => return Box::new( futures::future::err( e ) ),
+        };
+        let listener = match tokio::net::TcpListener::bind( &self.address ) {
+            Ok( listener ) => listener,
+            Err( e ) => return Box::new( futures::future::err(
+                    serco::ServiceError::transport( e ) ) ),
+        };
+
+        let limit = host.connection_limit();
+        let result = listener.incoming()
+            .map_err( serco::ServiceError::transport )
+            .map( move |socket| -> Box<Future<Item=(), Error=serco::ServiceError>> {
+
+                // The connections over the limit are closed right away.
+                let permit = match limit.acquire() {
+                    Ok( permit ) => permit,
+                    Err( _ ) => return Box::new( futures::future::ok( () ) ),
+                };
+                let host = host.clone();
+                let service = service.clone();
+                let session : Rc<RefCell<Option<String>>> = Rc::new( RefCell::new( None ) );
+                let closed = ( host.clone(), session.clone() );
+
+                // The calls are served concurrently with the connection so
+                // the connection keeps driving the responses.
+                Box::new( h2::server::handshake( socket )
+                    .and_then( move |connection| connection
+                        .map( move |( request, respond )| -> Box<Future<Item=(), Error=h2::Error>> {
+                            let credentials = request.headers().get( "authorization" )
+                                    .and_then( |value| value.to_str().ok() )
+                                    .and_then( Credentials::from_authorization )
+                                    .unwrap_or( Credentials::Anonymous );
+                            // The later requests of the connection must come
+                            // from the client that opened the session.
+                            let existing = session.borrow().clone();
+                            let target = match existing {
+                                Some( id ) => host.resume_session( &id, &credentials ),
+                                None => host.open_resumable_session( &credentials ).map( |( id, target )| {
+                                    *session.borrow_mut() = Some( id );
+                                    target
+                                } ),
+                            };
+                            match target {
+                                Ok( target ) => call::<TService, _>(
+                                        &service, target, request, respond,
+                                        *host.message_limits(),
+                                        host.endpoint_metrics( ENDPOINT_KIND ) ),
+                                Err( e ) => {
+                                    respond_error( respond, e );
+                                    Box::new( futures::future::ok( () ) )
+                                },
+                            }
+                        } )
+                        .buffer_unordered( usize::max_value() )
+                        .for_each( |_| Ok( () ) ) )
+                    // Failures of single connections don't concern the endpoint.
+                    .then( move |_| {
+                        let ( host, session ) = closed;
+                        if let Some( ref id ) = *session.borrow() {
+                            host.close_session( id );
+                        }
+                        drop( permit );
+                        Ok::<(), serco::ServiceError>( () )
+                    } ) )
+            } )
+            .buffer_unordered( usize::max_value() )
+            .for_each( |_| Ok( () ) );
+
+        Box::new( result )
+    }
+
+    fn address( &self ) -> Option<serco::registry::EndpointAddress> {
+        Some( serco::registry::EndpointAddress::new(
+                ENDPOINT_KIND, format!( "http://{}", self.address ) ) )
+    }
+}
+
+/// Serves a single gRPC call.
+fn call<C, T>(
+    service: &Rc<ProtoService>,
+    target: Rc<T>,
+    request: Request<RecvStream>,
+    respond: SendResponse<Bytes>,
+    limits: MessageLimits,
+    metrics: EndpointMetrics,
+) -> Box<Future<Item=(), Error=h2::Error>>
+    where C: ServiceContract + ?Sized + 'static,
+          T: InvokeTarget<C> + 'static,
+{
+    let is_grpc = request.headers().get( "content-type" )
+            .and_then( |value| value.to_str().ok() )
+            .map( |value| value.starts_with( "application/grpc" ) )
+            .unwrap_or( false );
+    if !is_grpc {
+        let response = Response::builder()
+                .status( StatusCode::UNSUPPORTED_MEDIA_TYPE )
+                .body( () )
+                .expect( "Static response" );
+        let mut respond = respond;
+        let _ = respond.send_response( response, true );
+        return Box::new( futures::future::ok( () ) );
+    }
+
+    let method : Method = match service.method_by_path( request.uri().path() ) {
+        Some( method ) => method.clone(),
+        None => {
+            respond_error( respond, ServiceError::new( ErrorKind::BadOperation,
+                    format!( "Unknown method '{}'", request.uri().path() ) ) );
+            return Box::new( futures::future::ok( () ) );
+        },
+    };
+
+    let trace = request.headers().get( serco::trace::TRACEPARENT_HEADER )
+            .and_then( |value| value.to_str().ok() )
+            .and_then( serco::TraceContext::from_traceparent );
+
+    let service = service.clone();
+    let mut body = request.into_body();
+    let mut release = body.release_capacity().clone();
+    Box::new( body
+        .map_err( ServiceError::transport )
+        .fold( vec![], move |mut data, chunk| {
+            let _ = release.release_capacity( chunk.len() );
+            limits.check_request_size( data.len() + chunk.len() )?;
+            data.extend_from_slice( &chunk );
+            Ok::<_, ServiceError>( data )
+        } )
+        .and_then( {
+            let service = service.clone();
+            let method = method.clone();
+            let metrics = metrics.clone();
+            move |data| {
+                metrics.received( data.len() );
+                let message = unframe( &data )?;
+                codec::decode( &service, &method.input, message )
+            }
+        } )
+        .and_then( {
+            let operation = method.operation.clone();
+            move |params| serco::trace::with_context( trace, || {
+                invoke_value::<C, _>( &*target, &operation, params )
+            } )
+        } )
+        .and_then( move |value| {
+            let message = codec::encode( &service, &method.output, &value )?;
+            limits.check_response( &message )?;
+            Ok( message )
+        } )
+        .then( move |result| {
+            match result {
+                Ok( message ) => {
+                    let message = frame( &message );
+                    metrics.sent( message.len() );
+                    respond_ok( respond, message )
+                },
+                Err( e ) => respond_error( respond, e ),
+            }
+            Ok::<(), h2::Error>( () )
+        } ) )
+}
+
+/// Invokes an operation and turns its result into a JSON value.
+fn invoke_value<C, T>(
+    target: &T,
+    name: &str,
+    params: serde_json::Value,
+) -> Box<Future<Item=serde_json::Value, Error=serco::ServiceError>>
+    where C: ServiceContract + ?Sized,
+          T: InvokeTarget<C> + ?Sized,
+{
+    let output = serde_json::Serializer::new( vec![] );
+    Box::new( target.invoke( name, params, output ).map( |ok| {
+        let bytes = ok.into_inner();
+        if bytes.is_empty() {
+            serde_json::Value::Null
+        } else {
+            serde_json::from_slice( &bytes ).unwrap()
+        }
+    } ) )
+}
+
+/// Prefixes the message with the gRPC message header.
+pub fn frame( message: &[u8] ) -> Bytes
+{
+    let len = message.len() as u32;
+    let mut framed = Vec::with_capacity( message.len() + 5 );
+    framed.push( 0 );
+    framed.extend_from_slice( &[
+        ( len >> 24 ) as u8, ( len >> 16 ) as u8, ( len >> 8 ) as u8, len as u8 ] );
+    framed.extend_from_slice( message );
+    Bytes::from( framed )
+}
+
+/// Extracts the single message of a unary call from the request body.
+pub fn unframe( data: &[u8] ) -> Result<&[u8], ServiceError>
+{
+    if data.len() < 5 {
+        return Err( ServiceError::new( ErrorKind::BadRequest, "Missing the request message" ) );
+    }
+    if data[ 0 ] != 0 {
+        return Err( ServiceError::new( ErrorKind::BadOperation,
+                                       "Compressed messages are not supported" ) );
+    }
+    let len = data[ 1..5 ].iter().fold( 0usize, |acc, &b| ( acc << 8 ) | b as usize );
+    if data.len() != len + 5 {
+        return Err( ServiceError::new( ErrorKind::BadRequest,
+                                       "Unary calls take a single request message" ) );
+    }
+    Ok( &data[ 5.. ] )
+}
+
+fn grpc_response() -> Response<()>
+{
+    Response::builder()
+        .status( StatusCode::OK )
+        .header( "content-type", "application/grpc+proto" )
+        .body( () )
+        .expect( "Static response" )
+}
+
+fn respond_ok( mut respond: SendResponse<Bytes>, message: Bytes )
+{
+    let mut trailers = HeaderMap::new();
+    trailers.insert( "grpc-status", HeaderValue::from( status::OK ) );
+
+    // Failures here mean the client is gone.
+    if let Ok( mut stream ) = respond.send_response( grpc_response(), false ) {
+        let _ = stream.send_data( message, false )
+            .and_then( |_| stream.send_trailers( trailers ) );
+    }
+}
+
+/// Responds with the trailers only response carrying the error.
+fn respond_error( mut respond: SendResponse<Bytes>, e: ServiceError )
+{
+    let mut response = grpc_response();
+    response.headers_mut().insert(
+            "grpc-status", HeaderValue::from( status_code( e.kind ) ) );
+    if let Ok( message ) = HeaderValue::from_str( &percent_encode( &e.message ) ) {
+        response.headers_mut().insert( "grpc-message", message );
+    }
+    let _ = respond.send_response( response, true );
+}
+
+/// Encodes the status message the way gRPC requires.
+fn percent_encode( message: &str ) -> String
+{
+    let mut encoded = String::with_capacity( message.len() );
+    for byte in message.bytes() {
+        if byte >= 0x20 && byte <= 0x7e && byte != b'%' {
+            encoded.push( byte as char );
+        } else {
+            encoded.push_str( &format!( "%{:02X}", byte ) );
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::thread;
+    use std::time::Duration;
+
+    use serco_derive::*;
+    use tokio_core::reactor::Core;
+
+    /// A point on a plane.
+    #[derive(Serialize, Deserialize, JsonSchema)]
+    pub struct Point {
+        x: i32,
+        y: i32,
+        label: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, JsonSchema)]
+    pub enum Shape {
+        Dot,
+        Circle( Point, f64 ),
+        Polygon { points: Vec<Point> },
+    }
+
+    #[service_contract(schema)]
+    pub trait Geometry {
+        fn translate( &self, point: Point, dx: i32, dy: i32 ) -> Point;
+        fn vertices( &self, shape: Shape ) -> Vec<Point>;
+        fn reset( &self );
+    }
+
+    #[service(Geometry)]
+    struct GeometryService;
+    impl Geometry for GeometryService {
+        fn translate( &self, point: Point, dx: i32, dy: i32 ) -> Point {
+            Point { x: point.x + dx, y: point.y + dy, label: point.label }
+        }
+
+        fn vertices( &self, shape: Shape ) -> Vec<Point> {
+            match shape {
+                Shape::Dot => vec![],
+                Shape::Circle( center, _ ) => vec![ center ],
+                Shape::Polygon { points } => points,
+            }
+        }
+
+        fn reset( &self ) {}
+    }
+
+    fn service() -> ProtoService {
+        ProtoService::of::<Geometry>( "geometry" ).unwrap()
+    }
+
+    #[test]
+    pub fn proto_definition() {
+        let proto = proto_file::<Geometry>( "geometry" ).unwrap();
+        assert!( proto.contains( "package geometry;" ) );
+        assert!( proto.contains(
+                "    rpc Translate (TranslateRequest) returns (TranslateResponse);" ) );
+        assert!( proto.contains(
+                "message Point {\n    optional string label = 1;\n    int32 x = 2;\n    int32 y = 3;\n}" ) );
+        assert!( proto.contains(
+                "message TranslateRequest {\n    Point point = 1;\n    int32 dx = 2;\n    int32 dy = 3;\n}" ) );
+        assert!( proto.contains( "message VerticesResponse {\n    repeated Point value = 1;\n}" ) );
+        assert!( proto.contains( "message ResetResponse {\n}" ) );
+        assert!( proto.contains( "    oneof value {\n        Empty dot = 1;" ) );
+    }
+
+    #[test]
+    pub fn round_trip() {
+        let service = service();
+        let values = vec![
+            ( "TranslateRequest", json!({
+                "point": { "x": -3, "y": 7, "label": "origin" },
+                "dx": 1,
+                "dy": -1,
+            }) ),
+            ( "VerticesRequest", json!({ "shape": "Dot" }) ),
+            ( "VerticesRequest", json!({ "shape": {
+                "Circle": [ { "x": 1, "y": 2, "label": null }, 0.5 ],
+            } }) ),
+            ( "VerticesRequest", json!({ "shape": {
+                "Polygon": { "points": [ { "x": 1, "y": 2, "label": null } ] },
+            } }) ),
+            ( "ResetResponse", json!( null ) ),
+        ];
+        for ( message, value ) in values {
+            let bytes = codec::encode( &service, message, &value ).unwrap();
+            assert_eq!( codec::decode( &service, message, &bytes ).unwrap(), value );
+        }
+    }
+
+    #[test]
+    pub fn wire_format() {
+        // Hand encoded { x: 150 } with x as the second field.
+        let point = codec::decode( &service(), "Point", &[ 0x10, 0x96, 0x01 ] ).unwrap();
+        assert_eq!( point, json!({ "x": 150, "y": 0, "label": null }) );
+    }
+
+    #[test]
+    pub fn loopback() {
+        let address : SocketAddr = "127.0.0.1:50551".parse().unwrap();
+        thread::spawn( move || {
+            let host = serco::ServiceHost::new( Geometry::singleton( GeometryService ) )
+                    .endpoint( GrpcEndpoint::new( address ).package( "geometry" ) )
+                    .run();
+            Core::new().unwrap().run( host ).ok();
+        } );
+        thread::sleep( Duration::from_millis( 100 ) );
+
+        let service = service();
+        let request = codec::encode( &service, "TranslateRequest", &json!({
+            "point": { "x": 1, "y": 2 },
+            "dx": 10,
+            "dy": 20,
+        }) ).unwrap();
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let calls = tokio::net::TcpStream::connect( &address )
+            .map_err( |e| format!( "{:?}", e ) )
+            .and_then( |socket| h2::client::handshake( socket )
+                    .map_err( |e| format!( "{:?}", e ) ) )
+            .and_then( move |( client, connection )| {
+                handle.spawn( connection.map_err( |_| () ) );
+                call( client.clone(), address, "/geometry.Geometry/Translate", request )
+                    .join( call( client, address, "/geometry.Geometry/Missing", vec![] ) )
+            } );
+
+        let ( ( status, data ), ( missing_status, _ ) ) = core.run( calls ).unwrap();
+        assert_eq!( status, Some( HeaderValue::from( status::OK ) ) );
+        assert_eq!( codec::decode( &service, "TranslateResponse", unframe( &data ).unwrap() ).unwrap(),
+                    json!({ "x": 11, "y": 22, "label": null }) );
+        assert_eq!( missing_status, Some( HeaderValue::from( status::UNIMPLEMENTED ) ) );
+    }
+
+    /// Makes a unary call and returns the status and the response body.
+    fn call(
+        client: h2::client::SendRequest<Bytes>,
+        address: SocketAddr,
+        path: &str,
+        message: Vec<u8>,
+    ) -> Box<Future<Item=( Option<HeaderValue>, Vec<u8> ), Error=String>>
+    {
+        let uri = format!( "http://{}{}", address, path );
+        Box::new( client.ready()
+            .and_then( move |mut client| {
+                let request = Request::post( uri.as_str() )
+                        .header( "content-type", "application/grpc" )
+                        .body( () )
+                        .unwrap();
+                let ( response, mut stream ) = client.send_request( request, false )?;
+                stream.send_data( frame( &message ), true )?;
+                Ok( response )
+            } )
+            .and_then( |response| response )
+            .and_then( |response| {
+                let headers_status = response.headers().get( "grpc-status" ).cloned();
+                let mut body = response.into_body();
+                let mut data = vec![];
+                futures::future::poll_fn( move || {
+                    while let Some( chunk ) = futures::try_ready!( body.poll() ) {
+                        data.extend_from_slice( &chunk );
+                    }
+
+                    // The data ends before the trailers. The trailers-only
+                    // responses end with the headers.
+                    let trailers = if body.is_end_stream() {
+                        None
+                    } else {
+                        futures::try_ready!( body.poll_trailers() )
+                    };
+                    let status = headers_status.clone().or_else( || trailers
+                            .and_then( |t| t.get( "grpc-status" ).cloned() ) );
+                    Ok( Async::Ready( ( status, ::std::mem::replace( &mut data, vec![] ) ) ) )
+                } )
+            } )
+            .map_err( |e: h2::Error| format!( "{:?}", e ) ) )
+    }
+}
@@ -0,0 +1,629 @@
+//! Protocol buffer model of the contracts.
+//!
+//! The model is derived from the contract JSON Schema so only contracts
+//! declared with `#[service_contract(schema)]` can be served over gRPC. Each
+//! operation becomes an rpc with an `<Operation>Request` message holding the
+//! arguments and an `<Operation>Response` message holding the result in its
+//! `value` field. The types are mapped as follows:
+//!
+//! - Structs become messages. The fields are numbered in the order of the
+//!   JSON Schema properties, which is the alphabetical order of the names.
+//! - Enums with only unit variants become protobuf enums. Other enums become
+//!   messages with a `oneof` field per variant.
+//! - `Option<T>` becomes an `optional` field, sequences `repeated` fields and
+//!   maps `map<string, T>` fields.
+//! - Tuples become messages with `item_<n>` fields.
+//! - Values without a protobuf equivalent, such as `serde_json::Value`, are
+//!   carried as JSON encoded strings.
+//!
+//! Streaming operations are not available over gRPC.
+
+use std::collections::{HashMap, HashSet};
+
+use serde_json::{Map, Value};
+
+use serco::{ServiceContract, ServiceError, ErrorKind};
+use serco::description::ContractDescription;
+use serco::schema::Schema;
+
+/// Type of a message field.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldType {
+    Double,
+    Float,
+    Int32,
+    Int64,
+    Uint32,
+    Uint64,
+    Bool,
+    String,
+    Enum( String ),
+    Message( String ),
+
+    /// The unit type, carried as the empty message.
+    Empty,
+
+    /// Any JSON value, carried as a JSON encoded string.
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Label {
+    Single,
+    Optional,
+    Repeated,
+
+    /// A `map<string, T>` field.
+    Map,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+
+    /// Name of the field in the JSON representation of the message.
+    pub json_name: String,
+    pub number: u32,
+    pub ty: FieldType,
+    pub label: Label,
+}
+
+/// How the JSON value of a message maps to its fields.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageKind {
+
+    /// The fields are the properties of a JSON object.
+    Object,
+
+    /// The fields are the items of a JSON array.
+    Tuple,
+
+    /// The fields are the variants of an enum, of which one is set.
+    Union,
+
+    /// The message wraps a single value in its `value` field.
+    Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub name: String,
+    pub kind: MessageKind,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Enum {
+    pub name: String,
+
+    /// The JSON names of the values in the order of their numbers.
+    pub values: Vec<String>,
+}
+
+impl Enum {
+
+    /// Resolves the protobuf name of a value.
+    pub fn value_name( &self, value: &str ) -> String {
+        format!( "{}_{}", upper_snake( &self.name ), upper_snake( value ) )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Method {
+    pub name: String,
+
+    /// Name of the contract operation.
+    pub operation: String,
+    pub doc: Option<String>,
+    pub input: String,
+    pub output: String,
+}
+
+/// The protobuf model of a contract.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtoService {
+    pub package: String,
+    pub name: String,
+    pub doc: Option<String>,
+    pub methods: Vec<Method>,
+    pub messages: Vec<Message>,
+    pub enums: Vec<Enum>,
+
+    /// The operations left out of the service.
+    pub skipped: Vec<String>,
+}
+
+impl ProtoService {
+
+    /// Builds the model of a contract.
+    pub fn of<S: ServiceContract + ?Sized>( package: &str ) -> Result<ProtoService, ServiceError>
+    {
+        let description = S::description();
+        match S::schema() {
+            Some( schema ) => ProtoService::from_schema( package, description, &schema ),
+            None => Err( ServiceError::new( ErrorKind::Internal, format!(
+                    "Contract '{}' has no schema. Declare it with \
+                    #[service_contract(schema)] to serve it over gRPC",
+                    description.name ) ) ),
+        }
+    }
+
+    /// Builds the model from the description and the JSON Schema of a
+    /// contract.
+    pub fn from_schema(
+        package: &str,
+        description: &ContractDescription,
+        schema: &Schema,
+    ) -> Result<ProtoService, ServiceError>
+    {
+        let empty = Map::new();
+        let definitions = match schema.get( "definitions" ) {
+            Some( &Value::Object( ref definitions ) ) => definitions,
+            _ => &empty,
+        };
+        let operations = schema.get( "operations" ).ok_or_else( || ServiceError::new(
+                ErrorKind::Internal, "The contract schema has no operations" ) )?;
+
+        let mut builder = Builder {
+            definitions: definitions,
+            messages: vec![],
+            enums: vec![],
+            resolved: HashMap::new(),
+            reserved: HashSet::new(),
+        };
+        let mut methods = vec![];
+        let mut skipped = vec![];
+
+        for op in description.operations {
+            let op_schema = match operations.get( op.name ) {
+                Some( op_schema ) if !op.is_streaming() => op_schema,
+                _ => {
+                    skipped.push( op.name.to_string() );
+                    continue;
+                }
+            };
+
+            let name = pascal_case( op.name );
+            let request = op_schema.get( "request" )
+                    .and_then( |r| r.get( "properties" ) )
+                    .and_then( |p| p.as_object() );
+            let fields = op.args.iter()
+                    .enumerate()
+                    .map( |( i, arg )| {
+                        let arg_schema = request.and_then( |r| r.get( arg.name ) )
+                                .cloned()
+                                .unwrap_or( Value::Bool( true ) );
+                        let hint = format!( "{}{}", name, pascal_case( arg.name ) );
+                        let ( ty, label ) = builder.resolve( &arg_schema, &hint );
+                        field( arg.name, i, ty, label )
+                    } )
+                    .collect();
+            let input = builder.add_message(
+                    &format!( "{}Request", name ), MessageKind::Object, fields );
+
+            let response = op_schema.get( "response" ).cloned().unwrap_or( Value::Bool( true ) );
+            let fields = if response.get( "type" ) == Some( &Value::from( "null" ) ) {
+                vec![]
+            } else {
+                let ( ty, label ) = builder.resolve( &response, &format!( "{}Result", name ) );
+                vec![ field( "value", 0, ty, label ) ]
+            };
+            let output = builder.add_message(
+                    &format!( "{}Response", name ), MessageKind::Value, fields );
+
+            methods.push( Method {
+                name: name,
+                operation: op.name.to_string(),
+                doc: op.doc.map( String::from ),
+                input: input,
+                output: output,
+            } );
+        }
+
+        Ok( ProtoService {
+            package: package.to_string(),
+            name: identifier( description.name ),
+            doc: description.doc.map( String::from ),
+            methods: methods,
+            messages: builder.messages,
+            enums: builder.enums,
+            skipped: skipped,
+        } )
+    }
+
+    pub fn message( &self, name: &str ) -> Option<&Message> {
+        self.messages.iter().find( |m| m.name == name )
+    }
+
+    pub fn enumeration( &self, name: &str ) -> Option<&Enum> {
+        self.enums.iter().find( |e| e.name == name )
+    }
+
+    /// Finds the method by its gRPC path, `/<package>.<service>/<method>`.
+    pub fn method_by_path( &self, path: &str ) -> Option<&Method> {
+        let prefix = if self.package.is_empty() {
+            format!( "/{}/", self.name )
+        } else {
+            format!( "/{}.{}/", self.package, self.name )
+        };
+        if !path.starts_with( &prefix ) {
+            return None;
+        }
+        let name = &path[ prefix.len().. ];
+        self.methods.iter().find( |m| m.name == name )
+    }
+
+    /// Formats the model as a `.proto` file.
+    pub fn to_proto( &self ) -> String
+    {
+        let mut out = String::new();
+        out.push_str( "// Generated by serco. Do not edit.\n\n" );
+        out.push_str( "syntax = \"proto3\";\n\n" );
+        if !self.package.is_empty() {
+            out.push_str( &format!( "package {};\n\n", self.package ) );
+        }
+
+        out.push_str( &comment( self.doc.as_ref(), "" ) );
+        out.push_str( &format!( "service {} {{\n", self.name ) );
+        for method in &self.methods {
+            out.push_str( &comment( method.doc.as_ref(), "    " ) );
+            out.push_str( &format!( "    rpc {} ({}) returns ({});\n",
+                    method.name, method.input, method.output ) );
+        }
+        for op in &self.skipped {
+            out.push_str( &format!(
+                    "    // '{}' streams and is not available over gRPC.\n", op ) );
+        }
+        out.push_str( "}\n" );
+
+        let uses_empty = self.messages.iter()
+                .flat_map( |m| m.fields.iter() )
+                .any( |f| f.ty == FieldType::Empty );
+        if uses_empty {
+            out.push_str( "\nmessage Empty {}\n" );
+        }
+
+        for message in &self.messages {
+            out.push_str( &format!( "\nmessage {} {{\n", message.name ) );
+            let indent = if message.kind == MessageKind::Union {
+                out.push_str( "    oneof value {\n" );
+                "        "
+            } else {
+                "    "
+            };
+            for f in &message.fields {
+                let ty = self.type_name( &f.ty );
+                let ty = match f.label {
+                    Label::Single => ty,
+                    Label::Optional => format!( "optional {}", ty ),
+                    Label::Repeated => format!( "repeated {}", ty ),
+                    Label::Map => format!( "map<string, {}>", ty ),
+                };
+                out.push_str( &format!( "{}{} {} = {};\n", indent, ty, f.name, f.number ) );
+            }
+            if message.kind == MessageKind::Union {
+                out.push_str( "    }\n" );
+            }
+            out.push_str( "}\n" );
+        }
+
+        for e in &self.enums {
+            out.push_str( &format!( "\nenum {} {{\n", e.name ) );
+            for ( i, value ) in e.values.iter().enumerate() {
+                out.push_str( &format!( "    {} = {};\n", e.value_name( value ), i ) );
+            }
+            out.push_str( "}\n" );
+        }
+
+        out
+    }
+
+    fn type_name( &self, ty: &FieldType ) -> String {
+        match *ty {
+            FieldType::Double => "double".to_string(),
+            FieldType::Float => "float".to_string(),
+            FieldType::Int32 => "int32".to_string(),
+            FieldType::Int64 => "int64".to_string(),
+            FieldType::Uint32 => "uint32".to_string(),
+            FieldType::Uint64 => "uint64".to_string(),
+            FieldType::Bool => "bool".to_string(),
+            FieldType::String | FieldType::Json => "string".to_string(),
+            FieldType::Empty => "Empty".to_string(),
+            FieldType::Enum( ref name ) | FieldType::Message( ref name ) => name.clone(),
+        }
+    }
+}
+
+/// Resolves the JSON Schema into protobuf types.
+struct Builder<'a> {
+    definitions: &'a Map<String, Value>,
+    messages: Vec<Message>,
+    enums: Vec<Enum>,
+
+    /// The types of the definitions resolved so far.
+    resolved: HashMap<String, ( FieldType, Label )>,
+
+    /// Message names of the definitions whose messages are being built.
+    reserved: HashSet<String>,
+}
+
+impl<'a> Builder<'a> {
+
+    /// Resolves the type of a schema.
+    ///
+    /// The hint names the messages and enums created for anonymous schemas.
+    fn resolve( &mut self, schema: &Value, hint: &str ) -> ( FieldType, Label )
+    {
+        let map = match *schema {
+            Value::Object( ref map ) => map,
+            _ => return ( FieldType::Json, Label::Single ),
+        };
+
+        if let Some( reference ) = map.get( "$ref" ).and_then( |r| r.as_str() ) {
+            return self.definition( reference.trim_left_matches( "#/definitions/" ) );
+        }
+
+        if let Some( options ) = map.get( "anyOf" ).and_then( |o| o.as_array() ) {
+            if options.len() == 2 && options[ 1 ] == json!({ "type": "null" }) {
+                return match self.resolve( &options[ 0 ], hint ) {
+                    ( ty, Label::Single ) => ( ty, Label::Optional ),
+                    other => other,
+                };
+            }
+            return ( FieldType::Json, Label::Single );
+        }
+
+        if let Some( variants ) = map.get( "oneOf" ).and_then( |o| o.as_array() ) {
+            return match self.union( variants, hint ) {
+                Some( name ) => ( FieldType::Message( name ), Label::Single ),
+                None => ( FieldType::Json, Label::Single ),
+            };
+        }
+
+        let ty = match map.get( "type" ).and_then( |t| t.as_str() ) {
+            Some( ty ) => ty,
+            None => return ( FieldType::Json, Label::Single ),
+        };
+        let format = map.get( "format" ).and_then( |f| f.as_str() );
+        let single = |ty| ( ty, Label::Single );
+        match ty {
+            "boolean" => single( FieldType::Bool ),
+            "null" => single( FieldType::Empty ),
+            "number" if format == Some( "float" ) => single( FieldType::Float ),
+            "number" => single( FieldType::Double ),
+            "integer" => single( match format {
+                Some( "int8" ) | Some( "int16" ) | Some( "int32" ) => FieldType::Int32,
+                Some( "uint8" ) | Some( "uint16" ) | Some( "uint32" ) => FieldType::Uint32,
+                Some( "uint64" ) => FieldType::Uint64,
+                _ if map.get( "minimum" ) == Some( &Value::from( 0 ) ) => FieldType::Uint64,
+                _ => FieldType::Int64,
+            } ),
+            "string" => match map.get( "enum" ).and_then( |e| e.as_array() ) {
+                Some( values ) => single( FieldType::Enum( self.add_enum( hint, values ) ) ),
+                None => single( FieldType::String ),
+            },
+            "array" => match map.get( "items" ) {
+                Some( &Value::Array( ref items ) ) =>
+                    single( FieldType::Message( self.tuple( items, hint ) ) ),
+                Some( items ) => {
+                    let item = self.resolve( items, &format!( "{}Item", hint ) );
+                    ( self.plain( item, &format!( "{}Item", hint ) ), Label::Repeated )
+                },
+                None => single( FieldType::Json ),
+            },
+            "object" => {
+                if let Some( properties ) = map.get( "properties" ).and_then( |p| p.as_object() ) {
+                    return single( FieldType::Message( self.object( hint, properties ) ) );
+                }
+                match map.get( "additionalProperties" ) {
+                    Some( values ) if values.is_object() => {
+                        let value = self.resolve( values, &format!( "{}Value", hint ) );
+                        ( self.plain( value, &format!( "{}Value", hint ) ), Label::Map )
+                    },
+                    _ => single( FieldType::Json ),
+                }
+            },
+            _ => single( FieldType::Json ),
+        }
+    }
+
+    /// Resolves a named definition.
+    fn definition( &mut self, name: &str ) -> ( FieldType, Label )
+    {
+        if let Some( resolved ) = self.resolved.get( name ) {
+            return resolved.clone();
+        }
+        let schema = match self.definitions.get( name ) {
+            Some( schema ) => schema.clone(),
+            None => return ( FieldType::Json, Label::Single ),
+        };
+
+        // Messages are registered before their fields are resolved so the
+        // recursive types refer to themselves.
+        let is_message = schema.get( "properties" ).is_some() ||
+                schema.get( "oneOf" ).is_some() ||
+                schema.get( "items" ).map( |i| i.is_array() ).unwrap_or( false );
+        let mut ident = identifier( name );
+        if is_message {
+            ident = self.unique_name( &ident );
+            self.reserved.insert( ident.clone() );
+            self.resolved.insert( name.to_string(),
+                    ( FieldType::Message( ident.clone() ), Label::Single ) );
+        }
+
+        let resolved = self.resolve( &schema, &ident );
+        self.resolved.insert( name.to_string(), resolved.clone() );
+        resolved
+    }
+
+    /// Turns the type into one that can be used without a label, wrapping
+    /// it into a message if necessary.
+    fn plain( &mut self, resolved: ( FieldType, Label ), hint: &str ) -> FieldType
+    {
+        match resolved {
+            ( ty, Label::Single ) => ty,
+            ( ty, label ) => FieldType::Message( self.add_message(
+                    hint, MessageKind::Value, vec![ field( "value", 0, ty, label ) ] ) ),
+        }
+    }
+
+    fn object( &mut self, name: &str, properties: &Map<String, Value> ) -> String
+    {
+        let fields = properties.iter()
+                .enumerate()
+                .map( |( i, ( property, schema ) )| {
+                    let ( ty, label ) = self.resolve(
+                            schema, &format!( "{}{}", name, pascal_case( property ) ) );
+                    field( property, i, ty, label )
+                } )
+                .collect();
+        self.add_message( name, MessageKind::Object, fields )
+    }
+
+    fn tuple( &mut self, items: &[Value], name: &str ) -> String
+    {
+        let fields = items.iter()
+                .enumerate()
+                .map( |( i, schema )| {
+                    let hint = format!( "{}Item{}", name, i );
+                    let item = self.resolve( schema, &hint );
+                    let mut f = field( &format!( "item_{}", i ), i, FieldType::Json, Label::Single );
+                    f.json_name = i.to_string();
+                    f.ty = self.plain( item, &hint );
+                    f
+                } )
+                .collect();
+        self.add_message( name, MessageKind::Tuple, fields )
+    }
+
+    /// Creates the message for an enum with data carrying variants.
+    ///
+    /// Returns `None` if the variants aren't in the form serde uses for
+    /// enums.
+    fn union( &mut self, variants: &[Value], name: &str ) -> Option<String>
+    {
+        let mut fields = vec![];
+        for variant in variants {
+            if let Some( values ) = variant.get( "enum" ).and_then( |e| e.as_array() ) {
+                for value in values {
+                    let value = value.as_str()?;
+                    let number = fields.len();
+                    fields.push( field( value, number, FieldType::Empty, Label::Single ) );
+                }
+                continue;
+            }
+
+            let properties = variant.get( "properties" ).and_then( |p| p.as_object() )?;
+            if properties.len() != 1 {
+                return None;
+            }
+            let ( tag, schema ) = properties.iter().next()?;
+            let hint = format!( "{}{}", name, pascal_case( tag ) );
+            let payload = self.resolve( schema, &hint );
+            let ty = match self.plain( payload, &hint ) {
+                // Keep the unit variants distinct from the data carrying ones.
+                FieldType::Empty => FieldType::Json,
+                ty => ty,
+            };
+            let number = fields.len();
+            fields.push( field( tag, number, ty, Label::Single ) );
+        }
+        Some( self.add_message( name, MessageKind::Union, fields ) )
+    }
+
+    fn add_enum( &mut self, name: &str, values: &[Value] ) -> String
+    {
+        let name = self.unique_name( name );
+        self.enums.push( Enum {
+            name: name.clone(),
+            values: values.iter()
+                    .map( |v| v.as_str().map( String::from ).unwrap_or_else( || v.to_string() ) )
+                    .collect(),
+        } );
+        name
+    }
+
+    fn add_message( &mut self, name: &str, kind: MessageKind, fields: Vec<Field> ) -> String
+    {
+        let name = if self.reserved.remove( name ) {
+            name.to_string()
+        } else {
+            self.unique_name( name )
+        };
+        self.messages.push( Message { name: name.clone(), kind: kind, fields: fields } );
+        name
+    }
+
+    fn unique_name( &self, name: &str ) -> String
+    {
+        let taken = |n: &str| self.reserved.contains( n ) ||
+                self.messages.iter().any( |m| m.name == n ) ||
+                self.enums.iter().any( |e| e.name == n );
+        let mut candidate = name.to_string();
+        let mut i = 2;
+        while taken( &candidate ) {
+            candidate = format!( "{}{}", name, i );
+            i += 1;
+        }
+        candidate
+    }
+}
+
+fn field( json_name: &str, index: usize, ty: FieldType, label: Label ) -> Field
+{
+    Field {
+        name: identifier( json_name ).to_lowercase(),
+        json_name: json_name.to_string(),
+        number: index as u32 + 1,
+        ty: ty,
+        label: label,
+    }
+}
+
+fn comment( doc: Option<&String>, indent: &str ) -> String
+{
+    doc.map( |doc| doc.lines()
+            .map( |line| format!( "{}// {}\n", indent, line ).replace( "// \n", "//\n" ) )
+            .collect() )
+        .unwrap_or_default()
+}
+
+/// Replaces the characters protobuf doesn't allow in identifiers.
+fn identifier( name: &str ) -> String
+{
+    let ident : String = name.chars()
+            .map( |c| if c.is_ascii_alphanumeric() { c } else { '_' } )
+            .collect();
+    if ident.starts_with( |c: char| c.is_ascii_digit() ) {
+        format!( "_{}", ident )
+    } else {
+        ident
+    }
+}
+
+fn pascal_case( name: &str ) -> String
+{
+    identifier( name ).split( '_' )
+        .map( |part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some( first ) => first.to_uppercase().chain( chars ).collect(),
+                None => String::new(),
+            }
+        } )
+        .collect()
+}
+
+fn upper_snake( name: &str ) -> String
+{
+    let mut out = String::new();
+    let mut prev_lower = false;
+    for c in identifier( name ).chars() {
+        if c.is_uppercase() && prev_lower {
+            out.push( '_' );
+        }
+        prev_lower = c.is_lowercase() || c.is_ascii_digit();
+        out.extend( c.to_uppercase() );
+    }
+    out
+}
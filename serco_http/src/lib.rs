@@ -0,0 +1,666 @@
+//! HTTP endpoint for serco services.
+//!
+//! Each operation is exposed as a `POST /<operation>` route that takes the
+//! operation parameters as a JSON object and responds with the JSON result.
+//! Failed calls respond with the `ServiceError` as the body and a status code
+//! matching the error kind.
+//!
+//! The requests without the `X-Serco-Session` header are served in a session
+//! of their own that ends with the response. The clients start a session
+//! they can continue by sending `X-Serco-Session: new`; the endpoint returns
+//! the ID of the session in the header of each response and the clients send
+//! it back to continue the session. Only the client that opened a session
+//! may continue it; the requests for the sessions of other clients respond
+//! with `403 Forbidden` and those for unknown or expired sessions with
+//! `400 Bad Request`. The requests are checked before they get a session so
+//! the invalid ones don't open sessions.
+//!
+//! The host authenticates the credentials of the `Authorization` header on
+//! each request. The requests it rejects respond with `401 Unauthorized`.
+//!
+//! The requests over the message limits of the host respond with
+//! `413 Payload Too Large` without being parsed. The bodies are read only
+//! up to the size limit.
+//!
+//! The calls continue the trace of the `traceparent` header of their
+//! request.
+//!
+//! The endpoint serves the OpenAPI document of the contract at
+//! `GET /openapi.json` and the metrics of hosts built with
+//! `ServiceHost::metrics` in the Prometheus text format at `GET /metrics`.
+//!
+//! HTTP has no way for the host to call the client so duplex callbacks and
+//! streaming operations are not available through this endpoint.
+
+#![cfg_attr(test, feature(proc_macro))]
+
+extern crate futures;
+use futures::prelude::*;
+
+extern crate tokio;
+extern crate hyper;
+
+extern crate serco;
+use serco::{ServiceContract, ServiceError, ErrorKind, InvokeTarget, Credentials};
+extern crate serde;
+#[macro_use] extern crate serde_json;
+
+#[cfg(test)] #[macro_use] extern crate serde_derive;
+#[cfg(test)] extern crate serco_derive;
+
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use hyper::{Method, StatusCode};
+use hyper::header::{ContentLength, ContentType};
+use hyper::server::{Http, Request, Response, Service};
+
+pub mod openapi;
+
+/// Header carrying the session ID.
+pub const SESSION_HEADER : &str = "X-Serco-Session";
+
+/// Value of the session header that starts a session the client can
+/// continue.
+pub const NEW_SESSION : &str = "new";
+
+/// Path of the OpenAPI document.
+pub const OPENAPI_PATH : &str = "/openapi.json";
+
+/// Path of the Prometheus metrics.
+pub const METRICS_PATH : &str = "/metrics";
+
+/// Kind under which the HTTP endpoints are registered in service registries.
+pub const ENDPOINT_KIND : &str = "http";
+
+pub struct HttpEndpoint {
+    address: SocketAddr,
+    api_version: String,
+}
+
+impl HttpEndpoint {
+    pub fn new( address: SocketAddr ) -> Self {
+        HttpEndpoint {
+            address: address,
+            api_version: "1.0.0".to_string(),
+        }
+    }
+
+    /// Specifies the API version reported in the OpenAPI document.
+    pub fn api_version<T: Into<String>>( mut self, version: T ) -> Self {
+        self.api_version = version.into();
+        self
+    }
+
+    fn url( &self ) -> String {
+        format!( "http://{}", self.address )
+    }
+}
+
+impl<TService,
+        THostImplementation,
+        TSessionFactory>
+    serco::ServiceEndpoint<TService,
+        TSessionFactory,
+        THostImplementation,
+    >
+    for HttpEndpoint
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+{
+    fn run(
+        &self,
+        host: Rc<serco::HostRuntime<
+                TService,
+                TSessionFactory,
+                THostImplementation,
+        >>
+    ) -> Box<Future<Item=(), Error=serco::ServiceError>>
+    {
+        let listener = match tokio::net::TcpListener::bind( &self.address ) {
+            Ok( listener ) => listener,
+            Err( e ) => return Box::new( futures::future::err(
+                    serco::ServiceError::transport( e ) ) ),
+        };
+
+        let document = openapi::document::<TService>(
+                &self.api_version, Some( &self.url() ) );
+        let document = Rc::new( document.to_string() );
+
+        let http = Http::<hyper::Chunk>::new();
+        let limit = host.connection_limit();
+        let result = listener.incoming()
+            .map_err( serco::ServiceError::transport )
+            .map( move |socket| -> Box<Future<Item=(), Error=serco::ServiceError>> {
+
+                // The connections over the limit are closed right away.
+                let permit = match limit.acquire() {
+                    Ok( permit ) => permit,
+                    Err( _ ) => return Box::new( futures::future::ok( () ) ),
+                };
+                let service = HttpService {
+                    host: host.clone(),
+                    document: document.clone(),
+                };
+
+                // Failures of single connections don't concern the endpoint.
+                Box::new( http.serve_connection( socket, service )
+                    .then( move |_| {
+                        drop( permit );
+                        Ok::<(), serco::ServiceError>( () )
+                    } ) )
+            } )
+            // Serve all connections concurrently.
+            .buffer_unordered( usize::max_value() )
+            .for_each( |_| Ok( () ) );
+
+        Box::new( result )
+    }
+
+    fn address( &self ) -> Option<serco::registry::EndpointAddress> {
+        Some( serco::registry::EndpointAddress::new( ENDPOINT_KIND, self.url() ) )
+    }
+}
+
+/// Serves the HTTP requests of a single connection.
+struct HttpService<TService, TSessionFactory, THostImplementation>
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService> + 'static,
+{
+    host: Rc<serco::HostRuntime<TService, TSessionFactory, THostImplementation>>,
+    document: Rc<String>,
+}
+
+impl<TService, TSessionFactory, THostImplementation>
+    HttpService<TService, TSessionFactory, THostImplementation>
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+{
+    /// Resolves the session for the request.
+    ///
+    /// The requests without a session ID get a session that isn't kept by
+    /// the host and ends once the request has been served. The sessions
+    /// started with `NEW_SESSION` are kept by the host as HTTP clients are
+    /// free to use a new connection for each request. Only the client that
+    /// opened a session may resume it. Resolves to the ID of the session the
+    /// client may resume, if any, and the session.
+    fn session(
+        host: &serco::HostRuntime<TService, TSessionFactory, THostImplementation>,
+        id: Option<String>,
+        credentials: &Credentials,
+    ) -> Result<( Option<String>, Rc<serco::sessions::SessionTarget<THostImplementation::ServiceInstance>> ), ServiceError>
+    {
+        match id {
+            None => host.open_session( credentials )
+                    .map( |( _, target )| ( None, Rc::new( target ) ) ),
+            Some( ref id ) if id == NEW_SESSION => host.open_resumable_session( credentials )
+                    .map( |( id, target )| ( Some( id ), target ) ),
+            Some( id ) => host.resume_session( &id, credentials )
+                    .map( |target| ( Some( id ), target ) ),
+        }
+    }
+}
+
+impl<TService, TSessionFactory, THostImplementation> Service
+    for HttpService<TService, TSessionFactory, THostImplementation>
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+{
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<Future<Item=Response, Error=hyper::Error>>;
+
+    fn call( &self, request: Request ) -> Self::Future
+    {
+        if *request.method() == Method::Get && request.path() == OPENAPI_PATH {
+            return Box::new( futures::future::ok( json_response(
+                    StatusCode::Ok, self.document.as_ref().clone() ) ) );
+        }
+
+        if *request.method() == Method::Get && request.path() == METRICS_PATH {
+            let response = match self.host.metrics() {
+                Some( metrics ) => {
+                    let mut response = Response::new().with_body( metrics.prometheus() );
+                    response.headers_mut().set_raw( "Content-Type", "text/plain; version=0.0.4" );
+                    response
+                },
+                None => Response::new().with_status( StatusCode::NotFound ),
+            };
+            return Box::new( futures::future::ok( response ) );
+        }
+
+        if *request.method() != Method::Post {
+            return Box::new( futures::future::ok( error_response(
+                    StatusCode::MethodNotAllowed,
+                    ServiceError::new( ErrorKind::BadRequest,
+                                       "Operations are invoked with POST" ) ) ) );
+        }
+
+        let name = request.path().trim_left_matches( '/' ).to_string();
+        let streaming = TService::description().operation( &name )
+                .map( |op| op.is_streaming() )
+                .unwrap_or( false );
+        if streaming {
+            return Box::new( futures::future::ok( error( ServiceError::new(
+                    ErrorKind::BadOperation,
+                    format!( "Operation '{}' streams and is not available over HTTP",
+                             name ) ) ) ) );
+        }
+
+        let limits = *self.host.message_limits();
+        if let Some( &ContentLength( length ) ) = request.headers().get() {
+            if let Err( e ) = limits.check_request_size( length as usize ) {
+                return Box::new( futures::future::ok( error( e ) ) );
+            }
+        }
+
+        let session_id = request.headers().get_raw( SESSION_HEADER )
+                .and_then( |raw| raw.one() )
+                .and_then( |value| std::str::from_utf8( value ).ok() )
+                .map( String::from );
+        let credentials = request.headers().get_raw( "Authorization" )
+                .and_then( |raw| raw.one() )
+                .and_then( |value| std::str::from_utf8( value ).ok() )
+                .and_then( Credentials::from_authorization )
+                .unwrap_or( Credentials::Anonymous );
+        let trace = request.headers().get_raw( serco::trace::TRACEPARENT_HEADER )
+                .and_then( |raw| raw.one() )
+                .and_then( |value| std::str::from_utf8( value ).ok() )
+                .and_then( serco::TraceContext::from_traceparent );
+        let host = self.host.clone();
+        let body = request.body()
+            .map_err( ServiceError::transport )
+            .fold( vec![], move |mut body, chunk| {
+                limits.check_request_size( body.len() + chunk.len() )?;
+                body.extend_from_slice( &chunk );
+                Ok::<_, ServiceError>( body )
+            } )
+            .and_then( move |body| limits.check_request( &body ).map( |_| body ) );
+
+        let metrics = self.host.endpoint_metrics( ENDPOINT_KIND );
+        Box::new( body.then( move |body| -> Self::Future {
+
+            let body = match body {
+                Ok( body ) => body,
+                Err( e ) => return Box::new( futures::future::ok( error( e ) ) ),
+            };
+            metrics.received( body.len() );
+            let params = if body.is_empty() {
+                json!({})
+            } else {
+                match serde_json::from_slice( &body ) {
+                    Ok( params ) => params,
+                    Err( e ) => return Box::new( futures::future::ok( error(
+                            ServiceError::new( ErrorKind::BadRequest,
+                                               format!( "{}", e ) ) ) ) )
+                            as Box<Future<Item=_, Error=_>>,
+                }
+            };
+
+            // Only the valid requests get a session.
+            let ( session_id, target ) = match Self::session( &host, session_id, &credentials ) {
+                Ok( session ) => session,
+                Err( e ) => return Box::new( futures::future::ok(
+                        error_response( status_code( e.kind ), e ) ) ),
+            };
+
+            let result = serco::trace::with_context( trace, || {
+                invoke_value::<TService, _>( &*target, &name, params )
+            } );
+            Box::new( result
+                .then( move |result| {
+
+                    // The sessions that can't be resumed end with the
+                    // response.
+                    drop( target );
+                    let mut response = match result {
+                        Ok( value ) => {
+                            let body = value.to_string();
+                            match limits.check_response( body.as_bytes() ) {
+                                Ok( () ) => {
+                                    metrics.sent( body.len() );
+                                    json_response( StatusCode::Ok, body )
+                                },
+                                Err( e ) => error( e ),
+                            }
+                        },
+                        Err( e ) => error( e ),
+                    };
+                    if let Some( session_id ) = session_id {
+                        response.headers_mut().set_raw( SESSION_HEADER, session_id );
+                    }
+                    Ok( response )
+                } ) )
+        } ) )
+    }
+}
+
+/// Invokes an operation and turns its result into a JSON value.
+fn invoke_value<C, T>(
+    target: &T,
+    name: &str,
+    params: serde_json::Value,
+) -> Box<Future<Item=serde_json::Value, Error=serco::ServiceError>>
+    where C: ServiceContract + ?Sized,
+          T: InvokeTarget<C> + ?Sized,
+{
+    let output = serde_json::Serializer::new( vec![] );
+    Box::new( target.invoke( name, params, output ).map( |ok| {
+        let bytes = ok.into_inner();
+        if bytes.is_empty() {
+            serde_json::Value::Null
+        } else {
+            serde_json::from_slice( &bytes ).unwrap()
+        }
+    } ) )
+}
+
+/// Resolves the status code for an error.
+pub fn status_code( kind: ErrorKind ) -> StatusCode {
+    match kind {
+        ErrorKind::Internal => StatusCode::InternalServerError,
+        ErrorKind::Transport => StatusCode::BadGateway,
+        ErrorKind::BadOperation => StatusCode::NotFound,
+        ErrorKind::BadRequest => StatusCode::BadRequest,
+        ErrorKind::Unauthenticated => StatusCode::Unauthorized,
+        ErrorKind::Unauthorized => StatusCode::Forbidden,
+        ErrorKind::Throttled => StatusCode::TooManyRequests,
+        ErrorKind::MessageTooLarge => StatusCode::PayloadTooLarge,
+    }
+}
+
+/// Creates a response with a JSON body. The length is sent up front so the
+/// body isn't chunked.
+fn json_response( status: StatusCode, body: String ) -> Response {
+    Response::new()
+        .with_status( status )
+        .with_header( ContentType::json() )
+        .with_header( ContentLength( body.len() as u64 ) )
+        .with_body( body )
+}
+
+fn error( e: ServiceError ) -> Response {
+    error_response( status_code( e.kind ), e )
+}
+
+fn error_response( status: StatusCode, e: ServiceError ) -> Response {
+    json_response( status, serde_json::to_string( &e ).unwrap() )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serco_derive::*;
+    use serde_json::Value;
+    use tokio::runtime::current_thread::Runtime;
+    use serco::auth::{AuthenticatedSession, PrincipalSessionFactory};
+    use std::cell::Cell;
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::Duration;
+
+    #[service_contract]
+    pub trait Calculator {
+        fn add( &self, a: i32, b: i32 ) -> i32;
+        fn reset( &self );
+        fn sum( &self, values: serco::ServiceStream<i32> ) -> i32;
+    }
+
+    #[service(Calculator)]
+    struct CalculatorService;
+    impl Calculator for CalculatorService {
+        fn add( &self, a: i32, b: i32 ) -> i32 { a + b }
+        fn reset( &self ) {}
+        fn sum( &self, values: serco::ServiceStream<i32> ) -> i32 {
+            values.fold( 0, |sum, value| Ok::<_, ServiceError>( sum + value ) ).wait().unwrap()
+        }
+    }
+
+    #[service_contract]
+    pub trait Counter {
+        fn next( &self ) -> u32;
+    }
+
+    #[service(Counter)]
+    struct CounterService {
+        count: Cell<u32>,
+    }
+    impl Counter for CounterService {
+        fn next( &self ) -> u32 {
+            self.count.set( self.count.get() + 1 );
+            self.count.get()
+        }
+    }
+    impl serco::SessionService<Counter> for CounterService {
+        type SessionInfo = AuthenticatedSession;
+        fn construct( _session: Rc<AuthenticatedSession> ) -> Box<Counter> {
+            Box::new( CounterService { count: Cell::new( 0 ) } )
+        }
+    }
+
+    fn calculator( address: SocketAddr ) {
+        thread::spawn( move || {
+            let host = serco::ServiceHost::new( Calculator::singleton( CalculatorService ) )
+                    .message_limits( serco::MessageLimits::new().max_request_size( 100 ) )
+                    .endpoint( HttpEndpoint::new( address ).api_version( "2.1.0" ) )
+                    .run();
+            Runtime::new().unwrap().block_on( host ).ok();
+        } );
+        thread::sleep( Duration::from_millis( 100 ) );
+    }
+
+    fn post( address: &SocketAddr, path: &str, body: &str, headers: &str ) -> ( String, Value ) {
+        let ( status, _, body ) = exchange( address, "POST", path, body, headers );
+        ( status, body )
+    }
+
+    /// Sends a request and returns the status line, the session ID and the
+    /// response body.
+    fn exchange(
+        address: &SocketAddr,
+        method: &str,
+        path: &str,
+        body: &str,
+        headers: &str,
+    ) -> ( String, Option<String>, Value ) {
+        let mut stream = std::net::TcpStream::connect( address ).unwrap();
+        write!( stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+                Content-Length: {}\r\n{}\r\n{}", method, path, body.len(), headers, body ).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string( &mut response ).unwrap();
+        let status = response.lines().next().unwrap().to_string();
+        let session = response.lines()
+                .find( |line| line.to_lowercase().starts_with( "x-serco-session:" ) )
+                .map( |line| line[ line.find( ':' ).unwrap() + 1.. ].trim().to_string() );
+        let body = &response[ response.find( "\r\n\r\n" ).unwrap() + 4.. ];
+        let body = if body.is_empty() { Value::Null } else { serde_json::from_str( body ).unwrap() };
+        ( status, session, body )
+    }
+
+    fn error_kind( body: &Value ) -> ErrorKind {
+        serde_json::from_value::<ServiceError>( body.clone() ).unwrap().kind
+    }
+
+    #[test]
+    pub fn routing() {
+        let address : SocketAddr = "127.0.0.1:50591".parse().unwrap();
+        calculator( address );
+
+        let ( status, body ) = post( &address, "/add", r#"{ "a": 1, "b": 2 }"#, "" );
+        assert!( status.contains( "200" ) );
+        assert_eq!( body, json!( 3 ) );
+
+        let ( status, body ) = post( &address, "/reset", "", "" );
+        assert!( status.contains( "200" ) );
+        assert_eq!( body, Value::Null );
+
+        let ( status, body ) = post( &address, "/subtract", r#"{ "a": 1, "b": 2 }"#, "" );
+        assert!( status.contains( "404" ) );
+        assert_eq!( error_kind( &body ), ErrorKind::BadOperation );
+
+        let ( status, body ) = post( &address, "/sum", "{}", "" );
+        assert!( status.contains( "404" ) );
+        assert_eq!( error_kind( &body ), ErrorKind::BadOperation );
+
+        let ( status, _, body ) = exchange( &address, "GET", "/add", "", "" );
+        assert!( status.contains( "405" ) );
+        assert_eq!( error_kind( &body ), ErrorKind::BadRequest );
+
+        let ( status, _, _ ) = exchange( &address, "GET", METRICS_PATH, "", "" );
+        assert!( status.contains( "404" ) );
+    }
+
+    #[test]
+    pub fn status_codes() {
+        assert_eq!( status_code( ErrorKind::Internal ), StatusCode::InternalServerError );
+        assert_eq!( status_code( ErrorKind::Transport ), StatusCode::BadGateway );
+        assert_eq!( status_code( ErrorKind::BadOperation ), StatusCode::NotFound );
+        assert_eq!( status_code( ErrorKind::BadRequest ), StatusCode::BadRequest );
+        assert_eq!( status_code( ErrorKind::Unauthenticated ), StatusCode::Unauthorized );
+        assert_eq!( status_code( ErrorKind::Unauthorized ), StatusCode::Forbidden );
+        assert_eq!( status_code( ErrorKind::Throttled ), StatusCode::TooManyRequests );
+        assert_eq!( status_code( ErrorKind::MessageTooLarge ), StatusCode::PayloadTooLarge );
+
+        let address : SocketAddr = "127.0.0.1:50592".parse().unwrap();
+        calculator( address );
+
+        let ( status, body ) = post( &address, "/add", r#"{ "a": 1 }"#, "" );
+        assert!( status.contains( "400" ) );
+        assert_eq!( error_kind( &body ), ErrorKind::BadRequest );
+
+        let ( status, body ) = post( &address, "/add", "{ a: 1", "" );
+        assert!( status.contains( "400" ) );
+        assert_eq!( error_kind( &body ), ErrorKind::BadRequest );
+
+        let large = format!( r#"{{ "a": 1, "b": 2, "padding": "{}" }}"#, "x".repeat( 100 ) );
+        let ( status, body ) = post( &address, "/add", &large, "" );
+        assert!( status.contains( "413" ) );
+        assert_eq!( error_kind( &body ), ErrorKind::MessageTooLarge );
+    }
+
+    #[test]
+    pub fn sessions() {
+        let address : SocketAddr = "127.0.0.1:50593".parse().unwrap();
+        thread::spawn( move || {
+            let host = serco::ServiceHost::new( Counter::session::<CounterService>() )
+                    .session_factory( PrincipalSessionFactory::new() )
+                    .authenticator( |credentials: &Credentials| match *credentials {
+                        Credentials::Token( ref token ) if token == "alice" || token == "bob" =>
+                                Ok( serco::Principal::new( token.clone() ) ),
+                        _ => Err( ServiceError::unauthenticated( "Unknown token" ) ),
+                    } )
+                    .endpoint( HttpEndpoint::new( address ) )
+                    .run();
+            Runtime::new().unwrap().block_on( host ).ok();
+        } );
+        thread::sleep( Duration::from_millis( 100 ) );
+
+        let alice = "Authorization: Bearer alice\r\n";
+        let bob = "Authorization: Bearer bob\r\n";
+
+        let ( status, _, body ) = exchange( &address, "POST", "/next", "", "" );
+        assert!( status.contains( "401" ) );
+        assert_eq!( error_kind( &body ), ErrorKind::Unauthenticated );
+
+        // The requests without the header get a session of their own.
+        let ( status, session, body ) = exchange( &address, "POST", "/next", "", alice );
+        assert!( status.contains( "200" ) );
+        assert_eq!( session, None );
+        assert_eq!( body, json!( 1 ) );
+        let ( _, _, body ) = exchange( &address, "POST", "/next", "", alice );
+        assert_eq!( body, json!( 1 ) );
+
+        // The session returned with the response continues with the header.
+        let start = format!( "{}{}: {}\r\n", alice, SESSION_HEADER, NEW_SESSION );
+        let ( status, session, body ) = exchange( &address, "POST", "/next", "", &start );
+        assert!( status.contains( "200" ) );
+        assert_eq!( body, json!( 1 ) );
+        let session = session.unwrap();
+        let resume = format!( "{}{}: {}\r\n", alice, SESSION_HEADER, session );
+        let ( status, resumed, body ) = exchange( &address, "POST", "/next", "", &resume );
+        assert!( status.contains( "200" ) );
+        assert_eq!( resumed, Some( session.clone() ) );
+        assert_eq!( body, json!( 2 ) );
+
+        let ( _, other, body ) = exchange( &address, "POST", "/next", "", &start );
+        assert_ne!( other, Some( session.clone() ) );
+        assert_eq!( body, json!( 1 ) );
+
+        let stolen = format!( "{}{}: {}\r\n", bob, SESSION_HEADER, session );
+        let ( status, body ) = post( &address, "/next", "", &stolen );
+        assert!( status.contains( "403" ) );
+        assert_eq!( error_kind( &body ), ErrorKind::Unauthorized );
+
+        let unknown = format!( "{}{}: unknown\r\n", alice, SESSION_HEADER );
+        let ( status, body ) = post( &address, "/next", "", &unknown );
+        assert!( status.contains( "400" ) );
+        assert_eq!( error_kind( &body ), ErrorKind::BadRequest );
+    }
+
+    #[test]
+    pub fn session_limit() {
+        let address : SocketAddr = "127.0.0.1:50595".parse().unwrap();
+        thread::spawn( move || {
+            let host = serco::ServiceHost::new( Counter::session::<CounterService>() )
+                    .session_factory( PrincipalSessionFactory::new() )
+                    .throttling( serco::Throttling::new().max_concurrent_sessions( 1 ) )
+                    .endpoint( HttpEndpoint::new( address ) )
+                    .run();
+            Runtime::new().unwrap().block_on( host ).ok();
+        } );
+        thread::sleep( Duration::from_millis( 100 ) );
+
+        // The sessions of the requests without the header end with the
+        // responses and the invalid requests don't get one.
+        for _ in 0..3 {
+            let ( status, session, _ ) = exchange( &address, "POST", "/next", "", "" );
+            assert!( status.contains( "200" ) );
+            assert_eq!( session, None );
+        }
+        let start = format!( "{}: {}\r\n", SESSION_HEADER, NEW_SESSION );
+        let ( status, session, body ) = exchange( &address, "POST", "/next", "{", &start );
+        assert!( status.contains( "400" ) );
+        assert_eq!( session, None );
+        assert_eq!( error_kind( &body ), ErrorKind::BadRequest );
+
+        // The session the client may continue is kept.
+        let ( status, session, _ ) = exchange( &address, "POST", "/next", "", &start );
+        assert!( status.contains( "200" ) );
+        assert!( session.is_some() );
+        let ( status, _, body ) = exchange( &address, "POST", "/next", "", "" );
+        assert!( status.contains( "429" ) );
+        assert_eq!( error_kind( &body ), ErrorKind::Throttled );
+    }
+
+    #[test]
+    pub fn openapi_document() {
+        let address : SocketAddr = "127.0.0.1:50594".parse().unwrap();
+        calculator( address );
+
+        let ( status, _, document ) = exchange( &address, "GET", OPENAPI_PATH, "", "" );
+        assert!( status.contains( "200" ) );
+        assert_eq!( document[ "openapi" ], json!( openapi::OPENAPI_VERSION ) );
+        assert_eq!( document[ "info" ][ "title" ], json!( "Calculator" ) );
+        assert_eq!( document[ "info" ][ "version" ], json!( "2.1.0" ) );
+        assert_eq!( document[ "servers" ][ 0 ][ "url" ], json!( "http://127.0.0.1:50594" ) );
+
+        // The streaming operations aren't available over HTTP.
+        let paths = document[ "paths" ].as_object().unwrap();
+        let mut routes : Vec<&str> = paths.keys().map( |k| k.as_ref() ).collect();
+        routes.sort();
+        assert_eq!( routes, vec![ "/add", "/reset" ] );
+
+        let add = &paths[ "/add" ][ "post" ];
+        assert_eq!( add[ "operationId" ], json!( "add" ) );
+        assert_eq!( add[ "requestBody" ][ "content" ][ "application/json" ][ "schema" ][ "required" ],
+                    json!([ "a", "b" ]) );
+        assert_eq!( add[ "responses" ][ "default" ][ "$ref" ], json!( "#/components/responses/Error" ) );
+        assert!( document[ "components" ][ "schemas" ][ "ServiceError" ].is_object() );
+    }
+}
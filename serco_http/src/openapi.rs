@@ -0,0 +1,204 @@
+//! OpenAPI document generation for the HTTP endpoint.
+//!
+//! The operations are described as `POST` routes. The request and response
+//! schemas come from the contract JSON Schema if the contract was declared
+//! with `#[service_contract(schema)]`. Otherwise the document lists the
+//! parameters by name with their Rust types in the descriptions.
+
+use serde_json::{Map, Value};
+
+use serco::ServiceContract;
+use serco::description::OperationDescription;
+use serco::schema::SchemaGenerator;
+
+use super::SESSION_HEADER;
+
+/// The OpenAPI version of the generated documents.
+pub const OPENAPI_VERSION : &str = "3.0.0";
+
+/// Generates the OpenAPI document of a contract.
+pub fn document<S: ServiceContract + ?Sized>(
+    version: &str,
+    server: Option<&str>,
+) -> Value
+{
+    let description = S::description();
+    let schema = S::schema();
+
+    let mut schemas = Map::new();
+    if let Some( definitions ) = schema.as_ref().and_then( |s| s.get( "definitions" ) ) {
+        if let Value::Object( ref definitions ) = *definitions {
+            for ( name, definition ) in definitions {
+                schemas.insert( name.clone(), to_openapi( definition.clone() ) );
+            }
+        }
+    }
+
+    // The error shape is part of the document even without the contract
+    // schema.
+    let mut gen = SchemaGenerator::new();
+    gen.subschema_for::<serco::ServiceError>();
+    for ( name, definition ) in gen.into_definitions() {
+        schemas.insert( name, to_openapi( definition ) );
+    }
+
+    let mut paths = Map::new();
+    for op in description.operations.iter().filter( |op| !op.is_streaming() ) {
+        let op_schema = schema.as_ref()
+                .and_then( |s| s.get( "operations" ) )
+                .and_then( |ops| ops.get( op.name ) );
+        let request = op_schema.and_then( |s| s.get( "request" ) ).cloned()
+                .map( to_openapi )
+                .unwrap_or_else( || untyped_request( op ) );
+        let response = op_schema.and_then( |s| s.get( "response" ) ).cloned()
+                .map( to_openapi )
+                .unwrap_or_else( || json!({ "description": op.output }) );
+
+        let mut post = Map::new();
+        post.insert( "operationId".to_string(), Value::from( op.name ) );
+        if let Some( doc ) = op.doc {
+            post.insert( "description".to_string(), Value::from( doc ) );
+        }
+        post.insert( "parameters".to_string(), json!([
+            { "$ref": "#/components/parameters/Session" }
+        ]) );
+        post.insert( "requestBody".to_string(), json!({
+            "required": true,
+            "content": { "application/json": { "schema": request } },
+        }) );
+        post.insert( "responses".to_string(), json!({
+            "200": {
+                "description": "The result of the operation.",
+                "headers": { SESSION_HEADER: { "$ref": "#/components/headers/Session" } },
+                "content": { "application/json": { "schema": response } },
+            },
+            "default": { "$ref": "#/components/responses/Error" },
+        }) );
+
+        paths.insert( format!( "/{}", op.name ), json!({ "post": post }) );
+    }
+
+    let mut info = Map::new();
+    info.insert( "title".to_string(), Value::from( description.name ) );
+    info.insert( "version".to_string(), Value::from( version ) );
+    if let Some( doc ) = description.doc {
+        info.insert( "description".to_string(), Value::from( doc ) );
+    }
+
+    let session_description = "Identifies the session. Omit to call the \
+            operation in a session of its own or send `new` to start a session \
+            and the value returned in the response to continue it.";
+
+    let mut document = json!({
+        "openapi": OPENAPI_VERSION,
+        "info": info,
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "parameters": {
+                "Session": {
+                    "name": SESSION_HEADER,
+                    "in": "header",
+                    "required": false,
+                    "description": session_description,
+                    "schema": { "type": "string" },
+                },
+            },
+            "headers": {
+                "Session": {
+                    "description": session_description,
+                    "schema": { "type": "string" },
+                },
+            },
+            "responses": {
+                "Error": {
+                    "description": "The call failed. The status code reflects the error kind.",
+                    "content": { "application/json": {
+                        "schema": { "$ref": "#/components/schemas/ServiceError" },
+                    } },
+                },
+            },
+        },
+    });
+
+    if let Some( server ) = server {
+        document[ "servers" ] = json!([ { "url": server } ]);
+    }
+
+    document
+}
+
+/// Describes the request of an operation without the contract schema.
+fn untyped_request( op: &OperationDescription ) -> Value {
+    let properties : Map<String, Value> = op.args.iter()
+            .filter( |a| a.stream.is_none() )
+            .map( |a| ( a.name.to_string(), json!({ "description": a.type_name }) ) )
+            .collect();
+    let required : Vec<&str> = op.args.iter()
+            .filter( |a| a.stream.is_none() )
+            .map( |a| a.name )
+            .collect();
+
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+/// Converts a JSON Schema into the schema dialect of OpenAPI 3.0.
+///
+/// The references are pointed to the components and the constructs OpenAPI
+/// doesn't support are replaced with their closest equivalents.
+pub fn to_openapi( schema: Value ) -> Value {
+    let mut map = match schema {
+        Value::Bool( true ) => return json!({}),
+        Value::Bool( false ) => return json!({ "not": {} }),
+        Value::Array( items ) => return Value::Array(
+                items.into_iter().map( to_openapi ).collect() ),
+        Value::Object( map ) => map,
+        other => return other,
+    };
+
+    map.remove( "$schema" );
+
+    if let Some( Value::String( reference ) ) = map.remove( "$ref" ) {
+        map.insert( "$ref".to_string(), Value::String(
+                reference.replace( "#/definitions/", "#/components/schemas/" ) ) );
+    }
+
+    // OpenAPI has no null type. Nullable values are flagged instead.
+    if map.get( "type" ) == Some( &Value::from( "null" ) ) {
+        map.remove( "type" );
+        map.insert( "nullable".to_string(), Value::Bool( true ) );
+        map.insert( "enum".to_string(), json!([ null ]) );
+    }
+    let nullable_of = match map.get( "anyOf" ) {
+        Some( &Value::Array( ref options ) ) if options.len() == 2 &&
+                options[ 1 ] == json!({ "type": "null" }) => Some( options[ 0 ].clone() ),
+        _ => None,
+    };
+    if let Some( schema ) = nullable_of {
+        map.remove( "anyOf" );
+        map.insert( "allOf".to_string(), json!([ schema ]) );
+        map.insert( "nullable".to_string(), Value::Bool( true ) );
+    }
+
+    // OpenAPI arrays have a single item schema so tuples accept any of the
+    // item types at each position.
+    let tuple_items = match map.get( "items" ) {
+        Some( &Value::Array( ref items ) ) => Some( items.clone() ),
+        _ => None,
+    };
+    if let Some( items ) = tuple_items {
+        map.insert( "items".to_string(), json!({ "oneOf": items }) );
+    }
+
+    Value::Object( map.into_iter().map( |( key, value )| {
+        let value = match key.as_ref() {
+            "enum" | "required" => value,
+            _ => to_openapi( value ),
+        };
+        ( key, value )
+    } ).collect() )
+}
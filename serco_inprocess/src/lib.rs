@@ -0,0 +1,475 @@
+//! In-process endpoint that invokes the services without serialization.
+//!
+//! The `InProcessForwarder` hands the parameters the proxies build to the
+//! service implementation as they are and passes the return value back the
+//! same way, so the contracts can be used as module boundaries within a
+//! binary with little overhead. The clients use the contracts through the
+//! same proxies as with the other transports, so the services can be moved
+//! behind a remote endpoint later without changes to the code using them.
+//!
+//! The endpoints are registered for the thread that runs the host and the
+//! clients connect to them from the same thread. The calls are invoked
+//! directly on the calling thread, so the host doesn't need to be run on a
+//! reactor. The endpoint serves the clients until the host future is
+//! dropped. The calls continue the trace of the caller as the trace context
+//! is current on the thread.
+//!
+//! The host authenticates the credentials given to
+//! `InProcessClient::credentials` like those of the remote clients.
+//!
+//! Streaming operations and batches are not available through this
+//! endpoint.
+
+#![cfg_attr(test, feature(proc_macro))]
+
+extern crate futures;
+use futures::prelude::*;
+use futures::future;
+
+extern crate serco;
+use serco::{ServiceContract, ServiceError, ErrorKind, InvokeTarget, Credentials};
+extern crate serde;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+#[cfg(test)] #[macro_use] extern crate serde_derive;
+#[cfg(test)] extern crate serco_derive;
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Kind under which the in-process endpoints are registered in service
+/// registries.
+pub const ENDPOINT_KIND : &str = "inprocess";
+
+/// Connects a client to a host. Takes the forwarder for calling the client
+/// back and the credentials of the client and returns the forwarder for
+/// calling the session.
+type Connect = Rc<Fn( InProcessForwarder, &Credentials ) -> Result<InProcessForwarder, ServiceError>>;
+
+thread_local! {
+    static ENDPOINTS : RefCell<HashMap<String, Connect>> = RefCell::new( HashMap::new() );
+}
+
+pub struct InProcessEndpoint {
+    name: String,
+}
+
+impl InProcessEndpoint {
+    pub fn new<T: Into<String>>( name: T ) -> Self {
+        InProcessEndpoint { name: name.into() }
+    }
+}
+
+impl<TService,
+        THostImplementation,
+        TSessionFactory>
+    serco::ServiceEndpoint<TService,
+        TSessionFactory,
+        THostImplementation,
+    >
+    for InProcessEndpoint
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+          THostImplementation::ServiceInstance: 'static,
+{
+    fn run(
+        &self,
+        host: Rc<serco::HostRuntime<
+                TService,
+                TSessionFactory,
+                THostImplementation,
+        >>
+    ) -> Box<Future<Item=(), Error=serco::ServiceError>>
+    {
+        let connect : Connect = Rc::new( move |callback: InProcessForwarder, credentials: &Credentials| {
+            let ( session_id, session ) = host.open_session( credentials )?;
+
+            let callback = Arc::new( serco::ServiceProxy::new( callback ) );
+            host.connect_session( &session_id, callback.clone() );
+
+            Ok( InProcessForwarder {
+                target: Rc::new( HostSession {
+                    host: host.clone(),
+                    session_id: session_id,
+                    target: session,
+                    callback: callback,
+                } ),
+            } )
+        } );
+
+        ENDPOINTS.with( |endpoints| endpoints.borrow_mut()
+                .insert( self.name.clone(), connect ) );
+        Box::new( Serving { name: self.name.clone() } )
+    }
+
+    fn address( &self ) -> Option<serco::registry::EndpointAddress> {
+        Some( serco::registry::EndpointAddress::new( ENDPOINT_KIND, self.name.clone() ) )
+    }
+}
+
+/// Keeps the endpoint registered until the host is dropped.
+struct Serving {
+    name: String,
+}
+
+impl Future for Serving {
+    type Item = ();
+    type Error = ServiceError;
+
+    fn poll( &mut self ) -> Poll<(), ServiceError> {
+        Ok( Async::NotReady )
+    }
+}
+
+impl Drop for Serving {
+    fn drop( &mut self ) {
+        ENDPOINTS.with( |endpoints| endpoints.borrow_mut().remove( &self.name ) );
+    }
+}
+
+/// Target the in-process forwarders invoke.
+trait DirectTarget {
+    fn invoke(
+        &self,
+        name: &str,
+        params: Box<Any>,
+    ) -> Box<Future<Item=Box<Any>, Error=ServiceError>>;
+}
+
+/// Session of a host connected to a client.
+///
+/// The session is disconnected once the client drops it.
+struct HostSession<TService, TSessionFactory, THostImplementation>
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+{
+    host: Rc<serco::HostRuntime<TService, TSessionFactory, THostImplementation>>,
+    session_id: String,
+    target: serco::sessions::SessionTarget<THostImplementation::ServiceInstance>,
+    callback: Arc<serco::ServiceProxy<TService::CallbackContract, InProcessForwarder>>,
+}
+
+impl<TService, TSessionFactory, THostImplementation> DirectTarget
+    for HostSession<TService, TSessionFactory, THostImplementation>
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+{
+    fn invoke(
+        &self,
+        name: &str,
+        params: Box<Any>,
+    ) -> Box<Future<Item=Box<Any>, Error=ServiceError>>
+    {
+        TService::CallbackContract::set_task_callback( self.callback.clone() );
+        InvokeTarget::<TService>::invoke_direct( &self.target, name, params )
+    }
+}
+
+impl<TService, TSessionFactory, THostImplementation> Drop
+    for HostSession<TService, TSessionFactory, THostImplementation>
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+{
+    fn drop( &mut self ) {
+        self.host.disconnect_session( &self.session_id );
+    }
+}
+
+/// The callback of a client.
+struct CallbackTarget<C: ?Sized, T> {
+    target: T,
+    phantom_data: PhantomData<C>,
+}
+
+impl<C, T> DirectTarget for CallbackTarget<C, T>
+    where C: ServiceContract + ?Sized,
+          T: InvokeTarget<C>,
+{
+    fn invoke(
+        &self,
+        name: &str,
+        params: Box<Any>,
+    ) -> Box<Future<Item=Box<Any>, Error=ServiceError>>
+    {
+        serco::trace::serve( C::contract_name(), name, None, || {
+            self.target.invoke_direct( name, params )
+        } )
+    }
+}
+
+pub struct InProcessClient {
+    endpoint: String,
+    credentials: Credentials,
+}
+
+impl InProcessClient {
+    pub fn new<T: Into<String>>( endpoint: T ) -> InProcessClient {
+        InProcessClient { endpoint: endpoint.into(), credentials: Credentials::Anonymous }
+    }
+
+    /// Presents the credentials to the authenticator of the host.
+    pub fn credentials( mut self, credentials: &Credentials ) -> Self {
+        self.credentials = credentials.clone();
+        self
+    }
+
+    pub fn connect<S>(
+        &self,
+    ) -> Box<Future<Item=InProcessConnection<S>, Error=String>>
+        where S: serco::ServiceContract<CallbackContract = ()> + ?Sized + 'static,
+    {
+        self.connect_duplex::<S, (), ()>( () )
+    }
+
+    /// Connects to an endpoint registered on the current thread. The host
+    /// calls the `callback` directly as well.
+    pub fn connect_duplex<S, C, T>(
+        &self,
+        callback: T,
+    ) -> Box<Future<Item=InProcessConnection<S>, Error=String>>
+        where S: serco::ServiceContract<CallbackContract = C> + ?Sized + 'static,
+            C: serco::ServiceContract<CallbackContract = ()> + ?Sized + 'static,
+            T: serco::InvokeTarget<C> + 'static,
+    {
+        let connect = ENDPOINTS.with( |endpoints| endpoints.borrow()
+                .get( &self.endpoint )
+                .cloned() );
+        let connect = match connect {
+            Some( connect ) => connect,
+            None => return Box::new( future::err( format!(
+                    "Endpoint '{}' not found on this thread", self.endpoint ) ) ),
+        };
+
+        let callback = InProcessForwarder {
+            target: Rc::new( CallbackTarget {
+                target: callback,
+                phantom_data: PhantomData::<C>,
+            } ),
+        };
+        Box::new( future::result( connect( callback, &self.credentials )
+            .map( |forwarder| InProcessConnection {
+                proxy: serco::ServiceProxy::new( forwarder ),
+            } )
+            .map_err( |e| e.message ) ) )
+    }
+}
+
+/// Connection to an in-process endpoint.
+///
+/// The session is disconnected when the connection is dropped.
+pub struct InProcessConnection<S: ?Sized> {
+    proxy: serco::ServiceProxy<S, InProcessForwarder>,
+}
+
+/// Dereference the connection into the service proxy.
+/// The proxy implements the actual service trait.
+impl<S: serco::ServiceContract + ?Sized> std::ops::Deref for InProcessConnection<S>
+{
+    type Target = serco::ServiceProxy<S, InProcessForwarder>;
+
+    fn deref( &self ) -> &Self::Target {
+        &self.proxy
+    }
+}
+
+/// Forwarder that invokes the target directly.
+pub struct InProcessForwarder {
+    target: Rc<DirectTarget>,
+}
+
+impl serco::Forwarder for InProcessForwarder
+{
+    fn forward<D, S>(
+        &self,
+        name: &'static str,
+        params: S
+    ) -> Box<Future<Item=D, Error=serco::ServiceError>>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static,
+    {
+        self.forward_call( serco::CallInfo::new( name ), params )
+    }
+
+    fn forward_call<D, S>(
+        &self,
+        call: serco::CallInfo,
+        params: S
+    ) -> Box<Future<Item=D, Error=serco::ServiceError>>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static,
+    {
+        // The call is invoked once the future is polled so the callback is
+        // set for the task that waits for the result.
+        let target = self.target.clone();
+        let name = call.name;
+        Box::new( future::lazy( move || target.invoke( name, Box::new( params ) ) )
+            .and_then( |result| match result.downcast::<D>() {
+                Ok( result ) => Ok( *result ),
+                Err( _ ) => Err( ServiceError::new(
+                        ErrorKind::Internal,
+                        "The operation returned an unexpected type" ) ),
+            } ) )
+    }
+
+    fn close( self ) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serco_derive::*;
+    use serde::{Serializer, Deserialize, Deserializer};
+    use std::cell::Cell;
+
+    /// Tally that panics if it is ever serialized.
+    pub struct Tally( Rc<Cell<i32>> );
+
+    impl Serialize for Tally {
+        fn serialize<S: Serializer>( &self, _: S ) -> Result<S::Ok, S::Error> {
+            panic!( "Tally was serialized" )
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Tally {
+        fn deserialize<D: Deserializer<'de>>( _: D ) -> Result<Self, D::Error> {
+            panic!( "Tally was deserialized" )
+        }
+    }
+
+    #[service_contract( callback = Listener )]
+    pub trait Counter {
+        fn count( &self, tally: Tally, amount: i32 ) -> Tally;
+        fn announce( &self, message: String ) -> String;
+    }
+
+    #[service_contract]
+    pub trait Listener {
+        fn hear( &self, message: String ) -> String;
+    }
+
+    #[test]
+    pub fn contract_id() {
+        assert_eq!( <Counter as serco::ServiceContract>::contract_name(), "Counter" );
+        assert_eq!( <Counter as serco::ServiceContract>::contract_id(),
+                    concat!( module_path!(), "::Counter" ) );
+    }
+
+    #[service(Counter)]
+    struct CounterService;
+    impl Counter for CounterService {
+        fn count( &self, tally: Tally, amount: i32 ) -> Tally {
+            tally.0.set( tally.0.get() + amount );
+            tally
+        }
+
+        fn announce( &self, message: String ) -> String {
+            let listener = Counter::get_callback();
+            format!( "< {} >", listener.hear( message ) )
+        }
+    }
+
+    #[service(Listener)]
+    struct LoudListener;
+    impl Listener for LoudListener {
+        fn hear( &self, message: String ) -> String {
+            message.to_uppercase()
+        }
+    }
+
+    #[service_contract( callback = Listener )]
+    pub trait Chat {
+        fn publish( &self, message: String );
+    }
+
+    #[service(Chat)]
+    struct ChatService( serco::ConnectedSessions<Listener> );
+    impl Chat for ChatService {
+        fn publish( &self, message: String ) {
+            self.0.broadcast( |_, listener| { listener.hear( message.clone() ); } );
+        }
+    }
+
+    #[service(Listener)]
+    struct RecordingListener( Rc<RefCell<Vec<String>>> );
+    impl Listener for RecordingListener {
+        fn hear( &self, message: String ) -> String {
+            self.0.borrow_mut().push( message.clone() );
+            message
+        }
+    }
+
+    #[test]
+    pub fn direct_calls() {
+        let _host = serco::ServiceHost::new( Counter::singleton( CounterService ) )
+                .endpoint( InProcessEndpoint::new( "direct_calls" ) )
+                .run();
+
+        let connection = InProcessClient::new( "direct_calls" )
+                .connect_duplex::<Counter, _, _>( LoudListener )
+                .wait()
+                .unwrap();
+
+        // The same tally is passed back and forth.
+        let tally = Rc::new( Cell::new( 1 ) );
+        let result = connection.count( Tally( tally.clone() ), 2 );
+        assert!( Rc::ptr_eq( &result.0, &tally ) );
+        assert_eq!( tally.get(), 3 );
+
+        assert_eq!( connection.announce( "hello".to_string() ), "< HELLO >" );
+    }
+
+    #[test]
+    pub fn host_lifetime() {
+        let host = serco::ServiceHost::new( Counter::singleton( CounterService ) )
+                .endpoint( InProcessEndpoint::new( "host_lifetime" ) )
+                .run();
+        assert!( InProcessClient::new( "host_lifetime" )
+                .connect_duplex::<Counter, _, _>( LoudListener )
+                .wait()
+                .is_ok() );
+
+        drop( host );
+        assert!( InProcessClient::new( "host_lifetime" )
+                .connect_duplex::<Counter, _, _>( LoudListener )
+                .wait()
+                .is_err() );
+    }
+
+    #[test]
+    pub fn broadcast() {
+        let sessions = serco::ConnectedSessions::new();
+        let _host = serco::ServiceHost::new( Chat::singleton( ChatService( sessions.clone() ) ) )
+                .connected_sessions( sessions.clone() )
+                .endpoint( InProcessEndpoint::new( "broadcast" ) )
+                .run();
+
+        let heard : Vec<_> = ( 0..3 ).map( |_| Rc::new( RefCell::new( vec![] ) ) ).collect();
+        let connections : Vec<_> = heard.iter()
+                .map( |heard| InProcessClient::new( "broadcast" )
+                        .connect_duplex::<Chat, _, _>( RecordingListener( heard.clone() ) )
+                        .wait()
+                        .unwrap() )
+                .collect();
+
+        // Each client is connected with a session of its own.
+        assert_eq!( sessions.len(), 3 );
+        connections[ 0 ].publish( "hello".to_string() );
+        for heard in &heard {
+            assert_eq!( *heard.borrow(), vec![ "hello".to_string() ] );
+        }
+
+        drop( connections );
+        assert!( sessions.is_empty() );
+    }
+}
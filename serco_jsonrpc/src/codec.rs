@@ -0,0 +1,238 @@
+//! Framing of the messages on the stream transports.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str;
+use std::thread;
+
+use bytes::{BytesMut, BufMut};
+use futures::prelude::*;
+use futures::sync::mpsc;
+use tokio::codec::{Decoder, Encoder};
+
+use serco::{ServiceError, ErrorKind, MessageLimits};
+
+/// How the messages are separated on a stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+
+    /// Each message is on its own line.
+    Lines,
+
+    /// Each message is preceded by a `Content-Length` header as in the
+    /// Language Server Protocol.
+    ContentLength,
+}
+
+/// Size of the `Content-Length` headers allowed before the end of the
+/// headers has been found.
+const MAX_HEADERS_SIZE : usize = 4096;
+
+/// Splits a stream into the messages.
+///
+/// The codec refuses the messages over the request size limit before
+/// buffering them in full. The errors refusing them are recovered with
+/// `message_error`.
+pub struct JsonRpcCodec {
+    framing: Framing,
+    limits: MessageLimits,
+}
+
+impl JsonRpcCodec {
+    pub fn new( framing: Framing ) -> Self {
+        JsonRpcCodec { framing: framing, limits: MessageLimits::new() }
+    }
+
+    /// Limits the size of the decoded messages.
+    pub fn limits( mut self, limits: MessageLimits ) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    fn check_size( &self, size: usize ) -> io::Result<()> {
+        self.limits.check_request_size( size ).map_err( |e| invalid_data( TooLarge( e ) ) )
+    }
+}
+
+fn invalid_data<T: Into<Box<Error + Send + Sync>>>( e: T ) -> io::Error {
+    io::Error::new( io::ErrorKind::InvalidData, e )
+}
+
+/// Error of a message over the limits carried through `io::Error`.
+#[derive(Debug)]
+struct TooLarge( ServiceError );
+
+impl fmt::Display for TooLarge {
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result {
+        write!( f, "{}", ( self.0 ).message )
+    }
+}
+
+impl Error for TooLarge {
+    fn description( &self ) -> &str {
+        &( self.0 ).message
+    }
+}
+
+/// Turns a decoding error into a service error. The messages over the
+/// limits fail with `MessageTooLarge` and the rest with `Transport`.
+pub fn message_error( e: io::Error ) -> ServiceError {
+    let too_large = e.get_ref()
+            .and_then( |inner| inner.downcast_ref::<TooLarge>() )
+            .map( |too_large| too_large.0.clone() );
+    too_large.unwrap_or_else( || ServiceError::transport( e ) )
+}
+
+impl Decoder for JsonRpcCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode( &mut self, buf: &mut BytesMut ) -> io::Result<Option<String>>
+    {
+        match self.framing {
+            Framing::Lines => loop {
+                let end = match buf.iter().position( |&b| b == b'\n' ) {
+                    Some( end ) => end,
+                    None => {
+                        self.check_size( buf.len() )?;
+                        return Ok( None );
+                    },
+                };
+                self.check_size( end )?;
+                let line = buf.split_to( end + 1 );
+                let text = str::from_utf8( &line[ ..end ] ).map_err( invalid_data )?.trim();
+
+                // Blank lines between the messages are allowed.
+                if !text.is_empty() {
+                    return Ok( Some( text.to_string() ) );
+                }
+            },
+            Framing::ContentLength => {
+                let end = match buf.windows( 4 ).position( |w| w == b"\r\n\r\n" ) {
+                    Some( end ) => end,
+                    None if buf.len() > MAX_HEADERS_SIZE =>
+                            return Err( invalid_data( "The headers are too long" ) ),
+                    None => return Ok( None ),
+                };
+                let length = {
+                    let headers = str::from_utf8( &buf[ ..end ] ).map_err( invalid_data )?;
+                    headers.split( "\r\n" )
+                        .filter_map( |header| {
+                            let mut parts = header.splitn( 2, ':' );
+                            match ( parts.next(), parts.next() ) {
+                                ( Some( name ), Some( value ) )
+                                    if name.trim().eq_ignore_ascii_case( "content-length" ) =>
+                                        Some( value.trim().parse::<usize>() ),
+                                _ => None,
+                            }
+                        } )
+                        .next()
+                        .ok_or_else( || invalid_data( "Missing the Content-Length header" ) )?
+                        .map_err( invalid_data )?
+                };
+                self.check_size( length )?;
+                if buf.len() < end + 4 + length {
+                    return Ok( None );
+                }
+                buf.split_to( end + 4 );
+                let body = buf.split_to( length );
+                Ok( Some( str::from_utf8( &body ).map_err( invalid_data )?.to_string() ) )
+            },
+        }
+    }
+}
+
+impl Encoder for JsonRpcCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn encode( &mut self, message: String, buf: &mut BytesMut ) -> io::Result<()>
+    {
+        let header = match self.framing {
+            Framing::Lines => String::new(),
+            Framing::ContentLength => format!( "Content-Length: {}\r\n\r\n", message.len() ),
+        };
+        buf.reserve( header.len() + message.len() + 1 );
+        buf.put_slice( header.as_bytes() );
+        buf.put_slice( message.as_bytes() );
+        if self.framing == Framing::Lines {
+            buf.put_u8( b'\n' );
+        }
+        Ok( () )
+    }
+}
+
+/// Reads the messages from a blocking stream.
+///
+/// Streams such as the standard input can't be read asynchronously so a
+/// thread reads them and passes the messages on. The messages end when the
+/// stream ends or can't be decoded. The messages over the limits of the
+/// codec end them with the error.
+pub fn read_messages<R>(
+    mut reader: R,
+    mut codec: JsonRpcCodec,
+) -> Box<Stream<Item=String, Error=ServiceError>>
+    where R: Read + Send + 'static
+{
+    let ( sender, receiver ) = mpsc::unbounded();
+    thread::spawn( move || {
+        let mut buffer = BytesMut::new();
+        let mut chunk = [ 0u8; 4096 ];
+        loop {
+            let read = match reader.read( &mut chunk ) {
+                Ok( 0 ) | Err( _ ) => return,
+                Ok( read ) => read,
+            };
+            buffer.extend_from_slice( &chunk[ ..read ] );
+            loop {
+                match codec.decode( &mut buffer ) {
+                    Ok( Some( message ) ) => if sender.unbounded_send( Ok( message ) ).is_err() {
+                        return;
+                    },
+                    Ok( None ) => break,
+                    Err( e ) => {
+                        let e = message_error( e );
+                        if e.kind == ErrorKind::MessageTooLarge {
+                            sender.unbounded_send( Err( e ) ).ok();
+                        }
+                        return;
+                    },
+                }
+            }
+        }
+    } );
+    Box::new( receiver
+        .map_err( |_| ServiceError::transport( "The reader stopped" ) )
+        .and_then( |message| message ) )
+}
+
+/// Writes the messages to a blocking stream such as the standard output.
+pub struct WriteSink<W> {
+    writer: W,
+    codec: JsonRpcCodec,
+}
+
+impl<W: Write> WriteSink<W> {
+    pub fn new( writer: W, framing: Framing ) -> Self {
+        WriteSink { writer: writer, codec: JsonRpcCodec::new( framing ) }
+    }
+}
+
+impl<W: Write> Sink for WriteSink<W> {
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send( &mut self, message: String ) -> StartSend<String, io::Error>
+    {
+        let mut buffer = BytesMut::new();
+        self.codec.encode( message, &mut buffer )?;
+        self.writer.write_all( &buffer )?;
+        self.writer.flush()?;
+        Ok( AsyncSink::Ready )
+    }
+
+    fn poll_complete( &mut self ) -> Poll<(), io::Error> {
+        Ok( Async::Ready( () ) )
+    }
+}
@@ -0,0 +1,981 @@
+//! JSON-RPC 2.0 endpoint for serco services.
+//!
+//! The `JsonRpcEndpoint` serves the contract operations as JSON-RPC methods
+//! over TCP, Unix domain sockets, the standard streams of the process or
+//! HTTP, so editors, scripts and other JSON-RPC clients can call serco
+//! hosts. See the `protocol` module for how the requests map to the
+//! operations.
+//!
+//! On the sockets and the standard streams each connection is a session and
+//! the messages are framed as configured with `framing`. On HTTP each
+//! request carries a single message in its body and the session is
+//! identified by the `X-Serco-Session` header as with the HTTP endpoint.
+//! Only the client that opened a session may resume it.
+//!
+//! The TCP connections are encrypted with TLS once configured with `tls`.
+//! The verified client certificates identify the sessions as described in
+//! `serco_tls`.
+//!
+//! The connections are authenticated with the authenticator of the host
+//! using the client certificates on TLS, the credentials of the peer process
+//! on Unix domain sockets and the `Authorization` header on HTTP. The
+//! standard streams are authenticated as anonymous. Rejected connections
+//! receive an error response with a null id and are closed.
+//!
+//! The messages are checked against the message limits of the host before
+//! they are parsed. The connections that send a request over the limits
+//! receive an error response with a null id and are closed. On HTTP the
+//! requests over the limits are refused with `413 Payload Too Large`.
+//!
+//! Streaming operations and duplex callbacks are not available through
+//! this endpoint. The `peer` module provides the connections on which the
+//! hosts call the clients back for the transports that support them.
+
+#![cfg_attr(test, feature(proc_macro))]
+
+extern crate futures;
+use futures::prelude::*;
+
+extern crate bytes;
+extern crate tokio;
+extern crate hyper;
+
+extern crate serco;
+use serco::{ServiceContract, ServiceError, ErrorKind, InvokeTarget, Credentials, MessageLimits};
+extern crate serco_tls;
+use serco_tls::TlsServer;
+extern crate serde;
+#[macro_use] extern crate serde_json;
+
+#[cfg(test)] #[macro_use] extern crate serde_derive;
+#[cfg(test)] extern crate serco_derive;
+
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)] use std::path::PathBuf;
+use std::rc::Rc;
+
+use hyper::{Method, StatusCode};
+use hyper::header::{ContentLength, ContentType};
+use hyper::server::{Http, Request, Response, Service};
+use tokio::codec::Decoder;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+pub mod codec;
+pub mod peer;
+pub mod protocol;
+
+pub use codec::{Framing, JsonRpcCodec, WriteSink};
+
+/// Kind under which the JSON-RPC endpoints are registered in service
+/// registries.
+pub const ENDPOINT_KIND : &str = "jsonrpc";
+
+/// Header carrying the session ID on HTTP.
+pub const SESSION_HEADER : &str = "X-Serco-Session";
+
+enum Transport {
+    Tcp( SocketAddr ),
+    #[cfg(unix)] Unix( PathBuf ),
+    Stdio,
+    Http( SocketAddr ),
+}
+
+pub struct JsonRpcEndpoint {
+    transport: Transport,
+    framing: Framing,
+    tls: Option<TlsServer>,
+}
+
+impl JsonRpcEndpoint {
+
+    /// Serves the clients connecting to the address.
+    pub fn tcp( address: SocketAddr ) -> Self {
+        JsonRpcEndpoint { transport: Transport::Tcp( address ), framing: Framing::Lines, tls: None }
+    }
+
+    /// Serves the clients connecting to the Unix domain socket at the path.
+    #[cfg(unix)]
+    pub fn unix<P: Into<PathBuf>>( path: P ) -> Self {
+        JsonRpcEndpoint { transport: Transport::Unix( path.into() ), framing: Framing::Lines, tls: None }
+    }
+
+    /// Serves the process that owns the standard streams.
+    pub fn stdio() -> Self {
+        JsonRpcEndpoint { transport: Transport::Stdio, framing: Framing::Lines, tls: None }
+    }
+
+    /// Serves the requests posted to the address.
+    pub fn http( address: SocketAddr ) -> Self {
+        JsonRpcEndpoint { transport: Transport::Http( address ), framing: Framing::Lines, tls: None }
+    }
+
+    /// Specifies how the messages are framed on the sockets and the
+    /// standard streams.
+    pub fn framing( mut self, framing: Framing ) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// Encrypts the TCP connections.
+    pub fn tls( mut self, tls: TlsServer ) -> Self {
+        self.tls = Some( tls );
+        self
+    }
+}
+
+impl<TService,
+        THostImplementation,
+        TSessionFactory>
+    serco::ServiceEndpoint<TService,
+        TSessionFactory,
+        THostImplementation,
+    >
+    for JsonRpcEndpoint
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+          THostImplementation::ServiceInstance: 'static,
+{
+    fn run(
+        &self,
+        host: Rc<serco::HostRuntime<
+                TService,
+                TSessionFactory,
+                THostImplementation,
+        >>
+    ) -> Box<Future<Item=(), Error=serco::ServiceError>>
+    {
+        match self.transport {
+            Transport::Tcp( ref address ) => run_tcp( address, self.framing, self.tls.as_ref(), host ),
+            #[cfg(unix)]
+            Transport::Unix( ref path ) => run_unix( path, self.framing, host ),
+            Transport::Stdio => run_stdio( self.framing, host ),
+            Transport::Http( ref address ) => run_http( address, host ),
+        }
+    }
+
+    fn address( &self ) -> Option<serco::registry::EndpointAddress> {
+        let address = match self.transport {
+            Transport::Tcp( ref address ) if self.tls.is_some() => format!( "tls://{}", address ),
+            Transport::Tcp( ref address ) => format!( "tcp://{}", address ),
+            #[cfg(unix)]
+            Transport::Unix( ref path ) => format!( "unix://{}", path.display() ),
+            Transport::Http( ref address ) => format!( "http://{}", address ),
+            Transport::Stdio => return None,
+        };
+        Some( serco::registry::EndpointAddress::new( ENDPOINT_KIND, address ) )
+    }
+}
+
+/// Serves the messages of a stream connection.
+///
+/// The requests are handled concurrently and the responses are sent in the
+/// order they complete. The connection ends after refusing a request over
+/// the limits.
+pub fn serve<C, T, St, Si>(
+    target: Rc<T>,
+    incoming: St,
+    outgoing: Si,
+    limits: MessageLimits,
+) -> Box<Future<Item=(), Error=ServiceError>>
+    where C: ServiceContract + ?Sized + 'static,
+          T: InvokeTarget<C> + ?Sized + 'static,
+          St: Stream<Item=String, Error=ServiceError> + 'static,
+          Si: Sink<SinkItem=String, SinkError=ServiceError> + 'static,
+{
+    let responses = protocol::check_requests( incoming, limits )
+        .map( move |request| protocol::handle_checked::<C, T>( target.clone(), request ) )
+        .buffer_unordered( usize::max_value() )
+        .filter_map( move |response| response.map( |r| protocol::encode_response( &r, &limits ) ) );
+
+    Box::new( outgoing.send_all( responses ).map( |_| () ) )
+}
+
+fn run_tcp<TService, TSessionFactory, THostImplementation>(
+    address: &SocketAddr,
+    framing: Framing,
+    tls: Option<&TlsServer>,
+    host: Rc<serco::HostRuntime<TService, TSessionFactory, THostImplementation>>,
+) -> Box<Future<Item=(), Error=ServiceError>>
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+          THostImplementation::ServiceInstance: 'static,
+{
+    let listener = match tokio::net::TcpListener::bind( address ) {
+        Ok( listener ) => listener,
+        Err( e ) => return Box::new( futures::future::err( ServiceError::transport( e ) ) ),
+    };
+
+    match tls.map( TlsServer::acceptor ) {
+        Some( Ok( acceptor ) ) => serve_sockets(
+                listener.incoming().map( move |socket| acceptor.accept( socket )
+                    .map( |( socket, identity )| ( socket, match identity {
+                        Some( identity ) => Credentials::Certificate( identity ),
+                        None => Credentials::Anonymous,
+                    } ) ) ),
+                framing, host ),
+        Some( Err( e ) ) => Box::new( futures::future::err( ServiceError::transport( e ) ) ),
+        None => serve_sockets(
+                listener.incoming().map( |socket|
+                        futures::future::ok( ( socket, Credentials::Anonymous ) ) ),
+                framing, host ),
+    }
+}
+
+#[cfg(unix)]
+fn run_unix<TService, TSessionFactory, THostImplementation>(
+    path: &PathBuf,
+    framing: Framing,
+    host: Rc<serco::HostRuntime<TService, TSessionFactory, THostImplementation>>,
+) -> Box<Future<Item=(), Error=ServiceError>>
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+          THostImplementation::ServiceInstance: 'static,
+{
+    match tokio::net::UnixListener::bind( path ) {
+        Ok( listener ) => serve_sockets(
+                listener.incoming().map( |socket| {
+                    let credentials = socket.peer_cred()
+                        .map( |cred| Credentials::Peer { pid: None, uid: cred.uid, gid: cred.gid } )
+                        .unwrap_or( Credentials::Anonymous );
+                    futures::future::ok( ( socket, credentials ) )
+                } ),
+                framing, host ),
+        Err( e ) => Box::new( futures::future::err( ServiceError::transport( e ) ) ),
+    }
+}
+
+/// Serves each accepted socket as a session of its own once the socket is
+/// ready, such as when its TLS handshake completes, and its credentials
+/// have been authenticated. The sockets over the connection limit of the
+/// endpoint are refused with an error message.
+fn serve_sockets<TService, TSessionFactory, THostImplementation, L, F, S>(
+    incoming: L,
+    framing: Framing,
+    host: Rc<serco::HostRuntime<TService, TSessionFactory, THostImplementation>>,
+) -> Box<Future<Item=(), Error=ServiceError>>
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+          THostImplementation::ServiceInstance: 'static,
+          L: Stream<Item=F, Error=io::Error> + 'static,
+          F: Future<Item=( S, Credentials ), Error=io::Error> + 'static,
+          S: AsyncRead + AsyncWrite + 'static,
+{
+    let limit = host.connection_limit();
+    let limits = *host.message_limits();
+    let metrics = host.endpoint_metrics( ENDPOINT_KIND );
+    Box::new( incoming
+        .map_err( ServiceError::transport )
+        .map( move |socket| {
+            let host = host.clone();
+            let metrics = metrics.clone();
+            let permit = limit.acquire();
+            socket.then( move |socket| -> Box<Future<Item=(), Error=ServiceError>> {
+
+                // Failures of single connections don't concern the endpoint.
+                let ( socket, credentials ) = match socket {
+                    Ok( socket ) => socket,
+                    Err( _ ) => return Box::new( futures::future::ok( () ) ),
+                };
+                let framed = JsonRpcCodec::new( framing ).limits( limits ).framed( socket );
+                let opened = permit.and_then( |permit| {
+                    host.open_session( &credentials ).map( |( _, session )| ( permit, session ) )
+                } );
+                let ( permit, session ) = match opened {
+                    Ok( opened ) => opened,
+                    Err( e ) => {
+                        let failure = protocol::failure( serde_json::Value::Null, &e ).to_string();
+                        return Box::new( framed.send( failure )
+                                .then( |_| Ok::<(), ServiceError>( () ) ) );
+                    },
+                };
+                let target = Rc::new( session );
+                let ( sink, stream ) = framed.split();
+                let sent = metrics.clone();
+
+                Box::new( serve::<TService, _, _, _>(
+                        target,
+                        stream.map_err( codec::message_error )
+                            .inspect( move |text| metrics.received( text.len() ) ),
+                        sink.sink_map_err( ServiceError::transport )
+                            .with( move |text: String| {
+                                sent.sent( text.len() );
+                                Ok::<_, ServiceError>( text )
+                            } ),
+                        limits )
+                    .then( move |_| {
+                        drop( permit );
+                        Ok::<(), ServiceError>( () )
+                    } ) )
+            } )
+        } )
+        // Serve all connections concurrently.
+        .buffer_unordered( usize::max_value() )
+        .for_each( |_| Ok( () ) ) )
+}
+
+fn run_stdio<TService, TSessionFactory, THostImplementation>(
+    framing: Framing,
+    host: Rc<serco::HostRuntime<TService, TSessionFactory, THostImplementation>>,
+) -> Box<Future<Item=(), Error=ServiceError>>
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+          THostImplementation::ServiceInstance: 'static,
+{
+    // The standard streams carry no credentials so the authenticator sees
+    // the session as anonymous.
+    let session = match host.open_session( &Credentials::Anonymous ) {
+        Ok( ( _, session ) ) => session,
+        Err( e ) => {
+            let failure = protocol::failure( serde_json::Value::Null, &e ).to_string();
+            return Box::new( WriteSink::new( io::stdout(), framing )
+                    .send( failure )
+                    .then( |_| Ok::<(), ServiceError>( () ) ) );
+        },
+    };
+    let target = Rc::new( session );
+    let limits = *host.message_limits();
+    let received = host.endpoint_metrics( ENDPOINT_KIND );
+    let sent = received.clone();
+    serve::<TService, _, _, _>(
+            target,
+            codec::read_messages( io::stdin(), JsonRpcCodec::new( framing ).limits( limits ) )
+                .inspect( move |text| received.received( text.len() ) ),
+            WriteSink::new( io::stdout(), framing ).sink_map_err( ServiceError::transport )
+                .with( move |text: String| {
+                    sent.sent( text.len() );
+                    Ok::<_, ServiceError>( text )
+                } ),
+            limits )
+}
+
+fn run_http<TService, TSessionFactory, THostImplementation>(
+    address: &SocketAddr,
+    host: Rc<serco::HostRuntime<TService, TSessionFactory, THostImplementation>>,
+) -> Box<Future<Item=(), Error=ServiceError>>
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+          THostImplementation::ServiceInstance: 'static,
+{
+    let listener = match tokio::net::TcpListener::bind( address ) {
+        Ok( listener ) => listener,
+        Err( e ) => return Box::new( futures::future::err( ServiceError::transport( e ) ) ),
+    };
+
+    let http = Http::<hyper::Chunk>::new();
+    let limit = host.connection_limit();
+    Box::new( listener.incoming()
+        .map_err( ServiceError::transport )
+        .map( move |socket| -> Box<Future<Item=(), Error=ServiceError>> {
+
+            // The connections over the limit are closed right away.
+            let permit = match limit.acquire() {
+                Ok( permit ) => permit,
+                Err( _ ) => return Box::new( futures::future::ok( () ) ),
+            };
+            let service = HttpService {
+                host: host.clone(),
+            };
+            Box::new( http.serve_connection( socket, service )
+                .then( move |_| {
+                    drop( permit );
+                    Ok::<(), ServiceError>( () )
+                } ) )
+        } )
+        .buffer_unordered( usize::max_value() )
+        .for_each( |_| Ok( () ) ) )
+}
+
+/// Reads the credentials from the `Authorization` header of the request.
+fn request_credentials( request: &Request ) -> Credentials {
+    request.headers().get_raw( "Authorization" )
+        .and_then( |raw| raw.one() )
+        .and_then( |value| std::str::from_utf8( value ).ok() )
+        .and_then( Credentials::from_authorization )
+        .unwrap_or( Credentials::Anonymous )
+}
+
+/// Serves the JSON-RPC messages posted over HTTP.
+struct HttpService<TService, TSessionFactory, THostImplementation>
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService> + 'static,
+{
+    host: Rc<serco::HostRuntime<TService, TSessionFactory, THostImplementation>>,
+}
+
+impl<TService, TSessionFactory, THostImplementation> Service
+    for HttpService<TService, TSessionFactory, THostImplementation>
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+          THostImplementation::ServiceInstance: 'static,
+{
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<Future<Item=Response, Error=hyper::Error>>;
+
+    fn call( &self, request: Request ) -> Self::Future
+    {
+        if *request.method() != Method::Post {
+            let e = ServiceError::new( ErrorKind::BadRequest, "JSON-RPC messages are posted" );
+            return Box::new( futures::future::ok( refusal( StatusCode::MethodNotAllowed, &e ) ) );
+        }
+
+        // The requests that tell their size up front are refused before
+        // they open a session.
+        let limits = *self.host.message_limits();
+        if let Some( &ContentLength( length ) ) = request.headers().get() {
+            if let Err( e ) = limits.check_request_size( length as usize ) {
+                return Box::new( futures::future::ok( refusal( StatusCode::PayloadTooLarge, &e ) ) );
+            }
+        }
+
+        let session_id = request.headers().get_raw( SESSION_HEADER )
+                .and_then( |raw| raw.one() )
+                .and_then( |value| std::str::from_utf8( value ).ok() )
+                .map( String::from );
+        let credentials = request_credentials( &request );
+
+        // Only the client that opened a session may resume it.
+        let session = match session_id {
+            Some( id ) => self.host.resume_session( &id, &credentials ).map( |target| ( id, target ) ),
+            None => self.host.open_resumable_session( &credentials ),
+        };
+        let ( session_id, target ) = match session {
+            Ok( session ) => session,
+            Err( e ) => return Box::new( futures::future::ok( refusal( match e.kind {
+                        ErrorKind::Throttled => StatusCode::TooManyRequests,
+                        ErrorKind::Unauthorized => StatusCode::Forbidden,
+                        ErrorKind::BadRequest => StatusCode::BadRequest,
+                        _ => StatusCode::Unauthorized,
+                    }, &e ) ) ),
+        };
+
+        // The bodies are read only up to the size limit.
+        let body = request.body()
+            .map_err( ServiceError::transport )
+            .fold( vec![], move |mut body, chunk| {
+                limits.check_request_size( body.len() + chunk.len() )?;
+                body.extend_from_slice( &chunk );
+                Ok::<_, ServiceError>( body )
+            } )
+            .and_then( move |body| limits.check_request( &body ).map( |_| body ) );
+
+        let metrics = self.host.endpoint_metrics( ENDPOINT_KIND );
+        Box::new( body.then( move |body| -> Self::Future {
+            let body = match body {
+                Ok( body ) => body,
+                Err( e ) => return Box::new( futures::future::ok( refusal( match e.kind {
+                        ErrorKind::MessageTooLarge => StatusCode::PayloadTooLarge,
+                        _ => StatusCode::BadRequest,
+                    }, &e ) ) ),
+            };
+            metrics.received( body.len() );
+            let text = String::from_utf8_lossy( &body ).into_owned();
+            Box::new( protocol::handle::<TService, _>( target, &text ).then( move |response| {
+                let mut response = match response {
+                    Ok( Some( response ) ) => {
+                        let text = protocol::encode_response( &response, &limits );
+                        metrics.sent( text.len() );
+                        json_response( StatusCode::Ok, text )
+                    },
+
+                    // Notifications get no content.
+                    _ => Response::new().with_status( StatusCode::NoContent ),
+                };
+                response.headers_mut().set_raw( SESSION_HEADER, session_id );
+                Ok( response )
+            } ) )
+        } ) )
+    }
+}
+
+/// Responds to a request that was refused before it was handled.
+fn refusal( status: StatusCode, e: &ServiceError ) -> Response {
+    json_response( status, protocol::failure( serde_json::Value::Null, e ).to_string() )
+}
+
+/// Creates a response with a JSON body. The length is sent up front so the
+/// body isn't chunked.
+fn json_response( status: StatusCode, text: String ) -> Response {
+    Response::new()
+        .with_status( status )
+        .with_header( ContentType::json() )
+        .with_header( ContentLength( text.len() as u64 ) )
+        .with_body( text )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serco_derive::*;
+    use serde_json::Value;
+    use bytes::BytesMut;
+    use tokio::codec::Encoder;
+    use tokio::runtime::current_thread::Runtime;
+    use serco::PeerIdentity;
+    use serco::auth::{AuthenticatedSession, PrincipalSessionFactory};
+    use serco_tls::TlsClient;
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    const CA : &[u8] = include_bytes!( "../../serco_tls/test-certs/ca.pem" );
+    const SERVER_CERT : &[u8] = include_bytes!( "../../serco_tls/test-certs/server.pem" );
+    const SERVER_KEY : &[u8] = include_bytes!( "../../serco_tls/test-certs/server.key" );
+    const CLIENT_CERT : &[u8] = include_bytes!( "../../serco_tls/test-certs/client.pem" );
+    const CLIENT_KEY : &[u8] = include_bytes!( "../../serco_tls/test-certs/client.key" );
+
+    #[service_contract]
+    pub trait Calculator {
+        fn add( &self, a: i32, b: i32 ) -> i32;
+        fn reset( &self );
+    }
+
+    #[service(Calculator)]
+    struct CalculatorService;
+    impl Calculator for CalculatorService {
+        fn add( &self, a: i32, b: i32 ) -> i32 { a + b }
+        fn reset( &self ) {}
+    }
+
+    #[service_contract]
+    pub trait Traced {
+        fn trace_id( &self ) -> Option<String>;
+    }
+
+    #[service(Traced)]
+    struct TracedService;
+    impl Traced for TracedService {
+        fn trace_id( &self ) -> Option<String> {
+            serco::trace::current().map( |context| context.trace_id )
+        }
+    }
+
+    fn handle( text: &str ) -> Option<Value> {
+        protocol::handle::<Calculator, _>( Rc::new( CalculatorService ), text )
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    pub fn requests() {
+        assert_eq!(
+            handle( r#"{"jsonrpc": "2.0", "id": 1, "method": "add", "params": {"a": 1, "b": 2}}"# ),
+            Some( json!({ "jsonrpc": "2.0", "id": 1, "result": 3 }) ) );
+        assert_eq!(
+            handle( r#"{"jsonrpc": "2.0", "id": "x", "method": "add", "params": [ 5, 6 ]}"# ),
+            Some( json!({ "jsonrpc": "2.0", "id": "x", "result": 11 }) ) );
+        assert_eq!(
+            handle( r#"{"jsonrpc": "2.0", "method": "reset"}"# ),
+            None );
+    }
+
+    #[test]
+    pub fn errors() {
+        let code = |text| handle( text ).unwrap()[ "error" ][ "code" ].clone();
+        assert_eq!( code( "{" ), json!( protocol::codes::PARSE_ERROR ) );
+        assert_eq!( code( r#"{"id": 1, "method": "add"}"# ),
+                    json!( protocol::codes::INVALID_REQUEST ) );
+        assert_eq!( code( r#"{"jsonrpc": "2.0", "id": 1, "method": "sub"}"# ),
+                    json!( protocol::codes::METHOD_NOT_FOUND ) );
+        assert_eq!( code( r#"{"jsonrpc": "2.0", "id": 1, "method": "add", "params": [ 1, 2, 3 ]}"# ),
+                    json!( protocol::codes::INVALID_PARAMS ) );
+        assert_eq!( code( "[]" ), json!( protocol::codes::INVALID_REQUEST ) );
+
+        let response = handle( r#"{"jsonrpc": "2.0", "id": 1, "method": "sub"}"# ).unwrap();
+        assert_eq!( protocol::service_error( &response[ "error" ] ).kind,
+                    ErrorKind::BadOperation );
+    }
+
+    #[test]
+    pub fn batches() {
+        assert_eq!(
+            handle( r#"[
+                {"jsonrpc": "2.0", "id": 1, "method": "add", "params": {"a": 1, "b": 1}},
+                {"jsonrpc": "2.0", "method": "reset"},
+                {"jsonrpc": "2.0", "id": 2, "method": "add", "params": {"a": 2, "b": 2}}
+            ]"# ),
+            Some( json!([
+                { "jsonrpc": "2.0", "id": 1, "result": 2 },
+                { "jsonrpc": "2.0", "id": 2, "result": 4 },
+            ]) ) );
+        assert_eq!( handle( r#"[ {"jsonrpc": "2.0", "method": "reset"} ]"# ), None );
+    }
+
+    #[test]
+    pub fn trace_context() {
+        let context = serco::TraceContext::root();
+        let request = serco::trace::with_context( Some( context.clone() ), || {
+            protocol::request( Some( Value::from( 1 ) ), "trace_id", json!({}) )
+        } );
+        assert_eq!( request[ "trace" ][ "trace_id" ], json!( context.trace_id ) );
+        assert_eq!( protocol::request( None, "trace_id", json!({}) ).get( "trace" ), None );
+
+        // The operation runs in a span of its own within the same trace.
+        let response = protocol::handle::<Traced, _>(
+                Rc::new( serco::trace::TracedTarget( TracedService ) ),
+                &request.to_string() )
+            .wait()
+            .unwrap()
+            .unwrap();
+        assert_eq!( response[ "result" ], json!( context.trace_id ) );
+    }
+
+    #[test]
+    pub fn framing() {
+        let mut codec = JsonRpcCodec::new( Framing::ContentLength );
+        let mut buffer = BytesMut::new();
+        codec.encode( "{}".to_string(), &mut buffer ).unwrap();
+        codec.encode( "[1]".to_string(), &mut buffer ).unwrap();
+        assert_eq!( &buffer[ ..], &b"Content-Length: 2\r\n\r\n{}Content-Length: 3\r\n\r\n[1]"[ .. ] );
+        assert_eq!( codec.decode( &mut buffer ).unwrap(), Some( "{}".to_string() ) );
+        assert_eq!( codec.decode( &mut buffer ).unwrap(), Some( "[1]".to_string() ) );
+        assert_eq!( codec.decode( &mut buffer ).unwrap(), None );
+
+        let mut codec = JsonRpcCodec::new( Framing::Lines );
+        let mut buffer = BytesMut::from( &b"{}\n\n[1]\n[2"[ .. ] );
+        assert_eq!( codec.decode( &mut buffer ).unwrap(), Some( "{}".to_string() ) );
+        assert_eq!( codec.decode( &mut buffer ).unwrap(), Some( "[1]".to_string() ) );
+        assert_eq!( codec.decode( &mut buffer ).unwrap(), None );
+    }
+
+    #[test]
+    pub fn limits() {
+        let limits = MessageLimits::new().max_request_size( 8 ).max_response_size( 64 );
+        let too_large = |e: io::Error| codec::message_error( e ).kind == ErrorKind::MessageTooLarge;
+
+        let mut codec = JsonRpcCodec::new( Framing::Lines ).limits( limits );
+        let mut buffer = BytesMut::from( &b"[1, 2]\n[1, 2, 3, 4]"[ .. ] );
+        assert_eq!( codec.decode( &mut buffer ).unwrap(), Some( "[1, 2]".to_string() ) );
+        assert!( too_large( codec.decode( &mut buffer ).unwrap_err() ) );
+
+        // The length is checked before the body arrives.
+        let mut codec = JsonRpcCodec::new( Framing::ContentLength ).limits( limits );
+        let mut buffer = BytesMut::from( &b"Content-Length: 100\r\n\r\n"[ .. ] );
+        assert!( too_large( codec.decode( &mut buffer ).unwrap_err() ) );
+
+        let response = protocol::success( json!( 1 ), json!( "ok" ) );
+        assert_eq!( protocol::encode_response( &response, &limits ), response.to_string() );
+        let response = protocol::success( json!( 1 ), json!( "x".repeat( 64 ) ) );
+        let refused : Value = serde_json::from_str(
+                &protocol::encode_response( &response, &limits ) ).unwrap();
+        assert_eq!( refused[ "id" ], json!( 1 ) );
+        assert_eq!( protocol::service_error( &refused[ "error" ] ).kind,
+                    ErrorKind::MessageTooLarge );
+    }
+
+    /// Records the names of the peers the sessions are created for.
+    struct IdentityFactory( Arc<Mutex<Vec<Option<String>>>> );
+    impl serco::SessionFactory for IdentityFactory {
+        type SessionInfo = serco::SessionId;
+        fn create_session( &self ) -> ( String, Rc<serco::SessionId> ) {
+            self.0.lock().unwrap().push( None );
+            ( String::new(), Rc::new( serco::SessionId( String::new() ) ) )
+        }
+        fn get_session( &self, key: &str ) -> Rc<serco::SessionId> {
+            Rc::new( serco::SessionId( key.to_string() ) )
+        }
+        fn create_peer_session( &self, identity: &PeerIdentity ) -> ( String, Rc<serco::SessionId> ) {
+            self.0.lock().unwrap().push( identity.name.clone() );
+            ( String::new(), Rc::new( serco::SessionId( String::new() ) ) )
+        }
+    }
+
+    #[test]
+    pub fn tls() {
+        let names = Arc::new( Mutex::new( vec![] ) );
+        let factory = IdentityFactory( names.clone() );
+        let address : SocketAddr = "127.0.0.1:50581".parse().unwrap();
+        thread::spawn( move || {
+            let server = TlsServer::from_pem( SERVER_CERT, SERVER_KEY ).unwrap()
+                    .client_certificates( CA ).unwrap();
+            let host = serco::ServiceHost::new( Calculator::singleton( CalculatorService ) )
+                    .session_factory( factory )
+                    .endpoint( JsonRpcEndpoint::tcp( address ).tls( server ) )
+                    .run();
+            Runtime::new().unwrap().block_on( host ).ok();
+        } );
+        thread::sleep( Duration::from_millis( 100 ) );
+
+        let call = |client: TlsClient| {
+            let request = tokio::net::TcpStream::connect( &address )
+                .and_then( move |socket| client.connect( "localhost", socket ) )
+                .and_then( |stream| tokio::io::write_all(
+                        stream, &b"{\"jsonrpc\": \"2.0\", \"id\": 1, \"method\": \"add\", \"params\": [1, 2]}\n"[ .. ] ) )
+                .and_then( |( stream, _ )| tokio::io::read_until(
+                        io::BufReader::new( stream ), b'\n', vec![] ) )
+                .map( |( _, line )| serde_json::from_slice::<Value>( &line ).unwrap() );
+            Runtime::new().unwrap().block_on( request ).unwrap()
+        };
+
+        let client = TlsClient::new( CA ).unwrap();
+        assert_eq!( call( client.clone() )[ "result" ], json!( 3 ) );
+        let client = client.client_certificate( CLIENT_CERT, CLIENT_KEY ).unwrap();
+        assert_eq!( call( client )[ "result" ], json!( 3 ) );
+
+        assert_eq!( *names.lock().unwrap(), vec![ None, Some( "alice".to_string() ) ] );
+    }
+
+    #[service_contract]
+    pub trait Account {
+        fn name( &self ) -> String;
+
+        #[authorize(role = "admin")]
+        fn close( &self ) -> bool;
+
+        #[authorize(policy = "is_alice")]
+        fn audit( &self ) -> bool;
+
+        fn deposit( &self ) -> bool;
+    }
+
+    fn is_alice( principal: &serco::Principal ) -> bool { principal.name == "alice" }
+
+    #[service(Account)]
+    struct AccountService {
+        session: Rc<AuthenticatedSession>,
+    }
+    #[service]
+    impl Account for AccountService {
+        fn name( &self ) -> String {
+            self.session.principal.as_ref().map( |p| p.name.clone() ).unwrap_or_default()
+        }
+
+        fn close( &self ) -> bool { true }
+        fn audit( &self ) -> bool { true }
+
+        // Authorized by the implementation instead of the contract.
+        #[authorize(role = "admin")]
+        fn deposit( &self ) -> bool { true }
+    }
+    impl serco::SessionService<Account> for AccountService {
+        type SessionInfo = AuthenticatedSession;
+        fn construct( session: Rc<AuthenticatedSession> ) -> Box<Account> {
+            Box::new( AccountService { session: session } )
+        }
+    }
+
+    /// Posts a request for an account operation and returns the status line
+    /// along with the response body.
+    fn post( address: &SocketAddr, method: &str, headers: &str ) -> ( String, Value ) {
+        let body = json!({ "jsonrpc": "2.0", "id": 1, "method": method }).to_string();
+        post_message( address, &body, headers )
+    }
+
+    fn post_message( address: &SocketAddr, body: &str, headers: &str ) -> ( String, Value ) {
+        let ( status, _, body ) = exchange( address, body, headers );
+        ( status, body )
+    }
+
+    /// Posts a message and returns the status line, the session ID and the
+    /// response body.
+    fn exchange( address: &SocketAddr, body: &str, headers: &str ) -> ( String, Option<String>, Value ) {
+        let mut stream = std::net::TcpStream::connect( address ).unwrap();
+        write!( stream, "POST / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+                Content-Length: {}\r\n{}\r\n{}", body.len(), headers, body ).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string( &mut response ).unwrap();
+        let status = response.lines().next().unwrap().to_string();
+        let session = response.lines()
+                .find( |line| line.to_lowercase().starts_with( "x-serco-session:" ) )
+                .map( |line| line[ line.find( ':' ).unwrap() + 1.. ].trim().to_string() );
+        let body = &response[ response.find( "\r\n\r\n" ).unwrap() + 4.. ];
+        ( status, session, serde_json::from_str( body ).unwrap() )
+    }
+
+    #[test]
+    pub fn authentication() {
+        let address : SocketAddr = "127.0.0.1:50582".parse().unwrap();
+        thread::spawn( move || {
+            let host = serco::ServiceHost::new( Account::session::<AccountService>() )
+                    .session_factory( PrincipalSessionFactory::new() )
+                    .authenticator( |credentials: &Credentials| match *credentials {
+                        Credentials::Token( ref token ) if token == "secret" =>
+                                Ok( serco::Principal::new( "alice" ) ),
+                        _ => Err( ServiceError::unauthenticated( "Unknown token" ) ),
+                    } )
+                    .endpoint( JsonRpcEndpoint::http( address ) )
+                    .run();
+            Runtime::new().unwrap().block_on( host ).ok();
+        } );
+        thread::sleep( Duration::from_millis( 100 ) );
+
+        let ( status, response ) = post( &address, "name", "" );
+        assert!( status.contains( "401" ) );
+        assert_eq!( protocol::service_error( &response[ "error" ] ).kind,
+                    ErrorKind::Unauthenticated );
+
+        let ( status, _ ) = post( &address, "name", "Authorization: Bearer guess\r\n" );
+        assert!( status.contains( "401" ) );
+
+        let ( status, response ) = post( &address, "name", "Authorization: Bearer secret\r\n" );
+        assert!( status.contains( "200" ) );
+        assert_eq!( response[ "result" ], json!( "alice" ) );
+    }
+
+    #[test]
+    pub fn authorization() {
+        let address : SocketAddr = "127.0.0.1:50583".parse().unwrap();
+        thread::spawn( move || {
+            let host = serco::ServiceHost::new( Account::session::<AccountService>() )
+                    .session_factory( PrincipalSessionFactory::new() )
+                    .authenticator( |credentials: &Credentials| match *credentials {
+                        Credentials::Token( ref token ) if token == "alice" =>
+                                Ok( serco::Principal::new( "alice" ) ),
+                        Credentials::Token( ref token ) if token == "bob" =>
+                                Ok( serco::Principal::new( "bob" ).role( "admin" ) ),
+                        _ => Err( ServiceError::unauthenticated( "Unknown token" ) ),
+                    } )
+                    .endpoint( JsonRpcEndpoint::http( address ) )
+                    .run();
+            Runtime::new().unwrap().block_on( host ).ok();
+        } );
+        thread::sleep( Duration::from_millis( 100 ) );
+
+        let alice = "Authorization: Bearer alice\r\n";
+        let bob = "Authorization: Bearer bob\r\n";
+        let unauthorized = |response: Value| protocol::service_error( &response[ "error" ] ).kind
+                == ErrorKind::Unauthorized;
+
+        assert!( unauthorized( post( &address, "close", alice ).1 ) );
+        assert_eq!( post( &address, "close", bob ).1[ "result" ], json!( true ) );
+        assert_eq!( post( &address, "audit", alice ).1[ "result" ], json!( true ) );
+        assert!( unauthorized( post( &address, "audit", bob ).1 ) );
+        assert!( unauthorized( post( &address, "deposit", alice ).1 ) );
+        assert_eq!( post( &address, "deposit", bob ).1[ "result" ], json!( true ) );
+        assert_eq!( post( &address, "name", bob ).1[ "result" ], json!( "bob" ) );
+    }
+
+    #[test]
+    pub fn throttling() {
+        let address : SocketAddr = "127.0.0.1:50584".parse().unwrap();
+        thread::spawn( move || {
+            let host = serco::ServiceHost::new( Account::session::<AccountService>() )
+                    .session_factory( PrincipalSessionFactory::new() )
+                    .throttling( serco::Throttling::new().max_concurrent_sessions( 1 ) )
+                    .endpoint( JsonRpcEndpoint::http( address ) )
+                    .run();
+            Runtime::new().unwrap().block_on( host ).ok();
+        } );
+        thread::sleep( Duration::from_millis( 100 ) );
+
+        // The HTTP sessions stay open so the second one is over the limit.
+        let ( status, response ) = post( &address, "name", "" );
+        assert!( status.contains( "200" ) );
+        assert_eq!( response[ "result" ], json!( "" ) );
+
+        let ( status, response ) = post( &address, "name", "" );
+        assert!( status.contains( "429" ) );
+        assert_eq!( protocol::service_error( &response[ "error" ] ).kind,
+                    ErrorKind::Throttled );
+    }
+
+    #[test]
+    pub fn session_timeout() {
+        let address : SocketAddr = "127.0.0.1:50587".parse().unwrap();
+        thread::spawn( move || {
+            let host = serco::ServiceHost::new( Account::session::<AccountService>() )
+                    .session_factory( PrincipalSessionFactory::new() )
+                    .throttling( serco::Throttling::new().max_concurrent_sessions( 1 ) )
+                    .session_timeout( Duration::from_millis( 100 ) )
+                    .endpoint( JsonRpcEndpoint::http( address ) )
+                    .run();
+            Runtime::new().unwrap().block_on( host ).ok();
+        } );
+        thread::sleep( Duration::from_millis( 100 ) );
+
+        let body = json!({ "jsonrpc": "2.0", "id": 1, "method": "name" }).to_string();
+        let ( status, session, _ ) = exchange( &address, &body, "" );
+        assert!( status.contains( "200" ) );
+        let resume = format!( "{}: {}\r\n", SESSION_HEADER, session.unwrap() );
+        assert!( post( &address, "name", &resume ).0.contains( "200" ) );
+        assert!( post( &address, "name", "" ).0.contains( "429" ) );
+
+        // The expired session gives its permit to the next one.
+        thread::sleep( Duration::from_millis( 200 ) );
+        assert!( post( &address, "name", "" ).0.contains( "200" ) );
+        assert!( post( &address, "name", &resume ).0.contains( "400" ) );
+    }
+
+    #[test]
+    pub fn message_limits() {
+        let address : SocketAddr = "127.0.0.1:50585".parse().unwrap();
+        thread::spawn( move || {
+            let host = serco::ServiceHost::new( Account::session::<AccountService>() )
+                    .session_factory( PrincipalSessionFactory::new() )
+                    .message_limits( MessageLimits::new().max_request_size( 100 ).max_depth( 3 ) )
+                    .endpoint( JsonRpcEndpoint::http( address ) )
+                    .run();
+            Runtime::new().unwrap().block_on( host ).ok();
+        } );
+        thread::sleep( Duration::from_millis( 100 ) );
+
+        let too_large = |( status, response ): ( String, Value )| status.contains( "413" ) &&
+                protocol::service_error( &response[ "error" ] ).kind == ErrorKind::MessageTooLarge;
+
+        assert_eq!( post( &address, "name", "" ).1[ "result" ], json!( "" ) );
+
+        let nested = json!({ "jsonrpc": "2.0", "id": 1, "method": "name", "params": [ [ [ 1 ] ] ] });
+        assert!( too_large( post_message( &address, &nested.to_string(), "" ) ) );
+
+        let long = json!({ "jsonrpc": "2.0", "id": 1, "method": "name", "params": [ "x".repeat( 100 ) ] });
+        assert!( too_large( post_message( &address, &long.to_string(), "" ) ) );
+    }
+
+    #[test]
+    pub fn session_resume() {
+        let address : SocketAddr = "127.0.0.1:50586".parse().unwrap();
+        thread::spawn( move || {
+            let host = serco::ServiceHost::new( Account::session::<AccountService>() )
+                    .session_factory( PrincipalSessionFactory::new() )
+                    .authenticator( |credentials: &Credentials| match *credentials {
+                        Credentials::Token( ref token ) if token == "alice" || token == "bob" =>
+                                Ok( serco::Principal::new( token.as_str() ) ),
+                        _ => Err( ServiceError::unauthenticated( "Unknown token" ) ),
+                    } )
+                    .endpoint( JsonRpcEndpoint::http( address ) )
+                    .run();
+            Runtime::new().unwrap().block_on( host ).ok();
+        } );
+        thread::sleep( Duration::from_millis( 100 ) );
+
+        let alice = "Authorization: Bearer alice\r\n";
+        let body = json!({ "jsonrpc": "2.0", "id": 1, "method": "name" }).to_string();
+        let ( status, session, _ ) = exchange( &address, &body, alice );
+        assert!( status.contains( "200" ) );
+        let session = session.unwrap();
+
+        // Only the client that opened the session may resume it.
+        let resume = |headers: &str| post( &address, "name",
+                &format!( "{}: {}\r\n{}", SESSION_HEADER, session, headers ) );
+        let ( status, response ) = resume( alice );
+        assert!( status.contains( "200" ) );
+        assert_eq!( response[ "result" ], json!( "alice" ) );
+
+        let ( status, response ) = resume( "Authorization: Bearer bob\r\n" );
+        assert!( status.contains( "403" ) );
+        assert_eq!( protocol::service_error( &response[ "error" ] ).kind,
+                    ErrorKind::Unauthorized );
+
+        let ( status, _ ) = resume( "" );
+        assert!( status.contains( "401" ) );
+
+        // Unknown sessions are refused rather than created.
+        let ( status, response ) = post( &address, "name",
+                &format!( "{}: unknown\r\n{}", SESSION_HEADER, alice ) );
+        assert!( status.contains( "400" ) );
+        assert_eq!( protocol::service_error( &response[ "error" ] ).kind,
+                    ErrorKind::BadRequest );
+    }
+}
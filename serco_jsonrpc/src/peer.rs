@@ -0,0 +1,430 @@
+//! Connections on which both ends make calls.
+//!
+//! The hosts call the operations of the callback contract on the clients
+//! over the same connection the clients use to call the hosts. The requests
+//! of both ends and the responses to them are interleaved on the
+//! connection. The responses are told apart from the requests by their lack
+//! of a `method` and matched to the calls by their ids.
+//!
+//! The service implementations and the client proxies block while they
+//! wait for the responses so the transports are served on threads of their
+//! own with `Link::run`.
+//!
+//! The hosts refuse the connections whose credentials the authenticator
+//! rejects with an error response that has no id before closing them. The
+//! calls on a refused connection fail with the error. The connections that
+//! send a request over the message limits of the host are closed in the
+//! same way.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use futures::prelude::*;
+use futures::future;
+use futures::sync::{mpsc, oneshot};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+use serco::{self, ServiceContract, ServiceError, InvokeTarget, Credentials, MessageLimits};
+use serco::metrics::EndpointMetrics;
+
+use protocol;
+
+/// Calls waiting for their responses by their request IDs.
+type Pending = Arc<Mutex<Calls>>;
+
+#[derive(Default)]
+struct Calls {
+    waiting: HashMap<u64, oneshot::Sender<Result<Value, ServiceError>>>,
+
+    /// Error the peer refused the connection with.
+    refused: Option<ServiceError>,
+}
+
+/// The side of a connection used by the host or the client.
+pub struct Connection {
+
+    /// Requests received from the peer.
+    pub requests: mpsc::UnboundedReceiver<String>,
+
+    /// Forwarder for calling the peer. The connection is closed once the
+    /// forwarder is dropped.
+    pub forwarder: PeerForwarder,
+
+    /// Credentials the peer presented to the transport. The host
+    /// authenticates them before creating the session of the connection.
+    pub credentials: Credentials,
+}
+
+/// The side of a connection that is served on the transport thread.
+pub struct Link {
+    requests: mpsc::UnboundedSender<String>,
+    outgoing: mpsc::UnboundedReceiver<String>,
+    closed: oneshot::Receiver<()>,
+    pending: Pending,
+    limits: MessageLimits,
+    metrics: EndpointMetrics,
+}
+
+/// Creates a connection and the link that passes its messages to the
+/// transport.
+pub fn connection() -> ( Connection, Link )
+{
+    let ( requests_tx, requests_rx ) = mpsc::unbounded();
+    let ( outgoing_tx, outgoing_rx ) = mpsc::unbounded();
+    let ( closed_tx, closed_rx ) = oneshot::channel();
+    let pending = Pending::default();
+
+    let link = Link {
+        requests: requests_tx,
+        outgoing: outgoing_rx,
+        closed: closed_rx,
+        pending: pending.clone(),
+        limits: MessageLimits::new(),
+        metrics: EndpointMetrics::new( None, "" ),
+    };
+    let connection = Connection {
+        requests: requests_rx,
+        forwarder: PeerForwarder {
+            outgoing: outgoing_tx,
+            pending: pending,
+            next_id: Arc::new( AtomicUsize::new( 1 ) ),
+            _closed: closed_tx,
+        },
+        credentials: Credentials::Anonymous,
+    };
+    ( connection, link )
+}
+
+impl Link {
+
+    /// Limits the messages received from the peer. The messages over the
+    /// limits aren't parsed by the link but passed on as requests for the
+    /// host to refuse.
+    pub fn message_limits( mut self, limits: MessageLimits ) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Counts the bytes of the messages passed over the transport.
+    pub fn metrics( mut self, metrics: EndpointMetrics ) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Passes the messages between the transport and the connection until
+    /// either the transport or the connection closes.
+    ///
+    /// The calls still waiting for their responses fail once the link is
+    /// done.
+    pub fn run<St, Si>(
+        self,
+        incoming: St,
+        outgoing: Si,
+    ) -> Box<Future<Item=(), Error=()>>
+        where St: Stream<Item=String> + 'static,
+              Si: Sink<SinkItem=String> + 'static,
+    {
+        let Link { requests, outgoing: messages, closed, pending, limits, metrics } = self;
+
+        let reader_pending = pending.clone();
+        let reader_metrics = metrics.clone();
+        let reader = incoming
+            .map_err( |_| () )
+            .for_each( move |text| {
+                reader_metrics.received( text.len() );
+                if limits.check_request( text.as_bytes() ).is_err() {
+                    requests.unbounded_send( text ).ok();
+                    return Ok( () );
+                }
+                receive( text, &requests, &reader_pending );
+                Ok( () )
+            } );
+        let writer = Outgoing { messages: messages, closed: closed }
+            .inspect( move |text| metrics.sent( text.len() ) )
+            .forward( outgoing.sink_map_err( |_| () ) )
+            .map( |_| () );
+
+        Box::new( reader.select( writer )
+            .then( move |result| {
+
+                // Release the transport before failing the calls that are
+                // still waiting so no new calls are made on it.
+                drop( result );
+                pending.lock().unwrap().waiting.clear();
+                Ok::<(), ()>( () )
+            } ) )
+    }
+}
+
+/// Messages to the peer. The messages end once the connection is closed,
+/// after the ones sent before closing it, such as the error refusing the
+/// connection.
+struct Outgoing {
+    messages: mpsc::UnboundedReceiver<String>,
+    closed: oneshot::Receiver<()>,
+}
+
+impl Stream for Outgoing {
+    type Item = String;
+    type Error = ();
+
+    fn poll( &mut self ) -> Poll<Option<String>, ()> {
+        match self.closed.poll() {
+            Ok( Async::NotReady ) => {},
+            _ => self.messages.close(),
+        }
+        self.messages.poll()
+    }
+}
+
+/// Routes a message received from the peer.
+///
+/// The responses complete the calls waiting for them. The errors without an
+/// id refuse the connection. Everything else is passed on as a request,
+/// including the messages that can't be parsed so the error is reported to
+/// the peer.
+fn receive(
+    text: String,
+    requests: &mpsc::UnboundedSender<String>,
+    pending: &Pending,
+) {
+    let response = match serde_json::from_str::<Value>( &text ) {
+        Ok( Value::Object( message ) ) => if message.contains_key( "method" ) {
+            None
+        } else {
+            Some( message )
+        },
+        _ => None,
+    };
+
+    let response = match response {
+        Some( response ) => response,
+        None => {
+            requests.unbounded_send( text ).ok();
+            return;
+        },
+    };
+
+    let id = response.get( "id" ).cloned().unwrap_or( Value::Null );
+    if let ( &Value::Null, Some( error ) ) = ( &id, response.get( "error" ) ) {
+        let error = protocol::service_error( error );
+        let mut pending = pending.lock().unwrap();
+        for ( _, call ) in pending.waiting.drain() {
+            call.send( Err( error.clone() ) ).ok();
+        }
+        pending.refused = Some( error );
+        return;
+    }
+
+    // Responses to calls that aren't waiting anymore are dropped.
+    let call = id.as_u64().and_then( |id| pending.lock().unwrap().waiting.remove( &id ) );
+    if let Some( call ) = call {
+        let result = match response.get( "error" ) {
+            Some( error ) => Err( protocol::service_error( error ) ),
+            None => Ok( response.get( "result" ).cloned().unwrap_or( Value::Null ) ),
+        };
+        call.send( result ).ok();
+    }
+}
+
+/// Serves the requests of a connection on the host.
+///
+/// Each connection is a session. The operations call the peer back through
+/// the same connection. The connections over the limit of the endpoint are
+/// refused like the ones that fail to authenticate. The connection is
+/// closed after refusing a request over the message limits.
+pub fn serve_session<TService, TSessionFactory, THostImplementation>(
+    host: &Rc<serco::HostRuntime<TService, TSessionFactory, THostImplementation>>,
+    connection: Connection,
+    limit: &serco::throttle::ConnectionLimit,
+) -> Box<Future<Item=(), Error=ServiceError>>
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+          THostImplementation::ServiceInstance: 'static,
+{
+    let Connection { requests, forwarder, credentials } = connection;
+    let opened = limit.acquire().and_then( |permit| {
+        host.open_session( &credentials ).map( |session| ( permit, session ) )
+    } );
+    let ( permit, ( session_id, session ) ) = match opened {
+        Ok( opened ) => opened,
+        Err( e ) => {
+
+            // Dropping the forwarder closes the connection once the error
+            // has been sent.
+            forwarder.sender()
+                .unbounded_send( protocol::failure( Value::Null, &e ).to_string() )
+                .ok();
+            return Box::new( future::ok( () ) );
+        },
+    };
+    let target = Rc::new( session );
+
+    let outgoing = forwarder.sender();
+    let callback = Arc::new( serco::ServiceProxy::new( forwarder ) );
+    host.connect_session( &session_id, callback.clone() );
+
+    let limits = *host.message_limits();
+    let requests = requests.map_err( |_| ServiceError::transport( "The transport stopped" ) );
+    let responses = protocol::check_requests( requests, limits )
+        .map( move |request| {
+            TService::CallbackContract::set_task_callback( callback.clone() );
+            protocol::handle_checked::<TService, _>( target.clone(), request )
+        } )
+        .buffer_unordered( usize::max_value() )
+        .filter_map( move |response| response.map( |r| protocol::encode_response( &r, &limits ) ) );
+
+    let host = host.clone();
+    Box::new( outgoing.sink_map_err( ServiceError::transport )
+        .send_all( responses )
+        // Failures of single connections don't concern the endpoint.
+        .then( move |_| {
+            host.disconnect_session( &session_id );
+            drop( permit );
+            Ok::<(), ServiceError>( () )
+        } ) )
+}
+
+/// Spawns the thread that handles the callbacks from the host.
+pub fn spawn_callback_handler<C, T>(
+    callback: T,
+    requests: mpsc::UnboundedReceiver<String>,
+    outgoing: mpsc::UnboundedSender<String>,
+) -> thread::JoinHandle<()>
+    where C: ServiceContract + ?Sized + 'static,
+          T: InvokeTarget<C> + Send + 'static,
+{
+    thread::spawn( move || {
+        ::serve::<C, _, _, _>(
+                Rc::new( serco::trace::TracedTarget( callback ) ),
+                requests.map_err( |_| ServiceError::transport( "The transport stopped" ) ),
+                outgoing.sink_map_err( ServiceError::transport ),
+                serco::MessageLimits::new() )
+            .wait()
+            .ok();
+    } )
+}
+
+/// Forwarder that calls the peer at the other end of a connection.
+///
+/// The hosts use the forwarder to call the clients back while the clients
+/// use it to call the hosts.
+pub struct PeerForwarder {
+    outgoing: mpsc::UnboundedSender<String>,
+    pending: Pending,
+    next_id: Arc<AtomicUsize>,
+    _closed: oneshot::Sender<()>,
+}
+
+impl PeerForwarder {
+
+    /// Sender for the messages to the peer, such as the responses to its
+    /// requests.
+    pub fn sender( &self ) -> mpsc::UnboundedSender<String> {
+        self.outgoing.clone()
+    }
+}
+
+impl serco::Forwarder for PeerForwarder
+{
+    fn forward<D, S>(
+        &self,
+        name: &'static str,
+        params: S
+    ) -> Box<Future<Item=D, Error=serco::ServiceError>>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static,
+    {
+        self.forward_call( serco::CallInfo::new( name ), params )
+    }
+
+    fn forward_call<D, S>(
+        &self,
+        call: serco::CallInfo,
+        params: S
+    ) -> Box<Future<Item=D, Error=serco::ServiceError>>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static,
+    {
+        let params = match serde_json::to_value( params ) {
+            Ok( params ) => params,
+            Err( e ) => return Box::new( future::err( ServiceError::from( e ) ) ),
+        };
+
+        // The call is registered before it is sent as the response may
+        // arrive on the transport thread before this thread gets to wait for
+        // it.
+        let id = self.next_id.fetch_add( 1, Ordering::SeqCst ) as u64;
+        let ( tx, rx ) = oneshot::channel();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if let Some( ref e ) = pending.refused {
+                return Box::new( future::err( e.clone() ) );
+            }
+            pending.waiting.insert( id, tx );
+        }
+
+        let request = protocol::request( Some( Value::from( id ) ), call.name, params );
+        if self.outgoing.unbounded_send( request.to_string() ).is_err() {
+            self.pending.lock().unwrap().waiting.remove( &id );
+            return Box::new( future::err( ServiceError::transport( "The connection is closed" ) ) );
+        }
+
+        Box::new( rx
+            .map_err( |_| ServiceError::transport( "The connection closed before the response" ) )
+            .and_then( |result| result )
+            .and_then( |value| serde_json::from_value( value ).map_err( ServiceError::from ) ) )
+    }
+
+    fn close( self ) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serco::Forwarder;
+
+    #[test]
+    pub fn routing() {
+        let ( connection, link ) = connection();
+        let Connection { requests, forwarder, .. } = connection;
+
+        let call = forwarder.forward::<i32, _>( "add", json!({ "a": 1, "b": 2 }) );
+        for message in &[
+            r#"{"jsonrpc": "2.0", "id": 1, "result": 3}"#,
+            r#"{"jsonrpc": "2.0", "id": 5, "method": "hear", "params": {}}"#,
+            r#"{"jsonrpc": "2.0", "id": 9, "result": 3}"#,
+        ] {
+            receive( message.to_string(), &link.requests, &link.pending );
+        }
+        drop( link );
+
+        assert_eq!( call.wait().unwrap(), 3 );
+        let requests : Vec<String> = requests.collect().wait().unwrap();
+        assert_eq!( requests.len(), 1 );
+        assert!( requests[ 0 ].contains( "hear" ) );
+    }
+
+    #[test]
+    pub fn refused() {
+        let ( connection, link ) = connection();
+        let forwarder = connection.forwarder;
+
+        let call = forwarder.forward::<i32, _>( "add", json!({ "a": 1, "b": 2 }) );
+        let error = ServiceError::unauthenticated( "Unknown token" );
+        receive( protocol::failure( Value::Null, &error ).to_string(),
+                 &link.requests, &link.pending );
+
+        assert_eq!( call.wait().unwrap_err().kind, serco::ErrorKind::Unauthenticated );
+        let call = forwarder.forward::<i32, _>( "add", json!({ "a": 1, "b": 2 }) );
+        assert_eq!( call.wait().unwrap_err().kind, serco::ErrorKind::Unauthenticated );
+    }
+}
@@ -0,0 +1,340 @@
+//! JSON-RPC 2.0 message handling.
+//!
+//! The operations are invoked by their names with the parameters passed by
+//! name. Positional parameters are accepted as well and matched to the
+//! arguments in the order of the contract. Requests without an `id` are
+//! notifications and get no response, which is how one-way operations are
+//! meant to be called.
+//!
+//! The errors carry the `ServiceError` in their `data` member. The error
+//! codes are mapped from the error kinds with `error_code`.
+//!
+//! The requests over the message limits of the host are refused with an
+//! error that has no id as they aren't parsed for their id. The responses
+//! over the limits are replaced with an error for the same request.
+//!
+//! The requests carry the trace context of the caller in a `trace` member
+//! next to the members of the specification. The hosts invoke the calls
+//! with the context so their spans continue the trace of the caller.
+
+use std::rc::Rc;
+
+use futures::prelude::*;
+use futures::future;
+use serde_json::{self, Map, Value};
+
+use serco::{ServiceContract, ServiceError, ErrorKind, InvokeTarget, MessageLimits};
+
+pub const VERSION : &str = "2.0";
+
+/// The error codes defined by the specification.
+pub mod codes {
+    pub const PARSE_ERROR : i64 = -32700;
+    pub const INVALID_REQUEST : i64 = -32600;
+    pub const METHOD_NOT_FOUND : i64 = -32601;
+    pub const INVALID_PARAMS : i64 = -32602;
+    pub const INTERNAL_ERROR : i64 = -32603;
+
+    /// The transport errors use the first code reserved for the servers.
+    pub const SERVER_ERROR : i64 = -32000;
+    pub const UNAUTHENTICATED : i64 = -32001;
+    pub const UNAUTHORIZED : i64 = -32002;
+    pub const THROTTLED : i64 = -32003;
+    pub const MESSAGE_TOO_LARGE : i64 = -32004;
+}
+
+/// Resolves the JSON-RPC error code for an error kind.
+pub fn error_code( kind: ErrorKind ) -> i64 {
+    match kind {
+        ErrorKind::Internal => codes::INTERNAL_ERROR,
+        ErrorKind::Transport => codes::SERVER_ERROR,
+        ErrorKind::BadOperation => codes::METHOD_NOT_FOUND,
+        ErrorKind::BadRequest => codes::INVALID_PARAMS,
+        ErrorKind::Unauthenticated => codes::UNAUTHENTICATED,
+        ErrorKind::Unauthorized => codes::UNAUTHORIZED,
+        ErrorKind::Throttled => codes::THROTTLED,
+        ErrorKind::MessageTooLarge => codes::MESSAGE_TOO_LARGE,
+    }
+}
+
+/// Creates a request object. Requests without an `id` are notifications.
+///
+/// The request carries the current trace context, if any.
+pub fn request( id: Option<Value>, method: &str, params: Value ) -> Value
+{
+    let mut request = Map::new();
+    request.insert( "jsonrpc".to_string(), Value::from( VERSION ) );
+    if let Some( id ) = id {
+        request.insert( "id".to_string(), id );
+    }
+    request.insert( "method".to_string(), Value::from( method ) );
+    request.insert( "params".to_string(), params );
+    if let Some( trace ) = serco::trace::current() {
+        request.insert( "trace".to_string(), serde_json::to_value( trace ).unwrap() );
+    }
+    Value::Object( request )
+}
+
+pub fn success( id: Value, result: Value ) -> Value
+{
+    json!({ "jsonrpc": VERSION, "id": id, "result": result })
+}
+
+pub fn failure( id: Value, e: &ServiceError ) -> Value
+{
+    failure_with_code( id, error_code( e.kind ), e )
+}
+
+fn failure_with_code( id: Value, code: i64, e: &ServiceError ) -> Value
+{
+    json!({
+        "jsonrpc": VERSION,
+        "id": id,
+        "error": { "code": code, "message": e.message, "data": e },
+    })
+}
+
+/// Recovers the service error from a JSON-RPC error object.
+///
+/// Errors from peers other than serco have no `ServiceError` in their data
+/// so the kind is resolved from the error code.
+pub fn service_error( error: &Value ) -> ServiceError
+{
+    if let Some( data ) = error.get( "data" ) {
+        if let Ok( e ) = serde_json::from_value( data.clone() ) {
+            return e;
+        }
+    }
+
+    let kind = match error.get( "code" ).and_then( |c| c.as_i64() ) {
+        Some( codes::METHOD_NOT_FOUND ) => ErrorKind::BadOperation,
+        Some( codes::INVALID_PARAMS ) |
+        Some( codes::INVALID_REQUEST ) |
+        Some( codes::PARSE_ERROR ) => ErrorKind::BadRequest,
+        Some( codes::SERVER_ERROR ) => ErrorKind::Transport,
+        Some( codes::UNAUTHENTICATED ) => ErrorKind::Unauthenticated,
+        Some( codes::UNAUTHORIZED ) => ErrorKind::Unauthorized,
+        Some( codes::THROTTLED ) => ErrorKind::Throttled,
+        Some( codes::MESSAGE_TOO_LARGE ) => ErrorKind::MessageTooLarge,
+        _ => ErrorKind::Internal,
+    };
+    let message = error.get( "message" ).and_then( |m| m.as_str() ).unwrap_or( "" );
+    ServiceError::new( kind, message )
+}
+
+/// Handles a message, which is either a single request or a batch.
+///
+/// Resolves to the response or `None` if the message contained only
+/// notifications. The future never fails; the errors are reported in the
+/// response.
+pub fn handle<C, T>(
+    target: Rc<T>,
+    text: &str,
+) -> Box<Future<Item=Option<Value>, Error=ServiceError>>
+    where C: ServiceContract + ?Sized + 'static,
+          T: InvokeTarget<C> + ?Sized + 'static,
+{
+    let message : Value = match serde_json::from_str( text ) {
+        Ok( message ) => message,
+        Err( e ) => return Box::new( future::ok( Some( failure_with_code(
+                Value::Null,
+                codes::PARSE_ERROR,
+                &ServiceError::new( ErrorKind::BadRequest, format!( "{}", e ) ) ) ) ) ),
+    };
+
+    match message {
+        Value::Array( ref batch ) if batch.is_empty() =>
+            Box::new( future::ok( Some( invalid_request( Value::Null, "Empty batch" ) ) ) ),
+        Value::Array( batch ) => Box::new( future::join_all( batch.into_iter()
+                    .map( |request| call::<C, T>( target.clone(), request ) )
+                    .collect::<Vec<_>>() )
+                .map( |responses| {
+                    let responses : Vec<Value> = responses.into_iter()
+                            .filter_map( |r| r )
+                            .collect();
+                    if responses.is_empty() { None } else { Some( Value::Array( responses ) ) }
+                } ) ),
+        request => call::<C, T>( target, request ),
+    }
+}
+
+/// Handles a request checked with `check_requests`.
+pub fn handle_checked<C, T>(
+    target: Rc<T>,
+    request: Result<String, ServiceError>,
+) -> Box<Future<Item=Option<Value>, Error=ServiceError>>
+    where C: ServiceContract + ?Sized + 'static,
+          T: InvokeTarget<C> + ?Sized + 'static,
+{
+    match request {
+        Ok( text ) => handle::<C, T>( target, &text ),
+        Err( e ) => Box::new( future::ok( Some( failure( Value::Null, &e ) ) ) ),
+    }
+}
+
+/// Checks the requests of a stream connection against the message limits.
+///
+/// The requests over the limits, including the ones the codec refused, are
+/// passed on as errors. The requests end after the first error as the rest
+/// of the stream can't be trusted.
+pub fn check_requests<S>( requests: S, limits: MessageLimits ) -> CheckedRequests<S>
+    where S: Stream<Item=String, Error=ServiceError>
+{
+    CheckedRequests { requests: requests, limits: limits, refused: false }
+}
+
+pub struct CheckedRequests<S> {
+    requests: S,
+    limits: MessageLimits,
+    refused: bool,
+}
+
+impl<S> Stream for CheckedRequests<S>
+    where S: Stream<Item=String, Error=ServiceError>
+{
+    type Item = Result<String, ServiceError>;
+    type Error = ServiceError;
+
+    fn poll( &mut self ) -> Poll<Option<Self::Item>, ServiceError> {
+        if self.refused {
+            return Ok( Async::Ready( None ) );
+        }
+        let request = match self.requests.poll() {
+            Ok( Async::Ready( Some( text ) ) ) =>
+                    self.limits.check_request( text.as_bytes() ).map( |_| text ),
+            Ok( Async::Ready( None ) ) => return Ok( Async::Ready( None ) ),
+            Ok( Async::NotReady ) => return Ok( Async::NotReady ),
+            Err( ref e ) if e.kind == ErrorKind::MessageTooLarge => Err( e.clone() ),
+            Err( e ) => return Err( e ),
+        };
+        self.refused = request.is_err();
+        Ok( Async::Ready( Some( request ) ) )
+    }
+}
+
+/// Serializes a response. The responses over the size limit are replaced
+/// with an error. The batches are replaced as a whole.
+pub fn encode_response( response: &Value, limits: &MessageLimits ) -> String
+{
+    let text = response.to_string();
+    match limits.check_response( text.as_bytes() ) {
+        Ok( () ) => text,
+        Err( e ) => {
+            let id = response.get( "id" ).cloned().unwrap_or( Value::Null );
+            failure( id, &e ).to_string()
+        },
+    }
+}
+
+fn invalid_request( id: Value, message: &str ) -> Value
+{
+    failure_with_code( id, codes::INVALID_REQUEST,
+                       &ServiceError::new( ErrorKind::BadRequest, message ) )
+}
+
+/// Handles a single request.
+fn call<C, T>(
+    target: Rc<T>,
+    request: Value,
+) -> Box<Future<Item=Option<Value>, Error=ServiceError>>
+    where C: ServiceContract + ?Sized + 'static,
+          T: InvokeTarget<C> + ?Sized + 'static,
+{
+    let invalid = |id, message| -> Box<Future<Item=_, Error=_>> {
+        Box::new( future::ok( Some( invalid_request( id, message ) ) ) )
+    };
+
+    let mut request = match request {
+        Value::Object( request ) => request,
+        _ => return invalid( Value::Null, "The request must be an object" ),
+    };
+
+    // Requests without an id are notifications.
+    let id = request.remove( "id" );
+    match id {
+        Some( Value::Null ) | Some( Value::Number( .. ) ) | Some( Value::String( .. ) ) | None => {},
+        Some( _ ) => return invalid( Value::Null, "The id must be a string or a number" ),
+    }
+    let response_id = id.clone().unwrap_or( Value::Null );
+
+    if request.get( "jsonrpc" ) != Some( &Value::from( VERSION ) ) {
+        return invalid( response_id, "The request must specify jsonrpc version 2.0" );
+    }
+    let method = match request.remove( "method" ) {
+        Some( Value::String( method ) ) => method,
+        _ => return invalid( response_id, "The request must specify the method" ),
+    };
+    let params = match request.remove( "params" ) {
+        None => Ok( Value::Object( Map::new() ) ),
+        Some( Value::Object( params ) ) => Ok( Value::Object( params ) ),
+        Some( Value::Array( params ) ) => by_name::<C>( &method, params ),
+        Some( _ ) => return invalid( response_id, "The params must be an object or an array" ),
+    };
+
+    // Malformed contexts start a new trace rather than fail the call.
+    let trace = request.remove( "trace" )
+            .and_then( |trace| serde_json::from_value( trace ).ok() );
+
+    let streaming = C::description().operation( &method )
+            .map( |op| op.is_streaming() )
+            .unwrap_or( false );
+    let result = match params {
+        _ if streaming => Box::new( future::err( ServiceError::new(
+                ErrorKind::BadOperation,
+                format!( "Operation '{}' streams and is not available over JSON-RPC",
+                         method ) ) ) ) as Box<Future<Item=_, Error=_>>,
+        Ok( params ) => serco::trace::with_context( trace, || {
+            invoke_value::<C, T>( &*target, &method, params )
+        } ),
+        Err( e ) => Box::new( future::err( e ) ),
+    };
+
+    Box::new( result.then( move |result| {
+        if id.is_none() {
+            return Ok( None );
+        }
+        Ok( Some( match result {
+            Ok( value ) => success( response_id, value ),
+            Err( e ) => failure( response_id, &e ),
+        } ) )
+    } ) )
+}
+
+/// Names the positional parameters after the operation arguments.
+fn by_name<C: ServiceContract + ?Sized>(
+    method: &str,
+    params: Vec<Value>,
+) -> Result<Value, ServiceError>
+{
+    let names : Vec<&str> = C::description().operation( method )
+            .map( |op| op.args.iter().map( |a| a.name ).collect() )
+            .unwrap_or_default();
+    if params.len() > names.len() {
+        return Err( ServiceError::new( ErrorKind::BadRequest, format!(
+                "'{}' takes {} parameters, {} given", method, names.len(), params.len() ) ) );
+    }
+    Ok( Value::Object( names.into_iter()
+            .map( String::from )
+            .zip( params )
+            .collect() ) )
+}
+
+/// Invokes an operation and turns its result into a JSON value.
+fn invoke_value<C, T>(
+    target: &T,
+    name: &str,
+    params: Value,
+) -> Box<Future<Item=Value, Error=ServiceError>>
+    where C: ServiceContract + ?Sized,
+          T: InvokeTarget<C> + ?Sized,
+{
+    let output = serde_json::Serializer::new( vec![] );
+    Box::new( target.invoke( name, params, output ).map( |ok| {
+        let bytes = ok.into_inner();
+        if bytes.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_slice( &bytes ).unwrap()
+        }
+    } ) )
+}
@@ -1,3 +1,5 @@
+#![cfg_attr(test, feature(proc_macro))]
+
 
 #[macro_use] extern crate lazy_static;
 
@@ -15,6 +17,7 @@ extern crate serde;
 extern crate serde_json;
 extern crate erased_serde;
 
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -22,13 +25,20 @@ use std::sync::Arc;
 use serde::*;
 use serde::de::DeserializeOwned;
 
-use futures::sync::mpsc::{Sender, channel};
+use futures::sync::mpsc::{Sender, Receiver, channel};
 
 /// Envelope used by the MPSC endpoints to communicate the calls.
 #[derive(Debug, Serialize, Deserialize)]
 struct RequestEnvelope<'a> {
     name: &'a str,
     params: serde_json::Value,
+
+    #[serde(default)]
+    request_id: Option<String>,
+
+    /// Trace context of the caller.
+    #[serde(default)]
+    trace: Option<serco::TraceContext>,
 }
 
 /// Envelope used by the MPSC endpoints to communicate the calls.
@@ -37,6 +47,54 @@ struct ResponseEnvelope {
     result: Result<serde_json::Value, serco::ServiceError>,
 }
 
+/// Messages sent through the MPSC request pipes.
+#[derive(Debug, Serialize, Deserialize)]
+enum RequestMessage<'a> {
+    Call( #[serde(borrow)] RequestEnvelope<'a> ),
+    Batch( BatchEnvelope ),
+}
+
+/// Envelope used to pass several calls in a single request.
+#[derive(Debug, Serialize, Deserialize)]
+struct BatchEnvelope {
+    calls: Vec<BatchCallEnvelope>,
+    mode: serco::batch::BatchMode,
+}
+
+/// A single call within a batch envelope.
+#[derive(Debug, Serialize, Deserialize)]
+struct BatchCallEnvelope {
+    name: String,
+    params: serde_json::Value,
+}
+
+/// Envelope used to return the results of a batch in the order of the calls.
+#[derive(Debug, Serialize, Deserialize)]
+struct BatchResponseEnvelope {
+    results: Vec<Result<serde_json::Value, serco::ServiceError>>,
+}
+
+/// Frames used to transfer the items of the stream arguments and results.
+#[derive(Debug, Serialize, Deserialize)]
+enum StreamFrame {
+    Item( serde_json::Value ),
+    Error( serco::ServiceError ),
+    End,
+}
+
+/// A call passed through the MPSC request pipes.
+///
+/// Streaming calls carry a pipe for each stream argument and a pipe for the
+/// result frames. The result of a streaming call is always delivered through
+/// the `output_stream`, in which case the `response` is not used.
+#[derive(Debug)]
+pub struct Request {
+    message: String,
+    input_streams: Vec<Receiver<String>>,
+    output_stream: Option<Sender<String>>,
+    response: oneshot::Sender<String>,
+}
+
 
 pub struct MpscEndpoint {
     endpoint: String,
@@ -59,6 +117,7 @@ impl<TService,
     where TService: serco::ServiceContract + ?Sized + 'static,
           TSessionFactory: serco::SessionFactory + 'static,
           THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+          THostImplementation::ServiceInstance: 'static,
 {
     fn run(
         &self,
@@ -72,59 +131,318 @@ impl<TService,
         let (endpoint_tx, endpoint_rx) = channel(1);
         set_endpoint( self.endpoint.clone(), endpoint_tx );
 
-        let result = endpoint_rx.for_each( move |(client_tx, callback_tx)| {
+        let result = endpoint_rx.map( move |(client_tx, callback_tx, resume_id, credentials)|
+                -> Box<Future<Item=(), Error=()>> {
 
-            let ( session_id, session ) = host.get_session( None );
-            let session = Rc::new( session );
+            // Clients that reconnect resume their existing session. The
+            // refusals are reported back to the client.
+            let session = match resume_id {
+                Some( id ) => host.resume_session( &id, &credentials )
+                        .map( |session| ( id, session ) ),
+                None => host.open_resumable_session( &credentials ),
+            };
+            let ( session_id, session ) = match session {
+                Ok( session ) => session,
+                Err( e ) => {
+                    let _ = client_tx.send( Err( e ) );
+                    return Box::new( futures::future::ok( () ) );
+                },
+            };
 
             let (tx, rx) = channel(1);
-            client_tx.send(( session_id.to_string(), tx )).unwrap();
+            client_tx.send( Ok(( session_id.clone(), tx )) ).unwrap();
 
             let forwarder = Arc::new( serco::ServiceProxy::new(
                     MpscForwarder {
-                        _id: session_id.to_string(),
+                        _id: session_id.clone(),
                         tx: callback_tx,
                     } ) );
+            host.connect_session( &session_id, forwarder.clone() );
 
-            rx.for_each( move |(msg, response_tx)| {
-
-                let forwarder = forwarder.clone();
-                let session = session.clone();
-                
-                let output = serde_json::Serializer::new( vec![] );
-                TService::CallbackContract::set_task_callback( forwarder );
-                let envelope : RequestEnvelope =
-                        serde_json::from_str(&msg).unwrap();
-                session.invoke(
-                        envelope.name,
-                        envelope.params,
-                        output )
-                .then( |result| {
-                    Ok( match result {
-                        Ok( ok ) => {
-                            ResponseEnvelope {
-                                result: Ok( serde_json::from_slice( &ok.into_inner() ).unwrap() )
-                            }
-                        }
-                        Err( e ) => ResponseEnvelope {
-                            result: Err( serco::ServiceError::from(e) )
-                        },
-                    }  )
-                } )
-                .map( |response| {
-                       let json = serde_json::to_string( &response ).unwrap();
-                       response_tx.send( json )
-                } )
-                .map( |_| () )
+            // The responses are cached with the session so the calls retried
+            // on a new connection are not executed twice.
+            let cache = host.session_responses( &session_id );
+            let limits = *host.message_limits();
+            let host = host.clone();
+            Box::new( rx.for_each( move |request| {
+                TService::CallbackContract::set_task_callback( forwarder.clone() );
+                dispatch::<TService, _>( &session, request, cache.as_ref(), &limits )
             } )
+            .then( move |result| {
+                host.disconnect_session( &session_id );
+                result
+            } ) )
 
-        } );
+        } )
+        // Serve all connected sessions concurrently.
+        .buffer_unordered( usize::max_value() )
+        .for_each( |_| Ok( () ) );
 
         // TODO: Report issue on bad diagnostics on missing map_err here.
         Box::new( result.map_err( |e| serco::ServiceError::from(e) ) )
     }
+
+    fn address( &self ) -> Option<serco::registry::EndpointAddress> {
+        Some( serco::registry::EndpointAddress::new( ENDPOINT_KIND, self.endpoint.clone() ) )
+    }
 }
 
+/// Kind under which the MPSC endpoints are registered in service registries.
+pub const ENDPOINT_KIND : &str = "mpsc";
+
+/// Resolves the MPSC endpoints registered for the contract.
+fn discover<S>(
+    registry: &serco::registry::ServiceRegistry,
+) -> Result<Vec<String>, serco::ServiceError>
+    where S: serco::ServiceContract + ?Sized
+{
+    let addresses = registry.resolve_addresses( S::contract_id(), ENDPOINT_KIND )?;
+    if addresses.is_empty() {
+        return Err( serco::ServiceError::transport( format!(
+                "No endpoints registered for '{}'", S::contract_name() ) ) );
+    }
+    Ok( addresses )
+}
+
+/// Responses of the recent calls of a session by their request IDs.
+type SessionCache = Rc<RefCell<serco::retry::ResponseCache<String>>>;
+
+/// Invokes the request on the target and sends the response back.
+///
+/// Shared by the service host and the client callback handler. Calls with
+/// request IDs that are found in the cache are responded to from the cache
+/// without invoking them again. The requests over the `limits` or that
+/// can't be parsed are refused with an error.
+fn dispatch<C, T>(
+    target: &Rc<T>,
+    request: Request,
+    cache: Option<&SessionCache>,
+    limits: &serco::MessageLimits,
+) -> Box<Future<Item=(), Error=()>>
+    where C: ServiceContract + ?Sized,
+          T: InvokeTarget<C> + ?Sized + 'static,
+{
+    let Request { message, input_streams, output_stream, response } = request;
+
+    let parsed = limits.check_request( message.as_bytes() ).and_then( |_| {
+        serde_json::from_str( &message ).map_err( |e| serco::ServiceError::new(
+                serco::ErrorKind::BadRequest,
+                format!( "Invalid request: {}", e ) ) )
+    } );
+    let envelope = match parsed {
+        Ok( RequestMessage::Call( envelope ) ) => envelope,
+        Ok( RequestMessage::Batch( batch ) ) =>
+                return dispatch_batch::<C, T>( target, batch, response ),
+        Err( e ) => return refuse( e, output_stream, response ),
+    };
+
+    // Requests with IDs are retries or may be retried later.
+    let cache_entry = match ( cache, envelope.request_id ) {
+        ( Some( cache ), Some( request_id ) ) => {
+            if let Some( json ) = cache.borrow().get( &request_id ) {
+                let _ = response.send( json );
+                return Box::new( futures::future::ok( () ) );
+            }
+            Some( ( cache.clone(), request_id ) )
+        },
+        _ => None,
+    };
+
+    serco::stream::set_incoming( input_streams
+            .into_iter()
+            .map( incoming_stream )
+            .collect() );
+
+    let RequestEnvelope { name, params, trace, .. } = envelope;
+    let result = serco::trace::with_context( trace, || invoke_value::<C, T>( target, name, params ) );
+    Box::new( result.then( move |result| -> Box<Future<Item=(), Error=()>> {
+
+        let output_stream = match output_stream {
+            Some( tx ) => tx,
+            None => {
+                let envelope = ResponseEnvelope { result: result };
+                let json = serde_json::to_string( &envelope ).unwrap();
+                if let Some( ( cache, request_id ) ) = cache_entry {
+                    cache.borrow_mut().insert( request_id, json.clone() );
+                }
+                let _ = response.send( json );
+                return Box::new( futures::future::ok( () ) );
+            }
+        };
+
+        let items : serco::stream::OutgoingStream =
+                match ( result, serco::stream::take_outgoing() ) {
+                    ( Err( e ), _ ) => Box::new( futures::stream::once( Err( e ) ) ),
+                    ( Ok( _ ), Some( stream ) ) => stream,
+                    ( Ok( value ), None ) => Box::new( futures::stream::once(
+                            Ok( Box::new( value ) as serco::stream::StreamItem ) ) ),
+                };
+
+        Box::new( outgoing_frames( items )
+            .forward( output_stream.sink_map_err( serco::ServiceError::transport ) )
+            .map( |_| () )
+            .map_err( |_| () ) )
+    } ) )
+}
+
+/// Responds to a request with an error without invoking it.
+///
+/// Streaming calls get the error through their result frames.
+fn refuse(
+    error: serco::ServiceError,
+    output_stream: Option<Sender<String>>,
+    response: oneshot::Sender<String>,
+) -> Box<Future<Item=(), Error=()>>
+{
+    match output_stream {
+        Some( tx ) => {
+            let frame = serde_json::to_string( &StreamFrame::Error( error ) ).unwrap();
+            Box::new( tx.send( frame ).map( |_| () ).map_err( |_| () ) )
+        },
+        None => {
+            let envelope = ResponseEnvelope { result: Err( error ) };
+            let _ = response.send( serde_json::to_string( &envelope ).unwrap() );
+            Box::new( futures::future::ok( () ) )
+        },
+    }
+}
+
+/// Executes the calls of a batch and sends the results back.
+///
+/// The results are in the order of the calls whichever way the calls are
+/// executed.
+fn dispatch_batch<C, T>(
+    target: &Rc<T>,
+    batch: BatchEnvelope,
+    response: oneshot::Sender<String>,
+) -> Box<Future<Item=(), Error=()>>
+    where C: ServiceContract + ?Sized,
+          T: InvokeTarget<C> + ?Sized + 'static,
+{
+    let results : Box<Future<Item=Vec<_>, Error=()>> = match batch.mode {
+        serco::batch::BatchMode::Parallel => Box::new( futures::future::join_all(
+            batch.calls.into_iter().map( |call| {
+                invoke_value::<C, T>( target, &call.name, call.params )
+                    .then( Ok::<_, ()> )
+            } ).collect::<Vec<_>>() ) ),
+        serco::batch::BatchMode::Sequential => {
+            let target = target.clone();
+            Box::new( futures::stream::iter_ok::<_, ()>( batch.calls )
+                .and_then( move |call| {
+                    invoke_value::<C, T>( &target, &call.name, call.params )
+                        .then( Ok::<_, ()> )
+                } )
+                .collect() )
+        },
+    };
+
+    Box::new( results.map( move |results| {
+        let envelope = BatchResponseEnvelope { results: results };
+        let json = serde_json::to_string( &envelope ).unwrap();
+        let _ = response.send( json );
+    } ) )
+}
+
+/// Invokes an operation and turns its result into a JSON value.
+///
+/// Operations returning streams produce `Null` values as the actual items
+/// are passed through `serco::stream::take_outgoing`.
+fn invoke_value<C, T>(
+    target: &T,
+    name: &str,
+    params: serde_json::Value,
+) -> Box<Future<Item=serde_json::Value, Error=serco::ServiceError>>
+    where C: ServiceContract + ?Sized,
+          T: InvokeTarget<C> + ?Sized,
+{
+    let output = serde_json::Serializer::new( vec![] );
+    Box::new( target.invoke( name, params, output ).map( |ok| {
+        let bytes = ok.into_inner();
+        if bytes.is_empty() {
+            serde_json::Value::Null
+        } else {
+            serde_json::from_slice( &bytes ).unwrap()
+        }
+    } ) )
+}
+
+/// Turns the stream frames received from a pipe into an incoming stream.
+fn incoming_stream( rx: Receiver<String> ) -> serco::stream::IncomingStream
+{
+    Box::new( frames( rx ).map( |value|
+            Box::new( erased_serde::Deserializer::erase( value ) )
+                as serco::stream::IncomingItem ) )
+}
+
+/// Parses the stream frames received from a pipe into values.
+fn frames(
+    rx: Receiver<String>
+) -> Box<Stream<Item=serde_json::Value, Error=serco::ServiceError>>
+{
+    Box::new( rx
+        .map_err( |_| serco::ServiceError::transport( "Stream pipe failed" ) )
+        .map( |frame| serde_json::from_str::<StreamFrame>( &frame ).unwrap() )
+        .take_while( |frame| Ok( match *frame {
+            StreamFrame::End => false,
+            _ => true,
+        } ) )
+        .and_then( |frame| match frame {
+            StreamFrame::Item( value ) => Ok( value ),
+            StreamFrame::Error( e ) => Err( e ),
+            StreamFrame::End => unreachable!( "End frames are filtered" ),
+        } ) )
+}
+
+/// Turns an outgoing stream into serialized stream frames.
+fn outgoing_frames(
+    stream: serco::stream::OutgoingStream
+) -> Box<Stream<Item=String, Error=serco::ServiceError>>
+{
+    Box::new( stream
+        .then( |item| Ok( match item {
+            Ok( item ) => StreamFrame::Item(
+                    serde_json::to_value( item ).unwrap() ),
+            Err( e ) => StreamFrame::Error( e ),
+        } ) )
+        .chain( futures::stream::once( Ok( StreamFrame::End ) ) )
+        .map( |frame| serde_json::to_string( &frame ).unwrap() ) )
+}
+
+/// Passes on the results of a streaming call while feeding its stream
+/// arguments. The arguments are fed only as long as the results last so a
+/// host that returns without reading its input doesn't leave the call
+/// waiting on the feeds.
+fn feed_while<F, D>(
+    feeds: Vec<F>,
+    mut results: serco::ServiceStream<D>,
+) -> serco::ServiceStream<D>
+    where
+        F: Future + 'static,
+        D: 'static,
+{
+    // A feed fails if the host drops the pipe, which only means the rest
+    // of the input isn't needed.
+    let feeds = feeds.into_iter()
+            .map( |feed| feed.then( |_| Ok::<(), ()>( () ) ) )
+            .collect::<Vec<_>>();
+    let mut feeds = Some( futures::future::join_all( feeds ) );
+    Box::new( futures::stream::poll_fn( move || {
+        let fed = match feeds {
+            Some( ref mut feeds ) => match feeds.poll() {
+                Ok( Async::NotReady ) => false,
+                _ => true,
+            },
+            None => false,
+        };
+        if fed {
+            feeds = None;
+        }
+        results.poll()
+    } ) )
+}
+
+
+
 
 
 
@@ -145,18 +463,19 @@ impl<TService,
 
 use std::sync::Mutex;
 lazy_static! {
+    // The channels of the listening endpoints by their addresses. Discovering
+    // the addresses by contract goes through `serco::registry`.
     static ref ENDPOINTS : Mutex<HashMap<String, Endpoint>>
             = Mutex::new( HashMap::new() );
 }
 type Endpoint = Sender<(  // Host listen callback.
-    oneshot::Sender<(     // Client on-connect callback
+    oneshot::Sender<Result<( // Client on-connect callback
         String,           // Session ID
-        Sender<(          // Client request pipe
-            String,
-            oneshot::Sender<String>
-        )>
-    )>,
-    Sender<( String, oneshot::Sender<String>)>  // Server callback pipe
+        Sender<Request>   // Client request pipe
+    ), serco::ServiceError>>,
+    Sender<Request>,      // Server callback pipe
+    Option<String>,       // Session ID to resume
+    serco::Credentials,   // Credentials of the client
 )>;
 
 pub fn get_endpoint( name : &str ) -> Option<Endpoint>
@@ -173,22 +492,93 @@ pub fn set_endpoint<T: Into<String>>( name : T, endpoint : Endpoint )
 
 pub struct MpscClient {
     endpoint : String,
+    credentials : serco::Credentials,
+    retry_policy : serco::retry::RetryPolicy,
+    reconnect_policy : serco::retry::RetryPolicy,
 }
 
+/// A connection that reconnects to the MPSC endpoint after connection loss.
+pub type MpscReconnectingConnection<S> =
+        serco::reconnect::ReconnectingConnection<S, MpscConnector>;
+
 impl MpscClient {
     pub fn new<T: Into<String>>( endpoint: T ) -> MpscClient {
         MpscClient {
             endpoint: endpoint.into(),
+            credentials: serco::Credentials::Anonymous,
+            retry_policy: serco::retry::RetryPolicy::none(),
+            reconnect_policy: serco::retry::RetryPolicy::default()
+                    .max_attempts( 5 ),
         }
     }
 
+    /// Creates a client for the first MPSC endpoint registered for the
+    /// contract.
+    pub fn discover<S>(
+        registry: &serco::registry::ServiceRegistry,
+    ) -> Result<MpscClient, serco::ServiceError>
+        where S: serco::ServiceContract + ?Sized
+    {
+        let addresses = discover::<S>( registry )?;
+        Ok( MpscClient::new( addresses[ 0 ].clone() ) )
+    }
+
+    /// Presents the credentials to the authenticator of the host.
+    pub fn credentials( mut self, credentials: &serco::Credentials ) -> Self {
+        self.credentials = credentials.clone();
+        self
+    }
+
+    /// Specifies the policy for retrying the calls of the connections.
+    pub fn retry_policy( mut self, policy: serco::retry::RetryPolicy ) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Specifies the backoff used by the reconnecting connections.
+    pub fn reconnect_policy( mut self, policy: serco::retry::RetryPolicy ) -> Self {
+        self.reconnect_policy = policy;
+        self
+    }
+
+    /// Connects with a connection that reconnects after connection loss.
+    pub fn connect_reconnecting<S>(
+        &self,
+    ) -> Box<Future<Item=MpscReconnectingConnection<S>, Error=String>>
+        where S: serco::ServiceContract<CallbackContract = ()> + ?Sized + 'static,
+    {
+        self.connect_duplex_reconnecting::<S, (), ()>( () )
+    }
+
+    /// Connects with a duplex connection that reconnects after connection
+    /// loss. The callback is registered again on each reconnect.
+    pub fn connect_duplex_reconnecting<S, C, T>(
+        &self,
+        callback: T,
+    ) -> Box<Future<Item=MpscReconnectingConnection<S>, Error=String>>
+        where S: serco::ServiceContract<CallbackContract = C> + ?Sized + 'static,
+            C: serco::ServiceContract<CallbackContract = ()> + ?Sized + 'static,
+            T: serco::InvokeTarget<C> + Send + 'static,
+    {
+        let connector = MpscConnector::new::<S, T>( &self.endpoint, callback )
+                .credentials( &self.credentials );
+        let connection = serco::reconnect::ReconnectingConnection::new(
+                connector,
+                self.reconnect_policy.clone(),
+                self.retry_policy.clone() );
+
+        let connected = connection.connect();
+        Box::new( connected
+            .map( move |_| connection )
+            .map_err( |e| format!( "{:?}", e ) ) )
+    }
+
     pub fn connect<S>(
         &self,
     ) -> Box<Future<Item=MpscServiceConnection<S>, Error=String>>
         where S: serco::ServiceContract<CallbackContract = ()> + ?Sized + 'static,
     {
-        MpscServiceConnection::<S>::connect(
-                &self.endpoint, () )
+        self.connect_duplex::<S, (), ()>( () )
     }
 
     pub fn connect_duplex<S, C, T>(
@@ -200,17 +590,120 @@ impl MpscClient {
             T: serco::InvokeTarget<C> + Send + 'static,
     {
         MpscServiceConnection::<S>::connect(
-                &self.endpoint, callback )
+                &self.endpoint, &self.credentials, callback, self.retry_policy.clone() )
     }
 
 }
 
+/// Client that spreads the calls across several MPSC endpoints.
+pub struct MpscBalancedClient {
+    endpoints : Vec<String>,
+    credentials : serco::Credentials,
+    policy : serco::balance::BalancePolicy,
+    sessionful : bool,
+    retry_policy : serco::retry::RetryPolicy,
+}
+
+/// A connection that spreads the calls across several MPSC endpoints.
+pub type MpscBalancedConnection<S> =
+        serco::balance::BalancedConnection<S, MpscConnector>;
+
+impl MpscBalancedClient {
+    pub fn new<T: Into<String>>(
+        endpoints: Vec<T>,
+        policy: serco::balance::BalancePolicy,
+    ) -> MpscBalancedClient
+    {
+        MpscBalancedClient {
+            endpoints: endpoints.into_iter().map( Into::into ).collect(),
+            credentials: serco::Credentials::Anonymous,
+            policy: policy,
+            sessionful: false,
+            retry_policy: serco::retry::RetryPolicy::none(),
+        }
+    }
+
+    /// Creates a client for all MPSC endpoints registered for the contract.
+    pub fn discover<S>(
+        registry: &serco::registry::ServiceRegistry,
+        policy: serco::balance::BalancePolicy,
+    ) -> Result<MpscBalancedClient, serco::ServiceError>
+        where S: serco::ServiceContract + ?Sized
+    {
+        Ok( MpscBalancedClient::new( discover::<S>( registry )?, policy ) )
+    }
+
+    /// Presents the credentials to the authenticators of the hosts.
+    pub fn credentials( mut self, credentials: &serco::Credentials ) -> Self {
+        self.credentials = credentials.clone();
+        self
+    }
+
+    /// Keeps the calls on the endpoint that owns the session.
+    pub fn sessionful( mut self, sessionful: bool ) -> Self {
+        self.sessionful = sessionful;
+        self
+    }
+
+    /// Specifies the policy for retrying the calls on other endpoints.
+    pub fn retry_policy( mut self, policy: serco::retry::RetryPolicy ) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    pub fn connect<S>(
+        &self,
+    ) -> Box<Future<Item=MpscBalancedConnection<S>, Error=String>>
+        where S: serco::ServiceContract<CallbackContract = ()> + ?Sized + 'static,
+    {
+        self.connect_duplex::<S, (), ()>( () )
+    }
+
+    /// Connects to the endpoints. The callback is registered with each of
+    /// them.
+    ///
+    /// Fails if none of the endpoints can be connected.
+    pub fn connect_duplex<S, C, T>(
+        &self,
+        callback: T,
+    ) -> Box<Future<Item=MpscBalancedConnection<S>, Error=String>>
+        where S: serco::ServiceContract<CallbackContract = C> + ?Sized + 'static,
+            C: serco::ServiceContract<CallbackContract = ()> + ?Sized + 'static,
+            T: serco::InvokeTarget<C> + Send + 'static,
+    {
+        let connectors = MpscConnector::for_endpoints::<S, T>(
+                &self.endpoints, callback );
+
+        let builder = connectors.into_iter().fold(
+                serco::balance::BalancingForwarderBuilder::new( self.policy.clone() )
+                    .sessionful( self.sessionful ),
+                |builder, connector| {
+                    let address = connector.endpoint.clone();
+                    builder.endpoint( address, connector.credentials( &self.credentials ) )
+                } );
+
+        let connection = serco::balance::BalancedConnection::new(
+                builder.build(), self.retry_policy.clone() );
+
+        let health = connection.balancer().check_health();
+        Box::new( health
+            .map_err( |e| format!( "{:?}", e ) )
+            .and_then( move |health| {
+                if health.iter().any( |h| h.healthy ) {
+                    Ok( connection )
+                } else {
+                    Err( "None of the endpoints could be connected".to_string() )
+                }
+            } ) )
+    }
+}
+
 /// Service connection used by the client implementation.
 ///
 /// (Since the real functionality is in the forwarder, this should probably
 /// move to the framework at some point)
 pub struct MpscServiceConnection<T : ?Sized> {
-    proxy: serco::ServiceProxy<T, MpscForwarder>,
+    proxy: serco::ServiceProxy<T, serco::retry::RetryForwarder<MpscForwarder>>,
     phantom_data: std::marker::PhantomData<T>,
     _callback_handle: std::thread::JoinHandle<()>,
 }
@@ -220,56 +713,21 @@ impl<T: serco::ServiceContract + ?Sized + 'static> MpscServiceConnection<T> {
     /// Connects to an MPSC endpoint.
     pub fn connect<C>(
         host_endpoint: &str,
+        credentials: &serco::Credentials,
         callback: C,
+        retry_policy: serco::retry::RetryPolicy,
     ) -> Box<Future<Item=MpscServiceConnection<T>, Error=String>>
         where C: serco::InvokeTarget<T::CallbackContract> + Send + 'static
     {
-        let endpoint = get_endpoint( host_endpoint ).unwrap();
-        let ( tx, rx ) = oneshot::channel();
-        let ( callback_tx, callback_rx ) =
-                channel::<(String, oneshot::Sender<String>)>(1);
-
-        let join_handle = std::thread::spawn( move || {
-            let mut core = Core::new().expect( "Failed to spawn callback core" );
-            core.run( callback_rx.for_each( move |(msg, response_tx)| {
-
-                let output = serde_json::Serializer::new( vec![] );
-                let envelope : RequestEnvelope =
-                        serde_json::from_str(&msg).unwrap();
-                callback.invoke(
-                        envelope.name,
-                        envelope.params,
-                        output )
-                .then( |result| {
-                    Ok( match result {
-                        Ok( ok ) => {
-                            ResponseEnvelope {
-                                result: Ok( serde_json::from_slice( &ok.into_inner() ).unwrap() )
-                            }
-                        }
-                        Err( e ) => ResponseEnvelope {
-                            result: Err( serco::ServiceError::from(e) )
-                        },
-                    }  )
-                } )
-                .map( |response| {
-                       let json = serde_json::to_string( &response ).unwrap();
-                       response_tx.send( json )
-                } )
-                .map( |_| () )
+        let ( callback_tx, join_handle ) =
+                spawn_callback_handler::<T::CallbackContract, _>( callback );
 
-            } ) ).unwrap();
-        } );
+        Box::new( connect_forwarder( host_endpoint, None, credentials.clone(), callback_tx )
+            .map( |( _, forwarder )| {
 
-        Box::new( endpoint.send(( tx, callback_tx ))
-            .then( |_| rx )
-            .map( |( id, connection_tx )| {
+                let forwarder = serco::retry::RetryForwarder::new(
+                        forwarder, retry_policy );
 
-                let forwarder = MpscForwarder {
-                    _id: id,
-                    tx: connection_tx,
-                };
-                
                 MpscServiceConnection {
                     proxy: serco::ServiceProxy::new( forwarder ),
                     phantom_data: std::marker::PhantomData,
@@ -284,11 +742,138 @@ impl<T: serco::ServiceContract + ?Sized + 'static> MpscServiceConnection<T> {
     }
 }
 
+/// Spawns the thread that handles the callbacks from the host.
+fn spawn_callback_handler<C, T>(
+    callback: T
+) -> ( Sender<Request>, std::thread::JoinHandle<()> )
+    where C: ServiceContract + ?Sized + 'static,
+          T: InvokeTarget<C> + Send + 'static,
+{
+    let ( callback_tx, callback_rx ) = channel::<Request>(1);
+
+    let join_handle = std::thread::spawn( move || {
+        let mut core = Core::new().expect( "Failed to spawn callback core" );
+        let callback = Rc::new( serco::trace::TracedTarget( callback ) );
+        core.run( callback_rx.for_each( move |request| {
+            dispatch::<C, _>( &callback, request, None, &serco::MessageLimits::new() )
+        } ) ).unwrap();
+    } );
+
+    ( callback_tx, join_handle )
+}
+
+/// Connects a forwarder to the host endpoint.
+///
+/// The callbacks from the host are sent to the `callback_tx`. Resolves to
+/// the session ID and the forwarder or fails if the host refuses the
+/// credentials or the session to resume.
+fn connect_forwarder(
+    host_endpoint: &str,
+    session_id: Option<String>,
+    credentials: serco::Credentials,
+    callback_tx: Sender<Request>,
+) -> Box<Future<Item=( String, MpscForwarder ), Error=serco::ServiceError>>
+{
+    let endpoint = match get_endpoint( host_endpoint ) {
+        Some( endpoint ) => endpoint,
+        None => return Box::new( futures::future::err(
+                serco::ServiceError::transport( format!(
+                    "Endpoint '{}' not found", host_endpoint ) ) ) ),
+    };
+
+    let ( tx, rx ) = oneshot::channel();
+    Box::new( endpoint.send(( tx, callback_tx, session_id, credentials ))
+        .map_err( serco::ServiceError::transport )
+        .and_then( |_| rx.map_err( serco::ServiceError::transport ) )
+        .and_then( |connected| connected )
+        .map( |( id, connection_tx )| {
+            let forwarder = MpscForwarder {
+                _id: id.clone(),
+                tx: connection_tx,
+            };
+            ( id, forwarder )
+        } ) )
+}
+
+/// Connector used by the reconnecting and load balanced MPSC connections.
+///
+/// The connector owns the callback handler so the same callback is
+/// registered with each new connection.
+pub struct MpscConnector {
+    endpoint: String,
+    credentials: serco::Credentials,
+    callback_tx: Sender<Request>,
+    _callback_handle: Arc<std::thread::JoinHandle<()>>,
+}
+
+impl MpscConnector {
+    pub fn new<S, T>(
+        endpoint: &str,
+        callback: T,
+    ) -> MpscConnector
+        where S: ServiceContract + ?Sized + 'static,
+              T: InvokeTarget<S::CallbackContract> + Send + 'static,
+    {
+        let ( callback_tx, join_handle ) =
+                spawn_callback_handler::<S::CallbackContract, _>( callback );
+
+        MpscConnector {
+            endpoint: endpoint.to_string(),
+            credentials: serco::Credentials::Anonymous,
+            callback_tx: callback_tx,
+            _callback_handle: Arc::new( join_handle ),
+        }
+    }
+
+    /// Creates connectors for several endpoints sharing the same callback.
+    pub fn for_endpoints<S, T>(
+        endpoints: &[String],
+        callback: T,
+    ) -> Vec<MpscConnector>
+        where S: ServiceContract + ?Sized + 'static,
+              T: InvokeTarget<S::CallbackContract> + Send + 'static,
+    {
+        let ( callback_tx, join_handle ) =
+                spawn_callback_handler::<S::CallbackContract, _>( callback );
+        let join_handle = Arc::new( join_handle );
+
+        endpoints.iter().map( |endpoint| MpscConnector {
+            endpoint: endpoint.clone(),
+            credentials: serco::Credentials::Anonymous,
+            callback_tx: callback_tx.clone(),
+            _callback_handle: join_handle.clone(),
+        } ).collect()
+    }
+
+    /// Presents the credentials to the authenticator of the host on each
+    /// connect.
+    pub fn credentials( mut self, credentials: &serco::Credentials ) -> Self {
+        self.credentials = credentials.clone();
+        self
+    }
+}
+
+impl serco::reconnect::Connector for MpscConnector {
+    type Forwarder = MpscForwarder;
+
+    fn connect(
+        &self,
+        session_id: Option<&str>
+    ) -> Box<Future<Item=( String, MpscForwarder ), Error=serco::ServiceError>>
+    {
+        connect_forwarder(
+                &self.endpoint,
+                session_id.map( String::from ),
+                self.credentials.clone(),
+                self.callback_tx.clone() )
+    }
+}
+
 /// Dereference the connection into the service proxy.
 /// The proxy implements the actual service trait.
 impl<T: serco::ServiceContract + ?Sized> std::ops::Deref for MpscServiceConnection<T>
 {
-    type Target = serco::ServiceProxy<T, MpscForwarder>;
+    type Target = serco::ServiceProxy<T, serco::retry::RetryForwarder<MpscForwarder>>;
 
     fn deref( &self ) -> &Self::Target {
         &self.proxy
@@ -299,7 +884,7 @@ impl<T: serco::ServiceContract + ?Sized> std::ops::Deref for MpscServiceConnecti
 /// them into messages that can be passed to the service host.
 pub struct MpscForwarder {
     _id: String,
-    tx: Sender<( String, oneshot::Sender<String> )>,
+    tx: Sender<Request>,
 }
 
 impl serco::Forwarder for MpscForwarder
@@ -312,29 +897,146 @@ impl serco::Forwarder for MpscForwarder
         where
             D: DeserializeOwned + 'static,
             S: Serialize + 'static,
+    {
+        self.forward_call( serco::CallInfo::new( name ), params )
+    }
+
+    fn forward_call<D, S>(
+        &self,
+        call: serco::CallInfo,
+        params: S
+    ) -> Box<Future<Item=D, Error=serco::ServiceError>>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static,
+    {
+        let tx = self.tx.clone();
+        let value = serde_json::to_value( params ).unwrap();
+        let envelope = RequestEnvelope {
+            name: call.name,
+            params: value,
+            request_id: call.request_id,
+            trace: serco::trace::current(),
+        };
+        let msg = serde_json::to_string(
+                &RequestMessage::Call( envelope ) ).unwrap();
+
+        Box::new( futures::future::lazy( move || {
+            let (tx_once, rx_once) = oneshot::channel();
+            let request = Request {
+                message: msg,
+                input_streams: vec![],
+                output_stream: None,
+                response: tx_once,
+            };
+            tx.send( request )
+                .map_err( serco::ServiceError::transport )
+                .and_then( |_| rx_once.map_err( serco::ServiceError::transport ) )
+        } )
+        .and_then( |envelope_str| {
+            let envelope : ResponseEnvelope = serde_json::from_str( &envelope_str ).unwrap();
+            envelope.result.map( |v| D::deserialize( v ).unwrap() )
+        } ) )
+    }
+
+    fn forward_streaming<D, S>(
+        &self,
+        name: &'static str,
+        params: S,
+        streams: Vec<serco::stream::OutgoingStream>,
+    ) -> serco::ServiceStream<D>
+        where
+            D: DeserializeOwned + 'static,
+            S: Serialize + 'static,
     {
         let tx = self.tx.clone();
         let value = serde_json::to_value( params ).unwrap();
         let envelope = RequestEnvelope {
             name: name,
             params: value,
+            request_id: None,
+            trace: serco::trace::current(),
+        };
+        let msg = serde_json::to_string(
+                &RequestMessage::Call( envelope ) ).unwrap();
+
+        // Each stream argument gets its own pipe. The items are fed into the
+        // pipes while the results are being received so bidirectional
+        // operations don't need to buffer either side.
+        let mut input_streams = vec![];
+        let mut feeds = vec![];
+        for stream in streams {
+            let ( stream_tx, stream_rx ) = channel(1);
+            input_streams.push( stream_rx );
+            feeds.push( outgoing_frames( stream )
+                .forward( stream_tx.sink_map_err( serco::ServiceError::transport ) )
+                .map( |_| () ) );
+        }
+
+        let ( output_tx, output_rx ) = channel(1);
+        let ( tx_once, _ ) = oneshot::channel();
+        let request = Request {
+            message: msg,
+            input_streams: input_streams,
+            output_stream: Some( output_tx ),
+            response: tx_once,
+        };
+
+        let results : serco::ServiceStream<D> = Box::new( frames( output_rx )
+                .and_then( |value| D::deserialize( value )
+                                    .map_err( serco::ServiceError::from ) ) );
+
+        Box::new( tx.send( request )
+            .map_err( serco::ServiceError::transport )
+            .map( move |_| feed_while( feeds, results ) )
+            .flatten_stream() )
+    }
+
+    fn forward_batch(
+        &self,
+        calls: Vec<serco::batch::Call>,
+        mode: serco::batch::BatchMode,
+    ) -> Box<Future<Item=Vec<Result<serco::stream::IncomingItem, serco::ServiceError>>,
+                    Error=serco::ServiceError>>
+    {
+        let tx = self.tx.clone();
+        let envelope = BatchEnvelope {
+            calls: calls.into_iter().map( |call| BatchCallEnvelope {
+                name: call.name.to_string(),
+                params: serde_json::to_value( call.params ).unwrap(),
+            } ).collect(),
+            mode: mode,
         };
-        let msg = serde_json::to_string( &envelope ).unwrap();
+        let msg = serde_json::to_string(
+                &RequestMessage::Batch( envelope ) ).unwrap();
 
         Box::new( futures::future::lazy( move || {
             let (tx_once, rx_once) = oneshot::channel();
-            tx.send( ( msg, tx_once ) )
-                .map_err( |e| serco::ServiceError::from(e) )
-                .then( |_| rx_once )
+            let request = Request {
+                message: msg,
+                input_streams: vec![],
+                output_stream: None,
+                response: tx_once,
+            };
+            tx.send( request )
+                .map_err( serco::ServiceError::transport )
+                .and_then( |_| rx_once.map_err( serco::ServiceError::transport ) )
         } )
-        .then( |result| {
-            match result {
-                Ok( envelope_str ) => {
-                    let envelope : ResponseEnvelope = serde_json::from_str( &envelope_str ).unwrap();
-                    envelope.result.map( |v| D::deserialize( v ).unwrap() )
-                },
-                Err(e) => Err( serco::ServiceError::from( e ) )
-            }
+        .and_then( |envelope_str| {
+
+            // Refused batches get a single error instead of the results.
+            let envelope : BatchResponseEnvelope =
+                    match serde_json::from_str( &envelope_str ) {
+                        Ok( envelope ) => envelope,
+                        Err( e ) => return match serde_json::from_str( &envelope_str ) {
+                            Ok( ResponseEnvelope { result: Err( e ) } ) => Err( e ),
+                            _ => Err( serco::ServiceError::from( e ) ),
+                        },
+                    };
+            Ok( envelope.results.into_iter().map( |result| result.map( |value|
+                    Box::new( erased_serde::Deserializer::erase( value ) )
+                        as serco::stream::IncomingItem ) )
+                .collect() )
         } ) )
     }
 
@@ -342,3 +1044,277 @@ impl serco::Forwarder for MpscForwarder
         self.tx.close().expect( "Failed to close tx" );
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serco::prelude::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[service_contract]
+    pub trait Lines {
+        fn shout( &self, lines: ServiceStream<String> ) -> ServiceStream<String>;
+        fn ignore( &self, lines: ServiceStream<String> ) -> ServiceStream<String>;
+        fn hold( &self, lines: ServiceStream<String> ) -> ServiceStream<String>;
+    }
+
+    #[service(Lines)]
+    struct LinesService;
+    impl Lines for LinesService {
+        fn shout( &self, lines: ServiceStream<String> ) -> ServiceStream<String> {
+            Box::new( lines.map( |line| line.to_uppercase() ) )
+        }
+
+        fn ignore( &self, _lines: ServiceStream<String> ) -> ServiceStream<String> {
+            Box::new( futures::stream::iter_ok( vec![ "ignored".to_string() ] ) )
+        }
+
+        fn hold( &self, lines: ServiceStream<String> ) -> ServiceStream<String> {
+            // Keeps the input open without reading it until the results
+            // are dropped.
+            Box::new( futures::stream::iter_ok( vec![ "held".to_string() ] )
+                .map( move |line| { let _ = &lines; line } ) )
+        }
+    }
+
+    fn host( name: &'static str ) -> MpscServiceConnection<Lines> {
+        thread::spawn( move || {
+            serco::ServiceHost::new( Lines::singleton( LinesService ) )
+                    .endpoint( MpscEndpoint::new( name ) )
+                    .run()
+                    .wait()
+                    .ok();
+        } );
+        while get_endpoint( name ).is_none() {
+            thread::sleep( Duration::from_millis( 10 ) );
+        }
+        MpscClient::new( name ).connect::<Lines>().wait().unwrap()
+    }
+
+    fn endless() -> ServiceStream<String> {
+        Box::new( futures::stream::repeat( "line".to_string() ) )
+    }
+
+    #[test]
+    pub fn streams() {
+        let connection = host( "test_streams" );
+        let lines = vec![ "foo".to_string(), "bar".to_string() ];
+        let result = connection.shout( Box::new( futures::stream::iter_ok( lines ) ) )
+                .collect().wait().unwrap();
+        assert_eq!( result, vec![ "FOO", "BAR" ] );
+    }
+
+    #[test]
+    pub fn unread_input() {
+        let connection = host( "test_unread_input" );
+
+        // The input is never read by the host so the calls must end with
+        // the results.
+        let result = connection.ignore( endless() ).collect().wait().unwrap();
+        assert_eq!( result, vec![ "ignored" ] );
+        let result = connection.hold( endless() ).collect().wait().unwrap();
+        assert_eq!( result, vec![ "held" ] );
+
+        // The connection stays usable.
+        let lines = vec![ "foo".to_string() ];
+        let result = connection.shout( Box::new( futures::stream::iter_ok( lines ) ) )
+                .collect().wait().unwrap();
+        assert_eq!( result, vec![ "FOO" ] );
+    }
+
+    #[service_contract]
+    pub trait Ledger {
+        fn record( &self, entry: String ) -> usize;
+        fn entries( &self ) -> Vec<String>;
+
+        // Named after the methods of the batches to show they don't
+        // collide with the builders.
+        fn execute( &self, entry: String ) -> usize;
+        fn len( &self ) -> usize;
+    }
+
+    #[service(Ledger)]
+    struct LedgerService {
+        entries: std::sync::Mutex<Vec<String>>,
+    }
+    impl Ledger for LedgerService {
+        fn record( &self, entry: String ) -> usize {
+            let mut entries = self.entries.lock().unwrap();
+            entries.push( entry );
+            entries.len()
+        }
+
+        fn entries( &self ) -> Vec<String> {
+            self.entries.lock().unwrap().clone()
+        }
+
+        fn execute( &self, entry: String ) -> usize {
+            self.record( format!( "executed {}", entry ) )
+        }
+
+        fn len( &self ) -> usize {
+            self.entries.lock().unwrap().len()
+        }
+    }
+
+    fn ledger( name: &'static str ) -> MpscServiceConnection<Ledger> {
+        thread::spawn( move || {
+            let service = LedgerService { entries: Default::default() };
+            serco::ServiceHost::new( Ledger::singleton( service ) )
+                    .endpoint( MpscEndpoint::new( name ) )
+                    .run()
+                    .wait()
+                    .ok();
+        } );
+        while get_endpoint( name ).is_none() {
+            thread::sleep( Duration::from_millis( 10 ) );
+        }
+        MpscClient::new( name ).connect::<Ledger>().wait().unwrap()
+    }
+
+    #[test]
+    pub fn batch() {
+        let connection = ledger( "test_batch" );
+
+        let mut batch = serco::batch::Batch::new( &*connection );
+        let first = batch.calls().record( "first".to_string() );
+        let second = batch.calls().execute( "second".to_string() );
+        let missing = batch.call::<usize, _>( "missing", () );
+        let invalid = batch.call::<usize, _>( "record", 5 );
+        let len = batch.calls().len();
+        let entries = batch.calls().entries();
+        assert_eq!( batch.len(), 6 );
+        let mut results = batch.execute().wait().unwrap();
+
+        // The calls are executed in order and fail one by one.
+        assert_eq!( results.len(), 6 );
+        assert_eq!( results.get( first ).unwrap(), 1 );
+        assert_eq!( results.get( second ).unwrap(), 2 );
+        assert_eq!( results.get( missing ).unwrap_err().kind,
+                    serco::ErrorKind::BadOperation );
+        assert_eq!( results.get( invalid ).unwrap_err().kind,
+                    serco::ErrorKind::BadRequest );
+        assert_eq!( results.get( len ).unwrap(), 2 );
+        assert_eq!( results.get( entries ).unwrap(),
+                    vec![ "first", "executed second" ] );
+
+        // Each result can be taken once.
+        assert_eq!( results.get( first ).unwrap_err().kind,
+                    serco::ErrorKind::BadRequest );
+
+        assert_eq!( connection.len(), 2 );
+    }
+
+    #[test]
+    pub fn parallel_batch() {
+        let connection = ledger( "test_parallel_batch" );
+
+        let mut batch = serco::batch::Batch::new( &*connection )
+                .mode( serco::batch::BatchMode::Parallel );
+        let missing = batch.call::<usize, _>( "missing", () );
+        let first = batch.calls().record( "first".to_string() );
+        let invalid = batch.call::<usize, _>( "record", 5 );
+        let second = batch.calls().record( "second".to_string() );
+        let mut results = batch.execute().wait().unwrap();
+
+        // The results and the errors keep the order of the calls.
+        assert_eq!( results.len(), 4 );
+        assert_eq!( results.get( missing ).unwrap_err().kind,
+                    serco::ErrorKind::BadOperation );
+        assert_eq!( results.get( invalid ).unwrap_err().kind,
+                    serco::ErrorKind::BadRequest );
+        let mut indices = vec![ results.get( first ).unwrap(),
+                                results.get( second ).unwrap() ];
+        indices.sort();
+        assert_eq!( indices, vec![ 1, 2 ] );
+
+        // An empty batch completes as well.
+        let batch = serco::batch::Batch::new( &*connection )
+                .mode( serco::batch::BatchMode::Parallel );
+        assert!( batch.execute().wait().unwrap().is_empty() );
+
+        assert_eq!( connection.len(), 2 );
+    }
+
+    #[test]
+    pub fn retry_after_reconnect() {
+        use serco::Forwarder;
+        use serco::reconnect::Connector;
+
+        let connection = ledger( "test_retry_after_reconnect" );
+        let connector = MpscConnector::new::<Ledger, _>( "test_retry_after_reconnect", () );
+        let record = |forwarder: &MpscForwarder, entry: &str| {
+            let call = serco::CallInfo {
+                name: "record",
+                idempotent: false,
+                request_id: Some( "request-1".to_string() ),
+            };
+            let mut params = serde_json::Map::new();
+            params.insert( "entry".to_string(), serde_json::Value::from( entry ) );
+            forwarder.forward_call::<usize, _>( call, params ).wait().unwrap()
+        };
+
+        let ( session_id, forwarder ) = connector.connect( None ).wait().unwrap();
+        assert_eq!( record( &forwarder, "first" ), 1 );
+        assert_eq!( record( &forwarder, "first" ), 1 );
+        drop( forwarder );
+
+        // The retry on the resumed session gets the response of the first
+        // attempt without recording the entry again.
+        let ( _, forwarder ) = connector.connect( Some( &session_id ) ).wait().unwrap();
+        assert_eq!( record( &forwarder, "retried" ), 1 );
+        assert_eq!( connection.entries(), vec![ "first" ] );
+
+        // The other sessions don't share the responses.
+        let ( _, other ) = connector.connect( None ).wait().unwrap();
+        assert_eq!( record( &other, "other" ), 2 );
+    }
+
+    #[test]
+    pub fn refused_requests() {
+        use serco::reconnect::Connector;
+
+        let name = "test_refused_requests";
+        thread::spawn( move || {
+            let service = LedgerService { entries: Default::default() };
+            serco::ServiceHost::new( Ledger::singleton( service ) )
+                    .message_limits( serco::MessageLimits::new().max_request_size( 200 ) )
+                    .endpoint( MpscEndpoint::new( name ) )
+                    .run()
+                    .wait()
+                    .ok();
+        } );
+        while get_endpoint( name ).is_none() {
+            thread::sleep( Duration::from_millis( 10 ) );
+        }
+        let connector = MpscConnector::new::<Ledger, _>( name, () );
+        let ( _, forwarder ) = connector.connect( None ).wait().unwrap();
+        let send = |message: String| {
+            let ( tx, rx ) = oneshot::channel();
+            let request = Request {
+                message: message,
+                input_streams: vec![],
+                output_stream: None,
+                response: tx,
+            };
+            forwarder.tx.clone().send( request ).wait().unwrap();
+            let envelope : ResponseEnvelope = serde_json::from_str( &rx.wait().unwrap() ).unwrap();
+            envelope.result.unwrap_err().kind
+        };
+
+        assert_eq!( send( "not json".to_string() ), serco::ErrorKind::BadRequest );
+        assert_eq!( send( format!( "\"{}\"", "x".repeat( 300 ) ) ),
+                    serco::ErrorKind::MessageTooLarge );
+
+        // The session stays usable after the refusals.
+        let proxy = serco::ServiceProxy::<Ledger, _>::new( forwarder );
+        assert_eq!( proxy.record( "first".to_string() ), 1 );
+        let mut batch = serco::batch::Batch::new( &proxy );
+        batch.calls().record( "x".repeat( 300 ) );
+        match batch.execute().wait() {
+            Err( e ) => assert_eq!( e.kind, serco::ErrorKind::MessageTooLarge ),
+            Ok( _ ) => panic!( "The batch over the limit was executed" ),
+        }
+    }
+}
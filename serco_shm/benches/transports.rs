@@ -0,0 +1,132 @@
+//! Compares the round trips of large messages through the shared memory
+//! endpoint with the JSON-RPC endpoint on Unix domain sockets and TCP.
+//!
+//! All of the transports carry the same JSON-RPC messages so the
+//! difference is in how the bytes travel between the processes.
+//!
+//!     cargo bench -p serco_shm
+
+#![feature(test, proc_macro)]
+
+extern crate test;
+use test::Bencher;
+
+extern crate futures;
+use futures::prelude::*;
+extern crate tokio_core;
+use tokio_core::reactor::Core;
+
+extern crate serco;
+extern crate serco_derive;
+use serco_derive::*;
+extern crate serco_jsonrpc;
+use serco_jsonrpc::JsonRpcEndpoint;
+extern crate serco_shm;
+use serco_shm::{ShmClient, ShmEndpoint};
+
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+#[macro_use] extern crate serde_json;
+use serde_json::Value;
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+/// Size of the message in each direction.
+const PAYLOAD : usize = 256 * 1024;
+
+#[service_contract]
+pub trait Echo {
+    fn echo( &self, data: String ) -> String;
+}
+
+#[service(Echo)]
+struct EchoService;
+impl Echo for EchoService {
+    fn echo( &self, data: String ) -> String { data }
+}
+
+macro_rules! spawn_host {
+    ( $endpoint:expr ) => {
+        thread::spawn( move || {
+            let host = serco::ServiceHost::new( Echo::singleton( EchoService ) )
+                    .endpoint( $endpoint )
+                    .run();
+            Core::new().unwrap().run( host ).ok();
+        } )
+    }
+}
+
+fn payload() -> String {
+    ( 0..PAYLOAD ).map( |i| ( b'a' + ( i % 26 ) as u8 ) as char ).collect()
+}
+
+fn socket_path( name: &str ) -> PathBuf {
+    let path = std::env::temp_dir().join(
+            format!( "serco_bench_{}_{}.sock", name, std::process::id() ) );
+    fs::remove_file( &path ).ok();
+    path
+}
+
+fn retry<T, E, F: Fn() -> Result<T, E>>( connect: F ) -> T {
+    loop {
+        if let Ok( connected ) = connect() {
+            return connected;
+        }
+        thread::sleep( Duration::from_millis( 10 ) );
+    }
+}
+
+/// Calls `echo` on a line framed JSON-RPC connection.
+fn echo_lines<S: Read + Write>( stream: &mut S, reader: &mut BufRead, data: &str ) -> usize {
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": "echo", "params": { "data": data } });
+    writeln!( stream, "{}", request ).unwrap();
+
+    let mut line = String::new();
+    reader.read_line( &mut line ).unwrap();
+    let response : Value = serde_json::from_str( &line ).unwrap();
+    response[ "result" ].as_str().unwrap().len()
+}
+
+#[bench]
+fn shared_memory( b: &mut Bencher ) {
+    let path = socket_path( "shm" );
+    let endpoint_path = path.clone();
+    spawn_host!( ShmEndpoint::new( endpoint_path ) );
+
+    let connection = retry( || ShmClient::new( path.clone() ).connect::<Echo>().wait() );
+    let data = payload();
+    b.bytes = 2 * PAYLOAD as u64;
+    b.iter( || assert_eq!( connection.echo( data.clone() ).len(), PAYLOAD ) );
+}
+
+#[bench]
+fn unix_socket( b: &mut Bencher ) {
+    let path = socket_path( "unix" );
+    let endpoint_path = path.clone();
+    spawn_host!( JsonRpcEndpoint::unix( endpoint_path ) );
+
+    let mut stream = retry( || UnixStream::connect( &path ) );
+    let mut reader = BufReader::new( stream.try_clone().unwrap() );
+    let data = payload();
+    b.bytes = 2 * PAYLOAD as u64;
+    b.iter( || assert_eq!( echo_lines( &mut stream, &mut reader, &data ), PAYLOAD ) );
+}
+
+#[bench]
+fn tcp( b: &mut Bencher ) {
+    let address : std::net::SocketAddr = "127.0.0.1:50571".parse().unwrap();
+    spawn_host!( JsonRpcEndpoint::tcp( address ) );
+
+    let mut stream = retry( || TcpStream::connect( &address ) );
+    stream.set_nodelay( true ).unwrap();
+    let mut reader = BufReader::new( stream.try_clone().unwrap() );
+    let data = payload();
+    b.bytes = 2 * PAYLOAD as u64;
+    b.iter( || assert_eq!( echo_lines( &mut stream, &mut reader, &data ), PAYLOAD ) );
+}
@@ -0,0 +1,648 @@
+//! Shared memory endpoint and client for serco services.
+//!
+//! Meant for processes on the same machine that exchange large messages.
+//! The messages are passed through a pair of rings in memory shared by the
+//! processes, one ring for each direction, instead of being copied through
+//! the kernel as with the sockets. The processes wake each other up with
+//! eventfd counters. See the `ring` module for the layout of the rings.
+//!
+//! The clients connect to the endpoint through a Unix domain socket. The
+//! client creates the shared memory and the counters and passes them to the
+//! host over the socket. The socket stays open for the lifetime of the
+//! connection so either end notices when the other goes away, even if the
+//! process crashes.
+//!
+//! The host authenticates the clients by the process, user and group IDs
+//! the kernel reports for the other end of the socket. The host only maps
+//! memory of the expected size whose size is sealed and checks the ring
+//! headers on every access, so a misbehaving client can't make it read
+//! outside of the memory. The host refuses the requests over its request
+//! size limit or over `MAX_MESSAGE_SIZE` before reading them and closes the
+//! connection.
+//!
+//! The messages are JSON-RPC 2.0 messages as described in
+//! `serco_jsonrpc::protocol` and the host may call the client back through
+//! the same rings. Each connection is a session. Streaming operations and
+//! batches are not available through this endpoint.
+//!
+//! Only available on Linux.
+
+#![cfg(target_os = "linux")]
+#![cfg_attr(test, feature(proc_macro))]
+
+extern crate futures;
+use futures::prelude::*;
+use futures::future;
+use futures::sync::mpsc;
+
+extern crate libc;
+
+extern crate serco;
+use serco::{ServiceError, Credentials, MessageLimits};
+use serco::metrics::EndpointMetrics;
+extern crate serco_jsonrpc;
+use serco_jsonrpc::{peer, protocol};
+
+extern crate serde_json;
+use serde_json::Value;
+
+#[cfg(test)] extern crate serde;
+#[cfg(test)] #[macro_use] extern crate serde_derive;
+#[cfg(test)] extern crate serco_derive;
+
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::net::Shutdown;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pub mod ring;
+mod sys;
+
+use ring::Ring;
+use sys::{EventFd, Fd, Region};
+
+/// Kind under which the shared memory endpoints are registered in service
+/// registries.
+pub const ENDPOINT_KIND : &str = "shm";
+
+/// Capacity of each ring unless specified with `ShmClient::ring_size`.
+pub const DEFAULT_RING_SIZE : usize = 1024 * 1024;
+
+/// Largest message either end accepts. The messages are allocated in full
+/// before they are read from the rings.
+pub const MAX_MESSAGE_SIZE : usize = 256 * 1024 * 1024;
+
+/// Time the clients have for passing the shared memory to the host once
+/// connected.
+pub const HANDSHAKE_TIMEOUT : Duration = Duration::from_secs( 5 );
+
+pub struct ShmEndpoint {
+    path: PathBuf,
+}
+
+impl ShmEndpoint {
+
+    /// Serves the clients connecting through the Unix domain socket at the
+    /// path.
+    pub fn new<P: Into<PathBuf>>( path: P ) -> Self {
+        ShmEndpoint { path: path.into() }
+    }
+}
+
+impl<TService,
+        THostImplementation,
+        TSessionFactory>
+    serco::ServiceEndpoint<TService,
+        TSessionFactory,
+        THostImplementation,
+    >
+    for ShmEndpoint
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+          THostImplementation::ServiceInstance: 'static,
+{
+    fn run(
+        &self,
+        host: Rc<serco::HostRuntime<
+                TService,
+                TSessionFactory,
+                THostImplementation,
+        >>
+    ) -> Box<Future<Item=(), Error=serco::ServiceError>>
+    {
+        let listener = match UnixListener::bind( &self.path ) {
+            Ok( listener ) => listener,
+            Err( e ) => return Box::new( future::err( ServiceError::transport( e ) ) ),
+        };
+
+        let ( connections_tx, connections_rx ) = mpsc::unbounded();
+        let limits = *host.message_limits();
+        let metrics = host.endpoint_metrics( ENDPOINT_KIND );
+        thread::spawn( move || accept( listener, limits, metrics, connections_tx ) );
+
+        let limit = host.connection_limit();
+        Box::new( connections_rx
+            .map_err( |_| ServiceError::transport( "The socket thread stopped" ) )
+            .map( move |connection| peer::serve_session( &host, connection, &limit ) )
+            // Serve all connected sessions concurrently.
+            .buffer_unordered( usize::max_value() )
+            .for_each( |_| Ok( () ) ) )
+    }
+
+    fn address( &self ) -> Option<serco::registry::EndpointAddress> {
+        Some( serco::registry::EndpointAddress::new(
+                ENDPOINT_KIND, format!( "shm://{}", self.path.display() ) ) )
+    }
+}
+
+/// Accepts the connections on the socket thread and passes them to the host.
+///
+/// Each handshake runs on a thread of its own so the clients that never
+/// finish theirs don't hold up the others.
+fn accept(
+    listener: UnixListener,
+    limits: MessageLimits,
+    metrics: EndpointMetrics,
+    connections: mpsc::UnboundedSender<peer::Connection>,
+) {
+    for socket in listener.incoming() {
+        let socket = match socket {
+            Ok( socket ) => socket,
+            Err( _ ) => continue,
+        };
+
+        let connections = connections.clone();
+        let metrics = metrics.clone();
+        thread::spawn( move || {
+
+            // Clients that fail the handshake are dropped.
+            let rings = match handshake( &socket ) {
+                Ok( rings ) => rings,
+                Err( _ ) => return,
+            };
+            let ( mut connection, link ) = peer::connection();
+            if let Ok( ( pid, uid, gid ) ) = sys::peer_credentials( socket.as_raw_fd() ) {
+                connection.credentials = Credentials::Peer { pid: Some( pid ), uid: uid, gid: gid };
+            }
+            if connections.unbounded_send( connection ).is_err() {
+                return;
+            }
+            spawn_link( link.message_limits( limits ).metrics( metrics ), rings, socket, Some( limits ) ).ok();
+        } );
+    }
+}
+
+/// Receives the rings of a new connection within the handshake timeout.
+fn handshake( socket: &UnixStream ) -> io::Result<Rings> {
+    socket.set_read_timeout( Some( HANDSHAKE_TIMEOUT ) )?;
+    let rings = Rings::accept( socket )?;
+    socket.set_read_timeout( None )?;
+    Ok( rings )
+}
+
+pub struct ShmClient {
+    path: PathBuf,
+    ring_size: usize,
+}
+
+impl ShmClient {
+
+    /// Creates a client for the endpoint listening at the socket path.
+    pub fn new<P: Into<PathBuf>>( path: P ) -> ShmClient {
+        ShmClient { path: path.into(), ring_size: DEFAULT_RING_SIZE }
+    }
+
+    /// Specifies the capacity of the rings in bytes. Larger messages are
+    /// passed through the rings in parts.
+    pub fn ring_size( mut self, ring_size: usize ) -> Self {
+        self.ring_size = ring_size;
+        self
+    }
+
+    pub fn connect<S>(
+        &self,
+    ) -> Box<Future<Item=ShmConnection<S>, Error=String>>
+        where S: serco::ServiceContract<CallbackContract = ()> + ?Sized + 'static,
+    {
+        self.connect_duplex::<S, (), ()>( () )
+    }
+
+    /// Connects to the endpoint. The host calls the `callback` through the
+    /// same rings.
+    pub fn connect_duplex<S, C, T>(
+        &self,
+        callback: T,
+    ) -> Box<Future<Item=ShmConnection<S>, Error=String>>
+        where S: serco::ServiceContract<CallbackContract = C> + ?Sized + 'static,
+            C: serco::ServiceContract<CallbackContract = ()> + ?Sized + 'static,
+            T: serco::InvokeTarget<C> + Send + 'static,
+    {
+        let ( connection, link_handle ) = match UnixStream::connect( &self.path )
+                .and_then( |socket| {
+                    let rings = Rings::connect( &socket, self.ring_size )?;
+                    let ( connection, link ) = peer::connection();
+                    let handle = spawn_link( link, rings, socket, None )?;
+                    Ok( ( connection, handle ) )
+                } ) {
+            Ok( connected ) => connected,
+            Err( e ) => return Box::new( future::err( format!( "{:?}", e ) ) ),
+        };
+
+        let peer::Connection { requests, forwarder, .. } = connection;
+        let callback_handle = peer::spawn_callback_handler::<C, _>(
+                callback, requests, forwarder.sender() );
+
+        Box::new( future::ok( ShmConnection {
+            proxy: serco::ServiceProxy::new( forwarder ),
+            _link_handle: link_handle,
+            _callback_handle: callback_handle,
+        } ) )
+    }
+}
+
+/// Connection to a shared memory endpoint.
+///
+/// The connection is closed when it is dropped.
+pub struct ShmConnection<S: ?Sized> {
+    proxy: serco::ServiceProxy<S, peer::PeerForwarder>,
+    _link_handle: thread::JoinHandle<()>,
+    _callback_handle: thread::JoinHandle<()>,
+}
+
+/// Dereference the connection into the service proxy.
+/// The proxy implements the actual service trait.
+impl<S: serco::ServiceContract + ?Sized> std::ops::Deref for ShmConnection<S>
+{
+    type Target = serco::ServiceProxy<S, peer::PeerForwarder>;
+
+    fn deref( &self ) -> &Self::Target {
+        &self.proxy
+    }
+}
+
+/// The rings of a connection as seen from one end.
+struct Rings {
+    incoming: Arc<Ring>,
+    outgoing: Arc<Ring>,
+}
+
+impl Rings {
+
+    /// Creates the shared memory of a new connection and passes it to the
+    /// host.
+    ///
+    /// The client to host ring comes first in the memory followed by the host
+    /// to client ring. The capacity is sent along with the descriptors.
+    fn connect( socket: &UnixStream, capacity: usize ) -> io::Result<Rings> {
+
+        // Keep the header of the second ring aligned.
+        let capacity = ( capacity + ring::HEADER_SIZE - 1 ) / ring::HEADER_SIZE * ring::HEADER_SIZE;
+        let ( region, memory ) = Region::create( 2 * Ring::size( capacity ) )?;
+        let ( to_host_readable, to_host_writable ) = ( EventFd::new()?, EventFd::new()? );
+        let ( to_client_readable, to_client_writable ) = ( EventFd::new()?, EventFd::new()? );
+        let fds = [
+            memory.0,
+            to_host_readable.fd(), to_host_writable.fd(),
+            to_client_readable.fd(), to_client_writable.fd() ];
+        let mut data = [ 0u8; 8 ];
+        for ( i, byte ) in data.iter_mut().enumerate() {
+            *byte = ( capacity as u64 >> ( 8 * i ) ) as u8;
+        }
+        sys::send_fds( socket.as_raw_fd(), &data, &fds )?;
+
+        let region = Arc::new( region );
+        Ok( Rings {
+            outgoing: Arc::new( Ring::new(
+                    region.clone(), 0, capacity,
+                    to_host_readable, to_host_writable )? ),
+            incoming: Arc::new( Ring::new(
+                    region, Ring::size( capacity ), capacity,
+                    to_client_readable, to_client_writable )? ),
+        } )
+    }
+
+    /// Receives the shared memory of a new connection from the client.
+    fn accept( socket: &UnixStream ) -> io::Result<Rings> {
+        let mut data = [ 0u8; 8 ];
+        let mut fds = sys::recv_fds( socket.as_raw_fd(), &mut data, 5 )?.into_iter();
+        let capacity = data.iter().rev().fold( 0u64, |capacity, &b| capacity << 8 | b as u64 ) as usize;
+
+        let memory : Fd = fds.next().unwrap();
+        let mut counters = fds.map( EventFd::from_fd ).collect::<io::Result<Vec<_>>>()?.into_iter();
+        let mut counter = || counters.next().unwrap();
+
+        // The memory must hold both of the rings.
+        let len = match capacity.checked_add( ring::HEADER_SIZE ).and_then( |size| size.checked_mul( 2 ) ) {
+            Some( len ) if capacity > 0 => len,
+            _ => return Err( io::Error::new( io::ErrorKind::InvalidData, "Invalid ring size" ) ),
+        };
+        let region = Region::open( &memory, len )?;
+
+        let region = Arc::new( region );
+        let ( to_host_readable, to_host_writable ) = ( counter(), counter() );
+        let ( to_client_readable, to_client_writable ) = ( counter(), counter() );
+        Ok( Rings {
+            incoming: Arc::new( Ring::new(
+                    region.clone(), 0, capacity,
+                    to_host_readable, to_host_writable )? ),
+            outgoing: Arc::new( Ring::new(
+                    region, Ring::size( capacity ), capacity,
+                    to_client_readable, to_client_writable )? ),
+        } )
+    }
+}
+
+/// Serves the rings of a connection on threads of their own.
+///
+/// The rings are closed once the link is done or the socket closes. The
+/// host passes its message `limits` to refuse the requests over them with
+/// an error before closing the connection.
+fn spawn_link(
+    link: peer::Link,
+    rings: Rings,
+    socket: UnixStream,
+    limits: Option<MessageLimits>,
+) -> io::Result<thread::JoinHandle<()>>
+{
+    let Rings { incoming, outgoing } = rings;
+
+    // Nothing is sent over the socket after the handshake so reading it
+    // only ends when either end shuts it down or exits.
+    let mut watch = socket.try_clone()?;
+    let closed = ( incoming.clone(), outgoing.clone() );
+    thread::spawn( move || {
+        let mut byte = [ 0u8; 1 ];
+        while let Ok( 1 ) = watch.read( &mut byte ) {}
+        closed.0.close();
+        closed.1.close();
+    } );
+
+    let refused = Arc::new( Mutex::new( None ) );
+    let messages = receive_messages( incoming, limits.unwrap_or_default(), refused.clone() );
+    Ok( thread::spawn( move || {
+        link.run( messages, RingSink { ring: outgoing.clone() } ).wait().ok();
+
+        // Nothing else writes to the ring once the link is done.
+        let refused = refused.lock().unwrap().take();
+        if let ( Some( e ), Some( _ ) ) = ( refused, limits ) {
+            outgoing.send( protocol::failure( Value::Null, &e ).to_string().as_bytes() ).ok();
+        }
+        outgoing.close();
+        socket.shutdown( Shutdown::Both ).ok();
+    } ) )
+}
+
+/// Reads the messages from the ring on a thread of its own.
+///
+/// The messages are read until the ring closes or fails. The length of each
+/// message is checked against the request size limit before the message is
+/// read. The error refusing a message is stored in `refused` before the
+/// messages end.
+fn receive_messages(
+    ring: Arc<Ring>,
+    limits: MessageLimits,
+    refused: Arc<Mutex<Option<ServiceError>>>,
+) -> mpsc::UnboundedReceiver<String>
+{
+    let ( messages_tx, messages_rx ) = mpsc::unbounded();
+    thread::spawn( move || {
+        let check = |len| check_message_size( &limits, len ).map_err( |e| {
+            let error = io::Error::new( io::ErrorKind::InvalidData, e.message.clone() );
+            *refused.lock().unwrap() = Some( e );
+            error
+        } );
+        while let Ok( message ) = ring.receive( &check ) {
+            let message = match String::from_utf8( message ) {
+                Ok( message ) => message,
+                Err( _ ) => break,
+            };
+            if messages_tx.unbounded_send( message ).is_err() {
+                break;
+            }
+        }
+    } );
+    messages_rx
+}
+
+/// Refuses the messages over `MAX_MESSAGE_SIZE` or the request size limit.
+fn check_message_size( limits: &MessageLimits, len: usize ) -> Result<(), ServiceError> {
+    if len > MAX_MESSAGE_SIZE {
+        return Err( ServiceError::message_too_large( format!(
+                "The message of {} bytes is over the limit of {} bytes", len, MAX_MESSAGE_SIZE ) ) );
+    }
+    limits.check_request_size( len )
+}
+
+/// Sink that writes the messages to a ring, blocking while the ring is full.
+struct RingSink {
+    ring: Arc<Ring>,
+}
+
+impl Sink for RingSink {
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send( &mut self, item: String ) -> StartSend<String, io::Error> {
+        self.ring.send( item.as_bytes() )?;
+        Ok( AsyncSink::Ready )
+    }
+
+    fn poll_complete( &mut self ) -> Poll<(), io::Error> {
+        Ok( Async::Ready( () ) )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serco_derive::*;
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[service_contract( callback = Listener )]
+    pub trait Chat {
+        fn say( &self, message: String ) -> String;
+    }
+
+    #[service_contract]
+    pub trait Listener {
+        fn hear( &self, message: String ) -> String;
+    }
+
+    #[service(Chat)]
+    struct ChatService;
+    impl Chat for ChatService {
+        fn say( &self, message: String ) -> String {
+            let listener = Chat::get_callback();
+            format!( "< {} >", listener.hear( message ) )
+        }
+    }
+
+    #[service(Listener)]
+    struct LoudListener;
+    impl Listener for LoudListener {
+        fn hear( &self, message: String ) -> String {
+            message.to_uppercase()
+        }
+    }
+
+    #[service_contract]
+    pub trait Echo {
+        fn echo( &self, data: String ) -> String;
+    }
+
+    #[service(Echo)]
+    struct EchoService;
+    impl Echo for EchoService {
+        fn echo( &self, data: String ) -> String { data }
+    }
+
+    fn socket_path( name: &str ) -> PathBuf {
+        let path = std::env::temp_dir().join(
+                format!( "serco_shm_{}_{}.sock", name, std::process::id() ) );
+        fs::remove_file( &path ).ok();
+        path
+    }
+
+    fn wait_for( path: &Path ) {
+        while !path.exists() {
+            thread::sleep( Duration::from_millis( 10 ) );
+        }
+    }
+
+    #[test]
+    pub fn large_messages() {
+        let path = socket_path( "echo" );
+        let endpoint = ShmEndpoint::new( path.clone() );
+        thread::spawn( move || {
+            serco::ServiceHost::new( Echo::singleton( EchoService ) )
+                    .endpoint( endpoint )
+                    .run()
+                    .wait()
+                    .ok();
+        } );
+        wait_for( &path );
+
+        // The messages are many times the size of the rings.
+        let connection = ShmClient::new( path.clone() )
+                .ring_size( 4096 )
+                .connect::<Echo>()
+                .wait()
+                .unwrap();
+        for len in &[ 0, 10, 4090, 100_000 ] {
+            let data : String = ( 0..*len ).map( |i| ( b'a' + ( i % 26 ) as u8 ) as char ).collect();
+            assert_eq!( connection.echo( data.clone() ), data );
+        }
+        fs::remove_file( &path ).ok();
+    }
+
+    #[test]
+    pub fn duplex() {
+        let path = socket_path( "chat" );
+        let endpoint = ShmEndpoint::new( path.clone() );
+        thread::spawn( move || {
+            serco::ServiceHost::new( Chat::singleton( ChatService ) )
+                    .endpoint( endpoint )
+                    .run()
+                    .wait()
+                    .ok();
+        } );
+        wait_for( &path );
+
+        for _ in 0..2 {
+            let connection = ShmClient::new( path.clone() )
+                    .connect_duplex::<Chat, _, _>( LoudListener )
+                    .wait()
+                    .unwrap();
+            assert_eq!( connection.say( "hello".to_string() ), "< HELLO >" );
+            assert_eq!( connection.say( "again".to_string() ), "< AGAIN >" );
+        }
+        fs::remove_file( &path ).ok();
+    }
+
+    #[test]
+    pub fn request_too_large() {
+        let path = socket_path( "limits" );
+        let endpoint = ShmEndpoint::new( path.clone() );
+        thread::spawn( move || {
+            serco::ServiceHost::new( Echo::singleton( EchoService ) )
+                    .message_limits( MessageLimits::new().max_request_size( 100 ) )
+                    .endpoint( endpoint )
+                    .run()
+                    .wait()
+                    .ok();
+        } );
+        wait_for( &path );
+
+        // The request is refused before the host reads it.
+        let socket = UnixStream::connect( &path ).unwrap();
+        let rings = Rings::connect( &socket, 4096 ).unwrap();
+        rings.outgoing.send( &[ b' '; 1000 ] ).unwrap();
+        let response = rings.incoming.receive( |_| Ok( () ) ).unwrap();
+        let response : Value = serde_json::from_slice( &response ).unwrap();
+        assert_eq!( response[ "id" ], Value::Null );
+        assert_eq!( protocol::service_error( &response[ "error" ] ).kind,
+                    serco::ErrorKind::MessageTooLarge );
+
+        // The connection is closed after refusing the request.
+        assert!( rings.incoming.receive( |_| Ok( () ) ).is_err() );
+        fs::remove_file( &path ).ok();
+    }
+
+    #[test]
+    pub fn rejected_handshake() {
+        let path = socket_path( "handshake" );
+        let listener = UnixListener::bind( &path ).unwrap();
+        let handle = thread::spawn( move || {
+            let ( socket, _ ) = listener.accept().unwrap();
+            Rings::accept( &socket ).is_err()
+        } );
+
+        // A client that sends no memory is refused.
+        let mut socket = UnixStream::connect( &path ).unwrap();
+        socket.write_all( &[ 0u8; 8 ] ).unwrap();
+        assert!( handle.join().unwrap() );
+        fs::remove_file( &path ).ok();
+    }
+
+    #[test]
+    pub fn unsealed_memory() {
+        let path = socket_path( "unsealed" );
+        let listener = UnixListener::bind( &path ).unwrap();
+        let handle = thread::spawn( move || {
+            let ( socket, _ ) = listener.accept().unwrap();
+            Rings::accept( &socket ).is_err()
+        } );
+
+        // Memory the client could still shrink is refused.
+        let socket = UnixStream::connect( &path ).unwrap();
+        let capacity = 4096;
+        let memory = unsafe {
+            libc::syscall( libc::SYS_memfd_create, b"unsealed\0".as_ptr(), 1 )
+        } as std::os::unix::io::RawFd;
+        assert!( memory >= 0 );
+        let memory = Fd( memory );
+        assert_eq!( unsafe { libc::ftruncate( memory.0, 2 * Ring::size( capacity ) as libc::off_t ) }, 0 );
+        let counters = [ EventFd::new().unwrap(), EventFd::new().unwrap(),
+                EventFd::new().unwrap(), EventFd::new().unwrap() ];
+        let mut fds = vec![ memory.0 ];
+        fds.extend( counters.iter().map( |counter| counter.fd() ) );
+        let mut data = [ 0u8; 8 ];
+        data[ 1 ] = ( capacity >> 8 ) as u8;
+        sys::send_fds( socket.as_raw_fd(), &data, &fds ).unwrap();
+        assert!( handle.join().unwrap() );
+        fs::remove_file( &path ).ok();
+    }
+
+    #[test]
+    pub fn invalid_counters() {
+        let path = socket_path( "counters" );
+        let listener = UnixListener::bind( &path ).unwrap();
+        let handle = thread::spawn( move || {
+            let ( socket, _ ) = listener.accept().unwrap();
+            Rings::accept( &socket ).is_err()
+        } );
+
+        // Counters that aren't eventfds are refused.
+        let socket = UnixStream::connect( &path ).unwrap();
+        let capacity = 4096;
+        let ( _region, memory ) = Region::create( 2 * Ring::size( capacity ) ).unwrap();
+        let mut pipe = [ 0; 2 ];
+        assert_eq!( unsafe { libc::pipe( pipe.as_mut_ptr() ) }, 0 );
+        let pipe = [ Fd( pipe[ 0 ] ), Fd( pipe[ 1 ] ) ];
+        let fds = [ memory.0, pipe[ 0 ].0, pipe[ 1 ].0, pipe[ 0 ].0, pipe[ 1 ].0 ];
+        let mut data = [ 0u8; 8 ];
+        data[ 1 ] = ( capacity >> 8 ) as u8;
+        sys::send_fds( socket.as_raw_fd(), &data, &fds ).unwrap();
+        assert!( handle.join().unwrap() );
+        fs::remove_file( &path ).ok();
+    }
+}
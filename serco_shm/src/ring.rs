@@ -0,0 +1,266 @@
+//! Single producer, single consumer byte rings in shared memory.
+//!
+//! Each ring starts with a header holding the total number of bytes written
+//! to and read from the ring followed by the data that wraps around. The
+//! messages are framed with their length and may be larger than the ring,
+//! in which case they are passed through it in parts.
+//!
+//! The writer waits on the `writable` counter when the ring is full and the
+//! reader on the `readable` counter when it is empty. The counters keep the
+//! notifications made before the wait so no wake up is lost between
+//! checking the ring and waiting on it.
+//!
+//! The other process can write anything to the shared memory so the header
+//! and the length prefixes are validated before they are used. A ring whose
+//! header doesn't add up fails with `InvalidData`.
+
+use std::cmp;
+use std::io;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use sys::{EventFd, Region};
+
+/// Space reserved for the header in front of the data. A cache line keeps
+/// the header apart from the data.
+pub const HEADER_SIZE : usize = 64;
+
+#[repr(C)]
+struct Header {
+    written: AtomicUsize,
+    read: AtomicUsize,
+    closed: AtomicBool,
+}
+
+pub struct Ring {
+    region: Arc<Region>,
+    offset: usize,
+    capacity: usize,
+    readable: EventFd,
+    writable: EventFd,
+}
+
+impl Ring {
+
+    /// Bytes of shared memory that a ring of the capacity takes.
+    pub fn size( capacity: usize ) -> usize {
+        HEADER_SIZE + capacity
+    }
+
+    /// Places a ring at the offset of the region.
+    ///
+    /// The memory of a new ring must be zeroed. The offset must be a multiple
+    /// of the `HEADER_SIZE` to keep the header aligned.
+    pub fn new(
+        region: Arc<Region>,
+        offset: usize,
+        capacity: usize,
+        readable: EventFd,
+        writable: EventFd,
+    ) -> io::Result<Ring> {
+        if capacity == 0 || offset % HEADER_SIZE != 0
+                || offset + Ring::size( capacity ) > region.len() {
+            return Err( io::Error::new( io::ErrorKind::InvalidInput, "Ring outside the region" ) );
+        }
+        Ok( Ring {
+            region: region,
+            offset: offset,
+            capacity: capacity,
+            readable: readable,
+            writable: writable,
+        } )
+    }
+
+    fn header( &self ) -> &Header {
+        unsafe { &*( self.region.ptr().offset( self.offset as isize ) as *const Header ) }
+    }
+
+    fn data( &self ) -> *mut u8 {
+        unsafe { self.region.ptr().offset( ( self.offset + HEADER_SIZE ) as isize ) }
+    }
+
+    /// Closes the ring for both ends. The reader still receives what was
+    /// written before.
+    pub fn close( &self ) {
+        self.header().closed.store( true, Ordering::SeqCst );
+        self.readable.notify().ok();
+        self.writable.notify().ok();
+    }
+
+    /// Writes a message to the ring, waiting for the reader whenever the
+    /// ring is full.
+    pub fn send( &self, message: &[u8] ) -> io::Result<()> {
+        if message.len() > u32::max_value() as usize {
+            return Err( io::Error::new( io::ErrorKind::InvalidInput, "Message too long" ) );
+        }
+        let len = message.len() as u32;
+        let prefix = [ len as u8, ( len >> 8 ) as u8, ( len >> 16 ) as u8, ( len >> 24 ) as u8 ];
+        self.write_all( &prefix )?;
+        self.write_all( message )
+    }
+
+    /// Reads the next message from the ring, waiting for the writer whenever
+    /// the ring is empty.
+    ///
+    /// The length of the message is passed to `check` before the memory for
+    /// the message is allocated. The messages it refuses are left unread and
+    /// fail the call. Fails with `UnexpectedEof` once the ring is closed and
+    /// empty.
+    pub fn receive<F>( &self, check: F ) -> io::Result<Vec<u8>>
+        where F: FnOnce( usize ) -> io::Result<()>
+    {
+        let mut prefix = [ 0u8; 4 ];
+        self.read_exact( &mut prefix )?;
+        let len = prefix.iter().rev().fold( 0usize, |len, &b| len << 8 | b as usize );
+        check( len )?;
+
+        let mut message = vec![ 0u8; len ];
+        self.read_exact( &mut message )?;
+        Ok( message )
+    }
+
+    /// Gets the number of bytes in the ring. Fails if the counters claim
+    /// more than the ring holds.
+    fn used( &self, written: usize, read: usize ) -> io::Result<usize> {
+        let used = written.wrapping_sub( read );
+        if used > self.capacity {
+            return Err( io::Error::new( io::ErrorKind::InvalidData, "Ring header corrupted" ) );
+        }
+        Ok( used )
+    }
+
+    fn write_all( &self, mut bytes: &[u8] ) -> io::Result<()> {
+        let header = self.header();
+        while !bytes.is_empty() {
+            if header.closed.load( Ordering::SeqCst ) {
+                return Err( io::Error::new( io::ErrorKind::BrokenPipe, "Ring closed" ) );
+            }
+
+            let written = header.written.load( Ordering::Relaxed );
+            let used = self.used( written, header.read.load( Ordering::Acquire ) )?;
+            let free = self.capacity - used;
+            if free == 0 {
+                self.writable.wait()?;
+                continue;
+            }
+
+            let count = cmp::min( free, bytes.len() );
+            let start = written % self.capacity;
+            let first = cmp::min( count, self.capacity - start );
+            unsafe {
+                ptr::copy_nonoverlapping(
+                        bytes.as_ptr(), self.data().offset( start as isize ), first );
+                ptr::copy_nonoverlapping(
+                        bytes.as_ptr().offset( first as isize ), self.data(), count - first );
+            }
+            header.written.store( written.wrapping_add( count ), Ordering::Release );
+            self.readable.notify()?;
+            bytes = &bytes[ count.. ];
+        }
+        Ok( () )
+    }
+
+    fn read_exact( &self, mut buffer: &mut [u8] ) -> io::Result<()> {
+        let header = self.header();
+        while !buffer.is_empty() {
+            let read = header.read.load( Ordering::Relaxed );
+            let available = self.used( header.written.load( Ordering::Acquire ), read )?;
+            if available == 0 {
+
+                // Check the ring again after seeing it closed as the writer
+                // may have written just before closing it.
+                if header.closed.load( Ordering::SeqCst )
+                        && header.written.load( Ordering::Acquire ) == read {
+                    return Err( io::Error::new( io::ErrorKind::UnexpectedEof, "Ring closed" ) );
+                }
+                self.readable.wait()?;
+                continue;
+            }
+
+            let count = cmp::min( available, buffer.len() );
+            let start = read % self.capacity;
+            let first = cmp::min( count, self.capacity - start );
+            unsafe {
+                ptr::copy_nonoverlapping(
+                        self.data().offset( start as isize ), buffer.as_mut_ptr(), first );
+                ptr::copy_nonoverlapping(
+                        self.data(), buffer.as_mut_ptr().offset( first as isize ), count - first );
+            }
+            header.read.store( read.wrapping_add( count ), Ordering::Release );
+            self.writable.notify()?;
+            let rest = buffer;
+            buffer = &mut rest[ count.. ];
+        }
+        Ok( () )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    fn any_size( _: usize ) -> io::Result<()> {
+        Ok( () )
+    }
+
+    fn ring( capacity: usize ) -> Arc<Ring> {
+        let ( region, _ ) = Region::create( Ring::size( capacity ) ).unwrap();
+        Arc::new( Ring::new(
+                Arc::new( region ), 0, capacity,
+                EventFd::new().unwrap(), EventFd::new().unwrap() ).unwrap() )
+    }
+
+    #[test]
+    pub fn wrapping() {
+        let ring = ring( 100 );
+        let writer = ring.clone();
+        let messages : Vec<Vec<u8>> = ( 0..50 )
+                .map( |i| ( 0..i * 7 ).map( |b| b as u8 ).collect() )
+                .collect();
+
+        let sent = messages.clone();
+        let handle = thread::spawn( move || {
+            for message in &sent {
+                writer.send( message ).unwrap();
+            }
+            writer.close();
+        } );
+
+        for message in &messages {
+            assert_eq!( &ring.receive( any_size ).unwrap(), message );
+        }
+        assert_eq!( ring.receive( any_size ).unwrap_err().kind(), io::ErrorKind::UnexpectedEof );
+        handle.join().unwrap();
+    }
+
+    #[test]
+    pub fn closed() {
+        let ring = ring( 8 );
+        ring.send( b"abc" ).unwrap();
+        ring.close();
+
+        assert_eq!( ring.send( b"d" ).unwrap_err().kind(), io::ErrorKind::BrokenPipe );
+        assert_eq!( ring.receive( any_size ).unwrap(), b"abc" );
+        assert_eq!( ring.receive( any_size ).unwrap_err().kind(), io::ErrorKind::UnexpectedEof );
+    }
+
+    #[test]
+    pub fn corrupted() {
+
+        // Lengths the reader refuses are never allocated.
+        let ring = ring( 8 );
+        ring.send( b"abc" ).unwrap();
+        let refuse = |_: usize| -> io::Result<()> {
+            Err( io::Error::new( io::ErrorKind::InvalidData, "Too long" ) )
+        };
+        assert_eq!( ring.receive( refuse ).unwrap_err().kind(), io::ErrorKind::InvalidData );
+
+        // Counters claiming more data than the ring holds are refused by
+        // both ends.
+        ring.header().written.store( 1000, Ordering::SeqCst );
+        assert_eq!( ring.receive( any_size ).unwrap_err().kind(), io::ErrorKind::InvalidData );
+        assert_eq!( ring.send( b"d" ).unwrap_err().kind(), io::ErrorKind::InvalidData );
+    }
+}
@@ -0,0 +1,272 @@
+//! The system calls behind the shared memory and the notifications.
+
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::ptr;
+
+use libc;
+
+// Not defined by all versions of libc.
+const MFD_CLOEXEC : libc::c_uint = 1;
+const MFD_ALLOW_SEALING : libc::c_uint = 2;
+const F_ADD_SEALS : libc::c_int = 1033;
+const F_GET_SEALS : libc::c_int = 1034;
+const F_SEAL_SEAL : libc::c_int = 1;
+const F_SEAL_SHRINK : libc::c_int = 2;
+const F_SEAL_GROW : libc::c_int = 4;
+
+/// The seals that keep the size of the memory fixed. Once the size is
+/// sealed the other process can't shrink the memory under the mapping.
+const SIZE_SEALS : libc::c_int = F_SEAL_SHRINK | F_SEAL_GROW;
+
+/// Owned file descriptor that is closed when dropped.
+pub struct Fd( pub RawFd );
+
+impl Drop for Fd {
+    fn drop( &mut self ) {
+        unsafe { libc::close( self.0 ); }
+    }
+}
+
+fn check( result: libc::c_int ) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err( io::Error::last_os_error() )
+    } else {
+        Ok( result )
+    }
+}
+
+/// Shared memory mapped into the process.
+pub struct Region {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The region is only accessed through the atomics and the ring protocol.
+unsafe impl Send for Region {}
+unsafe impl Sync for Region {}
+
+impl Region {
+
+    /// Creates anonymous shared memory of `len` bytes. The memory is zeroed
+    /// and its size is sealed.
+    ///
+    /// Returns the file descriptor of the memory along with the region so
+    /// the memory can be passed to another process.
+    pub fn create( len: usize ) -> io::Result<( Region, Fd )> {
+        let name = b"serco_shm\0";
+        let fd = unsafe {
+            libc::syscall( libc::SYS_memfd_create, name.as_ptr(), MFD_CLOEXEC | MFD_ALLOW_SEALING )
+        };
+        if fd < 0 {
+            return Err( io::Error::last_os_error() );
+        }
+        let fd = Fd( fd as RawFd );
+        check( unsafe { libc::ftruncate( fd.0, len as libc::off_t ) } )?;
+        check( unsafe { libc::fcntl( fd.0, F_ADD_SEALS, SIZE_SEALS | F_SEAL_SEAL ) } )?;
+
+        let region = Region::map( &fd, len )?;
+        Ok( ( region, fd ) )
+    }
+
+    /// Maps the shared memory received from another process.
+    ///
+    /// Fails unless the memory is `len` bytes and its size is sealed, so
+    /// the other process can't shrink it while it is mapped.
+    pub fn open( fd: &Fd, len: usize ) -> io::Result<Region> {
+        let seals = check( unsafe { libc::fcntl( fd.0, F_GET_SEALS ) } )?;
+        if seals & SIZE_SEALS != SIZE_SEALS {
+            return Err( io::Error::new( io::ErrorKind::InvalidData, "Memory size not sealed" ) );
+        }
+
+        let mut stat : libc::stat = unsafe { mem::zeroed() };
+        check( unsafe { libc::fstat( fd.0, &mut stat ) } )?;
+        if stat.st_size < 0 || stat.st_size as u64 != len as u64 {
+            return Err( io::Error::new( io::ErrorKind::InvalidData, "Invalid memory size" ) );
+        }
+        Region::map( fd, len )
+    }
+
+    fn map( fd: &Fd, len: usize ) -> io::Result<Region> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(), len,
+                libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED,
+                fd.0, 0 )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err( io::Error::last_os_error() );
+        }
+        Ok( Region { ptr: ptr as *mut u8, len: len } )
+    }
+
+    pub fn ptr( &self ) -> *mut u8 {
+        self.ptr
+    }
+
+    pub fn len( &self ) -> usize {
+        self.len
+    }
+}
+
+impl Drop for Region {
+    fn drop( &mut self ) {
+        unsafe { libc::munmap( self.ptr as *mut libc::c_void, self.len ); }
+    }
+}
+
+/// Counter that the processes use to wake each other up.
+pub struct EventFd( Fd );
+
+impl EventFd {
+    pub fn new() -> io::Result<EventFd> {
+        let fd = check( unsafe { libc::eventfd( 0, libc::EFD_CLOEXEC ) } )?;
+        Ok( EventFd( Fd( fd ) ) )
+    }
+
+    /// Takes a counter received from another process.
+    ///
+    /// Fails unless the descriptor is an eventfd. Anything else, such as a
+    /// pipe, could block or return garbage when waited on.
+    pub fn from_fd( fd: Fd ) -> io::Result<EventFd> {
+        let target = fs::read_link( format!( "/proc/self/fd/{}", fd.0 ) )?;
+        if target != Path::new( "anon_inode:[eventfd]" ) {
+            return Err( io::Error::new( io::ErrorKind::InvalidData, "Counter is not an eventfd" ) );
+        }
+        Ok( EventFd( fd ) )
+    }
+
+    pub fn fd( &self ) -> RawFd {
+        ( self.0 ).0
+    }
+
+    /// Wakes up the waiting side. The notifications made while nobody waits
+    /// are kept until the next wait.
+    pub fn notify( &self ) -> io::Result<()> {
+        let one : u64 = 1;
+        let written = unsafe {
+            libc::write( self.fd(), &one as *const u64 as *const libc::c_void, 8 )
+        };
+        if written < 0 {
+            return Err( io::Error::last_os_error() );
+        }
+        Ok( () )
+    }
+
+    /// Blocks until notified.
+    ///
+    /// Anything but a full 8 byte read means the counter is broken and the
+    /// connection can't continue.
+    pub fn wait( &self ) -> io::Result<()> {
+        let mut count : u64 = 0;
+        loop {
+            let read = unsafe {
+                libc::read( self.fd(), &mut count as *mut u64 as *mut libc::c_void, 8 )
+            };
+            if read == 8 {
+                return Ok( () );
+            }
+            if read >= 0 {
+                return Err( io::Error::new( io::ErrorKind::UnexpectedEof, "Invalid counter read" ) );
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err( e );
+            }
+        }
+    }
+}
+
+/// Sends the file descriptors over a Unix domain socket along with the data.
+pub fn send_fds( socket: RawFd, data: &[u8], fds: &[RawFd] ) -> io::Result<()> {
+    unsafe {
+        let fds_len = ( fds.len() * mem::size_of::<RawFd>() ) as u32;
+        let mut control = vec![ 0u8; libc::CMSG_SPACE( fds_len ) as usize ];
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+
+        let mut message : libc::msghdr = mem::zeroed();
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = control.len() as _;
+
+        let header = libc::CMSG_FIRSTHDR( &message );
+        ( *header ).cmsg_level = libc::SOL_SOCKET;
+        ( *header ).cmsg_type = libc::SCM_RIGHTS;
+        ( *header ).cmsg_len = libc::CMSG_LEN( fds_len ) as _;
+        ptr::copy_nonoverlapping(
+                fds.as_ptr(), libc::CMSG_DATA( header ) as *mut RawFd, fds.len() );
+
+        let sent = libc::sendmsg( socket, &message, 0 );
+        if sent < 0 {
+            return Err( io::Error::last_os_error() );
+        }
+        if sent as usize != data.len() {
+            return Err( io::Error::new( io::ErrorKind::WriteZero, "Partial handshake" ) );
+        }
+    }
+    Ok( () )
+}
+
+/// Receives the file descriptors sent with `send_fds`.
+///
+/// Fails unless exactly `count` descriptors and the full data arrive.
+pub fn recv_fds( socket: RawFd, data: &mut [u8], count: usize ) -> io::Result<Vec<Fd>> {
+    unsafe {
+        let fds_len = ( count * mem::size_of::<RawFd>() ) as u32;
+        let mut control = vec![ 0u8; libc::CMSG_SPACE( fds_len ) as usize ];
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+
+        let mut message : libc::msghdr = mem::zeroed();
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = control.len() as _;
+
+        let received = libc::recvmsg( socket, &mut message, libc::MSG_CMSG_CLOEXEC );
+        if received < 0 {
+            return Err( io::Error::last_os_error() );
+        }
+
+        // Take the ownership of whatever arrived before validating it so
+        // nothing leaks.
+        let mut fds = vec![];
+        let header = libc::CMSG_FIRSTHDR( &message );
+        if !header.is_null()
+                && ( *header ).cmsg_level == libc::SOL_SOCKET
+                && ( *header ).cmsg_type == libc::SCM_RIGHTS {
+            let len = ( *header ).cmsg_len as usize - libc::CMSG_LEN( 0 ) as usize;
+            let first = libc::CMSG_DATA( header ) as *const RawFd;
+            for i in 0..len / mem::size_of::<RawFd>() {
+                fds.push( Fd( ptr::read_unaligned( first.offset( i as isize ) ) ) );
+            }
+        }
+
+        if received as usize != data.len() || fds.len() != count {
+            return Err( io::Error::new( io::ErrorKind::InvalidData, "Invalid handshake" ) );
+        }
+        Ok( fds )
+    }
+}
+
+/// Gets the process, user and group IDs of the process at the other end of
+/// a Unix domain socket.
+pub fn peer_credentials( socket: RawFd ) -> io::Result<( u32, u32, u32 )> {
+    unsafe {
+        let mut credentials : libc::ucred = mem::zeroed();
+        let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+        check( libc::getsockopt(
+                socket, libc::SOL_SOCKET, libc::SO_PEERCRED,
+                &mut credentials as *mut libc::ucred as *mut libc::c_void, &mut len ) )?;
+        Ok( ( credentials.pid as u32, credentials.uid, credentials.gid ) )
+    }
+}
@@ -0,0 +1,310 @@
+//! Hosting services in child processes.
+//!
+//! The `StdioEndpoint` serves a host over the standard streams of the
+//! process while the `ChildProcessClient` spawns a command and connects to
+//! the endpoint it serves through the pipes of the child. The messages are
+//! JSON-RPC 2.0 messages as described in `serco_jsonrpc::protocol` and the
+//! host may call the client back over the same pipes.
+//!
+//! The standard error of the child is passed through to the standard error
+//! of the parent so the diagnostics of the child remain visible. The host
+//! stops once its standard input closes, which happens when the client
+//! closes the connection.
+
+#![cfg_attr(test, feature(proc_macro))]
+
+extern crate futures;
+use futures::prelude::*;
+use futures::future;
+
+extern crate serco;
+extern crate serco_jsonrpc;
+use serco_jsonrpc::{Framing, JsonRpcCodec, WriteSink};
+use serco_jsonrpc::codec::read_messages;
+use serco_jsonrpc::peer;
+
+#[cfg(test)] extern crate serde;
+#[cfg(test)] #[macro_use] extern crate serde_json;
+#[cfg(test)] #[macro_use] extern crate serde_derive;
+#[cfg(test)] extern crate serco_derive;
+
+use std::ffi::OsString;
+use std::io;
+use std::process::{Command, ExitStatus, Stdio};
+use std::rc::Rc;
+use std::thread;
+
+/// Kind under which the standard stream endpoints report their metrics.
+pub const ENDPOINT_KIND : &str = "stdio";
+
+pub struct StdioEndpoint {
+    framing: Framing,
+}
+
+impl StdioEndpoint {
+
+    /// Serves the process that owns the standard streams.
+    pub fn new() -> Self {
+        StdioEndpoint { framing: Framing::Lines }
+    }
+
+    /// Specifies how the messages are framed on the streams.
+    pub fn framing( mut self, framing: Framing ) -> Self {
+        self.framing = framing;
+        self
+    }
+}
+
+impl<TService,
+        THostImplementation,
+        TSessionFactory>
+    serco::ServiceEndpoint<TService,
+        TSessionFactory,
+        THostImplementation,
+    >
+    for StdioEndpoint
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+          THostImplementation::ServiceInstance: 'static,
+{
+    fn run(
+        &self,
+        host: Rc<serco::HostRuntime<
+                TService,
+                TSessionFactory,
+                THostImplementation,
+        >>
+    ) -> Box<Future<Item=(), Error=serco::ServiceError>>
+    {
+        // The streams are served on a thread of their own so the host can
+        // wait for the callbacks.
+        let ( connection, link ) = peer::connection();
+        let framing = self.framing;
+        let limits = *host.message_limits();
+        let metrics = host.endpoint_metrics( ENDPOINT_KIND );
+        thread::spawn( move || {
+            link.message_limits( limits ).metrics( metrics ).run(
+                    read_messages( io::stdin(), JsonRpcCodec::new( framing ).limits( limits ) ),
+                    WriteSink::new( io::stdout(), framing ) )
+                .wait()
+                .ok();
+        } );
+
+        // The standard streams carry a single session that ends with the
+        // standard input.
+        peer::serve_session( &host, connection, &host.connection_limit() )
+    }
+
+    fn address( &self ) -> Option<serco::registry::EndpointAddress> {
+        None
+    }
+}
+
+/// Client that spawns a command serving a `StdioEndpoint` and connects to it.
+pub struct ChildProcessClient {
+    program: OsString,
+    args: Vec<OsString>,
+    framing: Framing,
+}
+
+impl ChildProcessClient {
+    pub fn new<T: Into<OsString>>( program: T ) -> ChildProcessClient {
+        ChildProcessClient {
+            program: program.into(),
+            args: vec![],
+            framing: Framing::Lines,
+        }
+    }
+
+    /// Adds an argument to the command.
+    pub fn arg<T: Into<OsString>>( mut self, arg: T ) -> Self {
+        self.args.push( arg.into() );
+        self
+    }
+
+    /// Adds arguments to the command.
+    pub fn args<I, T>( mut self, args: I ) -> Self
+        where I: IntoIterator<Item=T>,
+              T: Into<OsString>,
+    {
+        self.args.extend( args.into_iter().map( Into::into ) );
+        self
+    }
+
+    /// Specifies how the messages are framed on the pipes.
+    pub fn framing( mut self, framing: Framing ) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    pub fn connect<S>(
+        &self,
+    ) -> Box<Future<Item=ChildProcessConnection<S>, Error=String>>
+        where S: serco::ServiceContract<CallbackContract = ()> + ?Sized + 'static,
+    {
+        self.connect_duplex::<S, (), ()>( () )
+    }
+
+    /// Spawns the command and connects to it. The child calls the
+    /// `callback` over the same pipes.
+    pub fn connect_duplex<S, C, T>(
+        &self,
+        callback: T,
+    ) -> Box<Future<Item=ChildProcessConnection<S>, Error=String>>
+        where S: serco::ServiceContract<CallbackContract = C> + ?Sized + 'static,
+            C: serco::ServiceContract<CallbackContract = ()> + ?Sized + 'static,
+            T: serco::InvokeTarget<C> + Send + 'static,
+    {
+        let mut child = match Command::new( &self.program )
+                .args( &self.args )
+                .stdin( Stdio::piped() )
+                .stdout( Stdio::piped() )
+                .stderr( Stdio::inherit() )
+                .spawn() {
+            Ok( child ) => child,
+            Err( e ) => return Box::new( future::err( format!( "{:?}", e ) ) ),
+        };
+        let stdin = child.stdin.take().expect( "Child stdin is piped" );
+        let stdout = child.stdout.take().expect( "Child stdout is piped" );
+
+        // The link owns the standard input of the child so the child sees
+        // its input end once the connection is closed. The link ends in turn
+        // when the child exits and its output ends.
+        let ( connection, link ) = peer::connection();
+        let framing = self.framing;
+        let process_handle = thread::spawn( move || {
+            link.run( read_messages( stdout, JsonRpcCodec::new( framing ) ),
+                      WriteSink::new( stdin, framing ) )
+                .wait()
+                .ok();
+            child.wait().ok()
+        } );
+
+        let peer::Connection { requests, forwarder, .. } = connection;
+        let callback_handle = peer::spawn_callback_handler::<C, _>(
+                callback, requests, forwarder.sender() );
+
+        Box::new( future::ok( ChildProcessConnection {
+            proxy: serco::ServiceProxy::new( forwarder ),
+            process_handle: process_handle,
+            _callback_handle: callback_handle,
+        } ) )
+    }
+}
+
+/// Connection to a child process.
+///
+/// The calls fail with a transport error once the child has exited.
+pub struct ChildProcessConnection<S: ?Sized> {
+    proxy: serco::ServiceProxy<S, peer::PeerForwarder>,
+    process_handle: thread::JoinHandle<Option<ExitStatus>>,
+    _callback_handle: thread::JoinHandle<()>,
+}
+
+impl<S: ?Sized> ChildProcessConnection<S> {
+
+    /// Closes the connection and waits for the child to exit.
+    ///
+    /// The child is expected to exit once its standard input closes, as the
+    /// hosts serving a `StdioEndpoint` do.
+    pub fn close( self ) -> Option<ExitStatus> {
+        let ChildProcessConnection { proxy, process_handle, .. } = self;
+        drop( proxy );
+        process_handle.join().ok().and_then( |status| status )
+    }
+}
+
+/// Dereference the connection into the service proxy.
+/// The proxy implements the actual service trait.
+impl<S: serco::ServiceContract + ?Sized> std::ops::Deref for ChildProcessConnection<S>
+{
+    type Target = serco::ServiceProxy<S, peer::PeerForwarder>;
+
+    fn deref( &self ) -> &Self::Target {
+        &self.proxy
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use serco::{ErrorKind, Forwarder};
+    use serco_derive::*;
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex};
+
+    #[service_contract( callback = Listener )]
+    pub trait Chat {
+        fn say( &self, message: String ) -> Value;
+    }
+
+    #[service_contract]
+    pub trait Listener {
+        fn hear( &self, message: String ) -> String;
+    }
+
+    #[service(Listener)]
+    struct LoudListener( Arc<Mutex<Vec<String>>> );
+    impl Listener for LoudListener {
+        fn hear( &self, message: String ) -> String {
+            self.0.lock().unwrap().push( message.clone() );
+            message.to_uppercase()
+        }
+    }
+
+    #[service_contract]
+    pub trait Calculator {
+        fn add( &self, a: i32, b: i32 ) -> i32;
+    }
+
+    /// Spawns a shell script in place of a host.
+    fn script( script: &str ) -> ChildProcessClient {
+        ChildProcessClient::new( "sh" ).arg( "-c" ).arg( script )
+    }
+
+    #[test]
+    pub fn child_exit() {
+        let connection = script( r#"
+                read -r request
+                echo '{"jsonrpc": "2.0", "id": 1, "result": 3}'
+            "# )
+            .connect::<Calculator>()
+            .wait()
+            .unwrap();
+
+        assert_eq!( connection.add( 1, 2 ), 3 );
+
+        // The child exits after the first call.
+        let e = connection.forwarder
+                .forward::<i32, _>( "add", json!({ "a": 1, "b": 2 }) )
+                .wait()
+                .unwrap_err();
+        assert_eq!( e.kind, ErrorKind::Transport );
+        assert!( connection.close().unwrap().success() );
+    }
+
+    #[test]
+    pub fn callbacks() {
+        let heard = Arc::new( Mutex::new( vec![] ) );
+        let connection = script( r#"
+                read -r request
+                echo '{"jsonrpc": "2.0", "id": "cb", "method": "hear", "params": {"message": "hi"}}'
+                read -r response
+                printf '{"jsonrpc": "2.0", "id": 1, "result": %s}\n' "$response"
+                read -r end || exit 0
+                exit 1
+            "# )
+            .connect_duplex::<Chat, _, _>( LoudListener( heard.clone() ) )
+            .wait()
+            .unwrap();
+
+        let response = connection.say( "hello".to_string() );
+        assert_eq!( response[ "id" ], json!( "cb" ) );
+        assert_eq!( response[ "result" ], json!( "HI" ) );
+        assert_eq!( *heard.lock().unwrap(), vec![ "hi".to_string() ] );
+
+        // Closing the connection ends the input of the child.
+        assert!( connection.close().unwrap().success() );
+    }
+}
@@ -0,0 +1,338 @@
+//! TLS for the stream transports.
+//!
+//! The endpoints that accept `TlsServer` wrap each accepted connection in
+//! TLS before serving it. The server may verify the client certificates
+//! against the configured certificate authorities, in which case the
+//! identity of the client is passed to `SessionFactory::create_peer_session`.
+//!
+//! The certificates and the keys are given in PEM. The private keys must be
+//! PKCS #8 keys.
+
+extern crate futures;
+use futures::prelude::*;
+use futures::future;
+
+extern crate tokio_io;
+use tokio_io::{AsyncRead, AsyncWrite};
+extern crate rustls;
+use rustls::{Certificate, PrivateKey, RootCertStore, Session};
+use rustls::internal::pemfile;
+extern crate tokio_rustls;
+extern crate webpki;
+
+extern crate serco;
+use serco::PeerIdentity;
+
+#[cfg(test)] extern crate tokio_core;
+
+use std::io;
+use std::sync::Arc;
+
+/// Stream of a connection accepted by a `TlsAcceptor`.
+pub type ServerStream<S> = tokio_rustls::TlsStream<S, rustls::ServerSession>;
+
+/// Stream of a connection made by a `TlsClient`.
+pub type ClientStream<S> = tokio_rustls::TlsStream<S, rustls::ClientSession>;
+
+/// TLS configuration of the endpoints.
+#[derive(Clone)]
+pub struct TlsServer {
+    certificates: Vec<Certificate>,
+    key: PrivateKey,
+    client_roots: Option<RootCertStore>,
+    require_client_certificate: bool,
+}
+
+impl TlsServer {
+
+    /// Uses the PEM encoded certificate chain and private key. The
+    /// certificate of the server comes first in the chain.
+    pub fn from_pem( certificates: &[u8], key: &[u8] ) -> io::Result<TlsServer> {
+        Ok( TlsServer {
+            certificates: read_certificates( certificates )?,
+            key: read_key( key )?,
+            client_roots: None,
+            require_client_certificate: false,
+        } )
+    }
+
+    /// Verifies the client certificates against the PEM encoded certificate
+    /// authorities.
+    ///
+    /// The clients that present no certificate are still accepted unless
+    /// `require_client_certificate` is set.
+    pub fn client_certificates( mut self, roots: &[u8] ) -> io::Result<Self> {
+        self.client_roots = Some( read_roots( roots )? );
+        Ok( self )
+    }
+
+    /// Refuses the clients that present no valid certificate.
+    pub fn require_client_certificate( mut self, required: bool ) -> Self {
+        self.require_client_certificate = required;
+        self
+    }
+
+    /// Creates the acceptor for the connections of an endpoint.
+    pub fn acceptor( &self ) -> io::Result<TlsAcceptor> {
+        let verifier = match self.client_roots {
+            Some( ref roots ) if self.require_client_certificate =>
+                rustls::AllowAnyAuthenticatedClient::new( roots.clone() ),
+            Some( ref roots ) =>
+                rustls::AllowAnyAnonymousOrAuthenticatedClient::new( roots.clone() ),
+            None if self.require_client_certificate =>
+                return Err( invalid( "Client certificates required without authorities" ) ),
+            None => rustls::NoClientAuth::new(),
+        };
+
+        let mut config = rustls::ServerConfig::new( verifier );
+        config.set_single_cert( self.certificates.clone(), self.key.clone() )
+                .map_err( |e| invalid( format!( "{:?}", e ) ) )?;
+        Ok( TlsAcceptor {
+            acceptor: tokio_rustls::TlsAcceptor::from( Arc::new( config ) ),
+        } )
+    }
+}
+
+/// Performs the server side of the handshakes.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    acceptor: tokio_rustls::TlsAcceptor,
+}
+
+impl TlsAcceptor {
+
+    /// Completes the handshake on an accepted connection.
+    ///
+    /// Resolves into the encrypted stream and the identity of the client if
+    /// it presented a certificate.
+    pub fn accept<S>(
+        &self,
+        stream: S,
+    ) -> Box<Future<Item=( ServerStream<S>, Option<PeerIdentity> ), Error=io::Error>>
+        where S: AsyncRead + AsyncWrite + 'static
+    {
+        Box::new( self.acceptor.accept( stream ).map( |stream| {
+            let identity = stream.get_ref().1
+                    .get_peer_certificates()
+                    .and_then( |certificates| peer_identity( &certificates ) );
+            ( stream, identity )
+        } ) )
+    }
+}
+
+/// TLS configuration of the clients.
+#[derive(Clone)]
+pub struct TlsClient {
+    roots: RootCertStore,
+    certificate: Option<( Vec<Certificate>, PrivateKey )>,
+}
+
+impl TlsClient {
+
+    /// Trusts the PEM encoded certificate authorities.
+    pub fn new( roots: &[u8] ) -> io::Result<TlsClient> {
+        Ok( TlsClient {
+            roots: read_roots( roots )?,
+            certificate: None,
+        } )
+    }
+
+    /// Presents the PEM encoded certificate chain to the servers.
+    pub fn client_certificate( mut self, certificates: &[u8], key: &[u8] ) -> io::Result<Self> {
+        self.certificate = Some( ( read_certificates( certificates )?, read_key( key )? ) );
+        Ok( self )
+    }
+
+    /// Completes the handshake with the server named `domain`.
+    pub fn connect<S>(
+        &self,
+        domain: &str,
+        stream: S,
+    ) -> Box<Future<Item=ClientStream<S>, Error=io::Error>>
+        where S: AsyncRead + AsyncWrite + 'static
+    {
+        let domain = match webpki::DNSNameRef::try_from_ascii_str( domain ) {
+            Ok( domain ) => domain,
+            Err( _ ) => return Box::new( future::err( invalid( "Invalid server name" ) ) ),
+        };
+
+        let mut config = rustls::ClientConfig::new();
+        config.root_store = self.roots.clone();
+        if let Some( ( ref certificates, ref key ) ) = self.certificate {
+            config.set_single_client_cert( certificates.clone(), key.clone() );
+        }
+        let connector = tokio_rustls::TlsConnector::from( Arc::new( config ) );
+        Box::new( connector.connect( domain, stream ) )
+    }
+}
+
+/// Gets the identity from the certificate chain the peer presented.
+pub fn peer_identity( certificates: &[Certificate] ) -> Option<PeerIdentity> {
+    certificates.first().map( |certificate| PeerIdentity {
+        name: common_name( &certificate.0 ),
+        certificate: certificate.0.clone(),
+    } )
+}
+
+fn invalid<E>( error: E ) -> io::Error
+    where E: Into<Box<std::error::Error + Send + Sync>>
+{
+    io::Error::new( io::ErrorKind::InvalidInput, error )
+}
+
+fn read_certificates( pem: &[u8] ) -> io::Result<Vec<Certificate>> {
+    match pemfile::certs( &mut io::BufReader::new( pem ) ) {
+        Ok( ref certificates ) if certificates.is_empty() => Err( invalid( "No certificates" ) ),
+        Ok( certificates ) => Ok( certificates ),
+        Err( _ ) => Err( invalid( "Invalid certificates" ) ),
+    }
+}
+
+fn read_key( pem: &[u8] ) -> io::Result<PrivateKey> {
+    pemfile::pkcs8_private_keys( &mut io::BufReader::new( pem ) )
+        .ok()
+        .and_then( |keys| keys.into_iter().next() )
+        .ok_or_else( || invalid( "No PKCS #8 private key" ) )
+}
+
+fn read_roots( pem: &[u8] ) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match roots.add_pem_file( &mut io::BufReader::new( pem ) ) {
+        Ok( ( added, 0 ) ) if added > 0 => Ok( roots ),
+        _ => Err( invalid( "Invalid certificate authorities" ) ),
+    }
+}
+
+/// Object identifier of the common name attribute, 2.5.4.3.
+const COMMON_NAME : &[u8] = &[ 0x55, 0x04, 0x03 ];
+
+/// Tag of the explicit version in front of the certificate fields.
+const VERSION_TAG : u8 = 0xa0;
+
+/// Reads the common name of the certificate subject.
+///
+/// The certificate has been verified by the time its identity is needed so
+/// only as much of the DER encoding is parsed as is needed to find the name.
+fn common_name( certificate: &[u8] ) -> Option<String> {
+    let ( _, certificate, _ ) = element( certificate )?;
+    let ( _, mut fields, _ ) = element( certificate )?;
+
+    // The subject follows the version, the serial number, the signature
+    // algorithm, the issuer and the validity.
+    if element( fields )?.0 == VERSION_TAG {
+        fields = element( fields )?.2;
+    }
+    for _ in 0..4 {
+        fields = element( fields )?.2;
+    }
+    let ( _, mut subject, _ ) = element( fields )?;
+
+    // The most specific name comes last.
+    let mut name = None;
+    while !subject.is_empty() {
+        let ( _, mut attributes, rest ) = element( subject )?;
+        subject = rest;
+        while !attributes.is_empty() {
+            let ( _, attribute, rest ) = element( attributes )?;
+            attributes = rest;
+            let ( _, oid, value ) = element( attribute )?;
+            if oid == COMMON_NAME {
+                name = String::from_utf8( element( value )?.1.to_vec() ).ok();
+            }
+        }
+    }
+    name
+}
+
+/// Splits a DER element into its tag, its contents and the input after it.
+fn element( input: &[u8] ) -> Option<( u8, &[u8], &[u8] )> {
+    let ( &tag, rest ) = input.split_first()?;
+    let ( &first, rest ) = rest.split_first()?;
+    let ( len, rest ) = if first < 0x80 {
+        ( first as usize, rest )
+    } else {
+        let count = ( first & 0x7f ) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[ ..count ].iter().fold( 0usize, |len, &b| len << 8 | b as usize );
+        ( len, &rest[ count.. ] )
+    };
+
+    if rest.len() < len {
+        return None;
+    }
+    Some( ( tag, &rest[ ..len ], &rest[ len.. ] ) )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio_core::reactor::Core;
+    use tokio_core::net::{TcpListener, TcpStream};
+
+    const CA : &[u8] = include_bytes!( "../test-certs/ca.pem" );
+    const SERVER_CERT : &[u8] = include_bytes!( "../test-certs/server.pem" );
+    const SERVER_KEY : &[u8] = include_bytes!( "../test-certs/server.key" );
+    const CLIENT_CERT : &[u8] = include_bytes!( "../test-certs/client.pem" );
+    const CLIENT_KEY : &[u8] = include_bytes!( "../test-certs/client.key" );
+
+    /// Connects the client to the server over a local socket.
+    fn handshake(
+        server: TlsServer,
+        client: TlsClient,
+    ) -> io::Result<Option<PeerIdentity>> {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let listener = TcpListener::bind( &"127.0.0.1:0".parse().unwrap(), &handle ).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let acceptor = server.acceptor()?;
+        let accepted = listener.incoming()
+            .into_future()
+            .map_err( |( e, _ )| e )
+            .and_then( move |( socket, _ )| acceptor.accept( socket.unwrap().0 ) )
+            .map( |( _, identity )| identity );
+        let connected = TcpStream::connect( &address, &handle )
+            .and_then( move |socket| client.connect( "localhost", socket ) );
+
+        core.run( accepted.join( connected ) ).map( |( identity, _ )| identity )
+    }
+
+    #[test]
+    pub fn names() {
+        let name = |pem| common_name( &read_certificates( pem ).unwrap()[ 0 ].0 );
+        assert_eq!( name( CLIENT_CERT ), Some( "alice".to_string() ) );
+        assert_eq!( name( SERVER_CERT ), Some( "localhost".to_string() ) );
+        assert_eq!( common_name( &[ 0x30, 0x05, 0x30 ] ), None );
+    }
+
+    #[test]
+    pub fn client_identity() {
+        let server = TlsServer::from_pem( SERVER_CERT, SERVER_KEY ).unwrap()
+                .client_certificates( CA ).unwrap();
+        let client = TlsClient::new( CA ).unwrap();
+
+        // Anonymous clients are accepted unless the certificates are
+        // required.
+        assert_eq!( handshake( server.clone(), client.clone() ).unwrap(), None );
+        assert!( handshake(
+                server.clone().require_client_certificate( true ),
+                client.clone() ).is_err() );
+
+        let client = client.client_certificate( CLIENT_CERT, CLIENT_KEY ).unwrap();
+        let identity = handshake( server.require_client_certificate( true ), client )
+                .unwrap()
+                .unwrap();
+        assert_eq!( identity.name, Some( "alice".to_string() ) );
+    }
+
+    #[test]
+    pub fn untrusted_server() {
+        let server = TlsServer::from_pem( SERVER_CERT, SERVER_KEY ).unwrap();
+
+        // The server certificate isn't trusted unless its authority is.
+        let client = TlsClient::new( CLIENT_CERT ).unwrap();
+        assert!( handshake( server, client ).is_err() );
+    }
+}
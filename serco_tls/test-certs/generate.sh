@@ -0,0 +1,35 @@
+#!/bin/sh
+# Generates the self-signed certificate authority and the certificates it
+# signs for the TLS tests. The files are only meant for the tests.
+set -e
+cd "$(dirname "$0")"
+
+days=36500
+key() {
+    openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1"
+}
+
+key ca.key
+openssl req -x509 -new -key ca.key -days $days -subj "/CN=serco test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" \
+    -addext "keyUsage=critical,keyCertSign,cRLSign" \
+    -out ca.pem
+
+sign() {
+    name=$1; subject=$2; extensions=$3
+    key "$name.key"
+    openssl req -new -key "$name.key" -subj "$subject" -out "$name.csr"
+    printf "%s\n" "$extensions" > "$name.ext"
+    openssl x509 -req -in "$name.csr" -CA ca.pem -CAkey ca.key -CAcreateserial \
+        -days $days -extfile "$name.ext" -out "$name.pem"
+    rm "$name.csr" "$name.ext"
+}
+
+sign server "/CN=localhost" "basicConstraints=critical,CA:FALSE
+subjectAltName=DNS:localhost
+extendedKeyUsage=serverAuth"
+
+sign client "/O=serco/CN=alice" "basicConstraints=critical,CA:FALSE
+extendedKeyUsage=clientAuth"
+
+rm -f ca.key ca.srl
@@ -0,0 +1,643 @@
+//! WebSocket endpoint and client for serco services.
+//!
+//! The messages are JSON-RPC 2.0 messages as described in
+//! `serco_jsonrpc::protocol`, one message in each text frame. Both ends of
+//! the socket may send requests: the clients call the contract operations
+//! while the host calls the operations of the callback contract on the
+//! client that made the call. This makes the duplex contracts available to
+//! remote clients and to the browsers through the generated TypeScript
+//! clients.
+//!
+//! The sockets are encrypted with TLS once the endpoint and the client are
+//! configured with `tls`. The verified client certificates identify the
+//! sessions as described in `serco_tls`.
+//!
+//! The host authenticates the credentials of the `Authorization` header of
+//! the handshake, or the client certificate if the header is missing,
+//! before it accepts the upgrade. The refused handshakes are answered with
+//! `401 Unauthorized` and the connection is closed.
+//!
+//! Each connection is a session. The service implementations block while
+//! they call the client back so the sockets are served on threads of their
+//! own, away from the host.
+//!
+//! Streaming operations and batches are not available through this
+//! endpoint.
+
+#![cfg_attr(test, feature(proc_macro))]
+
+extern crate futures;
+use futures::prelude::*;
+use futures::future;
+use futures::sync::{mpsc, oneshot};
+
+extern crate tokio_core;
+use tokio_core::reactor::{Core, Handle};
+use tokio_core::net::{TcpListener, TcpStream};
+extern crate tokio_io;
+use tokio_io::{AsyncRead, AsyncWrite};
+extern crate websocket;
+use websocket::{ClientBuilder, OwnedMessage};
+use websocket::header::Headers;
+use websocket::async::Client;
+use websocket::server::upgrade::async::IntoWs;
+extern crate url;
+use url::Url;
+
+extern crate serco;
+use serco::{ServiceError, Credentials, PeerIdentity, MessageLimits};
+use serco::metrics::EndpointMetrics;
+extern crate serco_jsonrpc;
+use serco_jsonrpc::peer;
+extern crate serco_tls;
+use serco_tls::{TlsAcceptor, TlsClient, TlsServer, ClientStream};
+extern crate serde;
+extern crate serde_json;
+
+#[cfg(test)] #[macro_use] extern crate serde_derive;
+#[cfg(test)] extern crate serco_derive;
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::rc::Rc;
+use std::thread;
+
+/// Kind under which the WebSocket endpoints are registered in service
+/// registries.
+pub const ENDPOINT_KIND : &str = "websocket";
+
+pub struct WebSocketEndpoint {
+    address: SocketAddr,
+    tls: Option<TlsServer>,
+}
+
+impl WebSocketEndpoint {
+
+    /// Serves the clients connecting to the address.
+    pub fn new( address: SocketAddr ) -> Self {
+        WebSocketEndpoint { address: address, tls: None }
+    }
+
+    /// Encrypts the sockets.
+    pub fn tls( mut self, tls: TlsServer ) -> Self {
+        self.tls = Some( tls );
+        self
+    }
+}
+
+impl<TService,
+        THostImplementation,
+        TSessionFactory>
+    serco::ServiceEndpoint<TService,
+        TSessionFactory,
+        THostImplementation,
+    >
+    for WebSocketEndpoint
+    where TService: serco::ServiceContract + ?Sized + 'static,
+          TSessionFactory: serco::SessionFactory + 'static,
+          THostImplementation: serco::HostedService<TService, SessionInfo=TSessionFactory::SessionInfo> + 'static,
+          THostImplementation::ServiceInstance: 'static,
+{
+    fn run(
+        &self,
+        host: Rc<serco::HostRuntime<
+                TService,
+                TSessionFactory,
+                THostImplementation,
+        >>
+    ) -> Box<Future<Item=(), Error=serco::ServiceError>>
+    {
+        let acceptor = match self.tls.as_ref().map( TlsServer::acceptor ) {
+            Some( Ok( acceptor ) ) => Some( acceptor ),
+            Some( Err( e ) ) => return Box::new( future::err( ServiceError::transport( e ) ) ),
+            None => None,
+        };
+        let listener = match std::net::TcpListener::bind( &self.address ) {
+            Ok( listener ) => listener,
+            Err( e ) => return Box::new( future::err( ServiceError::transport( e ) ) ),
+        };
+
+        let ( connections_tx, connections_rx ) = mpsc::unbounded();
+        let ( authenticate_tx, authenticate_rx ) = mpsc::unbounded::<Authentication>();
+        let limits = *host.message_limits();
+        let metrics = host.endpoint_metrics( ENDPOINT_KIND );
+        thread::spawn( move || accept(
+                listener, acceptor, limits, metrics, authenticate_tx, connections_tx ) );
+
+        // The socket thread asks the host to authenticate the handshakes
+        // before it accepts them.
+        let authenticator = host.clone();
+        let authentications = authenticate_rx
+            .for_each( move |( credentials, reply )| {
+                reply.send( authenticator.authenticate( &credentials ).map( |_| () ) ).ok();
+                Ok( () )
+            } )
+            .map_err( |_| ServiceError::transport( "The socket thread stopped" ) );
+
+        let limit = host.connection_limit();
+        let sessions = connections_rx
+            .map_err( |_| ServiceError::transport( "The socket thread stopped" ) )
+            .map( move |connection| peer::serve_session( &host, connection, &limit ) )
+            // Serve all connected sessions concurrently.
+            .buffer_unordered( usize::max_value() )
+            .for_each( |_| Ok( () ) );
+
+        Box::new( sessions.select( authentications )
+            .map( |_| () )
+            .map_err( |( e, _ )| e ) )
+    }
+
+    fn address( &self ) -> Option<serco::registry::EndpointAddress> {
+        let scheme = if self.tls.is_some() { "wss" } else { "ws" };
+        Some( serco::registry::EndpointAddress::new(
+                ENDPOINT_KIND, format!( "{}://{}", scheme, self.address ) ) )
+    }
+}
+
+/// Credentials passed to the host for authentication along with the channel
+/// for the result.
+type Authentication = ( Credentials, oneshot::Sender<Result<(), ServiceError>> );
+
+/// Accepts the connections on the socket thread and passes them to the host.
+fn accept(
+    listener: std::net::TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    limits: MessageLimits,
+    metrics: EndpointMetrics,
+    authenticate: mpsc::UnboundedSender<Authentication>,
+    connections: mpsc::UnboundedSender<peer::Connection>,
+) {
+    let mut core = Core::new().expect( "Failed to spawn socket core" );
+    let handle = core.handle();
+    let listener = match listener.local_addr()
+            .and_then( |address| TcpListener::from_listener( listener, &address, &handle ) ) {
+        Ok( listener ) => listener,
+        Err( _ ) => return,
+    };
+
+    let server = listener.incoming().for_each( move |( socket, _ )| {
+        let authenticate = authenticate.clone();
+        let connections = connections.clone();
+        let metrics = metrics.clone();
+        match acceptor {
+            Some( ref acceptor ) => handle.spawn( acceptor.accept( socket )
+                .map_err( |_| () )
+                .and_then( move |( socket, identity )| upgrade(
+                        socket, identity, limits, metrics, authenticate, connections ) ) ),
+            None => handle.spawn( upgrade(
+                    socket, None, limits, metrics, authenticate, connections ) ),
+        }
+        Ok( () )
+    } );
+    core.run( server ).ok();
+}
+
+/// Upgrades an accepted connection into a WebSocket and passes it to the
+/// host.
+///
+/// The upgrade is accepted only once the host has authenticated the
+/// credentials of the handshake.
+fn upgrade<S>(
+    socket: S,
+    identity: Option<PeerIdentity>,
+    limits: MessageLimits,
+    metrics: EndpointMetrics,
+    authenticate: mpsc::UnboundedSender<Authentication>,
+    connections: mpsc::UnboundedSender<peer::Connection>,
+) -> Box<Future<Item=(), Error=()>>
+    where S: AsyncRead + AsyncWrite + Send + 'static
+{
+    Box::new( socket.into_ws()
+        .map_err( |_| () )
+        .and_then( move |upgrade| {
+            let authorization = upgrade.request.headers.get_raw( "Authorization" )
+                    .and_then( |values| values.first() )
+                    .and_then( |value| std::str::from_utf8( value ).ok() )
+                    .and_then( Credentials::from_authorization );
+            let credentials = match ( authorization, identity ) {
+                ( Some( credentials ), _ ) => credentials,
+                ( None, Some( identity ) ) => Credentials::Certificate( identity ),
+                ( None, None ) => Credentials::Anonymous,
+            };
+            let ( reply, result ) = oneshot::channel();
+            future::result( authenticate.unbounded_send( ( credentials.clone(), reply ) ) )
+                .map_err( |_| () )
+                .and_then( |_| result.map_err( |_| () ) )
+                .map( move |result| ( upgrade, credentials, result ) )
+        } )
+        .and_then( move |( upgrade, credentials, result )| -> Box<Future<Item=(), Error=()>> {
+            if let Err( e ) = result {
+                return Box::new( tokio_io::io::write_all( upgrade.stream, unauthorized( &e ) )
+                    .map( |_| () )
+                    .map_err( |_| () ) );
+            }
+            Box::new( upgrade.accept()
+                .map_err( |_| () )
+                .and_then( move |( client, _ )| -> Box<Future<Item=(), Error=()>> {
+                    let ( mut connection, link ) = peer::connection();
+                    connection.credentials = credentials;
+                    match connections.unbounded_send( connection ) {
+                        Ok( _ ) => run_socket( link.message_limits( limits ).metrics( metrics ), client ),
+                        Err( _ ) => Box::new( future::ok( () ) ),
+                    }
+                } ) )
+        } ) )
+}
+
+/// The response to a handshake the host refused to authenticate.
+fn unauthorized( e: &ServiceError ) -> Vec<u8> {
+    format!( "HTTP/1.1 401 Unauthorized\r\n\
+              Content-Type: text/plain\r\n\
+              Content-Length: {}\r\n\
+              Connection: close\r\n\
+              \r\n\
+              {}",
+             e.message.len(), e.message ).into_bytes()
+}
+
+pub struct WebSocketClient {
+    url: String,
+    tls: Option<TlsClient>,
+    headers: Headers,
+}
+
+impl WebSocketClient {
+
+    /// Creates a client for the endpoint at the `ws://` URL.
+    pub fn new<T: Into<String>>( url: T ) -> WebSocketClient {
+        WebSocketClient { url: url.into(), tls: None, headers: Headers::new() }
+    }
+
+    /// Encrypts the socket. Used with the `wss://` URLs.
+    pub fn tls( mut self, tls: TlsClient ) -> Self {
+        self.tls = Some( tls );
+        self
+    }
+
+    /// Presents the credentials in the `Authorization` header of the
+    /// handshake. Only the tokens and the passwords can be presented this
+    /// way; the certificates are configured with `tls`.
+    pub fn credentials( mut self, credentials: &Credentials ) -> Self {
+        if let Some( authorization ) = credentials.to_authorization() {
+            self.headers.set_raw( "Authorization", vec![ authorization.into_bytes() ] );
+        }
+        self
+    }
+
+    pub fn connect<S>(
+        &self,
+    ) -> Box<Future<Item=WebSocketConnection<S>, Error=String>>
+        where S: serco::ServiceContract<CallbackContract = ()> + ?Sized + 'static,
+    {
+        self.connect_duplex::<S, (), ()>( () )
+    }
+
+    /// Connects to the endpoint. The host calls the `callback` over the same
+    /// socket.
+    pub fn connect_duplex<S, C, T>(
+        &self,
+        callback: T,
+    ) -> Box<Future<Item=WebSocketConnection<S>, Error=String>>
+        where S: serco::ServiceContract<CallbackContract = C> + ?Sized + 'static,
+            C: serco::ServiceContract<CallbackContract = ()> + ?Sized + 'static,
+            T: serco::InvokeTarget<C> + Send + 'static,
+    {
+        let ( connection, link ) = peer::connection();
+        let ( connected_tx, connected_rx ) = oneshot::channel();
+
+        let url = self.url.clone();
+        let tls = self.tls.clone();
+        let headers = self.headers.clone();
+        let socket_handle = thread::spawn( move || {
+            let mut core = Core::new().expect( "Failed to spawn socket core" );
+            let handle = core.handle();
+            let socket = match tls {
+                Some( tls ) => serve_connected(
+                        connect_tls( &url, &headers, tls, &handle ), link, connected_tx ),
+                None => serve_connected( connect( &url, &headers, &handle ), link, connected_tx ),
+            };
+            core.run( socket ).ok();
+        } );
+
+        let peer::Connection { requests, forwarder, .. } = connection;
+        let callback_handle = peer::spawn_callback_handler::<C, _>(
+                callback, requests, forwarder.sender() );
+
+        Box::new( connected_rx
+            .map_err( |_| "The socket thread stopped".to_string() )
+            .and_then( |result| result )
+            .map( move |_| WebSocketConnection {
+                proxy: serco::ServiceProxy::new( forwarder ),
+                _socket_handle: socket_handle,
+                _callback_handle: callback_handle,
+            } ) )
+    }
+}
+
+/// Connection to a WebSocket endpoint.
+///
+/// The connection is closed when it is dropped.
+pub struct WebSocketConnection<S: ?Sized> {
+    proxy: serco::ServiceProxy<S, peer::PeerForwarder>,
+    _socket_handle: thread::JoinHandle<()>,
+    _callback_handle: thread::JoinHandle<()>,
+}
+
+/// Dereference the connection into the service proxy.
+/// The proxy implements the actual service trait.
+impl<S: serco::ServiceContract + ?Sized> std::ops::Deref for WebSocketConnection<S>
+{
+    type Target = serco::ServiceProxy<S, peer::PeerForwarder>;
+
+    fn deref( &self ) -> &Self::Target {
+        &self.proxy
+    }
+}
+
+fn connect(
+    url: &str,
+    headers: &Headers,
+    handle: &Handle,
+) -> Box<Future<Item=Client<TcpStream>, Error=String>>
+{
+    match ClientBuilder::new( url ) {
+        Ok( builder ) => Box::new( builder.custom_headers( headers ).async_connect_insecure( handle )
+            .map( |( client, _ )| client )
+            .map_err( |e| format!( "{:?}", e ) ) ),
+        Err( e ) => Box::new( future::err( format!( "{:?}", e ) ) ),
+    }
+}
+
+/// Connects to the host named in the URL and completes the TLS handshake
+/// before the WebSocket handshake.
+fn connect_tls(
+    url: &str,
+    headers: &Headers,
+    tls: TlsClient,
+    handle: &Handle,
+) -> Box<Future<Item=Client<ClientStream<TcpStream>>, Error=String>>
+{
+    let builder = match ClientBuilder::new( url ) {
+        Ok( builder ) => builder.custom_headers( headers ),
+        Err( e ) => return Box::new( future::err( format!( "{:?}", e ) ) ),
+    };
+    let url = match Url::parse( url ) {
+        Ok( url ) => url,
+        Err( e ) => return Box::new( future::err( format!( "{:?}", e ) ) ),
+    };
+    let host = match url.host_str() {
+        Some( host ) => host.to_string(),
+        None => return Box::new( future::err( "The URL has no host".to_string() ) ),
+    };
+    let addresses = match ( host.as_str(), url.port_or_known_default().unwrap_or( 443 ) )
+            .to_socket_addrs() {
+        Ok( addresses ) => addresses,
+        Err( e ) => return Box::new( future::err( format!( "{:?}", e ) ) ),
+    };
+
+    // Try the addresses in turn as the names such as localhost may resolve
+    // to addresses the host doesn't listen on.
+    let unresolved = io::Error::new( io::ErrorKind::NotFound, "No addresses" );
+    let handle = handle.clone();
+    let socket = addresses.fold(
+            Box::new( future::err( unresolved ) ) as Box<Future<Item=TcpStream, Error=io::Error>>,
+            |previous, address| {
+                let handle = handle.clone();
+                Box::new( previous.or_else( move |_| TcpStream::connect( &address, &handle ) ) )
+            } );
+
+    Box::new( socket
+        .and_then( move |socket| tls.connect( &host, socket ) )
+        .map_err( |e| format!( "{:?}", e ) )
+        .and_then( move |stream| builder.async_connect_on( stream )
+            .map( |( client, _ )| client )
+            .map_err( |e| format!( "{:?}", e ) ) ) )
+}
+
+/// Reports whether the client connected and serves the socket once it has.
+fn serve_connected<S, F>(
+    connect: F,
+    link: peer::Link,
+    connected: oneshot::Sender<Result<(), String>>,
+) -> Box<Future<Item=(), Error=()>>
+    where S: AsyncRead + AsyncWrite + 'static,
+          F: Future<Item=Client<S>, Error=String> + 'static,
+{
+    Box::new( connect.then( move |result| -> Box<Future<Item=(), Error=()>> {
+        match result {
+            Ok( client ) => {
+                connected.send( Ok( () ) ).ok();
+                run_socket( link, client )
+            },
+            Err( e ) => {
+                connected.send( Err( e ) ).ok();
+                Box::new( future::ok( () ) )
+            },
+        }
+    } ) )
+}
+
+/// Passes the messages between the socket and the connection.
+fn run_socket<S>(
+    link: peer::Link,
+    client: Client<S>,
+) -> Box<Future<Item=(), Error=()>>
+    where S: AsyncRead + AsyncWrite + 'static
+{
+    let ( sink, stream ) = client.split();
+
+    // The pings are answered on the same socket as the messages.
+    let ( frames_tx, frames_rx ) = mpsc::unbounded();
+    let pongs = frames_tx.clone();
+    let incoming = stream
+        .take_while( |message| Ok( !message.is_close() ) )
+        .filter_map( move |message| match message {
+            OwnedMessage::Text( text ) => Some( text ),
+            OwnedMessage::Ping( data ) => {
+                pongs.unbounded_send( OwnedMessage::Pong( data ) ).ok();
+                None
+            },
+            _ => None,
+        } );
+    let outgoing = frames_tx.with( |text|
+            Ok::<_, mpsc::SendError<OwnedMessage>>( OwnedMessage::Text( text ) ) );
+    let frames = frames_rx
+        .forward( sink.sink_map_err( |_| () ) )
+        .map( |_| () );
+
+    Box::new( link.run( incoming, outgoing )
+        .select( frames )
+        .then( |_| Ok::<(), ()>( () ) ) )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use serco_derive::*;
+    use std::time::Duration;
+
+    const CA : &[u8] = include_bytes!( "../../serco_tls/test-certs/ca.pem" );
+    const SERVER_CERT : &[u8] = include_bytes!( "../../serco_tls/test-certs/server.pem" );
+    const SERVER_KEY : &[u8] = include_bytes!( "../../serco_tls/test-certs/server.key" );
+    const CLIENT_CERT : &[u8] = include_bytes!( "../../serco_tls/test-certs/client.pem" );
+    const CLIENT_KEY : &[u8] = include_bytes!( "../../serco_tls/test-certs/client.key" );
+
+    #[service_contract( callback = Listener )]
+    pub trait Chat {
+        fn say( &self, message: String ) -> String;
+        fn add( &self, a: i32, b: i32 ) -> i32;
+    }
+
+    #[service_contract]
+    pub trait Listener {
+        fn hear( &self, message: String ) -> String;
+    }
+
+    #[service(Chat)]
+    struct ChatService;
+    impl Chat for ChatService {
+        fn say( &self, message: String ) -> String {
+            let listener = Chat::get_callback();
+            format!( "< {} >", listener.hear( message ) )
+        }
+
+        fn add( &self, a: i32, b: i32 ) -> i32 { a + b }
+    }
+
+    #[service(Listener)]
+    struct LoudListener;
+    impl Listener for LoudListener {
+        fn hear( &self, message: String ) -> String {
+            message.to_uppercase()
+        }
+    }
+
+    #[service_contract]
+    pub trait Calculator {
+        fn add( &self, a: i32, b: i32 ) -> i32;
+    }
+
+    #[service(Calculator)]
+    struct CalculatorService;
+    impl Calculator for CalculatorService {
+        fn add( &self, a: i32, b: i32 ) -> i32 { a + b }
+    }
+
+    #[test]
+    pub fn loopback() {
+        let address : SocketAddr = "127.0.0.1:50561".parse().unwrap();
+        thread::spawn( move || {
+            let host = serco::ServiceHost::new( Calculator::singleton( CalculatorService ) )
+                    .endpoint( WebSocketEndpoint::new( address ) )
+                    .run();
+            Core::new().unwrap().run( host ).ok();
+        } );
+        thread::sleep( Duration::from_millis( 100 ) );
+
+        let connection = WebSocketClient::new( format!( "ws://{}", address ) )
+                .connect::<Calculator>()
+                .wait()
+                .unwrap();
+        assert_eq!( connection.add( 1, 2 ), 3 );
+        assert_eq!( connection.add( 3, 4 ), 7 );
+    }
+
+    #[test]
+    pub fn duplex() {
+        let address : SocketAddr = "127.0.0.1:50562".parse().unwrap();
+        thread::spawn( move || {
+            let host = serco::ServiceHost::new( Chat::singleton( ChatService ) )
+                    .endpoint( WebSocketEndpoint::new( address ) )
+                    .run();
+            Core::new().unwrap().run( host ).ok();
+        } );
+        thread::sleep( Duration::from_millis( 100 ) );
+
+        let connection = WebSocketClient::new( format!( "ws://{}", address ) )
+                .connect_duplex::<Chat, _, _>( LoudListener )
+                .wait()
+                .unwrap();
+        assert_eq!( connection.say( "hello".to_string() ), "< HELLO >" );
+        assert_eq!( connection.add( 1, 2 ), 3 );
+        assert_eq!( connection.say( "again".to_string() ), "< AGAIN >" );
+    }
+
+    #[test]
+    pub fn tls() {
+        let address : SocketAddr = "127.0.0.1:50563".parse().unwrap();
+        thread::spawn( move || {
+            let server = TlsServer::from_pem( SERVER_CERT, SERVER_KEY ).unwrap()
+                    .client_certificates( CA ).unwrap()
+                    .require_client_certificate( true );
+            let host = serco::ServiceHost::new( Calculator::singleton( CalculatorService ) )
+                    .endpoint( WebSocketEndpoint::new( address ).tls( server ) )
+                    .run();
+            Core::new().unwrap().run( host ).ok();
+        } );
+        thread::sleep( Duration::from_millis( 100 ) );
+
+        // The host refuses the clients without certificates.
+        let url = format!( "wss://localhost:{}", address.port() );
+        let tls = TlsClient::new( CA ).unwrap();
+        assert!( WebSocketClient::new( url.clone() )
+                .tls( tls.clone() )
+                .connect::<Calculator>()
+                .wait()
+                .is_err() );
+
+        let tls = tls.client_certificate( CLIENT_CERT, CLIENT_KEY ).unwrap();
+        let connection = WebSocketClient::new( url )
+                .tls( tls )
+                .connect::<Calculator>()
+                .wait()
+                .unwrap();
+        assert_eq!( connection.add( 1, 2 ), 3 );
+    }
+
+    #[test]
+    pub fn authentication() {
+        let address : SocketAddr = "127.0.0.1:50564".parse().unwrap();
+        thread::spawn( move || {
+            let host = serco::ServiceHost::new( Calculator::singleton( CalculatorService ) )
+                    .authenticator( |credentials: &Credentials| match *credentials {
+                        Credentials::Password { ref username, ref password }
+                                if password == "secret" => Ok( serco::Principal::new( username.clone() ) ),
+                        _ => Err( ServiceError::unauthenticated( "Wrong password" ) ),
+                    } )
+                    .endpoint( WebSocketEndpoint::new( address ) )
+                    .run();
+            Core::new().unwrap().run( host ).ok();
+        } );
+        thread::sleep( Duration::from_millis( 100 ) );
+
+        let connect = |password: &str| WebSocketClient::new( format!( "ws://{}", address ) )
+                .credentials( &Credentials::Password {
+                    username: "alice".to_string(),
+                    password: password.to_string(),
+                } )
+                .connect::<Calculator>()
+                .wait();
+
+        let connection = connect( "secret" ).unwrap();
+        assert_eq!( connection.add( 1, 2 ), 3 );
+
+        // The refused handshakes are answered with an HTTP error instead of
+        // the upgrade.
+        assert!( connect( "guess" ).is_err() );
+
+        let mut socket = std::net::TcpStream::connect( address ).unwrap();
+        socket.write_all( b"GET / HTTP/1.1\r\n\
+                            Host: localhost\r\n\
+                            Connection: Upgrade\r\n\
+                            Upgrade: websocket\r\n\
+                            Sec-WebSocket-Version: 13\r\n\
+                            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                            Authorization: Basic YWxpY2U6Z3Vlc3M=\r\n\
+                            \r\n" ).unwrap();
+        let mut response = String::new();
+        socket.read_to_string( &mut response ).unwrap();
+        assert!( response.starts_with( "HTTP/1.1 401 Unauthorized\r\n" ) );
+        assert!( response.ends_with( "Wrong password" ) );
+    }
+}